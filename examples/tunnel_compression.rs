@@ -0,0 +1,166 @@
+//! Compare compression modes of tunneled slots on HTTP-like traffic.
+//!
+//! cargo run --release --example tunnel_compression
+
+use exogress_common::tunnel::{Compression, Compressor, Decompressor};
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 20;
+
+/// Requests and responses of a keep-alive connection, split into frames as they are
+/// read from the socket
+fn frames(frame_len: usize) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+
+    for i in 0..200u64 {
+        let request = format!(
+            "GET /api/v1/items/{id}?page={page} HTTP/1.1\r\n\
+             host: app.example.com\r\n\
+             user-agent: Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko)\r\n\
+             accept: application/json\r\n\
+             cookie: session={session:016x}; theme=dark\r\n\
+             x-request-id: {request_id:032x}\r\n\r\n",
+            id = i * 31 % 97,
+            page = i % 7,
+            session = 0x5eed_u64.wrapping_mul(i / 50 + 1),
+            request_id = i.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+        );
+        frames.push(request.into_bytes());
+
+        let body = (0..(i % 20 + 1))
+            .map(|n| {
+                format!(
+                    r#"{{"id":{},"name":"item-{}","price":{}.{:02},"tags":["new","sale"]}}"#,
+                    i * 100 + n,
+                    n,
+                    (i + n) % 500,
+                    n * 7 % 100
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             content-type: application/json\r\n\
+             content-length: {}\r\n\
+             cache-control: no-cache\r\n\
+             x-request-id: {:032x}\r\n\r\n[{}]",
+            body.len() + 2,
+            i.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+            body
+        );
+        frames.extend(response.as_bytes().chunks(frame_len).map(|c| c.to_vec()));
+    }
+
+    frames
+}
+
+struct Measurement {
+    original: usize,
+    compressed: usize,
+    compress_time: Duration,
+    decompress_time: Duration,
+}
+
+/// Frames of an already compressed body, e.g. an image
+fn incompressible_frames(frame_len: usize) -> Vec<Vec<u8>> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..200)
+        .map(|_| {
+            (0..frame_len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn measure(compression: Compression, adaptive: bool, frames: &[Vec<u8>]) -> Measurement {
+    let mut measurement = Measurement {
+        original: 0,
+        compressed: 0,
+        compress_time: Duration::default(),
+        decompress_time: Duration::default(),
+    };
+
+    for _ in 0..ITERATIONS {
+        // new slot on each iteration
+        let mut compressor = if adaptive {
+            Compressor::adaptive(compression)
+        } else {
+            Compressor::new(compression)
+        };
+        let mut decompressor = Decompressor::new(compression);
+
+        for frame in frames {
+            let started_at = Instant::now();
+            let (compressed, is_compressed) = compressor.compress(frame.clone());
+            measurement.compress_time += started_at.elapsed();
+
+            measurement.original += frame.len();
+            measurement.compressed += compressed.len();
+
+            if is_compressed {
+                let started_at = Instant::now();
+                let decompressed = decompressor.decompress(compressed).unwrap();
+                measurement.decompress_time += started_at.elapsed();
+                assert_eq!(&decompressed, frame);
+            }
+        }
+    }
+
+    measurement
+}
+
+fn throughput(bytes: usize, time: Duration) -> String {
+    if time == Duration::default() {
+        return "-".to_string();
+    }
+    format!("{:.1}", bytes as f64 / 1024.0 / 1024.0 / time.as_secs_f64())
+}
+
+fn report(frames: &[Vec<u8>]) {
+    println!(
+        "{:>20} {:>8} {:>16} {:>18}",
+        "mode", "ratio", "compress MB/s", "decompress MB/s"
+    );
+
+    for &adaptive in &[false, true] {
+        for &compression in &[
+            Compression::Plain,
+            Compression::Zstd,
+            Compression::ZstdStream,
+            Compression::Lz4,
+            Compression::Brotli,
+        ] {
+            let m = measure(compression, adaptive, frames);
+            let mode = if adaptive {
+                format!("{:?} (adaptive)", compression)
+            } else {
+                format!("{:?}", compression)
+            };
+            println!(
+                "{:>20} {:>8.2} {:>16} {:>18}",
+                mode,
+                m.original as f64 / m.compressed as f64,
+                throughput(m.original, m.compress_time),
+                throughput(m.original, m.decompress_time),
+            );
+        }
+    }
+    println!();
+}
+
+fn main() {
+    for &frame_len in &[512, 4096, 16384] {
+        println!("response frames up to {} bytes", frame_len);
+        report(&frames(frame_len));
+    }
+
+    println!("incompressible frames of 16384 bytes");
+    report(&incompressible_frames(16384));
+}
@@ -29,9 +29,10 @@ use tracing_futures::Instrument;
 
 use crate::{
     access_tokens::generate_jwt_token,
-    client_core::{health::UpstreamsHealth, internal_server::internal_server},
+    client_core::{health::UpstreamsHealth, internal_server::internal_server, TunnelsStats},
     common_utils::backoff::Backoff,
     config_core::DEFAULT_CONFIG_FILE,
+    tunnel::{TunnelSettings, UpstreamBalancer},
 };
 use dashmap::DashMap;
 use derive_builder::Builder;
@@ -81,6 +82,14 @@ pub struct Client {
 
     #[builder(setter(into), default = "Default::default()")]
     pub additional_connection_params: HashMap<SmolStr, SmolStr>,
+
+    /// Keepalive and timeouts of tunnels
+    #[builder(default = "Default::default()")]
+    pub tunnel_settings: TunnelSettings,
+
+    /// Stats of the established tunnels. Clone it before `spawn` to read them
+    #[builder(default = "Default::default()")]
+    pub tunnels_stats: TunnelsStats,
 }
 
 impl ClientBuilder {
@@ -113,6 +122,8 @@ impl Client {
         mut reload_config_rx: mpsc::UnboundedReceiver<()>,
         resolver: TokioAsyncResolver,
     ) -> anyhow::Result<()> {
+        self.tunnel_settings.validate()?;
+
         let project_name: ProjectName = self.project.parse()?;
         let account_name: AccountName = self.account.parse()?;
         let maybe_identity = self.maybe_identity.clone();
@@ -193,9 +204,12 @@ impl Client {
 
         let profile = self.profile;
 
+        let upstream_balancer = UpstreamBalancer::default();
+
         let upstream_health_checkers = UpstreamsHealth::new(
             &client_config,
             health_update_tx,
+            upstream_balancer.clone(),
             &profile,
             tokio::runtime::Handle::current(),
         )?;
@@ -377,6 +391,8 @@ impl Client {
                 let secret_access_key = self.secret_access_key;
                 let gw_tunnels_port = self.gw_tunnels_port;
                 let additional_connection_params = self.additional_connection_params;
+                let tunnel_settings = self.tunnel_settings;
+                let tunnels_stats = self.tunnels_stats;
 
                 async move {
                     while let Some(incoming_msg) = send_rx.next().await {
@@ -407,8 +423,10 @@ impl Client {
                                             current_config,
                                             tunnels,
                                             resolver,
+                                            upstream_balancer,
                                             mut internal_server_connector,
-                                            additional_connection_params
+                                            additional_connection_params,
+                                            tunnels_stats
                                         );
 
                                             {
@@ -459,6 +477,9 @@ impl Client {
                                                                         &additional_connection_params,
                                                                         internal_server_connector.clone(),
                                                                         resolver.clone(),
+                                                                        upstream_balancer.clone(),
+                                                                        tunnel_settings,
+                                                                        &tunnels_stats,
                                                                     )
                                                                         .await;
                                                                     match tunnel_spawn_result {
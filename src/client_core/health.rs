@@ -1,35 +1,68 @@
 //! Upstream healthchecks
 
 use crate::{
-    config_core::{is_profile_active, ClientConfig, Probe, UpstreamDefinition},
-    entities::{HealthCheckProbeName, ProfileName, Upstream},
+    config_core::{
+        is_profile_active, BodyMatcher, ClientConfig, ExecProbe, Probe, ProbeError,
+        UpstreamDefinition, UpstreamTls, CIRCUIT_BREAKER_PROBE_NAME,
+    },
+    entities::{HealthCheckProbeName, ProfileName, SmolStr, Upstream},
     signaling::{ProbeHealthStatus, UnhealthyReason},
+    tunnel::{
+        CircuitState, EndpointAddr, EndpointStream, UpstreamBalancer, UpstreamTlsConnector,
+        UpstreamTlsConnectorError,
+    },
 };
 use core::mem;
 use futures::{
     channel::{mpsc, oneshot},
-    SinkExt,
+    future, SinkExt, StreamExt,
 };
 use hashbrown::{HashMap, HashSet};
-use http::Request;
-use hyper::{client::HttpConnector, Body};
+use http::{header::HOST, HeaderValue, Request, Response};
+use hyper::{
+    body::HttpBody,
+    client::{conn, HttpConnector},
+    Body,
+};
 use parking_lot::Mutex;
 use shadow_clone::shadow_clone;
-use std::sync::Arc;
-use tokio::runtime::Handle;
-use tracing::{error, span, Level};
+use std::{path::PathBuf, process::Stdio, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    process::Command,
+    runtime::Handle,
+};
+use tracing::{error, info, span, Level};
 use tracing_futures::Instrument;
 use url::Url;
 
+/// Responses with larger bodies don't match the expected body
+const MAX_PROBE_BODY_SIZE: usize = 64 * 1024;
+
 pub struct HealthCheckProbeInner {
     probe: Probe,
+    expected_body: Option<BodyMatcher>,
     upstream: Upstream,
-    probe_url: Url,
+    probe_targets: Vec<ProbeTarget>,
+    tls: Option<(UpstreamTls, UpstreamTlsConnector)>,
 
     status: ProbeHealthStatus,
     probe_name: HealthCheckProbeName,
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum HealthCheckError {
+    #[error("bad probe URL: {0}")]
+    Url(#[from] url::ParseError),
+
+    #[error("upstream TLS error: {0}")]
+    Tls(#[from] UpstreamTlsConnectorError),
+
+    #[error("bad probe: {0}")]
+    Probe(#[from] ProbeError),
+}
+
 #[derive(Debug)]
 pub struct ProbeStatusUpdate {
     pub upstream: Upstream,
@@ -53,6 +86,7 @@ impl ProbeStatusUpdate {
 pub struct HealthCheckProbe {
     inner: Arc<Mutex<HealthCheckProbeInner>>,
     update_tx: mpsc::Sender<ProbeStatusUpdate>,
+    balancer: UpstreamBalancer,
     handle: Handle,
     _stop_tx: oneshot::Sender<()>,
 }
@@ -60,14 +94,24 @@ pub struct HealthCheckProbe {
 pub async fn start_checker(
     probe_inner: Arc<Mutex<HealthCheckProbeInner>>,
     update_tx: mpsc::Sender<ProbeStatusUpdate>,
+    balancer: UpstreamBalancer,
     stop_rx: oneshot::Receiver<()>,
     hyper_client: hyper::Client<HttpConnector>,
 ) {
     let locked = probe_inner.lock();
 
-    let mut interval = tokio::time::interval(locked.probe.period.0);
+    let first_check_at = tokio::time::Instant::now()
+        + locked
+            .probe
+            .initial_delay
+            .as_ref()
+            .map(|delay| delay.0)
+            .unwrap_or_default();
+    let mut interval = tokio::time::interval_at(first_check_at, locked.probe.period.0);
     let probe = locked.probe.clone();
-    let url = locked.probe_url.clone();
+    let expected_body = locked.expected_body.clone();
+    let targets = locked.probe_targets.clone();
+    let tls_connector = locked.tls.as_ref().map(|(_, connector)| connector.clone());
     mem::drop(locked);
 
     let probe_inner = probe_inner.clone();
@@ -85,64 +129,112 @@ pub async fn start_checker(
 
                     #[allow(unreachable_code)]
                     async move {
+                        let mut trackers = HashMap::<SmolStr, ThresholdTracker>::new();
+                        let mut exec_tracker = ThresholdTracker::default();
+
+                        let report = |source: &str, status: &ProbeHealthStatus| {
+                            let is_failed = matches!(status, ProbeHealthStatus::Unhealthy { .. });
+                            if probe.is_readiness() {
+                                balancer.set_endpoint_readiness(
+                                    &upstream,
+                                    &probe_name,
+                                    source,
+                                    !is_failed,
+                                );
+                            } else {
+                                balancer.set_endpoint_health(
+                                    &upstream,
+                                    &probe_name,
+                                    source,
+                                    !is_failed,
+                                );
+                            }
+                        };
+
                         loop {
                             interval.tick().await;
-                            let mut health_request = Request::builder()
-                                .uri(url.as_str())
-                                .method(&probe.method.0)
-                                .body(Body::empty())
-                                .unwrap();
-
-                            *health_request.headers_mut() = probe.headers.0.clone();
 
                             let was_status = probe_inner.lock().status.clone();
 
-                            let res = tokio::time::timeout(
-                                probe.timeout.0,
-                                hyper_client.request(health_request),
-                            )
-                            .await;
-
-                            {
-                                let mut probe_locked = probe_inner.lock();
-                                match res {
-                                    Ok(Ok(res)) => {
-                                        let status_code = res.status();
-                                        if !probe.expected_status_code.is_belongs(&status_code) {
-                                            probe_locked.status = ProbeHealthStatus::Unhealthy {
-                                                reason: UnhealthyReason::BadStatus {
-                                                    status: res.status(),
-                                                },
-                                            };
-                                        } else {
-                                            probe_locked.status = ProbeHealthStatus::Healthy;
-                                        }
-                                    }
-                                    Ok(Err(e)) => {
-                                        probe_locked.status = ProbeHealthStatus::Unhealthy {
-                                            reason: UnhealthyReason::RequestError {
-                                                err: e.to_string(),
-                                            },
-                                        };
+                            let statuses = match &probe.exec {
+                                Some(exec) => {
+                                    let result =
+                                        tokio::time::timeout(probe.timeout.0, exec_probe(exec))
+                                            .await
+                                            .unwrap_or(ProbeHealthStatus::Unhealthy {
+                                                reason: UnhealthyReason::Timeout,
+                                            });
+                                    let status = exec_tracker.observe(&probe, result).clone();
+
+                                    // the command checks the upstream as a whole
+                                    for target in &targets {
+                                        report(&target.source, &status);
                                     }
-                                    Err(_) => {
-                                        probe_locked.status = ProbeHealthStatus::Unhealthy {
+                                    vec![status]
+                                }
+                                None => {
+                                    let results = future::join_all(targets.iter().map(|target| {
+                                        let res = tokio::time::timeout(
+                                            probe.timeout.0,
+                                            check_target(
+                                                &probe,
+                                                expected_body.as_ref(),
+                                                &hyper_client,
+                                                tls_connector.as_ref(),
+                                                target,
+                                            ),
+                                        );
+
+                                        async move { (&target.source, res.await) }
+                                    }))
+                                    .await;
+
+                                    let mut statuses = Vec::with_capacity(results.len());
+                                    for (source, res) in results {
+                                        let result = res.unwrap_or(ProbeHealthStatus::Unhealthy {
                                             reason: UnhealthyReason::Timeout,
-                                        };
+                                        });
+                                        let status = trackers
+                                            .entry(source.clone())
+                                            .or_default()
+                                            .observe(&probe, result)
+                                            .clone();
+
+                                        report(source, &status);
+                                        statuses.push(status);
                                     }
+                                    statuses
                                 }
-                            }
+                            };
+
+                            // upstream is healthy while at least one of its endpoints is
+                            probe_inner.lock().status = statuses
+                                .iter()
+                                .find(|status| **status == ProbeHealthStatus::Healthy)
+                                .or_else(|| {
+                                    statuses
+                                        .iter()
+                                        .find(|status| **status == ProbeHealthStatus::Unknown)
+                                })
+                                .or_else(|| statuses.first())
+                                .cloned()
+                                .unwrap_or_default();
 
                             let new_status = probe_inner.lock().status.clone();
 
                             if was_status != new_status {
-                                update_tx
-                                    .send(ProbeStatusUpdate {
-                                        upstream: upstream.clone(),
-                                        probe: probe_name.clone(),
-                                        status: Some(new_status),
-                                    })
-                                    .await?;
+                                let update = ProbeStatusUpdate {
+                                    upstream: upstream.clone(),
+                                    probe: probe_name.clone(),
+                                    status: Some(new_status),
+                                };
+                                if probe.is_readiness() {
+                                    // readiness only stops new connections, it's not reported
+                                    // to the cloud
+                                    info!(status = %update.status_desc(), "readiness updated");
+                                } else {
+                                    update_tx.send(update).await?;
+                                }
                             }
                         }
 
@@ -169,6 +261,257 @@ pub async fn start_checker(
     );
 }
 
+fn circuit_open_status() -> ProbeHealthStatus {
+    ProbeHealthStatus::Unhealthy {
+        reason: UnhealthyReason::CircuitOpen,
+    }
+}
+
+/// Applies success and failure thresholds to the results of the probe
+#[derive(Debug, Default)]
+struct ThresholdTracker {
+    successes: u32,
+    failures: u32,
+    status: ProbeHealthStatus,
+}
+
+impl ThresholdTracker {
+    /// Record the result of the check and return the resulting status
+    fn observe(&mut self, probe: &Probe, result: ProbeHealthStatus) -> &ProbeHealthStatus {
+        if result == ProbeHealthStatus::Healthy {
+            self.failures = 0;
+            self.successes = self.successes.saturating_add(1);
+            if self.successes >= probe.success_threshold {
+                self.status = result;
+            }
+        } else {
+            self.successes = 0;
+            self.failures = self.failures.saturating_add(1);
+            if self.failures >= probe.failure_threshold {
+                self.status = result;
+            }
+        }
+
+        &self.status
+    }
+}
+
+/// Check one of upstream addresses
+async fn check_target(
+    probe: &Probe,
+    expected_body: Option<&BodyMatcher>,
+    hyper_client: &hyper::Client<HttpConnector>,
+    tls_connector: Option<&UpstreamTlsConnector>,
+    target: &ProbeTarget,
+) -> ProbeHealthStatus {
+    let res = if probe.tcp {
+        connect_target(target)
+            .await
+            .map(|_| ProbeHealthStatus::Healthy)
+    } else {
+        http_probe(probe, expected_body, hyper_client, tls_connector, target).await
+    };
+
+    res.unwrap_or_else(|e| ProbeHealthStatus::Unhealthy {
+        reason: UnhealthyReason::RequestError { err: e.to_string() },
+    })
+}
+
+async fn http_probe(
+    probe: &Probe,
+    expected_body: Option<&BodyMatcher>,
+    hyper_client: &hyper::Client<HttpConnector>,
+    tls_connector: Option<&UpstreamTlsConnector>,
+    target: &ProbeTarget,
+) -> anyhow::Result<ProbeHealthStatus> {
+    let mut health_request = Request::builder()
+        .uri(target.url.as_str())
+        .method(&probe.method.0)
+        .body(Body::empty())?;
+
+    *health_request.headers_mut() = probe.headers.0.clone();
+
+    let res = send_probe(hyper_client, tls_connector, target, health_request).await?;
+
+    let status = res.status();
+    if !probe.expected_status_code.is_belongs(&status) {
+        return Ok(ProbeHealthStatus::Unhealthy {
+            reason: UnhealthyReason::BadStatus { status },
+        });
+    }
+
+    if let Some(expected_body) = expected_body {
+        let unexpected_body = ProbeHealthStatus::Unhealthy {
+            reason: UnhealthyReason::UnexpectedBody,
+        };
+
+        let mut body = res.into_body();
+        let mut buf = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if buf.len() + chunk.len() > MAX_PROBE_BODY_SIZE {
+                return Ok(unexpected_body);
+            }
+            buf.extend_from_slice(&chunk);
+        }
+
+        if !expected_body.is_match(&buf) {
+            return Ok(unexpected_body);
+        }
+    }
+
+    Ok(ProbeHealthStatus::Healthy)
+}
+
+/// Run the local command, output is discarded
+async fn exec_probe(exec: &ExecProbe) -> ProbeHealthStatus {
+    let (program, args) = match exec.command.split_first() {
+        Some(command) => command,
+        None => {
+            return ProbeHealthStatus::Unhealthy {
+                reason: UnhealthyReason::RequestError {
+                    err: "empty command".to_string(),
+                },
+            }
+        }
+    };
+
+    let res = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await;
+
+    match res {
+        Ok(status) if status.success() => ProbeHealthStatus::Healthy,
+        Ok(status) => ProbeHealthStatus::Unhealthy {
+            reason: UnhealthyReason::BadExitCode {
+                code: status.code(),
+            },
+        },
+        Err(e) => ProbeHealthStatus::Unhealthy {
+            reason: UnhealthyReason::RequestError { err: e.to_string() },
+        },
+    }
+}
+
+async fn connect_target(target: &ProbeTarget) -> anyhow::Result<EndpointStream> {
+    let url = &target.url;
+    Ok(match &target.unix_socket {
+        Some(path) => EndpointAddr::Unix(path.clone()).connect().await?,
+        None => Box::new(
+            TcpStream::connect((
+                url.host_str().unwrap_or_default(),
+                url.port_or_known_default().unwrap_or(443),
+            ))
+            .await?,
+        ),
+    })
+}
+
+/// Send the probe request. Requests over TLS or Unix socket use a dedicated connection
+async fn send_probe(
+    hyper_client: &hyper::Client<HttpConnector>,
+    tls_connector: Option<&UpstreamTlsConnector>,
+    target: &ProbeTarget,
+    mut request: Request<Body>,
+) -> anyhow::Result<Response<Body>> {
+    if tls_connector.is_none() && target.unix_socket.is_none() {
+        return Ok(hyper_client.request(request).await?);
+    }
+
+    let url = &target.url;
+    let stream = connect_target(target).await?;
+
+    *request.uri_mut() = url[url::Position::BeforePath..].parse()?;
+    if !request.headers().contains_key(HOST) {
+        request.headers_mut().insert(
+            HOST,
+            HeaderValue::from_str(&url[url::Position::BeforeHost..url::Position::AfterPort])?,
+        );
+    }
+
+    match tls_connector {
+        Some(tls_connector) => {
+            let host = url.host_str().unwrap_or_default();
+            send_over(tls_connector.connect(host, stream).await?, request).await
+        }
+        None => send_over(stream, request).await,
+    }
+}
+
+async fn send_over<T>(stream: T, request: Request<Body>) -> anyhow::Result<Response<Body>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut send_request, connection) = conn::handshake(stream).await?;
+    tokio::spawn(connection);
+
+    Ok(send_request.send_request(request).await?)
+}
+
+/// Where the probe is sent for one of upstream addresses
+#[derive(Debug, Clone, PartialEq)]
+struct ProbeTarget {
+    /// Configured address of the upstream
+    source: SmolStr,
+    url: Url,
+    unix_socket: Option<PathBuf>,
+}
+
+fn probe_targets(
+    probe: &Probe,
+    upstream_definition: &UpstreamDefinition,
+) -> Result<Vec<ProbeTarget>, url::ParseError> {
+    let scheme = if upstream_definition.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+
+    upstream_definition
+        .all_addrs()
+        .map(|addr| {
+            let authority = match &addr.unix {
+                Some(_) => "localhost".to_string(),
+                None => format!("{}:{}", addr.get_host(), addr.port),
+            };
+            Ok(ProbeTarget {
+                source: addr.to_string().into(),
+                url: format!(
+                    "{}://{}{}",
+                    scheme,
+                    authority,
+                    probe.path.as_deref().unwrap_or("/")
+                )
+                .parse()?,
+                unix_socket: addr.unix.as_ref().map(PathBuf::from),
+            })
+        })
+        .collect()
+}
+
+fn tls_connector(
+    upstream_definition: &UpstreamDefinition,
+) -> Result<Option<(UpstreamTls, UpstreamTlsConnector)>, UpstreamTlsConnectorError> {
+    upstream_definition
+        .tls
+        .as_ref()
+        .map(|tls| Ok((tls.clone(), UpstreamTlsConnector::new(tls)?)))
+        .transpose()
+}
+
+fn body_matcher(probe: &Probe) -> Result<Option<BodyMatcher>, ProbeError> {
+    probe
+        .expected_body
+        .as_ref()
+        .map(|expected_body| expected_body.compile())
+        .transpose()
+}
+
 impl HealthCheckProbe {
     pub fn new(
         probe_name: HealthCheckProbeName,
@@ -176,27 +519,27 @@ impl HealthCheckProbe {
         upstream: Upstream,
         upstream_definition: UpstreamDefinition,
         update_tx: mpsc::Sender<ProbeStatusUpdate>,
+        balancer: UpstreamBalancer,
         handle: Handle,
-    ) -> Result<Self, url::ParseError> {
+    ) -> Result<Self, HealthCheckError> {
         let (stop_tx, stop_rx) = oneshot::channel();
 
-        let probe_url: Url = format!(
-            "http://{}:{}{}",
-            upstream_definition.get_host(),
-            upstream_definition.addr.port,
-            probe.path
-        )
-        .parse()?;
+        let probe_targets = probe_targets(&probe, &upstream_definition)?;
+        let tls = tls_connector(&upstream_definition)?;
+        let expected_body = body_matcher(&probe)?;
 
         let probe = HealthCheckProbe {
             inner: Arc::new(Mutex::new(HealthCheckProbeInner {
                 probe,
+                expected_body,
                 upstream,
-                probe_url,
+                probe_targets,
+                tls,
                 status: ProbeHealthStatus::default(),
                 probe_name,
             })),
             update_tx,
+            balancer,
             _stop_tx: stop_tx,
             handle: handle.clone(),
         };
@@ -204,6 +547,7 @@ impl HealthCheckProbe {
         handle.spawn(start_checker(
             probe.inner.clone(),
             probe.update_tx.clone(),
+            probe.balancer.clone(),
             stop_rx,
             hyper::Client::new(),
         ));
@@ -215,37 +559,56 @@ impl HealthCheckProbe {
         &mut self,
         probe: Probe,
         upstream_definition: UpstreamDefinition,
-    ) -> Result<(), url::ParseError> {
+    ) -> Result<(), HealthCheckError> {
         let (stop_tx, stop_rx) = oneshot::channel();
 
-        let probe_url: Url = format!(
-            "http://{}:{}{}",
-            upstream_definition.get_host(),
-            upstream_definition.addr.port,
-            probe.path
-        )
-        .parse()?;
+        let probe_targets = probe_targets(&probe, &upstream_definition)?;
         {
             let mut locked = self.inner.lock();
 
-            if locked.probe == probe && locked.probe_url == probe_url {
+            let is_tls_same =
+                locked.tls.as_ref().map(|(tls, _)| tls) == upstream_definition.tls.as_ref();
+            if locked.probe == probe && locked.probe_targets == probe_targets && is_tls_same {
                 return Ok(());
             }
 
+            if !is_tls_same {
+                locked.tls = tls_connector(&upstream_definition)?;
+            }
+            locked.expected_body = body_matcher(&probe)?;
             locked.probe = probe;
-            locked.probe_url = probe_url;
+            locked.probe_targets = probe_targets;
         }
         self._stop_tx = stop_tx;
+        self.clear_reported();
 
         self.handle.spawn(start_checker(
             self.inner.clone(),
             self.update_tx.clone(),
+            self.balancer.clone(),
             stop_rx,
             hyper::Client::new(),
         ));
 
         Ok(())
     }
+
+    fn is_readiness(&self) -> bool {
+        self.inner.lock().probe.is_readiness()
+    }
+
+    /// Forget health and readiness reported by the previous checker
+    fn clear_reported(&self) {
+        let locked = self.inner.lock();
+        self.balancer
+            .clear_probe(&locked.upstream, &locked.probe_name);
+    }
+}
+
+impl Drop for HealthCheckProbe {
+    fn drop(&mut self) {
+        self.clear_reported();
+    }
 }
 
 #[derive(Clone)]
@@ -253,15 +616,18 @@ pub struct UpstreamsHealth {
     inner:
         Arc<tokio::sync::Mutex<HashMap<Upstream, HashMap<HealthCheckProbeName, HealthCheckProbe>>>>,
     update_tx: mpsc::Sender<ProbeStatusUpdate>,
+    balancer: UpstreamBalancer,
     active_profile: Option<ProfileName>,
     handle: Handle,
 }
 
 impl UpstreamsHealth {
+    /// Status of liveness probes, along with open circuit breakers
     pub async fn dump_health(
         &self,
     ) -> HashMap<Upstream, HashMap<HealthCheckProbeName, ProbeHealthStatus>> {
-        self.inner
+        let mut health = self
+            .inner
             .lock()
             .await
             .iter()
@@ -270,19 +636,36 @@ impl UpstreamsHealth {
                     upstream.clone(),
                     probes
                         .iter()
-                        .map(|(probe, health)| (probe.clone(), health.inner.lock().status.clone()))
+                        .filter_map(|(probe, health)| {
+                            let locked = health.inner.lock();
+                            if locked.probe.is_readiness() {
+                                None
+                            } else {
+                                Some((probe.clone(), locked.status.clone()))
+                            }
+                        })
                         .collect::<HashMap<_, _>>(),
                 )
             })
-            .collect::<HashMap<_, _>>()
+            .collect::<HashMap<_, _>>();
+
+        for upstream in self.balancer.open_circuits() {
+            health.entry(upstream).or_default().insert(
+                CIRCUIT_BREAKER_PROBE_NAME.parse().unwrap(),
+                circuit_open_status(),
+            );
+        }
+
+        health
     }
 
     pub fn new(
         config: &ClientConfig,
         update_tx: mpsc::Sender<ProbeStatusUpdate>,
+        balancer: UpstreamBalancer,
         active_profile: &Option<ProfileName>,
         handle: Handle,
-    ) -> Result<Self, url::ParseError> {
+    ) -> Result<Self, HealthCheckError> {
         let mut storage =
             HashMap::<Upstream, HashMap<HealthCheckProbeName, HealthCheckProbe>>::new();
 
@@ -300,15 +683,39 @@ impl UpstreamsHealth {
                         upstream.clone(),
                         upstream_definition.clone(),
                         update_tx.clone(),
+                        balancer.clone(),
                         handle.clone(),
                     )?,
                 );
             }
         }
 
+        handle.spawn({
+            let mut circuit_changes = balancer.subscribe_circuit_changes();
+            shadow_clone!(mut update_tx);
+
+            async move {
+                while let Some((upstream, state)) = circuit_changes.next().await {
+                    let status = match state {
+                        CircuitState::Closed => ProbeHealthStatus::Healthy,
+                        CircuitState::Open | CircuitState::HalfOpen => circuit_open_status(),
+                    };
+                    let update = ProbeStatusUpdate {
+                        upstream,
+                        probe: CIRCUIT_BREAKER_PROBE_NAME.parse().unwrap(),
+                        status: Some(status),
+                    };
+                    if update_tx.send(update).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
         Ok(UpstreamsHealth {
             inner: Arc::new(tokio::sync::Mutex::new(storage)),
             update_tx,
+            balancer,
             active_profile: active_profile.clone(),
             handle,
         })
@@ -336,9 +743,24 @@ impl UpstreamsHealth {
         let span = span!(Level::INFO, "healthcheck config");
         let _enter = span.enter();
 
+        let open_circuits = self.balancer.open_circuits();
         for to_delete_upstream in existing_upstreams.difference(&new_upstreams) {
+            self.balancer.remove_upstream(to_delete_upstream);
+            if open_circuits.contains(to_delete_upstream) {
+                let _ = update_tx
+                    .send(ProbeStatusUpdate {
+                        upstream: to_delete_upstream.clone(),
+                        probe: CIRCUIT_BREAKER_PROBE_NAME.parse().unwrap(),
+                        status: None,
+                    })
+                    .await;
+            }
+
             let removed_probes = locked.remove(to_delete_upstream).unwrap();
-            for (probe_name, _probe) in removed_probes.into_iter() {
+            for (probe_name, probe) in removed_probes.into_iter() {
+                if probe.is_readiness() {
+                    continue;
+                }
                 let _ = update_tx
                     .send(ProbeStatusUpdate {
                         upstream: to_delete_upstream.clone(),
@@ -371,7 +793,10 @@ impl UpstreamsHealth {
                 let span = span!(Level::INFO, "", probe = to_delete_probe.as_str());
                 let _enter = span.enter();
 
-                existing_probes.remove(to_delete_probe);
+                let removed = existing_probes.remove(to_delete_probe);
+                if matches!(removed, Some(probe) if probe.is_readiness()) {
+                    continue;
+                }
 
                 let _ = update_tx
                     .send(ProbeStatusUpdate {
@@ -394,6 +819,7 @@ impl UpstreamsHealth {
                     upstream_name.clone(),
                     upstream.clone(),
                     update_tx.clone(),
+                    self.balancer.clone(),
                     self.handle.clone(),
                 ) {
                     Ok(r) => {
@@ -422,3 +848,130 @@ impl UpstreamsHealth {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_thresholds() {
+        let probe: Probe = serde_yaml::from_str(
+            "{kind: readiness, tcp: true, success-threshold: 2, timeout: 1s, period: 1s}",
+        )
+        .unwrap();
+        assert_eq!(probe.failure_threshold, 3);
+
+        let failure = ProbeHealthStatus::Unhealthy {
+            reason: UnhealthyReason::Timeout,
+        };
+        let mut tracker = ThresholdTracker::default();
+
+        let mut observe =
+            |result: &ProbeHealthStatus| tracker.observe(&probe, result.clone()).clone();
+
+        assert_eq!(
+            observe(&ProbeHealthStatus::Healthy),
+            ProbeHealthStatus::Unknown
+        );
+        assert_eq!(
+            observe(&ProbeHealthStatus::Healthy),
+            ProbeHealthStatus::Healthy
+        );
+        assert_eq!(observe(&failure), ProbeHealthStatus::Healthy);
+        assert_eq!(observe(&failure), ProbeHealthStatus::Healthy);
+        assert_eq!(
+            observe(&ProbeHealthStatus::Healthy),
+            ProbeHealthStatus::Healthy
+        );
+        for _ in 0..2 {
+            assert_eq!(observe(&failure), ProbeHealthStatus::Healthy);
+        }
+        assert_eq!(observe(&failure), failure);
+        assert_eq!(observe(&ProbeHealthStatus::Healthy), failure);
+    }
+
+    #[tokio::test]
+    async fn test_exec_probe() {
+        let exec = |command: &[&str]| ExecProbe {
+            command: command.iter().map(|s| s.to_string()).collect(),
+        };
+
+        assert_eq!(
+            exec_probe(&exec(&["true"])).await,
+            ProbeHealthStatus::Healthy
+        );
+        assert_eq!(
+            exec_probe(&exec(&["sh", "-c", "exit 3"])).await,
+            ProbeHealthStatus::Unhealthy {
+                reason: UnhealthyReason::BadExitCode { code: Some(3) }
+            }
+        );
+        assert!(matches!(
+            exec_probe(&exec(&["/nonexistent/probe"])).await,
+            ProbeHealthStatus::Unhealthy {
+                reason: UnhealthyReason::RequestError { .. }
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_probe_body_limit() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let len = stream.read(&mut buf).await.unwrap();
+                let body = if buf[..len].starts_with(b"GET /large") {
+                    format!("ok{}", " ".repeat(MAX_PROBE_BODY_SIZE))
+                } else {
+                    "ok".to_string()
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let probe: Probe = serde_yaml::from_str(
+            "{kind: liveness, path: /, expected-body: {contains: ok}, timeout: 1s, period: 1s}",
+        )
+        .unwrap();
+        let expected_body = body_matcher(&probe).unwrap();
+        let check = |path: &str| {
+            let target = ProbeTarget {
+                source: "backend".into(),
+                url: format!("http://{}{}", addr, path).parse().unwrap(),
+                unix_socket: None,
+            };
+            let probe = probe.clone();
+            let expected_body = expected_body.clone();
+            async move {
+                check_target(
+                    &probe,
+                    expected_body.as_ref(),
+                    &hyper::Client::new(),
+                    None,
+                    &target,
+                )
+                .await
+            }
+        };
+
+        assert_eq!(check("/small").await, ProbeHealthStatus::Healthy);
+        assert_eq!(
+            check("/large").await,
+            ProbeHealthStatus::Unhealthy {
+                reason: UnhealthyReason::UnexpectedBody
+            }
+        );
+    }
+}
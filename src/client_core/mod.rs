@@ -6,6 +6,7 @@ mod signal_client;
 mod tunnel;
 
 pub use client::{Client, ClientBuilder, DEFAULT_CLOUD_ENDPOINT};
+pub use tunnel::TunnelsStats;
 use dashmap::DashMap;
 use futures::channel::oneshot;
 use hashbrown::HashMap;
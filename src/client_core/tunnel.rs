@@ -4,29 +4,35 @@ use crate::{
     access_tokens::{generate_jwt_token, JwtError},
     common_utils::tls::load_native_certs_safe,
     config_core::ClientConfig,
-    entities::{AccessKeyId, AccountName, InstanceId, ProfileName, ProjectName, SmolStr},
+    entities::{AccessKeyId, AccountName, InstanceId, ProfileName, ProjectName, SmolStr, TunnelId},
     tunnel::{
-        client_framed, client_listener, MixedChannel, TunnelHello, TunnelHelloResponse,
-        ALPN_PROTOCOL,
+        client_framed, client_listener, encode_prometheus, Capabilities, MixedChannel, Negotiated,
+        ResumeRequest, ResumeToken, Session, TunnelHello, TunnelHelloResponse, TunnelSettings,
+        TunnelStats, UpstreamBalancer, ALPN_PROTOCOL, RESUME_WINDOW, SUPPORTED_PROTOCOL_VERSIONS,
     },
 };
 use core::time::Duration;
-use futures::channel::mpsc;
+use futures::{channel::mpsc, future};
 use hashbrown::HashMap;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rand::{seq::IteratorRandom, thread_rng};
 use rustls::ClientConfig as RustlsClientConfig;
 use rw_stream_sink::RwStreamSink;
-use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use std::{
+    convert::TryInto,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    time::{sleep, timeout_at, Instant},
 };
 use tokio_rustls::{rustls, webpki::DNSNameRef, TlsConnector};
-use tracing::{error, field, info, info_span};
+use tracing::{error, field, info, info_span, warn};
 use trust_dns_resolver::{error::ResolveError, TokioAsyncResolver};
 use url::Url;
-use warp::hyper::client::conn;
+use warp::hyper::{client::conn, upgrade::Upgraded};
 use webpki::InvalidDNSNameError;
 
 #[derive(thiserror::Error, Debug)]
@@ -65,6 +71,275 @@ pub enum Error {
     Jwt(#[from] JwtError),
 }
 
+/// Pause between attempts to resume the session
+const RESUME_RETRY_PERIOD: Duration = Duration::from_millis(500);
+
+/// Parameters of the connection to the gateway
+struct Gateway<'a> {
+    client_config: &'a Arc<RwLock<ClientConfig>>,
+    account_name: &'a AccountName,
+    project_name: &'a ProjectName,
+    instance_id: InstanceId,
+    access_key_id: AccessKeyId,
+    secret_access_key: &'a SmolStr,
+    gw_hostname: &'a SmolStr,
+    gw_port: u16,
+    additional_connection_params: &'a HashMap<SmolStr, SmolStr>,
+    resolver: &'a TokioAsyncResolver,
+}
+
+/// Tunnel, accepted by the gateway
+struct Accepted {
+    tunnel_id: TunnelId,
+    negotiated: Negotiated,
+    /// Gateway address, which should be used to resume the session
+    gw_addr: IpAddr,
+    resume_token: Option<ResumeToken>,
+    /// Frames received by the gateway, if the session is resumed
+    resumed: Option<u64>,
+}
+
+impl Gateway<'_> {
+    async fn connect(
+        &self,
+        gw_addr: Option<IpAddr>,
+        resume: Option<ResumeRequest>,
+    ) -> Result<(Accepted, Upgraded), Error> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let gw_addr = match gw_addr {
+                Some(gw_addr) => gw_addr,
+                None => {
+                    let gw_addrs = self
+                        .resolver
+                        .lookup_ip(self.gw_hostname.to_string())
+                        .await
+                        .map_err(Box::new)?;
+                    gw_addrs
+                        .iter()
+                        .choose(&mut thread_rng())
+                        .ok_or(Error::NothingResolved)?
+                }
+            };
+
+            let socket = TcpStream::connect(SocketAddr::new(gw_addr, self.gw_port)).await?;
+            let _ = socket.set_nodelay(true);
+            let mut config = RustlsClientConfig::new();
+            config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec(), b"http/1.1".to_vec()];
+            load_native_certs_safe(&mut config);
+            let config = TlsConnector::from(Arc::new(config));
+            let dns_name = DNSNameRef::try_from_ascii_str(self.gw_hostname)?;
+
+            let tls_stream = config.connect(dns_name, socket).await?;
+
+            let (mut send_request, http_connection) = conn::Builder::new()
+                .http2_only(false)
+                .handshake(tls_stream)
+                .await?;
+
+            tokio::spawn(http_connection);
+
+            let mut url: Url = format!("https://{}/exotun", self.gw_hostname)
+                .parse()
+                .unwrap();
+
+            url.query_pairs_mut()
+                .append_pair("exogress_version", crate::client_core::VERSION);
+
+            for (k, v) in self.additional_connection_params.iter() {
+                url.query_pairs_mut().append_pair(k.as_str(), v.as_str());
+            }
+
+            let req = http::Request::builder()
+                .uri(url.as_str())
+                .header("upgrade", "exotun")
+                .header("connection", "upgrade")
+                .body(hyper::Body::empty())
+                .unwrap();
+
+            let mut res = send_request.send_request(req).await?;
+
+            if res.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+                return Err(Error::BadHttpStatus(res.status()));
+            }
+
+            let mut stream = hyper::upgrade::on(&mut res).await?;
+
+            let hello = TunnelHello {
+                config_name: self.client_config.read().name.clone(),
+                account_name: self.account_name.clone(),
+                project_name: self.project_name.clone(),
+                instance_id: self.instance_id,
+
+                jwt_token: generate_jwt_token(self.secret_access_key, &self.access_key_id)?.into(),
+                protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+                capabilities: Capabilities::supported(),
+                resume,
+            };
+
+            let encoded_hello: Vec<u8> = serde_cbor::to_vec(&hello).unwrap();
+            stream
+                .write_u16(encoded_hello.len().try_into().unwrap())
+                .await?;
+            stream.write_all(&encoded_hello).await?;
+
+            let resp_len = stream.read_u16().await?.into();
+            let mut tunnel_hello_response = vec![0u8; resp_len];
+            stream.read_exact(&mut tunnel_hello_response).await?;
+            let hello_response =
+                serde_cbor::from_slice::<TunnelHelloResponse>(&tunnel_hello_response)
+                    .map_err(crate::tunnel::Error::DecodeError)?;
+
+            match hello_response {
+                TunnelHelloResponse::Ok {
+                    tunnel_id,
+                    protocol_version,
+                    capabilities,
+                    resume_token,
+                    resumed,
+                } => {
+                    let negotiated = Negotiated::accept(protocol_version, capabilities)?;
+                    let resume_token = resume_token.filter(|_| negotiated.capabilities.resumption);
+                    let accepted = Accepted {
+                        tunnel_id,
+                        negotiated,
+                        gw_addr,
+                        resume_token,
+                        resumed,
+                    };
+                    Ok((accepted, stream))
+                }
+                TunnelHelloResponse::Err { msg } => Err(Error::Rejected(msg)),
+            }
+        })
+        .await
+        .map_err(|_| Error::EstablishTimeout)?
+    }
+
+    /// Re-attach to the session during `RESUME_WINDOW`. Returns the connection and the
+    /// number of frames received by the gateway
+    async fn resume(
+        &self,
+        accepted: &Accepted,
+        resume_token: &ResumeToken,
+        received: u64,
+    ) -> Option<(Upgraded, u64)> {
+        let deadline = Instant::now() + RESUME_WINDOW;
+
+        loop {
+            let resume_request = ResumeRequest {
+                tunnel_id: accepted.tunnel_id,
+                token: resume_token.clone(),
+                received,
+            };
+
+            match timeout_at(
+                deadline,
+                self.connect(Some(accepted.gw_addr), Some(resume_request)),
+            )
+            .await
+            {
+                Ok(Ok((resumed, stream))) => {
+                    return match resumed.resumed {
+                        Some(gw_received) if resumed.negotiated == accepted.negotiated => {
+                            Some((stream, gw_received))
+                        }
+                        _ => {
+                            warn!("tunnel session is not resumed by the gateway");
+                            None
+                        }
+                    };
+                }
+                Ok(Err(Error::Rejected(msg))) => {
+                    warn!("tunnel session resumption rejected: {}", msg);
+                    return None;
+                }
+                Ok(Err(e)) => {
+                    warn!("error resuming tunnel session: {}", e);
+                    sleep(RESUME_RETRY_PERIOD).await;
+                }
+                Err(_) => {
+                    warn!("tunnel session is not resumed in {:?}", RESUME_WINDOW);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Stats of the tunnels, currently established by the client
+#[derive(Default, Debug, Clone)]
+pub struct TunnelsStats {
+    inner: Arc<Mutex<HashMap<(SmolStr, TunnelId), TunnelStats>>>,
+}
+
+impl TunnelsStats {
+    /// Stats of each tunnel, along with the gateway hostname and the tunnel ID
+    pub fn tunnels(&self) -> Vec<(SmolStr, TunnelId, TunnelStats)> {
+        let mut tunnels = self
+            .inner
+            .lock()
+            .iter()
+            .map(|((gw_hostname, tunnel_id), stats)| {
+                (gw_hostname.clone(), *tunnel_id, stats.clone())
+            })
+            .collect::<Vec<_>>();
+        tunnels.sort_by(|(a_gw, a_id, _), (b_gw, b_id, _)| (a_gw, a_id).cmp(&(b_gw, b_id)));
+        tunnels
+    }
+
+    /// Stats of all the tunnels, labeled with `gw` and `tunnel_id`
+    pub fn to_prometheus(&self) -> String {
+        let tunnels = self.tunnels();
+        let tunnel_ids = tunnels
+            .iter()
+            .map(|(_, tunnel_id, _)| tunnel_id.to_string())
+            .collect::<Vec<_>>();
+        let labels = tunnels
+            .iter()
+            .zip(&tunnel_ids)
+            .map(|((gw_hostname, _, _), tunnel_id)| {
+                [
+                    ("gw", gw_hostname.as_str()),
+                    ("tunnel_id", tunnel_id.as_str()),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        encode_prometheus(
+            labels
+                .iter()
+                .zip(&tunnels)
+                .map(|(labels, (_, _, stats))| (&labels[..], stats)),
+        )
+    }
+
+    /// Keep the stats, until the returned guard is dropped
+    fn register(
+        &self,
+        gw_hostname: SmolStr,
+        tunnel_id: TunnelId,
+        stats: TunnelStats,
+    ) -> RegisteredTunnel {
+        let key = (gw_hostname, tunnel_id);
+        self.inner.lock().insert(key.clone(), stats);
+        RegisteredTunnel {
+            tunnels: self.clone(),
+            key,
+        }
+    }
+}
+
+struct RegisteredTunnel {
+    tunnels: TunnelsStats,
+    key: (SmolStr, TunnelId),
+}
+
+impl Drop for RegisteredTunnel {
+    fn drop(&mut self) {
+        self.tunnels.inner.lock().remove(&self.key);
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 /// Returns true if tunnel creation should be retried, false otherwise
 pub async fn spawn(
@@ -80,110 +355,151 @@ pub async fn spawn(
     additional_connection_params: &HashMap<SmolStr, SmolStr>,
     internal_server_connector: mpsc::Sender<RwStreamSink<MixedChannel>>,
     resolver: TokioAsyncResolver,
+    balancer: UpstreamBalancer,
+    settings: TunnelSettings,
+    tunnels_stats: &TunnelsStats,
 ) -> Result<bool, Error> {
     let span = info_span!("spawn", tunnel_id = field::Empty);
-    let (tunnel_id, stream) = tokio::time::timeout(Duration::from_secs(5), async {
-        let gw_addrs = resolver
-            .lookup_ip(gw_hostname.to_string())
-            .await
-            .map_err(Box::new)?;
-        let gw_addr = gw_addrs
-            .iter()
-            .choose(&mut thread_rng())
-            .ok_or(Error::NothingResolved)?;
-
-        let socket = TcpStream::connect(SocketAddr::new(gw_addr, gw_port)).await?;
-        let _ = socket.set_nodelay(true);
-        let mut config = RustlsClientConfig::new();
-        config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec(), b"http/1.1".to_vec()];
-        load_native_certs_safe(&mut config);
-        let config = TlsConnector::from(Arc::new(config));
-        let dns_name = DNSNameRef::try_from_ascii_str(&gw_hostname)?;
-
-        let tls_stream = config.connect(dns_name, socket).await?;
-
-        let (mut send_request, http_connection) = conn::Builder::new()
-            .http2_only(false)
-            .handshake(tls_stream)
-            .await?;
-
-        tokio::spawn(http_connection);
-
-        let mut url: Url = format!("https://{}/exotun", gw_hostname).parse().unwrap();
-
-        url.query_pairs_mut()
-            .append_pair("exogress_version", crate::client_core::VERSION);
-
-        for (k, v) in additional_connection_params.iter() {
-            url.query_pairs_mut().append_pair(k.as_str(), v.as_str());
+    let gateway = Gateway {
+        client_config: &client_config,
+        account_name: &account_name,
+        project_name: &project_name,
+        instance_id,
+        access_key_id,
+        secret_access_key: &secret_access_key,
+        gw_hostname: &gw_hostname,
+        gw_port,
+        additional_connection_params,
+        resolver: &resolver,
+    };
+    let (accepted, stream) = gateway.connect(None, None).await?;
+
+    span.record("tunnel_id", &accepted.tunnel_id.to_string().as_str());
+
+    info!(
+        parent: &span,
+        protocol_version = accepted.negotiated.protocol_version,
+        resumable = accepted.resume_token.is_some(),
+        "connected"
+    );
+
+    let register = |stats: &TunnelStats| {
+        tunnels_stats.register(gw_hostname.clone(), accepted.tunnel_id, stats.clone())
+    };
+
+    let (r, stats) = match &accepted.resume_token {
+        None => {
+            let (listener, stats) = client_listener(
+                client_framed(stream),
+                accepted.negotiated.clone(),
+                settings,
+                client_config.clone(),
+                internal_server_connector,
+                active_profile,
+                resolver.clone(),
+                balancer,
+            );
+            let _registered = register(&stats);
+
+            (listener.await?, stats)
         }
-
-        let req = http::Request::builder()
-            .uri(url.as_str())
-            .header("upgrade", "exotun")
-            .header("connection", "upgrade")
-            .body(hyper::Body::empty())
-            .unwrap();
-
-        let mut res = send_request.send_request(req).await?;
-
-        if res.status() != http::StatusCode::SWITCHING_PROTOCOLS {
-            return Err(Error::BadHttpStatus(res.status()));
-        }
-
-        let mut stream = hyper::upgrade::on(&mut res).await?;
-
-        let hello = TunnelHello {
-            config_name: client_config.read().name.clone(),
-            account_name,
-            project_name,
-            instance_id,
-
-            jwt_token: generate_jwt_token(&secret_access_key, &access_key_id)?.into(),
-        };
-
-        let encoded_hello: Vec<u8> = serde_cbor::to_vec(&hello).unwrap();
-        stream
-            .write_u16(encoded_hello.len().try_into().unwrap())
-            .await?;
-        stream.write_all(&encoded_hello).await?;
-
-        let resp_len = stream.read_u16().await?.into();
-        let mut tunnel_hello_response = vec![0u8; resp_len];
-        stream.read_exact(&mut tunnel_hello_response).await?;
-        let hello_response = serde_cbor::from_slice::<TunnelHelloResponse>(&tunnel_hello_response)
-            .map_err(crate::tunnel::Error::DecodeError)?;
-
-        match hello_response {
-            TunnelHelloResponse::Ok { tunnel_id } => Ok((tunnel_id, stream)),
-            TunnelHelloResponse::Err { msg } => Err(Error::Rejected(msg)),
+        Some(resume_token) => {
+            let (mut session, session_tunnel) = Session::new();
+
+            let (listener, stats) = client_listener(
+                session_tunnel,
+                accepted.negotiated.clone(),
+                settings,
+                client_config.clone(),
+                internal_server_connector,
+                active_profile,
+                resolver.clone(),
+                balancer,
+            );
+            let _registered = register(&stats);
+
+            let connections = async {
+                let (mut stream, mut gw_received) = (stream, 0);
+
+                loop {
+                    match session.attach(client_framed(stream), gw_received).await {
+                        Ok(()) => {
+                            // the listener is finished and returns the result
+                            return future::pending().await;
+                        }
+                        Err(e) => {
+                            warn!(parent: &span, "tunnel connection lost: {}. resuming", e);
+                        }
+                    }
+
+                    match gateway
+                        .resume(&accepted, resume_token, session.received())
+                        .await
+                    {
+                        Some((resumed_stream, resumed_gw_received)) => {
+                            info!(parent: &span, "resumed");
+                            stats.rtt().reattached();
+                            stream = resumed_stream;
+                            gw_received = resumed_gw_received;
+                        }
+                        None => {
+                            // open slots are dropped along with the session
+                            return true;
+                        }
+                    }
+                }
+            };
+
+            let r = tokio::select! {
+                r = listener => r?,
+                should_retry = connections => should_retry,
+            };
+
+            (r, stats)
         }
-    })
-    .await
-    .map_err(|_| Error::EstablishTimeout)??;
+    };
 
-    span.record("tunnel_id", &tunnel_id.to_string().as_str());
-
-    info!(parent: &span, "connected");
-
-    let r = client_listener(
-        client_framed(stream),
-        client_config,
-        internal_server_connector,
-        active_profile,
-        resolver.clone(),
-    )
-    .await?;
-
-    info!(parent: &span, "closed successfully");
+    info!(
+        parent: &span,
+        rtt = ?stats.rtt().smoothed(),
+        slots_opened = stats
+            .targets()
+            .iter()
+            .map(|(_, target)| target.slots_opened())
+            .sum::<u64>(),
+        "closed successfully"
+    );
 
     Ok(r)
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
     use serde::{Deserialize, Serialize};
 
+    #[test]
+    fn test_tunnels_stats() {
+        let tunnels_stats = TunnelsStats::default();
+        let tunnel_id = TunnelId::new();
+        let stats = TunnelStats::default();
+        stats.rtt().sample(Duration::from_millis(20));
+
+        let registered = tunnels_stats.register("gw.example.com".into(), tunnel_id, stats);
+        let _other =
+            tunnels_stats.register("gw.example.com".into(), TunnelId::new(), Default::default());
+        assert_eq!(tunnels_stats.tunnels().len(), 2);
+        assert!(tunnels_stats.to_prometheus().contains(&format!(
+            "exogress_tunnel_rtt_seconds{{gw=\"gw.example.com\",tunnel_id=\"{}\"}} 0.02",
+            tunnel_id
+        )));
+
+        drop(registered);
+        let tunnels = tunnels_stats.tunnels();
+        assert_eq!(tunnels.len(), 1);
+        assert_ne!(tunnels[0].1, tunnel_id);
+    }
+
     #[test]
     fn cbor_evolution() {
         #[derive(Debug, Serialize, Deserialize)]
@@ -167,6 +167,18 @@ pub struct RescueItem {
     pub handle: CatchAction,
 }
 
+impl RescueItem {
+    pub fn disable_substitutions(&mut self) {
+        if let CatchAction::StaticResponse {
+            static_response: Container::Value(static_response),
+            ..
+        } = &mut self.handle
+        {
+            static_response.disable_substitutions();
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1,6 +1,6 @@
 use hashbrown::{HashMap, HashSet};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::{
     config_core::{
@@ -10,16 +10,25 @@ use crate::{
         is_profile_active, is_version_supported,
         proxy::Proxy,
         proxy_public::ProxyPublic,
+        rate_limit::{validate_rate_limiters, RateLimiter, RateLimiterError, RateLimitersError},
         rebase::Rebase,
         refinable::Refinable,
         s3::S3BucketAccess,
         schema::validate_schema,
+        split_proxy::{SplitProxy, SplitProxyError},
         static_dir::StaticDir,
-        upstream::{ProbeError, UpstreamDefinition, UpstreamSocketAddr},
+        substitution::SubstitutionError,
+        upstream::{
+            CircuitBreakerError, ProbeError, UpstreamDefinition, UpstreamProtocol,
+            UpstreamProtocolError, UpstreamSocketAddr, UpstreamSocketAddrParseError,
+            UpstreamTlsError, CIRCUIT_BREAKER_PROBE_NAME,
+        },
         validate_extra_keys, Auth, ConfigVersion, PassThrough, Rule, CURRENT_VERSION,
+        SUBSTITUTIONS_MIN_VERSION,
     },
     entities::{
-        ConfigName, HandlerName, HealthCheckProbeName, MountPointName, ProfileName, Upstream,
+        ConfigName, HandlerName, HealthCheckProbeName, MountPointName, ProfileName,
+        RateLimiterName, Upstream,
     },
 };
 use core::fmt;
@@ -29,7 +38,6 @@ use std::{
     collections::BTreeMap,
     fmt::Formatter,
     hash::{Hash, Hasher},
-    mem,
 };
 
 #[derive(
@@ -51,6 +59,8 @@ impl From<u64> for ClientConfigRevision {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, JsonSchema)]
+#[serde(remote = "Self")]
+#[schemars(rename = "ClientConfig")]
 // #[schemars(deny_unknown_fields)]
 pub struct ClientConfig {
     pub version: ConfigVersion,
@@ -65,6 +75,9 @@ pub struct ClientConfig {
     #[serde(default)]
     pub upstreams: BTreeMap<Upstream, UpstreamDefinition>,
 
+    #[serde(default, rename = "rate-limiters")]
+    pub rate_limiters: BTreeMap<RateLimiterName, RateLimiter>,
+
     #[serde(flatten)]
     pub refinable: Refinable,
 }
@@ -89,9 +102,17 @@ impl ClientConfig {
                 addr: UpstreamSocketAddr {
                     port: 3000,
                     host: None,
+                    unix: None,
                 },
                 health_checks: Default::default(),
                 profiles: None,
+                addresses: vec![],
+                resolve_all: false,
+                load_balancing: Default::default(),
+                tls: None,
+                circuit_breaker: None,
+                protocol: Default::default(),
+                udp_idle_timeout: None,
             },
         );
 
@@ -147,6 +168,7 @@ impl ClientConfig {
             name: config_name,
             mount_points,
             upstreams,
+            rate_limiters: Default::default(),
             refinable: Refinable {
                 static_responses: Default::default(),
                 rescue: vec![],
@@ -154,6 +176,24 @@ impl ClientConfig {
         }
     }
 
+    /// Render the rules as written, if the config predates substitutions
+    pub fn disable_legacy_substitutions(&mut self) {
+        if self.version.0 >= *SUBSTITUTIONS_MIN_VERSION {
+            return;
+        }
+
+        self.refinable.disable_substitutions();
+        for mount in self.mount_points.values_mut() {
+            mount.refinable.disable_substitutions();
+            for handler in mount.handlers.values_mut() {
+                handler.refinable.disable_substitutions();
+                for rule in &mut handler.rules {
+                    rule.disable_substitutions();
+                }
+            }
+        }
+    }
+
     pub fn parse_with_redefined_upstreams(
         yaml: impl AsRef<[u8]>,
         redefined_upstreams: &HashMap<Upstream, UpstreamSocketAddr>,
@@ -161,9 +201,19 @@ impl ClientConfig {
         let mut cfg = Self::parse(yaml.as_ref())?;
 
         for (upstream_name, addr) in redefined_upstreams {
-            let upstream = cfg.upstreams.get_mut(upstream_name);
-            if let Some(definition) = upstream {
-                let _ = mem::replace(&mut definition.addr, addr.clone());
+            addr.validate()
+                .map_err(|error| ClientConfigError::BadUpstreamAddr {
+                    upstream: upstream_name.clone(),
+                    error,
+                })?;
+
+            if let Some(definition) = cfg.upstreams.get_mut(upstream_name) {
+                // the redefined address replaces all the addresses of the upstream
+                definition.addr = addr.clone();
+                definition.addresses.clear();
+                definition.resolve_all = false;
+
+                validate_upstream_addrs(upstream_name, definition)?;
             }
         }
 
@@ -182,6 +232,91 @@ impl ClientConfig {
             })
             .cloned()
     }
+
+    /// Check that rules refer only to captures provided by their filters. Older configs
+    /// don't have substitutions, so they are not checked
+    pub fn validate_substitutions(&self) -> Result<(), ClientConfigError> {
+        if self.version.0 < *SUBSTITUTIONS_MIN_VERSION {
+            return Ok(());
+        }
+
+        for mount in self.mount_points.values() {
+            for (handler_name, handler) in &mount.handlers {
+                for (rule_idx, rule) in handler.rules.iter().enumerate() {
+                    rule.validate_substitutions().map_err(|error| {
+                        ClientConfigError::BadSubstitution {
+                            handler: handler_name.clone(),
+                            rule: rule_idx,
+                            error,
+                        }
+                    })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check rate limiters definitions and their usage in rules
+    pub fn validate_rate_limiters(&self) -> Result<(), ClientConfigError> {
+        let handlers = self.mount_points.values().flat_map(|mount| {
+            mount
+                .handlers
+                .iter()
+                .map(|(handler_name, handler)| (handler_name, handler.rules.as_slice()))
+        });
+
+        Ok(validate_rate_limiters(&self.rate_limiters, handlers)?)
+    }
+}
+
+/// Checks, which depend on the upstream addresses, so that they are repeated when the
+/// addresses are redefined
+fn validate_upstream_addrs(
+    upstream_name: &Upstream,
+    upstream: &UpstreamDefinition,
+) -> Result<(), ClientConfigError> {
+    for addr in upstream.all_addrs() {
+        addr.validate()
+            .map_err(|error| ClientConfigError::BadUpstreamAddr {
+                upstream: upstream_name.clone(),
+                error,
+            })?;
+    }
+
+    upstream
+        .validate_tls()
+        .map_err(|error| ClientConfigError::BadUpstreamTls {
+            upstream: upstream_name.clone(),
+            error,
+        })?;
+
+    upstream
+        .validate_protocol()
+        .map_err(|error| ClientConfigError::BadUpstreamProtocol {
+            upstream: upstream_name.clone(),
+            error,
+        })
+}
+
+impl Serialize for ClientConfig {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        ClientConfig::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut config = ClientConfig::deserialize(deserializer)?;
+        config.disable_legacy_substitutions();
+        Ok(config)
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -195,6 +330,58 @@ pub enum ClientConfigError {
     #[error("unsupported config version {}", _0)]
     UnsupportedVersion(ConfigVersion),
 
+    #[error("bad substitution in rule #{rule} of handler {handler}: {error}")]
+    BadSubstitution {
+        handler: HandlerName,
+        rule: usize,
+        error: SubstitutionError,
+    },
+
+    #[error("rate limiters {} not defined", .0.iter().map(| s | s.to_string()).collect::< Vec < _ >> ().join(", "))]
+    RateLimiterNotDefined(Vec<RateLimiterName>),
+
+    #[error("bad rate limiter {name}: {error}")]
+    BadRateLimiter {
+        name: RateLimiterName,
+        error: RateLimiterError,
+    },
+
+    #[error("bad split proxy in handler {handler}: {error}")]
+    BadSplitProxy {
+        handler: HandlerName,
+        error: SplitProxyError,
+    },
+
+    #[error("bad address of upstream {upstream}: {error}")]
+    BadUpstreamAddr {
+        upstream: Upstream,
+        error: UpstreamSocketAddrParseError,
+    },
+
+    #[error("bad TLS settings of upstream {upstream}: {error}")]
+    BadUpstreamTls {
+        upstream: Upstream,
+        error: UpstreamTlsError,
+    },
+
+    #[error("bad circuit breaker of upstream {upstream}: {error}")]
+    BadCircuitBreaker {
+        upstream: Upstream,
+        error: CircuitBreakerError,
+    },
+
+    #[error("bad protocol settings of upstream {upstream}: {error}")]
+    BadUpstreamProtocol {
+        upstream: Upstream,
+        error: UpstreamProtocolError,
+    },
+
+    #[error("UDP upstream {0} can't be used by HTTP handlers")]
+    UdpUpstreamInHandler(Upstream),
+
+    #[error("health check name {0} is reserved")]
+    ReservedProbeName(HealthCheckProbeName),
+
     #[error("bad health check values on probe {probe_name}: {probe_error}")]
     BadHealthCheckValues {
         probe_name: HealthCheckProbeName,
@@ -202,6 +389,26 @@ pub enum ClientConfigError {
     },
 }
 
+impl From<RateLimitersError> for ClientConfigError {
+    fn from(e: RateLimitersError) -> Self {
+        match e {
+            RateLimitersError::NotDefined(names) => ClientConfigError::RateLimiterNotDefined(names),
+            RateLimitersError::BadRateLimiter { name, error } => {
+                ClientConfigError::BadRateLimiter { name, error }
+            }
+            RateLimitersError::BadCaptures {
+                handler,
+                rule,
+                error,
+            } => ClientConfigError::BadSubstitution {
+                handler,
+                rule,
+                error,
+            },
+        }
+    }
+}
+
 impl Config for ClientConfig {
     type Error = ClientConfigError;
 
@@ -235,6 +442,22 @@ impl Config for ClientConfig {
             return Err(ClientConfigError::UnsupportedVersion(self.version.clone()));
         }
 
+        self.validate_substitutions()?;
+        self.validate_rate_limiters()?;
+
+        for mount in self.mount_points.values() {
+            for (handler_name, handler) in &mount.handlers {
+                if let ClientHandlerVariant::SplitProxy(split_proxy) = &handler.variant {
+                    split_proxy
+                        .validate()
+                        .map_err(|error| ClientConfigError::BadSplitProxy {
+                            handler: handler_name.clone(),
+                            error,
+                        })?;
+                }
+            }
+        }
+
         let defined_upstreams = self.upstreams.keys().cloned().collect::<HashSet<_>>();
         let used_upstreams = self
             .mount_points
@@ -243,9 +466,14 @@ impl Config for ClientConfig {
                 mount
                     .handlers
                     .values()
-                    .filter_map(|handler| match &handler.variant {
-                        ClientHandlerVariant::Proxy(proxy) => Some(proxy.upstream.clone()),
-                        _ => None,
+                    .flat_map(|handler| match &handler.variant {
+                        ClientHandlerVariant::Proxy(proxy) => vec![proxy.upstream.clone()],
+                        ClientHandlerVariant::SplitProxy(split_proxy) => split_proxy
+                            .upstreams
+                            .iter()
+                            .map(|weighted| weighted.upstream.clone())
+                            .collect(),
+                        _ => vec![],
                     })
             })
             .flatten()
@@ -269,6 +497,34 @@ impl Config for ClientConfig {
             })
             .collect::<Result<Vec<_>, _>>()?;
 
+        for (upstream_name, upstream) in &self.upstreams {
+            validate_upstream_addrs(upstream_name, upstream)?;
+
+            if let Some(circuit_breaker) = &upstream.circuit_breaker {
+                circuit_breaker.validate().map_err(|error| {
+                    ClientConfigError::BadCircuitBreaker {
+                        upstream: upstream_name.clone(),
+                        error,
+                    }
+                })?;
+            }
+
+            if upstream.protocol == UpstreamProtocol::Udp && used_upstreams.contains(upstream_name)
+            {
+                return Err(ClientConfigError::UdpUpstreamInHandler(
+                    upstream_name.clone(),
+                ));
+            }
+
+            if let Some(probe_name) = upstream
+                .health_checks
+                .keys()
+                .find(|probe_name| probe_name.as_str() == CIRCUIT_BREAKER_PROBE_NAME)
+            {
+                return Err(ClientConfigError::ReservedProbeName(probe_name.clone()));
+            }
+        }
+
         let mut not_defined = used_upstreams.difference(&defined_upstreams).peekable();
         if not_defined.peek().is_some() {
             return Err(ClientConfigError::UpstreamNotDefined(
@@ -284,6 +540,7 @@ impl Config for ClientConfig {
 
         validate_extra_keys(&deserialized_cfg, yaml.as_ref())?;
         validate_schema(yaml.as_ref(), "client.json")?;
+        deserialized_cfg.validate_substitutions()?;
 
         Ok(deserialized_cfg)
     }
@@ -312,6 +569,9 @@ pub enum ClientHandlerVariant {
     #[serde(rename = "proxy-public")]
     ProxyPublic(ProxyPublic),
 
+    #[serde(rename = "split-proxy")]
+    SplitProxy(SplitProxy),
+
     #[serde(rename = "static-dir")]
     StaticDir(StaticDir),
 
@@ -340,6 +600,7 @@ impl ClientHandlerVariant {
             ClientHandlerVariant::GcsBucket(v) => Some(&v.rebase),
             ClientHandlerVariant::PassThrough(_) => None,
             ClientHandlerVariant::ProxyPublic(v) => Some(&v.rebase),
+            ClientHandlerVariant::SplitProxy(v) => Some(&v.rebase),
         }
     }
 
@@ -352,6 +613,7 @@ impl ClientHandlerVariant {
             ClientHandlerVariant::GcsBucket(v) => Some(&v.cache),
             ClientHandlerVariant::PassThrough(_) => None,
             ClientHandlerVariant::ProxyPublic(v) => Some(&v.cache),
+            ClientHandlerVariant::SplitProxy(v) => Some(&v.cache),
         }
     }
 }
@@ -385,6 +647,9 @@ pub struct ClientHandler {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config_core::{substitute_path_segments, LoadBalancing, MatchRequest};
+    use http::HeaderMap;
+    use smol_str::SmolStr;
 
     #[test]
     pub fn test_language() {
@@ -486,6 +751,32 @@ mount-points:
         ClientConfig::parse_with_redefined_upstreams(YAML, &Default::default()).unwrap();
     }
 
+    #[test]
+    pub fn test_parsing_1_2_0() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: pass-through
+        priority: 30
+        rules:
+          - filter:
+              path: ["*"]
+              headers:
+                Accept: "/json/"
+                X-Api-Version: ["2", "3"]
+              cookies:
+                beta: "1"
+              host: "api.example.com"
+            action: invoke
+"#;
+        ClientConfig::parse_with_redefined_upstreams(YAML, &Default::default()).unwrap();
+    }
+
     #[test]
     pub fn test_validate_upstream_not_defined() {
         const YAML: &str = r#"---
@@ -512,6 +803,533 @@ mount-points:
         assert!(matches!(e, ClientConfigError::UpstreamNotDefined(_)));
     }
 
+    #[test]
+    pub fn test_bad_substitution() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: pass-through
+        priority: 30
+        rules:
+          - filter:
+              path: ["users", "?"]
+            action: invoke
+            modify-request:
+              path: ["user", "{{ segments.id }}"]
+"#;
+        let e = ClientConfig::parse(YAML).err().unwrap();
+
+        assert!(matches!(
+            e.downcast_ref::<ClientConfigError>(),
+            Some(ClientConfigError::BadSubstitution { rule: 0, .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_literal_substitutions_before_1_2_0() {
+        const YAML: &str = r#"---
+version: 1.1.0
+revision: 10
+name: repository-1
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: pass-through
+        priority: 30
+        rules:
+          - filter:
+              path: ["users", "/^(?P<id>[0-9]+)$/", "*"]
+            action: invoke
+            modify-request:
+              path: ["user", "$1", "{{ segments.id }}", "{{ id", "a/b", "$$"]
+              query-params:
+                strategy: keep
+                set:
+                  id: "$1"
+                  price: "$$5"
+              headers:
+                insert:
+                  x-price: "from $1"
+                  x-template: "{{ segments.id }}"
+"#;
+
+        fn render(config: &ClientConfig) -> (Vec<SmolStr>, BTreeMap<SmolStr, String>, HeaderMap) {
+            let mount = config.mount_points.values().next().unwrap();
+            let rule = &mount.handlers.values().next().unwrap().rules[0];
+            let uri: http::Uri = "/users/42/x/y".parse().unwrap();
+            let captures = rule
+                .filter
+                .matches(&MatchRequest::new(&http::Method::GET, &uri))
+                .unwrap();
+            let modify_request = rule.action.modify_request().unwrap();
+            (
+                substitute_path_segments(modify_request.path.as_ref().unwrap(), &captures),
+                modify_request.query_params.substitute_set(&captures),
+                modify_request.headers.insert.substitute(&captures).unwrap(),
+            )
+        }
+
+        let config = ClientConfig::parse(YAML).unwrap();
+        config.validate().unwrap();
+
+        let expected = (
+            vec![
+                SmolStr::from("user"),
+                "$1".into(),
+                "{{ segments.id }}".into(),
+                "{{ id".into(),
+                "a/b".into(),
+                "$$".into(),
+            ],
+            btreemap! {
+                SmolStr::from("id") => "$1".to_string(),
+                SmolStr::from("price") => "$$5".to_string(),
+            },
+            {
+                let mut headers = HeaderMap::new();
+                headers.insert("x-price", "from $1".parse().unwrap());
+                headers.insert("x-template", "{{ segments.id }}".parse().unwrap());
+                headers
+            },
+        );
+        assert_eq!(render(&config), expected);
+
+        // the same after passing the config over the wire
+        let config =
+            serde_json::from_str::<ClientConfig>(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(render(&config), expected);
+
+        let config = ClientConfig::parse(
+            YAML.replace("version: 1.1.0", "version: 1.2.0")
+                .replace(r#""{{ id", "#, ""),
+        )
+        .unwrap();
+        let (path, query, headers) = render(&config);
+        assert_eq!(path, vec!["user", "42", "42", "a/b", "$"]);
+        assert_eq!(query["price"], "$5");
+        assert_eq!(headers["x-template"], "42");
+    }
+
+    #[test]
+    pub fn test_split_proxy() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+upstreams:
+  stable:
+    port: 3000
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: split-proxy
+        priority: 30
+        upstreams:
+          - upstream: stable
+            weight: 95
+          - upstream: canary
+            weight: 5
+        sticky:
+          by: header
+          name: x-user-id
+"#;
+        let cfg = ClientConfig::parse(YAML).unwrap();
+
+        match cfg.validate() {
+            Err(ClientConfigError::UpstreamNotDefined(upstreams)) => {
+                assert_eq!(upstreams, vec!["canary".parse().unwrap()])
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    pub fn test_upstream_addresses() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+upstreams:
+  backend:
+    port: 3000
+    addresses:
+      - port: 3001
+      - host: backend.local
+        port: 3000
+    resolve-all: true
+    load-balancing: least-connections
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: proxy
+        priority: 30
+        upstream: backend
+"#;
+        let cfg = ClientConfig::parse(YAML).unwrap();
+        cfg.validate().unwrap();
+
+        let upstream = cfg.upstreams.get(&"backend".parse().unwrap()).unwrap();
+        assert_eq!(upstream.load_balancing, LoadBalancing::LeastConnections);
+        assert_eq!(
+            upstream
+                .all_addrs()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>(),
+            vec!["127.0.0.1:3000", "127.0.0.1:3001", "backend.local:3000"]
+        );
+
+        // the instance serves the upstream on its own address only
+        let mut redefined = HashMap::new();
+        redefined.insert("backend".parse().unwrap(), ":4000".parse().unwrap());
+        let cfg = ClientConfig::parse_with_redefined_upstreams(YAML, &redefined).unwrap();
+        let upstream = cfg.upstreams.get(&"backend".parse().unwrap()).unwrap();
+        assert_eq!(
+            upstream
+                .all_addrs()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>(),
+            vec!["127.0.0.1:4000"]
+        );
+        assert!(!upstream.resolve_all);
+    }
+
+    #[test]
+    pub fn test_unix_upstream() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+upstreams:
+  backend:
+    unix: /run/app/gunicorn.sock
+  php:
+    port: 9000
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: proxy
+        priority: 30
+        upstream: backend
+"#;
+        let backend: Upstream = "backend".parse().unwrap();
+        let php: Upstream = "php".parse().unwrap();
+
+        let cfg = ClientConfig::parse(YAML).unwrap();
+        cfg.validate().unwrap();
+        assert_eq!(
+            cfg.upstreams[&backend].addr.to_string(),
+            "unix:/run/app/gunicorn.sock"
+        );
+
+        let mut redefined = HashMap::new();
+        redefined.insert(php.clone(), "unix:/run/php/fpm.sock".parse().unwrap());
+        let cfg = ClientConfig::parse_with_redefined_upstreams(YAML, &redefined).unwrap();
+        assert_eq!(
+            cfg.upstreams[&php].addr.unix.as_deref(),
+            Some("/run/php/fpm.sock")
+        );
+
+        assert!("unix:".parse::<UpstreamSocketAddr>().is_err());
+        redefined.insert(
+            php,
+            UpstreamSocketAddr {
+                port: 9000,
+                host: None,
+                unix: Some("/run/php/fpm.sock".to_string()),
+            },
+        );
+        assert!(ClientConfig::parse_with_redefined_upstreams(YAML, &redefined).is_err());
+    }
+
+    #[test]
+    pub fn test_probe_types() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+upstreams:
+  postgres:
+    port: 5432
+    health-checks:
+      connect:
+        kind: readiness
+        tcp: true
+        success-threshold: 2
+        failure-threshold: 5
+        initial-delay: 30s
+        timeout: 2s
+        period: 10s
+      ready:
+        kind: liveness
+        exec:
+          command: [pg_isready, -h, localhost]
+        timeout: 5s
+        period: 30s
+  backend:
+    port: 3000
+    health-checks:
+      main:
+        kind: liveness
+        path: /health
+        expected-body:
+          json-pointer: /status
+          equals: ok
+        timeout: 2s
+        period: 10s
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: proxy
+        priority: 30
+        upstream: backend
+"#;
+        let mut cfg = ClientConfig::parse(YAML).unwrap();
+        cfg.validate().unwrap();
+
+        let postgres = cfg.upstreams.get_mut(&"postgres".parse().unwrap()).unwrap();
+        postgres.health_checks.values_mut().next().unwrap().path = Some("/".into());
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::BadHealthCheckValues {
+                probe_error: ProbeError::HttpOnly(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    pub fn test_upstream_tls() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+upstreams:
+  backend:
+    port: 443
+    host: 10.0.0.5
+    tls:
+      server-name: backend.internal
+      ca-bundle: /etc/exogress/internal-ca.pem
+      client-certificate:
+        certificate: /etc/exogress/client.pem
+        private-key: /etc/exogress/client.key
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: proxy
+        priority: 30
+        upstream: backend
+"#;
+        let mut cfg = ClientConfig::parse(YAML).unwrap();
+        cfg.validate().unwrap();
+
+        let upstream = cfg.upstreams.values_mut().next().unwrap();
+        let tls = upstream.tls.as_mut().unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("backend.internal"));
+        tls.insecure_skip_verify = true;
+
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::BadUpstreamTls {
+                error: UpstreamTlsError::CaBundleWithoutVerification,
+                ..
+            })
+        ));
+
+        // the certificate can't be verified against the IP address
+        let upstream = cfg.upstreams.values_mut().next().unwrap();
+        let tls = upstream.tls.as_mut().unwrap();
+        tls.ca_bundle = None;
+        tls.server_name = None;
+        tls.insecure_skip_verify = false;
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::BadUpstreamTls {
+                error: UpstreamTlsError::ServerNameRequired(_),
+                ..
+            })
+        ));
+
+        let upstream = cfg.upstreams.values_mut().next().unwrap();
+        upstream.tls.as_mut().unwrap().insecure_skip_verify = true;
+        cfg.validate().unwrap();
+
+        // redefined addresses are checked as well
+        let yaml = YAML
+            .replace("host: 10.0.0.5", "host: backend.internal")
+            .replace("      server-name: backend.internal\n", "");
+        ClientConfig::parse_with_redefined_upstreams(&yaml, &Default::default()).unwrap();
+        for addr in &["10.0.0.6:443", "unix:/run/backend.sock"] {
+            let mut redefined = HashMap::new();
+            redefined.insert("backend".parse().unwrap(), addr.parse().unwrap());
+            let e = ClientConfig::parse_with_redefined_upstreams(&yaml, &redefined)
+                .err()
+                .unwrap();
+            assert!(matches!(
+                e.downcast_ref::<ClientConfigError>(),
+                Some(ClientConfigError::BadUpstreamTls {
+                    error: UpstreamTlsError::ServerNameRequired(_),
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_circuit_breaker() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+upstreams:
+  backend:
+    port: 3000
+    circuit-breaker:
+      failure-threshold: 3
+      cool-down: 1m
+    health-checks:
+      circuit-breaker:
+        kind: liveness
+        path: /
+        timeout: 2s
+        period: 10s
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: proxy
+        priority: 30
+        upstream: backend
+"#;
+        let mut cfg = ClientConfig::parse(YAML).unwrap();
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::ReservedProbeName(_))
+        ));
+
+        let upstream = cfg.upstreams.values_mut().next().unwrap();
+        upstream.health_checks.clear();
+        let circuit_breaker = upstream.circuit_breaker.as_mut().unwrap();
+        assert_eq!(
+            circuit_breaker.cool_down.0,
+            std::time::Duration::from_secs(60)
+        );
+        cfg.validate().unwrap();
+
+        let upstream = cfg.upstreams.values_mut().next().unwrap();
+        upstream.circuit_breaker.as_mut().unwrap().failure_threshold = 0;
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::BadCircuitBreaker { .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_udp_upstream() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+upstreams:
+  backend:
+    port: 3000
+  dns:
+    port: 53
+    protocol: udp
+    udp-idle-timeout: 30s
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: proxy
+        priority: 30
+        upstream: backend
+"#;
+        let mut cfg = ClientConfig::parse(YAML).unwrap();
+        cfg.validate().unwrap();
+
+        let dns = &cfg.upstreams[&"dns".parse().unwrap()];
+        assert_eq!(dns.protocol, UpstreamProtocol::Udp);
+        assert_eq!(dns.udp_idle_timeout(), std::time::Duration::from_secs(30));
+
+        cfg.upstreams
+            .get_mut(&"backend".parse().unwrap())
+            .unwrap()
+            .protocol = UpstreamProtocol::Udp;
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::UdpUpstreamInHandler(_))
+        ));
+
+        cfg.upstreams
+            .get_mut(&"backend".parse().unwrap())
+            .unwrap()
+            .protocol = UpstreamProtocol::Tcp;
+        cfg.upstreams
+            .get_mut(&"dns".parse().unwrap())
+            .unwrap()
+            .protocol = UpstreamProtocol::Tcp;
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::BadUpstreamProtocol {
+                error: UpstreamProtocolError::UdpOnly(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    pub fn test_rate_limiters() {
+        const YAML: &str = r#"---
+version: 1.2.0
+revision: 10
+name: repository-1
+rate-limiters:
+  per-user:
+    algorithm: token-bucket
+    burst: 20
+    rate: 10
+    period: 1s
+    key:
+      kind: path-capture
+      capture: "{{ segments.id }}"
+mount-points:
+  mount_point:
+    handlers:
+      main:
+        kind: pass-through
+        priority: 30
+        rules:
+          - filter:
+              path: ["users", "/(?P<id>[0-9]+)/"]
+            action: invoke
+            rate-limit: per-user
+            rescue:
+              - catch: "exception:rate-limit-error:exceeded"
+                action: next-handler
+"#;
+        let cfg = ClientConfig::parse(YAML).unwrap();
+        cfg.validate().unwrap();
+
+        let mut cfg = cfg;
+        cfg.rate_limiters.clear();
+        assert!(matches!(
+            cfg.validate(),
+            Err(ClientConfigError::RateLimiterNotDefined(names)) if names.len() == 1
+        ));
+    }
+
     #[test]
     pub fn test_checksum() {
         const YAML1: &str = r#"---
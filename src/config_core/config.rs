@@ -23,6 +23,10 @@ pub fn default_rules() -> Vec<Rule> {
             },
             methods: Default::default(),
             trailing_slash: Default::default(),
+            headers: Default::default(),
+            cookies: Default::default(),
+            host: None,
+            client_ip: None,
         },
         action: Action::Invoke {
             modify_request: Default::default(),
@@ -30,6 +34,8 @@ pub fn default_rules() -> Vec<Rule> {
             rescue: Default::default(),
         },
         cache: Default::default(),
+        rate_limit: None,
+        cors: None,
         profiles: None,
     }]
 }
@@ -0,0 +1,323 @@
+use crate::config_core::{query::MatchQuerySingleValue, rule::MethodWrapper, DurationWrapper};
+use http::{
+    header::{
+        HeaderName, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN, VARY,
+    },
+    HeaderMap, HeaderValue, Method,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+/// Cross-origin resource sharing policy.
+///
+/// Origins are matched the same way as query parameters: exact value, `/regex/` or `*`.
+/// Allowed headers may contain `*` to accept any requested header.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+pub struct CorsPolicy {
+    #[serde(rename = "allowed-origins")]
+    pub allowed_origins: Vec<MatchQuerySingleValue>,
+
+    #[serde(rename = "allowed-methods", default = "default_allowed_methods")]
+    pub allowed_methods: Vec<MethodWrapper>,
+
+    #[serde(rename = "allowed-headers", default)]
+    pub allowed_headers: Vec<SmolStr>,
+
+    #[serde(rename = "exposed-headers", default)]
+    pub exposed_headers: Vec<SmolStr>,
+
+    #[serde(rename = "allow-credentials", default)]
+    pub allow_credentials: bool,
+
+    #[serde(rename = "max-age", default)]
+    pub max_age: Option<DurationWrapper>,
+}
+
+fn default_allowed_methods() -> Vec<MethodWrapper> {
+    vec![
+        MethodWrapper(Method::GET),
+        MethodWrapper(Method::HEAD),
+        MethodWrapper(Method::POST),
+    ]
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CorsError {
+    #[error("origin `{0}` is not allowed")]
+    OriginNotAllowed(String),
+
+    #[error("method `{0}` is not allowed")]
+    MethodNotAllowed(String),
+
+    #[error("header `{0}` is not allowed")]
+    HeaderNotAllowed(String),
+}
+
+/// Result of evaluating the CORS policy against the request
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsDecision {
+    /// Not a cross-origin request, process as usual
+    NotApplicable,
+
+    /// Preflight request, which should be answered with an empty response with these headers
+    Preflight(HeaderMap),
+
+    /// Cross-origin request, these headers should be added to the response
+    Actual(HeaderMap),
+
+    /// Request violates the policy
+    Rejected(CorsError),
+}
+
+impl CorsPolicy {
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.is_match(origin))
+    }
+
+    fn is_any_origin_allowed(&self) -> bool {
+        self.allowed_origins.iter().any(|allowed| {
+            allowed.is_any_single_path_segment() || allowed.is_may_be_multiple_path_segments()
+        })
+    }
+
+    pub fn is_method_allowed(&self, method: &str) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.0.as_str() == method)
+    }
+
+    pub fn is_header_allowed(&self, header: &str) -> bool {
+        self.allowed_headers
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(header))
+    }
+
+    /// Compute headers for the preflight or the actual response
+    pub fn evaluate(&self, method: &Method, request_headers: &HeaderMap) -> CorsDecision {
+        let origin = match request_headers
+            .get(ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+        {
+            Some(origin) => origin,
+            None => return CorsDecision::NotApplicable,
+        };
+
+        if !self.is_origin_allowed(origin) {
+            return CorsDecision::Rejected(CorsError::OriginNotAllowed(origin.to_string()));
+        }
+
+        let mut headers = HeaderMap::new();
+        self.add_origin_headers(origin, &mut headers);
+
+        let requested_method = request_headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| method.to_str().ok());
+
+        match requested_method {
+            Some(requested_method) if method == Method::OPTIONS => {
+                if !self.is_method_allowed(requested_method) {
+                    return CorsDecision::Rejected(CorsError::MethodNotAllowed(
+                        requested_method.to_string(),
+                    ));
+                }
+
+                let requested_headers = request_headers
+                    .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .map(|header| header.trim())
+                    .filter(|header| !header.is_empty())
+                    .collect::<Vec<_>>();
+
+                if let Some(not_allowed) = requested_headers
+                    .iter()
+                    .find(|header| !self.is_header_allowed(header))
+                {
+                    return CorsDecision::Rejected(CorsError::HeaderNotAllowed(
+                        not_allowed.to_string(),
+                    ));
+                }
+
+                let methods = self
+                    .allowed_methods
+                    .iter()
+                    .map(|method| method.0.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                insert_header(&mut headers, ACCESS_CONTROL_ALLOW_METHODS, &methods);
+
+                if !requested_headers.is_empty() {
+                    insert_header(
+                        &mut headers,
+                        ACCESS_CONTROL_ALLOW_HEADERS,
+                        &requested_headers.join(", "),
+                    );
+                }
+
+                if let Some(max_age) = &self.max_age {
+                    headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.0.as_secs().into());
+                }
+
+                CorsDecision::Preflight(headers)
+            }
+            _ => {
+                if !self.exposed_headers.is_empty() {
+                    insert_header(
+                        &mut headers,
+                        ACCESS_CONTROL_EXPOSE_HEADERS,
+                        &self.exposed_headers.join(", "),
+                    );
+                }
+
+                CorsDecision::Actual(headers)
+            }
+        }
+    }
+
+    fn add_origin_headers(&self, origin: &str, headers: &mut HeaderMap) {
+        // wildcard can't be used with credentials, so the origin is echoed back
+        if self.is_any_origin_allowed() && !self.allow_credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            insert_header(headers, ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(VARY, HeaderValue::from_static("Origin"));
+        }
+
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+fn insert_header(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> CorsPolicy {
+        serde_yaml::from_str(
+            r#"
+---
+allowed-origins:
+  - https://example.com
+  - '/^https://.+\.example\.org$/'
+allowed-methods: ["GET", "PUT"]
+allowed-headers: ["Content-Type", "X-Requested-With"]
+exposed-headers: ["X-Request-Id"]
+allow-credentials: true
+max-age: 10m
+"#,
+        )
+        .unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    pub fn test_preflight() {
+        let policy = policy();
+
+        let decision = policy.evaluate(
+            &Method::OPTIONS,
+            &headers(&[
+                ("origin", "https://api.example.org"),
+                ("access-control-request-method", "PUT"),
+                (
+                    "access-control-request-headers",
+                    "content-type, x-requested-with",
+                ),
+            ]),
+        );
+        let response_headers = match decision {
+            CorsDecision::Preflight(response_headers) => response_headers,
+            other => panic!("unexpected decision {:?}", other),
+        };
+        assert_eq!(
+            response_headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://api.example.org"
+        );
+        assert_eq!(response_headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(
+            response_headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-requested-with"
+        );
+        assert_eq!(response_headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(response_headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(response_headers[VARY], "Origin");
+
+        assert_eq!(
+            policy.evaluate(
+                &Method::OPTIONS,
+                &headers(&[
+                    ("origin", "https://example.com"),
+                    ("access-control-request-method", "DELETE"),
+                ]),
+            ),
+            CorsDecision::Rejected(CorsError::MethodNotAllowed("DELETE".to_string()))
+        );
+
+        assert_eq!(
+            policy.evaluate(
+                &Method::OPTIONS,
+                &headers(&[
+                    ("origin", "https://example.com"),
+                    ("access-control-request-method", "GET"),
+                    ("access-control-request-headers", "authorization"),
+                ]),
+            ),
+            CorsDecision::Rejected(CorsError::HeaderNotAllowed("authorization".to_string()))
+        );
+    }
+
+    #[test]
+    pub fn test_actual_request() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.evaluate(&Method::GET, &HeaderMap::new()),
+            CorsDecision::NotApplicable
+        );
+        assert_eq!(
+            policy.evaluate(&Method::GET, &headers(&[("origin", "https://evil.com")])),
+            CorsDecision::Rejected(CorsError::OriginNotAllowed("https://evil.com".to_string()))
+        );
+
+        match policy.evaluate(&Method::GET, &headers(&[("origin", "https://example.com")])) {
+            CorsDecision::Actual(headers) => {
+                assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+                assert_eq!(headers[ACCESS_CONTROL_EXPOSE_HEADERS], "X-Request-Id");
+            }
+            other => panic!("unexpected decision {:?}", other),
+        }
+
+        let any_origin = serde_yaml::from_str::<CorsPolicy>("allowed-origins: [\"*\"]").unwrap();
+        match any_origin.evaluate(&Method::POST, &headers(&[("origin", "https://a.com")])) {
+            CorsDecision::Actual(headers) => {
+                assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+                assert!(headers.get(VARY).is_none());
+            }
+            other => panic!("unexpected decision {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config_core::query::{match_named_values, MatchQuerySingleValue, MatchQueryValue};
+use http::{header::HeaderName, HeaderMap};
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use smol_str::SmolStr;
+use std::collections::BTreeMap;
+
+/// Request headers matcher.
+///
+/// Follows the `query-params` conventions: `null` requires the header to be absent, `*`
+/// accepts any value or absence, `?` requires the header with any value. Header names are
+/// case-insensitive.
+#[derive(Serialize, Debug, Default, Hash, Eq, PartialEq, Clone, JsonSchema)]
+#[serde(transparent)]
+pub struct HeaderMatcher {
+    pub inner: BTreeMap<SmolStr, Option<MatchQueryValue>>,
+}
+
+impl HeaderMatcher {
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn is_match(&self, headers: &HeaderMap) -> bool {
+        match_named_values(&self.inner, |name| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect()
+        })
+        .is_some()
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderMatcher {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = BTreeMap::<SmolStr, Option<MatchQueryValue>>::deserialize(deserializer)?;
+
+        for name in raw.keys() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| de::Error::custom(format!("bad header name `{}`: {}", name, e)))?;
+        }
+
+        Ok(HeaderMatcher { inner: raw })
+    }
+}
+
+/// Request cookies matcher, following the `query-params` conventions
+#[derive(Serialize, Deserialize, Debug, Default, Hash, Eq, PartialEq, Clone, JsonSchema)]
+#[serde(transparent)]
+pub struct CookieMatcher {
+    pub inner: BTreeMap<SmolStr, Option<MatchQueryValue>>,
+}
+
+impl CookieMatcher {
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn is_match(&self, headers: &HeaderMap) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let cookies = request_cookies(headers);
+
+        match_named_values(&self.inner, |name| {
+            cookies
+                .iter()
+                .filter(|(cookie_name, _)| *cookie_name == name)
+                .map(|(_, value)| *value)
+                .collect()
+        })
+        .is_some()
+    }
+}
+
+fn request_cookies(headers: &HeaderMap) -> Vec<(&str, &str)> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            Some((name.trim(), value.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// Match the requested host, ignoring the port. Configured names are compared
+/// case-insensitively, regexes are matched against the lowercased host
+pub fn is_host_match(matcher: &MatchQueryValue, host: Option<&str>) -> bool {
+    match host {
+        Some(host) => {
+            let host = match host.rsplit_once(':') {
+                Some((name, port))
+                    if !name.is_empty() && port.chars().all(|c| c.is_ascii_digit()) =>
+                {
+                    name
+                }
+                _ => host,
+            };
+            let host = host.to_ascii_lowercase();
+            match matcher {
+                MatchQueryValue::Single(MatchQuerySingleValue::Exact(expected)) => {
+                    expected.eq_ignore_ascii_case(&host)
+                }
+                MatchQueryValue::Choice(choices) => choices
+                    .iter()
+                    .any(|choice| choice.eq_ignore_ascii_case(&host)),
+                matcher => matcher.is_match(&host),
+            }
+        }
+        None => matches!(
+            matcher,
+            MatchQueryValue::Single(MatchQuerySingleValue::MayBeAnyMultipleSegments)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    pub fn test_header_matcher() {
+        let matcher = serde_yaml::from_str::<HeaderMatcher>(
+            r#"
+---
+Accept: /json/
+X-Api-Version: ["2", "3"]
+X-Debug: ~
+X-Trace: "*"
+Authorization: "?"
+"#,
+        )
+        .unwrap();
+
+        let matching = headers(&[
+            ("accept", "text/html"),
+            ("accept", "application/json"),
+            ("x-api-version", "3"),
+            ("authorization", "Bearer 1"),
+        ]);
+        assert!(matcher.is_match(&matching));
+
+        let mut with_debug = matching.clone();
+        with_debug.insert("x-debug", "1".parse().unwrap());
+        assert!(!matcher.is_match(&with_debug));
+
+        let mut bad_version = matching.clone();
+        bad_version.insert("x-api-version", "1".parse().unwrap());
+        assert!(!matcher.is_match(&bad_version));
+
+        let mut no_auth = matching;
+        no_auth.remove("authorization");
+        assert!(!matcher.is_match(&no_auth));
+
+        assert!(serde_yaml::from_str::<HeaderMatcher>("bad header: \"?\"").is_err());
+    }
+
+    #[test]
+    pub fn test_cookie_matcher() {
+        let matcher = serde_yaml::from_str::<CookieMatcher>(
+            r#"
+---
+beta: "1"
+session: "?"
+"#,
+        )
+        .unwrap();
+
+        assert!(matcher.is_match(&headers(&[("cookie", "session=abc; beta=1")])));
+        assert!(matcher.is_match(&headers(&[
+            ("cookie", "session=abc"),
+            ("cookie", "beta=\"1\"")
+        ])));
+        assert!(!matcher.is_match(&headers(&[("cookie", "session=abc; beta=0")])));
+        assert!(!matcher.is_match(&headers(&[("cookie", "beta=1")])));
+        assert!(!matcher.is_match(&HeaderMap::new()));
+    }
+
+    #[test]
+    pub fn test_host_match() {
+        let matcher =
+            serde_yaml::from_str::<MatchQueryValue>(r#"'/^(.+\.)?example\.com$/'"#).unwrap();
+        assert!(is_host_match(&matcher, Some("api.example.com:8443")));
+        assert!(is_host_match(&matcher, Some("Example.com")));
+        assert!(!is_host_match(&matcher, Some("example.org")));
+        assert!(!is_host_match(&matcher, None));
+
+        let matcher = serde_yaml::from_str::<MatchQueryValue>(r#"["a.com", "b.com"]"#).unwrap();
+        assert!(is_host_match(&matcher, Some("b.com")));
+        assert!(!is_host_match(&matcher, Some("c.com")));
+
+        let matcher = serde_yaml::from_str::<MatchQueryValue>("API.example.com").unwrap();
+        assert!(is_host_match(&matcher, Some("api.example.com")));
+        assert!(is_host_match(&matcher, Some("Api.Example.com:443")));
+
+        let matcher = serde_yaml::from_str::<MatchQueryValue>(r#"["A.com", "b.com"]"#).unwrap();
+        assert!(is_host_match(&matcher, Some("a.com")));
+        assert!(is_host_match(&matcher, Some("B.COM")));
+    }
+}
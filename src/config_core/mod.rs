@@ -7,33 +7,47 @@ pub use client_config::{
     ClientConfig, ClientConfigRevision, ClientHandler, ClientHandlerVariant, ClientMount, Languages,
 };
 pub use config::{default_rules, Config};
+pub use cors::{CorsDecision, CorsError, CorsPolicy};
 pub use duration::DurationWrapper;
+pub use header_matcher::{CookieMatcher, HeaderMatcher};
 use include_dir::{include_dir, Dir};
 use lazy_static::lazy_static;
 pub use methods::MethodMatcher;
 pub use pass_through::PassThrough;
-pub use path::{MatchPathSegment, MatchPathSingleSegment, MatchingPath};
-pub use path_modify::PathSegmentsModify;
+pub use path::{MatchPathSegment, MatchPathSingleSegment, MatchingPath, PathCaptures};
+pub use path_modify::{substitute_path_segments, PathSegmentsModify};
 pub use path_segment::UrlPathSegment;
 pub use post_processing::{Encoding, PostProcessing};
 pub use project_config::{ProjectConfig, ProjectHandler, ProjectHandlerVariant};
 pub use proxy::Proxy;
 pub use query::{MatchQuerySingleValue, MatchQueryValue, QueryMatcher};
+pub use rate_limit::{
+    InProcessRateLimiter, RateLimitAlgorithm, RateLimitExceeded, RateLimitKey, RateLimiter,
+    RateLimiterError,
+};
 pub use rebase::Rebase;
 pub use redirect::RedirectTo;
 pub use response::{RawResponse, RedirectResponse, ResponseBody, StaticResponse, TemplateEngine};
 pub use rule::{
-    Action, Filter, ModifyHeaders, ModifyQueryStrategy, OnResponse, RequestModifications,
-    ResponseModifications, Rule, RuleCacheMode, TrailingSlashFilterRule, TrailingSlashModification,
+    Action, Filter, FilterMatch, MatchRequest, ModifyHeaders, ModifyQueryStrategy, OnResponse,
+    RequestModifications, ResponseModifications, Rule, RuleCacheMode, TrailingSlashFilterRule,
+    TrailingSlashModification,
 };
 pub use schema::get_schema;
 pub use scope::Scope;
 use semver::{Version, VersionReq};
 use serde::{de::DeserializeOwned, Serialize};
+pub use split_proxy::{SplitProxy, SplitProxyError, StickyAssignment, WeightedUpstream};
 pub use static_dir::StaticDir;
 pub use status_code::{StatusCode, StatusCodeRange};
 use std::collections::BTreeSet;
-pub use upstream::{Probe, UpstreamDefinition, UpstreamSocketAddr};
+pub use substitution::{AvailableCaptures, CaptureRef, SubstitutionError, SubstitutionTemplate};
+pub use upstream::{
+    BodyAssertion, BodyMatcher, CircuitBreaker, CircuitBreakerError, ClientCertificate, ExecProbe,
+    LoadBalancing, Probe, ProbeDetails, ProbeError, UpstreamDefinition, UpstreamProtocol,
+    UpstreamProtocolError, UpstreamSocketAddr, UpstreamSocketAddrParseError, UpstreamTls,
+    UpstreamTlsError, CIRCUIT_BREAKER_PROBE_NAME, DEFAULT_UDP_IDLE_TIMEOUT, UNIX_SOCKET_PREFIX,
+};
 pub use version::ConfigVersion;
 
 // mod application_firewall;
@@ -42,8 +56,10 @@ mod cache;
 mod catch;
 mod client_config;
 mod config;
+mod cors;
 mod duration;
 mod gcs;
+mod header_matcher;
 mod methods;
 mod pass_through;
 mod path;
@@ -55,6 +71,7 @@ mod proxy;
 mod proxy_public;
 mod query;
 // mod query_modify;
+mod rate_limit;
 mod rebase;
 mod redirect;
 pub mod referenced;
@@ -64,8 +81,11 @@ mod rule;
 mod s3;
 mod schema;
 mod scope;
+pub mod simulator;
+mod split_proxy;
 mod static_dir;
 mod status_code;
+mod substitution;
 mod upstream;
 mod version;
 
@@ -75,7 +95,9 @@ static PARAMETERS_SCHEMAS: Dir = include_dir!("schemas/parameters");
 
 lazy_static! {
     pub static ref MIN_SUPPORTED_VERSION: Version = "1.0.0".parse().unwrap();
-    pub static ref CURRENT_VERSION: ConfigVersion = ConfigVersion("1.1.0".parse().unwrap());
+    pub static ref CURRENT_VERSION: ConfigVersion = ConfigVersion("1.2.0".parse().unwrap());
+    /// Configs before this version may contain `$` and `{{` literally
+    pub static ref SUBSTITUTIONS_MIN_VERSION: Version = "1.2.0".parse().unwrap();
     pub static ref VERSION_REQUIREMENT: VersionReq = format!(
         ">={} <={} <2",
         MIN_SUPPORTED_VERSION.to_string(),
@@ -149,6 +171,7 @@ mod test {
         assert!(!is_version_supported(&"0.2.4".parse().unwrap()));
         assert!(is_version_supported(&"1.0.0".parse().unwrap()));
         assert!(is_version_supported(&"1.1.0".parse().unwrap()));
+        assert!(is_version_supported(&"1.2.0".parse().unwrap()));
         assert!(!is_version_supported(&"1.0.0-pre.2".parse().unwrap()));
         assert!(!is_version_supported(&"1.23.1".parse().unwrap()));
         assert!(!is_version_supported(&"2.0.0".parse().unwrap()));
@@ -17,7 +17,10 @@ use schemars::schema::{
     ArrayValidation, InstanceType, Metadata, SchemaObject, StringValidation, SubschemaValidation,
 };
 use smol_str::SmolStr;
-use std::hash::{Hash, Hasher};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+};
 
 pub const ANY_SEGMENTS_MATCH_STR: &str = "*";
 pub const ANY_STR: &str = "?";
@@ -131,6 +134,59 @@ impl MatchPathSingleSegment {
             _ => None,
         }
     }
+
+    pub fn is_match(&self, segment: &str) -> bool {
+        self.capture(segment, &mut Default::default())
+    }
+
+    fn capture(&self, segment: &str, captures: &mut PathCaptures) -> bool {
+        match self {
+            MatchPathSingleSegment::Any => {
+                captures.positional.push(segment.into());
+                true
+            }
+            MatchPathSingleSegment::Exact(expected) => expected.as_str() == segment,
+            MatchPathSingleSegment::Regex(regex) => match regex.captures(segment) {
+                Some(found) => {
+                    for name in regex.capture_names().flatten() {
+                        if let Some(m) = found.name(name) {
+                            captures.named.insert(name.into(), m.as_str().into());
+                        }
+                    }
+                    captures.positional.push(segment.into());
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+impl MatchPathSegment {
+    pub fn is_capturing(&self) -> bool {
+        !matches!(
+            self,
+            MatchPathSegment::Single(MatchPathSingleSegment::Exact(_))
+        )
+    }
+
+    pub fn is_match(&self, segment: &str) -> bool {
+        self.capture(segment, &mut Default::default())
+    }
+
+    fn capture(&self, segment: &str, captures: &mut PathCaptures) -> bool {
+        match self {
+            MatchPathSegment::Single(single) => single.capture(segment, captures),
+            MatchPathSegment::Choice(choices) => {
+                if choices.iter().any(|choice| choice.as_str() == segment) {
+                    captures.positional.push(segment.into());
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
 }
 
 impl Hash for MatchPathSingleSegment {
@@ -255,6 +311,126 @@ impl MatchingPath {
             None
         }
     }
+
+    pub fn is_match<S: AsRef<str>>(&self, segments: &[S]) -> bool {
+        self.matches(segments).is_some()
+    }
+
+    fn segment_matchers(&self) -> (Vec<&MatchPathSegment>, bool) {
+        match self {
+            MatchingPath::Root => (vec![], false),
+            MatchingPath::Wildcard => (vec![], true),
+            MatchingPath::Strict(matchers) => (matchers.iter().collect(), false),
+            MatchingPath::LeftWildcardRight(left, right) => {
+                (left.iter().chain(right.iter()).collect(), true)
+            }
+            MatchingPath::LeftWildcard(left) => (left.iter().collect(), true),
+            MatchingPath::WildcardRight(right) => (right.iter().collect(), true),
+        }
+    }
+
+    /// Number of positional captures produced on the successful match
+    pub fn positional_captures_count(&self) -> usize {
+        let (matchers, has_wildcard) = self.segment_matchers();
+        matchers
+            .into_iter()
+            .filter(|matcher| matcher.is_capturing())
+            .count()
+            + has_wildcard as usize
+    }
+
+    /// Names of regex groups captured on the successful match
+    pub fn named_captures(&self) -> BTreeSet<SmolStr> {
+        self.segment_matchers()
+            .0
+            .into_iter()
+            .filter_map(|matcher| match matcher {
+                MatchPathSegment::Single(MatchPathSingleSegment::Regex(regex)) => Some(regex),
+                _ => None,
+            })
+            .flat_map(|regex| regex.capture_names().flatten().map(SmolStr::from))
+            .collect()
+    }
+
+    /// Match request path segments, returning captured values on success
+    pub fn matches<S: AsRef<str>>(&self, segments: &[S]) -> Option<PathCaptures> {
+        let mut captures = PathCaptures::default();
+
+        let is_matched = match self {
+            MatchingPath::Root => segments.is_empty(),
+            MatchingPath::Wildcard => {
+                captures.push_wildcard(segments);
+                true
+            }
+            MatchingPath::Strict(matchers) => capture_segments(matchers, segments, &mut captures),
+            MatchingPath::LeftWildcardRight(left, right) => {
+                segments.len() >= left.len() + right.len() && {
+                    let (head, rest) = segments.split_at(left.len());
+                    let (middle, tail) = rest.split_at(rest.len() - right.len());
+                    capture_segments(left, head, &mut captures) && {
+                        captures.push_wildcard(middle);
+                        capture_segments(right, tail, &mut captures)
+                    }
+                }
+            }
+            MatchingPath::LeftWildcard(left) => {
+                segments.len() >= left.len() && {
+                    let (head, rest) = segments.split_at(left.len());
+                    capture_segments(left, head, &mut captures) && {
+                        captures.push_wildcard(rest);
+                        true
+                    }
+                }
+            }
+            MatchingPath::WildcardRight(right) => {
+                segments.len() >= right.len() && {
+                    let (rest, tail) = segments.split_at(segments.len() - right.len());
+                    captures.push_wildcard(rest);
+                    capture_segments(right, tail, &mut captures)
+                }
+            }
+        };
+
+        if is_matched {
+            Some(captures)
+        } else {
+            None
+        }
+    }
+}
+
+fn capture_segments<S: AsRef<str>>(
+    matchers: &[MatchPathSegment],
+    segments: &[S],
+    captures: &mut PathCaptures,
+) -> bool {
+    matchers.len() == segments.len()
+        && matchers
+            .iter()
+            .zip(segments)
+            .all(|(matcher, segment)| matcher.capture(segment.as_ref(), captures))
+}
+
+/// Values captured from the request path by `MatchingPath`.
+///
+/// Every non-exact matcher produces a positional capture in the order of appearance: `?`,
+/// choices and regexes capture the matched segment, while `*` captures all covered
+/// segments joined with `/`. Named groups of regexes are additionally captured by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathCaptures {
+    pub positional: Vec<SmolStr>,
+    pub named: BTreeMap<SmolStr, SmolStr>,
+}
+
+impl PathCaptures {
+    fn push_wildcard<S: AsRef<str>>(&mut self, segments: &[S]) {
+        let joined = segments
+            .iter()
+            .map(|segment| segment.as_ref())
+            .collect::<Vec<_>>()
+            .join("/");
+        self.positional.push(joined.into());
+    }
 }
 
 impl Serialize for MatchingPath {
@@ -705,6 +881,79 @@ mod test {
         );
     }
 
+    fn path(yaml: &str) -> MatchingPath {
+        serde_yaml::from_str::<MatchingPath>(yaml).unwrap()
+    }
+
+    #[test]
+    pub fn test_path_is_match() {
+        assert!(path("[]").is_match::<&str>(&[]));
+        assert!(!path("[]").is_match(&["a"]));
+
+        assert!(path("[\"*\"]").is_match::<&str>(&[]));
+        assert!(path("[\"*\"]").is_match(&["a", "b"]));
+
+        assert!(path("[a, b]").is_match(&["a", "b"]));
+        assert!(!path("[a, b]").is_match(&["a"]));
+        assert!(!path("[a, b]").is_match(&["a", "b", "c"]));
+        assert!(!path("[a, b]").is_match(&["a", "c"]));
+
+        assert!(path("[a, \"*\"]").is_match(&["a"]));
+        assert!(path("[a, \"*\"]").is_match(&["a", "b", "c"]));
+        assert!(!path("[a, \"*\"]").is_match(&["b", "a"]));
+
+        assert!(path("[\"*\", c]").is_match(&["c"]));
+        assert!(path("[\"*\", c]").is_match(&["a", "b", "c"]));
+        assert!(!path("[\"*\", c]").is_match(&["c", "b"]));
+
+        assert!(path("[a, \"*\", c]").is_match(&["a", "c"]));
+        assert!(path("[a, \"*\", c]").is_match(&["a", "b", "b", "c"]));
+        assert!(!path("[a, \"*\", c]").is_match(&["a"]));
+        assert!(!path("[a, \"*\", c]").is_match(&["c"]));
+
+        assert!(path("[\"?\", [b, c]]").is_match(&["a", "c"]));
+        assert!(!path("[\"?\", [b, c]]").is_match(&["a", "d"]));
+
+        assert!(path("[\"/^[0-9]+$/\"]").is_match(&["123"]));
+        assert!(!path("[\"/^[0-9]+$/\"]").is_match(&["12a"]));
+    }
+
+    #[test]
+    pub fn test_path_captures() {
+        let captures = path("[v1, \"?\", [a, b], \"/^(?P<id>[0-9]+)\\\\.json$/\", \"*\"]")
+            .matches(&["v1", "users", "b", "42.json", "x", "y"])
+            .unwrap();
+
+        assert_eq!(
+            captures.positional,
+            vec![
+                SmolStr::from("users"),
+                SmolStr::from("b"),
+                SmolStr::from("42.json"),
+                SmolStr::from("x/y")
+            ]
+        );
+        assert_eq!(captures.named.get("id").map(SmolStr::as_str), Some("42"));
+
+        assert_eq!(
+            path("[v1, \"?\", [a, b], \"/^(?P<id>[0-9]+)\\\\.json$/\", \"*\"]")
+                .positional_captures_count(),
+            4
+        );
+        assert_eq!(
+            path("[v1, \"/^(?P<id>[0-9]+)$/\", \"/(?P<ext>[a-z]+)/\"]").named_captures(),
+            vec![SmolStr::from("ext"), SmolStr::from("id")]
+                .into_iter()
+                .collect()
+        );
+
+        let captures = path("[\"*\", \"?\"]").matches(&["a", "b", "c"]).unwrap();
+        assert_eq!(
+            captures.positional,
+            vec![SmolStr::from("a/b"), SmolStr::from("c")]
+        );
+    }
+
     #[test]
     pub fn test_path_error() {
         assert!(serde_yaml::from_str::<MatchingPath>("[\"*\", \"*\"]").is_err());
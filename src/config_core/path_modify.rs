@@ -1,3 +1,7 @@
+use crate::config_core::{
+    rule::FilterMatch,
+    substitution::{SubstitutionError, SubstitutionTemplate},
+};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -6,7 +10,7 @@ use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, Debug, Hash, Clone, Eq, PartialEq, JsonSchema)]
 #[serde(transparent)]
-pub struct PathSegmentsModify(pub SmolStr);
+pub struct PathSegmentsModify(pub SubstitutionTemplate);
 
 impl AsRef<str> for PathSegmentsModify {
     fn as_ref(&self) -> &str {
@@ -18,12 +22,43 @@ impl PathSegmentsModify {
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+
+    /// Substitute captures, which may expand to multiple path segments. Segments without
+    /// captures are kept as a single segment
+    pub fn substitute(&self, captures: &FilterMatch) -> Vec<SmolStr> {
+        let substituted = self.0.substitute(captures);
+        if self.0.is_static() {
+            return vec![substituted.into()];
+        }
+
+        substituted
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(SmolStr::from)
+            .collect()
+    }
+
+    /// Keep the segment as written, without substituting captures
+    pub fn disable_substitutions(&mut self) {
+        self.0 = SubstitutionTemplate::literal(self.0.as_str());
+    }
+}
+
+/// Build path segments from the list of modifications
+pub fn substitute_path_segments(
+    segments: &[PathSegmentsModify],
+    captures: &FilterMatch,
+) -> Vec<SmolStr> {
+    segments
+        .iter()
+        .flat_map(|segment| segment.substitute(captures))
+        .collect()
 }
 
 impl FromStr for PathSegmentsModify {
-    type Err = ();
+    type Err = SubstitutionError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(PathSegmentsModify(s.into()))
+        Ok(PathSegmentsModify(s.parse()?))
     }
 }
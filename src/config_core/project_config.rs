@@ -8,24 +8,28 @@ use crate::{
         gcs::GcsBucketAccess,
         is_version_supported,
         proxy_public::ProxyPublic,
+        rate_limit::{validate_rate_limiters, RateLimiter, RateLimiterError, RateLimitersError},
         referenced::Container,
         refinable::Refinable,
         s3::S3BucketAccess,
         schema::validate_schema,
+        substitution::SubstitutionError,
         validate_extra_keys, Auth, ClientHandler, ClientHandlerVariant, Config, ConfigVersion,
-        PassThrough, Rule, CURRENT_VERSION,
+        PassThrough, Rule, CURRENT_VERSION, SUBSTITUTIONS_MIN_VERSION,
     },
-    entities::{HandlerName, MountPointName},
+    entities::{HandlerName, MountPointName, RateLimiterName},
 };
 use maplit::btreemap;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::BTreeMap,
     hash::{Hash, Hasher},
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, JsonSchema)]
+#[serde(remote = "Self")]
+#[schemars(rename = "ProjectConfig")]
 // #[schemars(deny_unknown_fields)]
 pub struct ProjectConfig {
     pub version: ConfigVersion,
@@ -33,6 +37,9 @@ pub struct ProjectConfig {
     #[serde(rename = "mount-points", default)]
     pub mount_points: BTreeMap<MountPointName, ProjectMount>,
 
+    #[serde(default, rename = "rate-limiters")]
+    pub rate_limiters: BTreeMap<RateLimiterName, RateLimiter>,
+
     #[serde(flatten)]
     pub refinable: Refinable,
 }
@@ -80,6 +87,7 @@ impl ProjectConfig {
         ProjectConfig {
             version: CURRENT_VERSION.clone(),
             mount_points,
+            rate_limiters: Default::default(),
             refinable: Refinable {
                 rescue: Default::default(),
                 static_responses: Default::default(),
@@ -87,6 +95,60 @@ impl ProjectConfig {
         }
     }
 
+    /// Render the rules as written, if the config predates substitutions
+    pub fn disable_legacy_substitutions(&mut self) {
+        if self.version.0 >= *SUBSTITUTIONS_MIN_VERSION {
+            return;
+        }
+
+        self.refinable.disable_substitutions();
+        for mount in self.mount_points.values_mut() {
+            mount.refinable.disable_substitutions();
+            for handler in mount.handlers.values_mut() {
+                handler.refinable.disable_substitutions();
+                for rule in &mut handler.rules {
+                    rule.disable_substitutions();
+                }
+            }
+        }
+    }
+
+    /// Check that rules refer only to captures provided by their filters. Older configs
+    /// don't have substitutions, so they are not checked
+    pub fn validate_substitutions(&self) -> Result<(), ProjectConfigError> {
+        if self.version.0 < *SUBSTITUTIONS_MIN_VERSION {
+            return Ok(());
+        }
+
+        for mount in self.mount_points.values() {
+            for (handler_name, handler) in &mount.handlers {
+                for (rule_idx, rule) in handler.rules.iter().enumerate() {
+                    rule.validate_substitutions().map_err(|error| {
+                        ProjectConfigError::BadSubstitution {
+                            handler: handler_name.clone(),
+                            rule: rule_idx,
+                            error,
+                        }
+                    })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check rate limiters definitions and their usage in rules
+    pub fn validate_rate_limiters(&self) -> Result<(), ProjectConfigError> {
+        let handlers = self.mount_points.values().flat_map(|mount| {
+            mount
+                .handlers
+                .iter()
+                .map(|(handler_name, handler)| (handler_name, handler.rules.as_slice()))
+        });
+
+        Ok(validate_rate_limiters(&self.rate_limiters, handlers)?)
+    }
+
     pub fn default_with_mount_point(mount_point_name: &MountPointName) -> Self {
         ProjectConfig {
             version: CURRENT_VERSION.clone(),
@@ -99,6 +161,7 @@ impl ProjectConfig {
                     }
                 }
             },
+            rate_limiters: Default::default(),
             refinable: Refinable {
                 rescue: Default::default(),
                 static_responses: Default::default(),
@@ -112,6 +175,7 @@ impl Default for ProjectConfig {
         ProjectConfig {
             version: CURRENT_VERSION.clone(),
             mount_points: Default::default(),
+            rate_limiters: Default::default(),
             refinable: Refinable {
                 rescue: Default::default(),
                 static_responses: Default::default(),
@@ -120,6 +184,26 @@ impl Default for ProjectConfig {
     }
 }
 
+impl Serialize for ProjectConfig {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        ProjectConfig::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProjectConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut config = ProjectConfig::deserialize(deserializer)?;
+        config.disable_legacy_substitutions();
+        Ok(config)
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ProjectConfigError {
     #[error("mount points {} not defined", .0.iter().map(| s | s.to_string()).collect::< Vec < _ >> ().join(", "))]
@@ -127,6 +211,44 @@ pub enum ProjectConfigError {
 
     #[error("unsupported config version {}", _0)]
     UnsupportedVersion(ConfigVersion),
+
+    #[error("bad substitution in rule #{rule} of handler {handler}: {error}")]
+    BadSubstitution {
+        handler: HandlerName,
+        rule: usize,
+        error: SubstitutionError,
+    },
+
+    #[error("rate limiters {} not defined", .0.iter().map(| s | s.to_string()).collect::< Vec < _ >> ().join(", "))]
+    RateLimiterNotDefined(Vec<RateLimiterName>),
+
+    #[error("bad rate limiter {name}: {error}")]
+    BadRateLimiter {
+        name: RateLimiterName,
+        error: RateLimiterError,
+    },
+}
+
+impl From<RateLimitersError> for ProjectConfigError {
+    fn from(e: RateLimitersError) -> Self {
+        match e {
+            RateLimitersError::NotDefined(names) => {
+                ProjectConfigError::RateLimiterNotDefined(names)
+            }
+            RateLimitersError::BadRateLimiter { name, error } => {
+                ProjectConfigError::BadRateLimiter { name, error }
+            }
+            RateLimitersError::BadCaptures {
+                handler,
+                rule,
+                error,
+            } => ProjectConfigError::BadSubstitution {
+                handler,
+                rule,
+                error,
+            },
+        }
+    }
 }
 
 impl Config for ProjectConfig {
@@ -162,7 +284,8 @@ impl Config for ProjectConfig {
             return Err(ProjectConfigError::UnsupportedVersion(self.version.clone()));
         }
 
-        Ok(())
+        self.validate_substitutions()?;
+        self.validate_rate_limiters()
     }
 
     fn parse(yaml: impl AsRef<[u8]>) -> anyhow::Result<Self> {
@@ -170,6 +293,7 @@ impl Config for ProjectConfig {
 
         validate_extra_keys(&deserialized_cfg, yaml.as_ref())?;
         validate_schema(yaml.as_ref(), "project.json")?;
+        deserialized_cfg.validate_substitutions()?;
 
         Ok(deserialized_cfg)
     }
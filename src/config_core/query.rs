@@ -28,6 +28,59 @@ impl QueryMatcher {
     pub fn is_empty(&self) -> bool {
         self.inner.is_empty()
     }
+
+    pub fn is_match(&self, query: &BTreeMap<SmolStr, SmolStr>) -> bool {
+        self.matches(query).is_some()
+    }
+
+    /// Match request query parameters, returning values of matched non-exact parameters,
+    /// keyed by the parameter name.
+    ///
+    /// Parameters set to `null` should be absent in the query, `*` accepts any value or
+    /// absence, `?` requires the parameter with any value. Parameters not mentioned in the
+    /// matcher are ignored.
+    pub fn matches(
+        &self,
+        query: &BTreeMap<SmolStr, SmolStr>,
+    ) -> Option<BTreeMap<SmolStr, SmolStr>> {
+        match_named_values(&self.inner, |name| {
+            query.get(name).map(SmolStr::as_str).into_iter().collect()
+        })
+    }
+}
+
+/// Match named values, such as query parameters or headers, following `QueryMatcher` rules.
+///
+/// `lookup` returns all values provided under the name; the matcher is satisfied if any of
+/// them matches. Values of non-exact matchers are captured by name.
+pub(crate) fn match_named_values<'a>(
+    matchers: &BTreeMap<SmolStr, Option<MatchQueryValue>>,
+    lookup: impl Fn(&str) -> Vec<&'a str>,
+) -> Option<BTreeMap<SmolStr, SmolStr>> {
+    let mut captures = BTreeMap::new();
+
+    for (name, expected) in matchers {
+        let provided = lookup(name);
+        match expected {
+            None => {
+                if !provided.is_empty() {
+                    return None;
+                }
+            }
+            Some(MatchQueryValue::Single(MatchQuerySingleValue::MayBeAnyMultipleSegments))
+                if provided.is_empty() => {}
+            Some(expected) => {
+                let value = provided
+                    .into_iter()
+                    .find(|value| expected.is_match(value))?;
+                if !expected.is_exact() {
+                    captures.insert(name.clone(), value.into());
+                }
+            }
+        }
+    }
+
+    Some(captures)
 }
 
 impl Default for QueryMatcher {
@@ -44,6 +97,22 @@ pub enum MatchQueryValue {
     Choice(Vec<SmolStr>),
 }
 
+impl MatchQueryValue {
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            MatchQueryValue::Single(single) => single.is_match(value),
+            MatchQueryValue::Choice(choices) => choices.iter().any(|choice| choice == value),
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        matches!(
+            self,
+            MatchQueryValue::Single(MatchQuerySingleValue::Exact(_))
+        )
+    }
+}
+
 impl JsonSchema for MatchQueryValue {
     fn schema_name() -> String {
         "MatchQueryValue".to_string()
@@ -131,6 +200,15 @@ impl MatchQuerySingleValue {
             _ => None,
         }
     }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            MatchQuerySingleValue::AnySingleSegment => true,
+            MatchQuerySingleValue::MayBeAnyMultipleSegments => true,
+            MatchQuerySingleValue::Exact(expected) => expected == value,
+            MatchQuerySingleValue::Regex(regex) => regex.is_match(value),
+        }
+    }
 }
 
 impl Hash for MatchQuerySingleValue {
@@ -313,4 +391,63 @@ p9: ["a", "b", "c"]
             }
         );
     }
+
+    #[test]
+    pub fn test_matches() {
+        const YAML: &str = r#"
+---
+p1: v1
+p2: "*"
+p3: "?"
+p4: ~
+p5: /^[0-9]+$/
+p6: ["a", "b"]
+"#;
+        let matcher = serde_yaml::from_str::<QueryMatcher>(YAML).unwrap();
+
+        let query = btreemap! {
+            SmolStr::from("p1") => SmolStr::from("v1"),
+            SmolStr::from("p3") => SmolStr::from("x"),
+            SmolStr::from("p5") => SmolStr::from("12"),
+            SmolStr::from("p6") => SmolStr::from("b"),
+            SmolStr::from("other") => SmolStr::from("ignored"),
+        };
+        assert_eq!(
+            matcher.matches(&query),
+            Some(btreemap! {
+                SmolStr::from("p3") => SmolStr::from("x"),
+                SmolStr::from("p5") => SmolStr::from("12"),
+                SmolStr::from("p6") => SmolStr::from("b"),
+            })
+        );
+
+        let mut with_optional = query.clone();
+        with_optional.insert("p2".into(), "any".into());
+        assert_eq!(
+            matcher
+                .matches(&with_optional)
+                .unwrap()
+                .get("p2")
+                .map(SmolStr::as_str),
+            Some("any")
+        );
+
+        let mut with_absent = query.clone();
+        with_absent.insert("p4".into(), "".into());
+        assert!(!matcher.is_match(&with_absent));
+
+        let mut bad_regex = query.clone();
+        bad_regex.insert("p5".into(), "1a".into());
+        assert!(!matcher.is_match(&bad_regex));
+
+        let mut bad_choice = query.clone();
+        bad_choice.insert("p6".into(), "c".into());
+        assert!(!matcher.is_match(&bad_choice));
+
+        let mut missing = query;
+        missing.remove("p3");
+        assert!(!matcher.is_match(&missing));
+
+        assert!(QueryMatcher::default().is_match(&Default::default()));
+    }
 }
@@ -0,0 +1,558 @@
+use crate::{
+    config_core::{
+        rule::{FilterMatch, MatchRequest},
+        substitution::{AvailableCaptures, SubstitutionError, SubstitutionTemplate},
+        DurationWrapper, Rule,
+    },
+    entities::{HandlerName, RateLimiterName},
+};
+use hashbrown::HashMap;
+use http::header::HeaderName;
+use humantime::format_duration;
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Named rate limiter definition
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+pub struct RateLimiter {
+    #[serde(flatten)]
+    pub algorithm: RateLimitAlgorithm,
+
+    pub key: RateLimitKey,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+#[serde(tag = "algorithm")]
+pub enum RateLimitAlgorithm {
+    /// Refill `rate` tokens every `period`, allowing bursts up to `burst` requests
+    #[serde(rename = "token-bucket")]
+    TokenBucket {
+        burst: u32,
+        rate: u32,
+        period: DurationWrapper,
+    },
+
+    /// Allow up to `limit` requests within the sliding `window`
+    #[serde(rename = "sliding-window")]
+    SlidingWindow { limit: u32, window: DurationWrapper },
+}
+
+/// What requests are accounted together
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+#[serde(tag = "kind")]
+pub enum RateLimitKey {
+    #[serde(rename = "client-ip")]
+    ClientIp,
+
+    #[serde(rename = "header")]
+    Header { name: SmolStr },
+
+    /// Value captured by the rule filter, e.g. `$1` or `{{ segments.user }}`
+    #[serde(rename = "path-capture")]
+    PathCapture { capture: SubstitutionTemplate },
+
+    /// Identity of the user authenticated by the `auth` handler
+    #[serde(rename = "auth-identity")]
+    AuthIdentity,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RateLimiterError {
+    #[error("rate limit should be greater than zero")]
+    ZeroLimit,
+
+    #[error("period is below threshold of {}", format_duration(*threshold))]
+    PeriodBelowThreshold { threshold: Duration },
+
+    #[error("bad header name `{0}`")]
+    BadHeaderName(SmolStr),
+
+    #[error("path capture key doesn't refer to any capture")]
+    NoCapture,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RateLimitersError {
+    #[error("rate limiters {} not defined", .0.iter().map(| s | s.to_string()).collect::< Vec < _ >> ().join(", "))]
+    NotDefined(Vec<RateLimiterName>),
+
+    #[error("bad rate limiter {name}: {error}")]
+    BadRateLimiter {
+        name: RateLimiterName,
+        error: RateLimiterError,
+    },
+
+    #[error("bad substitution in rule #{rule} of handler {handler}: {error}")]
+    BadCaptures {
+        handler: HandlerName,
+        rule: usize,
+        error: SubstitutionError,
+    },
+}
+
+/// Check rate limiters definitions and their usage in rules of the handlers
+pub fn validate_rate_limiters<'a>(
+    rate_limiters: &BTreeMap<RateLimiterName, RateLimiter>,
+    handlers: impl IntoIterator<Item = (&'a HandlerName, &'a [Rule])>,
+) -> Result<(), RateLimitersError> {
+    for (name, rate_limiter) in rate_limiters {
+        rate_limiter
+            .validate()
+            .map_err(|error| RateLimitersError::BadRateLimiter {
+                name: name.clone(),
+                error,
+            })?;
+    }
+
+    let mut not_defined = BTreeSet::new();
+    for (handler_name, rules) in handlers {
+        for (rule_idx, rule) in rules.iter().enumerate() {
+            if let Some(rate_limit) = &rule.rate_limit {
+                match rate_limiters.get(rate_limit) {
+                    Some(rate_limiter) => rate_limiter
+                        .validate_captures(&rule.filter.available_captures())
+                        .map_err(|error| RateLimitersError::BadCaptures {
+                            handler: handler_name.clone(),
+                            rule: rule_idx,
+                            error,
+                        })?,
+                    None => {
+                        not_defined.insert(rate_limit.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    if !not_defined.is_empty() {
+        return Err(RateLimitersError::NotDefined(
+            not_defined.into_iter().collect(),
+        ));
+    }
+
+    Ok(())
+}
+
+impl RateLimiter {
+    const PERIOD_THRESHOLD: Duration = Duration::from_millis(1);
+
+    pub fn validate(&self) -> Result<(), RateLimiterError> {
+        let (limit, period) = match &self.algorithm {
+            RateLimitAlgorithm::TokenBucket {
+                burst,
+                rate,
+                period,
+            } => ((*burst).min(*rate), period),
+            RateLimitAlgorithm::SlidingWindow { limit, window } => (*limit, window),
+        };
+
+        if limit == 0 {
+            return Err(RateLimiterError::ZeroLimit);
+        }
+        if period.0 < RateLimiter::PERIOD_THRESHOLD {
+            return Err(RateLimiterError::PeriodBelowThreshold {
+                threshold: RateLimiter::PERIOD_THRESHOLD,
+            });
+        }
+
+        match &self.key {
+            RateLimitKey::Header { name } => {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| RateLimiterError::BadHeaderName(name.clone()))?;
+            }
+            RateLimitKey::PathCapture { capture } => {
+                if capture.is_static() {
+                    return Err(RateLimiterError::NoCapture);
+                }
+            }
+            RateLimitKey::ClientIp | RateLimitKey::AuthIdentity => {}
+        }
+
+        Ok(())
+    }
+
+    /// Ensure that the captures used as the key are provided by the rule filter
+    pub fn validate_captures(
+        &self,
+        available: &AvailableCaptures,
+    ) -> Result<(), SubstitutionError> {
+        match &self.key {
+            RateLimitKey::PathCapture { capture } => capture.validate(available),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl RateLimitKey {
+    /// Build the accounting key for the request.
+    ///
+    /// Returns `None` if the request doesn't carry the key attribute, e.g. the header is absent
+    /// or the user is not authenticated.
+    pub fn resolve(
+        &self,
+        request: &MatchRequest<'_>,
+        captures: &FilterMatch,
+        client_ip: Option<IpAddr>,
+        identity: Option<&str>,
+    ) -> Option<SmolStr> {
+        match self {
+            RateLimitKey::ClientIp => client_ip.map(|ip| SmolStr::new(ip.to_string())),
+            RateLimitKey::Header { name } => request
+                .headers?
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(SmolStr::new),
+            RateLimitKey::PathCapture { capture } => Some(capture.substitute(captures).into()),
+            RateLimitKey::AuthIdentity => identity.map(SmolStr::new),
+        }
+    }
+}
+
+/// Request rejected by the rate limiter
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("rate limit exceeded, retry after {}", format_duration(*retry_after))]
+pub struct RateLimitExceeded {
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LimiterState {
+    TokenBucket {
+        tokens: f64,
+        updated_at: Instant,
+    },
+    SlidingWindow {
+        previous: u32,
+        current: u32,
+        window_started_at: Instant,
+    },
+}
+
+/// In-process rate limiter, accounting requests per key.
+///
+/// Sliding window is approximated by weighting the previous fixed window counter, so the
+/// memory used per key is constant.
+#[derive(Debug)]
+pub struct InProcessRateLimiter {
+    algorithm: RateLimitAlgorithm,
+    states: Mutex<HashMap<SmolStr, LimiterState>>,
+}
+
+impl InProcessRateLimiter {
+    pub fn new(algorithm: RateLimitAlgorithm) -> Self {
+        InProcessRateLimiter {
+            algorithm,
+            states: Default::default(),
+        }
+    }
+
+    pub fn algorithm(&self) -> &RateLimitAlgorithm {
+        &self.algorithm
+    }
+
+    pub fn check(&self, key: &str) -> Result<(), RateLimitExceeded> {
+        self.check_at(key, Instant::now())
+    }
+
+    /// Account the request with the key at the given moment
+    pub fn check_at(&self, key: &str, now: Instant) -> Result<(), RateLimitExceeded> {
+        let mut states = self.states.lock();
+        let state = states
+            .entry(SmolStr::new(key))
+            .or_insert_with(|| self.initial_state(now));
+
+        match (&self.algorithm, state) {
+            (
+                RateLimitAlgorithm::TokenBucket {
+                    burst,
+                    rate,
+                    period,
+                },
+                LimiterState::TokenBucket { tokens, updated_at },
+            ) => {
+                let tokens_per_sec = f64::from(*rate) / period.0.as_secs_f64();
+                let elapsed = now.saturating_duration_since(*updated_at);
+                *tokens = (*tokens + elapsed.as_secs_f64() * tokens_per_sec).min(f64::from(*burst));
+                *updated_at = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    Ok(())
+                } else {
+                    Err(RateLimitExceeded {
+                        retry_after: Duration::from_secs_f64((1.0 - *tokens) / tokens_per_sec),
+                    })
+                }
+            }
+            (
+                RateLimitAlgorithm::SlidingWindow { limit, window },
+                LimiterState::SlidingWindow {
+                    previous,
+                    current,
+                    window_started_at,
+                },
+            ) => {
+                let window = window.0;
+
+                let mut elapsed = now.saturating_duration_since(*window_started_at);
+                if elapsed >= window {
+                    if elapsed - window < window {
+                        *previous = *current;
+                        *window_started_at += window;
+                    } else {
+                        // the previous window is empty as well, start over
+                        *previous = 0;
+                        *window_started_at = now;
+                    }
+                    *current = 0;
+                    elapsed = now.saturating_duration_since(*window_started_at);
+                }
+
+                let previous_weight = 1.0 - elapsed.as_secs_f64() / window.as_secs_f64();
+                let estimated = f64::from(*previous) * previous_weight + f64::from(*current);
+
+                if estimated < f64::from(*limit) {
+                    *current += 1;
+                    return Ok(());
+                }
+
+                let retry_after = if *current >= *limit {
+                    window - elapsed
+                } else {
+                    // wait until the previous window weight decays enough
+                    let excess = estimated - f64::from(*limit) + 1.0;
+                    Duration::from_secs_f64(
+                        window.as_secs_f64() * excess / f64::from((*previous).max(1)),
+                    )
+                    .min(window - elapsed)
+                };
+                Err(RateLimitExceeded { retry_after })
+            }
+            _ => unreachable!("limiter state doesn't match the algorithm"),
+        }
+    }
+
+    fn initial_state(&self, now: Instant) -> LimiterState {
+        match &self.algorithm {
+            RateLimitAlgorithm::TokenBucket { burst, .. } => LimiterState::TokenBucket {
+                tokens: f64::from(*burst),
+                updated_at: now,
+            },
+            RateLimitAlgorithm::SlidingWindow { .. } => LimiterState::SlidingWindow {
+                previous: 0,
+                current: 0,
+                window_started_at: now,
+            },
+        }
+    }
+
+    /// Drop keys which have been idle long enough to be fully replenished
+    pub fn purge_idle(&self, now: Instant) {
+        let idle_after = match &self.algorithm {
+            RateLimitAlgorithm::TokenBucket {
+                burst,
+                rate,
+                period,
+            } => period
+                .0
+                .mul_f64(f64::from(*burst) / f64::from((*rate).max(1))),
+            RateLimitAlgorithm::SlidingWindow { window, .. } => window.0 * 2,
+        };
+
+        self.states.lock().retain(|_, state| {
+            let last_seen = match state {
+                LimiterState::TokenBucket { updated_at, .. } => *updated_at,
+                LimiterState::SlidingWindow {
+                    window_started_at, ..
+                } => *window_started_at,
+            };
+            now.saturating_duration_since(last_seen) < idle_after
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<&RateLimiter> for InProcessRateLimiter {
+    fn from(definition: &RateLimiter) -> Self {
+        InProcessRateLimiter::new(definition.algorithm.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::{HeaderMap, Method, Uri};
+
+    fn token_bucket(burst: u32, rate: u32, period: Duration) -> InProcessRateLimiter {
+        InProcessRateLimiter::new(RateLimitAlgorithm::TokenBucket {
+            burst,
+            rate,
+            period: DurationWrapper(period),
+        })
+    }
+
+    #[test]
+    pub fn test_parse() {
+        let limiter = serde_yaml::from_str::<RateLimiter>(
+            r#"
+---
+algorithm: token-bucket
+burst: 10
+rate: 5
+period: 1s
+key:
+  kind: header
+  name: X-Api-Key
+"#,
+        )
+        .unwrap();
+        limiter.validate().unwrap();
+
+        let limiter = serde_yaml::from_str::<RateLimiter>(
+            r#"
+---
+algorithm: sliding-window
+limit: 0
+window: 1m
+key:
+  kind: client-ip
+"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            limiter.validate(),
+            Err(RateLimiterError::ZeroLimit)
+        ));
+
+        let limiter = serde_yaml::from_str::<RateLimiter>(
+            r#"
+---
+algorithm: sliding-window
+limit: 100
+window: 1m
+key:
+  kind: path-capture
+  capture: "{{ segments.user }}"
+"#,
+        )
+        .unwrap();
+        limiter.validate().unwrap();
+        assert!(limiter
+            .validate_captures(&AvailableCaptures::default())
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_resolve_key() {
+        let method = Method::GET;
+        let uri: Uri = "/users/alice".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "secret".parse().unwrap());
+        let request = MatchRequest::new(&method, &uri).with_headers(&headers);
+        let captures = FilterMatch {
+            path: crate::config_core::PathCaptures {
+                positional: vec!["alice".into()],
+                named: Default::default(),
+            },
+            query: Default::default(),
+        };
+
+        let key = |key: RateLimitKey| {
+            key.resolve(&request, &captures, Some("10.0.0.1".parse().unwrap()), None)
+        };
+
+        assert_eq!(key(RateLimitKey::ClientIp), Some("10.0.0.1".into()));
+        assert_eq!(
+            key(RateLimitKey::Header {
+                name: "X-Api-Key".into()
+            }),
+            Some("secret".into())
+        );
+        assert_eq!(
+            key(RateLimitKey::PathCapture {
+                capture: "user-$1".parse().unwrap()
+            }),
+            Some("user-alice".into())
+        );
+        assert_eq!(key(RateLimitKey::AuthIdentity), None);
+    }
+
+    #[test]
+    pub fn test_token_bucket() {
+        let limiter = token_bucket(2, 1, Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", now).is_ok());
+        assert!(limiter.check_at("a", now).is_ok());
+        let exceeded = limiter.check_at("a", now).unwrap_err();
+        assert_eq!(exceeded.retry_after, Duration::from_secs(1));
+
+        assert!(limiter.check_at("b", now).is_ok());
+
+        let later = now + Duration::from_millis(1500);
+        assert!(limiter.check_at("a", later).is_ok());
+        assert!(limiter.check_at("a", later).is_err());
+
+        limiter.purge_idle(now + Duration::from_secs(10));
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    pub fn test_sliding_window() {
+        let limiter = InProcessRateLimiter::new(RateLimitAlgorithm::SlidingWindow {
+            limit: 4,
+            window: DurationWrapper(Duration::from_secs(10)),
+        });
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert!(limiter.check_at("a", now).is_ok());
+        }
+        assert_eq!(
+            limiter.check_at("a", now).unwrap_err().retry_after,
+            Duration::from_secs(10)
+        );
+
+        // half of the previous window is still accounted
+        let next_window = now + Duration::from_secs(15);
+        assert!(limiter.check_at("a", next_window).is_ok());
+        assert!(limiter.check_at("a", next_window).is_ok());
+        assert!(limiter.check_at("a", next_window).is_err());
+
+        // previous window is fully expired
+        assert!(limiter.check_at("a", now + Duration::from_secs(30)).is_ok());
+    }
+
+    #[test]
+    pub fn test_sliding_window_long_idle() {
+        let limiter = InProcessRateLimiter::new(RateLimitAlgorithm::SlidingWindow {
+            limit: 1,
+            window: DurationWrapper(Duration::from_millis(1)),
+        });
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", now).is_ok());
+
+        // more windows passed than fit into u32
+        let later = now + Duration::from_millis(1 << 33);
+        assert!(limiter.check_at("a", later).is_ok());
+        assert_eq!(
+            limiter.check_at("a", later).unwrap_err().retry_after,
+            Duration::from_millis(1)
+        );
+    }
+}
@@ -1,7 +1,11 @@
 use std::fmt;
 
 use crate::{
-    config_core::path_modify::PathSegmentsModify,
+    config_core::{
+        path_modify::{substitute_path_segments, PathSegmentsModify},
+        rule::FilterMatch,
+        substitution::{AvailableCaptures, SubstitutionError},
+    },
     entities::schemars::{gen::SchemaGenerator, schema::Schema},
 };
 use schemars::{
@@ -53,6 +57,49 @@ impl JsonSchema for RedirectTo {
     }
 }
 
+impl RedirectTo {
+    /// Build redirect destination, substituting captures into path segments
+    pub fn destination(&self, captures: &FilterMatch) -> String {
+        match self {
+            RedirectTo::AbsoluteUrl(url) => url.to_string(),
+            RedirectTo::WithBaseUrl(base_url, segments) => format!(
+                "{}/{}",
+                base_url.to_string().trim_end_matches('/'),
+                substitute_path_segments(segments, captures).join("/")
+            ),
+            RedirectTo::Segments(segments) => {
+                format!(
+                    "/{}",
+                    substitute_path_segments(segments, captures).join("/")
+                )
+            }
+            RedirectTo::Root => "/".to_string(),
+        }
+    }
+
+    pub fn validate_substitutions(
+        &self,
+        available: &AvailableCaptures,
+    ) -> Result<(), SubstitutionError> {
+        let segments = match self {
+            RedirectTo::WithBaseUrl(_, segments) | RedirectTo::Segments(segments) => segments,
+            RedirectTo::AbsoluteUrl(_) | RedirectTo::Root => return Ok(()),
+        };
+        for segment in segments {
+            segment.0.validate(available)?;
+        }
+        Ok(())
+    }
+
+    pub fn disable_substitutions(&mut self) {
+        if let RedirectTo::WithBaseUrl(_, segments) | RedirectTo::Segments(segments) = self {
+            for segment in segments {
+                segment.disable_substitutions();
+            }
+        }
+    }
+}
+
 struct RedirectToItemVisitor;
 
 impl<'de> Visitor<'de> for RedirectToItemVisitor {
@@ -172,4 +219,34 @@ mod test {
     pub fn test_schema() {
         serde_json::to_string_pretty(&schemars::schema_for!(RedirectTo)).unwrap();
     }
+
+    #[test]
+    pub fn test_destination() {
+        use crate::config_core::path::PathCaptures;
+
+        let captures = FilterMatch {
+            path: PathCaptures {
+                positional: vec!["users".into(), "a/b".into()],
+                named: Default::default(),
+            },
+            query: Default::default(),
+        };
+
+        let redirect_to =
+            serde_yaml::from_str::<RedirectTo>(r#"["https://example.com/", "$1", "$2"]"#).unwrap();
+        assert_eq!(
+            redirect_to.destination(&captures),
+            "https://example.com/users/a/b"
+        );
+
+        let redirect_to = serde_yaml::from_str::<RedirectTo>(r#"["v2", "$1"]"#).unwrap();
+        assert_eq!(redirect_to.destination(&captures), "/v2/users");
+
+        // malformed templates are literals in older configs, and are reported on validation
+        let redirect_to = serde_yaml::from_str::<RedirectTo>(r#"["{{ segments }}"]"#).unwrap();
+        assert_eq!(redirect_to.destination(&captures), "/{{ segments }}");
+        assert!(redirect_to
+            .validate_substitutions(&Default::default())
+            .is_err());
+    }
 }
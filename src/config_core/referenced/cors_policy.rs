@@ -0,0 +1,20 @@
+use crate::config_core::referenced::{Parameter, ParameterSchema, ReferencedConfigValue};
+pub use crate::config_core::CorsPolicy;
+use std::convert::TryFrom;
+
+impl TryFrom<Parameter> for CorsPolicy {
+    type Error = ();
+
+    fn try_from(value: Parameter) -> Result<Self, Self::Error> {
+        match value {
+            Parameter::CorsPolicy(policy) => Ok(policy),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ReferencedConfigValue for CorsPolicy {
+    fn schema() -> ParameterSchema {
+        ParameterSchema::CorsPolicy
+    }
+}
@@ -0,0 +1,434 @@
+use crate::{
+    config_core::referenced::{Parameter, ParameterSchema, ReferencedConfigValue},
+    entities::schemars::{gen::SchemaGenerator, schema::Schema},
+};
+use core::fmt;
+use schemars::{
+    schema::{InstanceType, Metadata, SchemaObject},
+    JsonSchema,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// IP addresses list, evaluated in order like `Acl`: the first matching entry wins.
+/// Addresses not covered by any entry are denied.
+#[derive(Debug, Clone)]
+pub struct IpList {
+    entries: Vec<IpListEntry>,
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash, JsonSchema)]
+#[serde(untagged)]
+pub enum IpListEntry {
+    Allow {
+        #[serde(rename = "allow")]
+        network: IpNetwork,
+    },
+    Deny {
+        #[serde(rename = "deny")]
+        network: IpNetwork,
+    },
+}
+
+impl IpListEntry {
+    pub fn network(&self) -> &IpNetwork {
+        match self {
+            IpListEntry::Allow { network } | IpListEntry::Deny { network } => network,
+        }
+    }
+
+    pub fn is_allow(&self) -> bool {
+        matches!(self, IpListEntry::Allow { .. })
+    }
+}
+
+impl IpList {
+    pub fn new(entries: Vec<IpListEntry>) -> Self {
+        let mut v4 = PrefixTrie::default();
+        let mut v6 = PrefixTrie::default();
+
+        for (idx, entry) in entries.iter().enumerate() {
+            let network = entry.network().normalized();
+            match network.addr {
+                IpAddr::V4(addr) => v4.insert(u32::from(addr).into(), 32, network.prefix_len, idx),
+                IpAddr::V6(addr) => v6.insert(u128::from(addr), 128, network.prefix_len, idx),
+            }
+        }
+
+        IpList { entries, v4, v6 }
+    }
+
+    pub fn entries(&self) -> &[IpListEntry] {
+        &self.entries
+    }
+
+    /// The first entry covering the address
+    pub fn find(&self, addr: IpAddr) -> Option<&IpListEntry> {
+        let idx = match normalize(addr) {
+            IpAddr::V4(addr) => self.v4.lookup(u32::from(addr).into(), 32),
+            IpAddr::V6(addr) => self.v6.lookup(u128::from(addr), 128),
+        }?;
+
+        self.entries.get(idx)
+    }
+
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        self.find(addr).map(IpListEntry::is_allow).unwrap_or(false)
+    }
+}
+
+/// Treat IPv4-mapped IPv6 addresses as IPv4
+fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Binary trie over address bits, keeping the lowest entry index on each prefix
+#[derive(Debug, Clone)]
+struct PrefixTrie {
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: [Option<u32>; 2],
+    entry: Option<usize>,
+}
+
+impl Default for PrefixTrie {
+    fn default() -> Self {
+        PrefixTrie {
+            nodes: vec![TrieNode::default()],
+        }
+    }
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, bits: u128, width: u8, prefix_len: u8, idx: usize) {
+        let mut node = 0;
+        for depth in 0..prefix_len {
+            let bit = ((bits >> (width - 1 - depth)) & 1) as usize;
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child as usize,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child as u32);
+                    child
+                }
+            };
+        }
+
+        let entry = &mut self.nodes[node].entry;
+        if entry.is_none() {
+            *entry = Some(idx);
+        }
+    }
+
+    fn lookup(&self, bits: u128, width: u8) -> Option<usize> {
+        let mut node = 0;
+        let mut found = self.nodes[node].entry;
+
+        for depth in 0..width {
+            let bit = ((bits >> (width - 1 - depth)) & 1) as usize;
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child as usize,
+                None => break,
+            };
+            found = match (found, self.nodes[node].entry) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+
+        found
+    }
+}
+
+impl PartialEq for IpList {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl Eq for IpList {}
+
+impl Hash for IpList {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.entries.hash(state)
+    }
+}
+
+impl Serialize for IpList {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.entries.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpList {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(IpList::new(Vec::deserialize(deserializer)?))
+    }
+}
+
+impl JsonSchema for IpList {
+    fn schema_name() -> String {
+        <Vec<IpListEntry>>::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        <Vec<IpListEntry>>::json_schema(gen)
+    }
+}
+
+/// IP network in CIDR notation. Single address is accepted as well.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct IpNetwork {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IpNetworkParseError {
+    #[error("bad address")]
+    BadAddress,
+
+    #[error("bad prefix length")]
+    BadPrefixLength,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let normalized = self.normalized();
+        match (normalized.addr, normalize(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = prefix_mask(32, normalized.prefix_len);
+                u128::from(u32::from(network)) & mask == u128::from(u32::from(addr)) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = prefix_mask(128, normalized.prefix_len);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Treat networks within the IPv4-mapped IPv6 range as IPv4, like the looked up addresses
+    pub fn normalized(self) -> IpNetwork {
+        match self.addr {
+            IpAddr::V6(v6) if self.prefix_len >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => IpNetwork {
+                    addr: IpAddr::V4(v4),
+                    prefix_len: self.prefix_len - 96,
+                },
+                None => self,
+            },
+            _ => self,
+        }
+    }
+}
+
+fn prefix_mask(width: u8, prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        (u128::MAX << (128 - prefix_len)) >> (128 - width)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = IpNetworkParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| IpNetworkParseError::BadAddress)?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or(IpNetworkParseError::BadPrefixLength)?,
+            None => max_len,
+        };
+
+        // drop host bits, so that the network address is canonical
+        let mask = prefix_mask(max_len, prefix_len);
+        let addr = match addr {
+            IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from((u128::from(u32::from(v4)) & mask) as u32)),
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask)),
+        };
+
+        Ok(IpNetwork { addr, prefix_len }.normalized())
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpNetwork {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|e| de::Error::custom(format!("bad network `{}`: {}", s, e)))
+    }
+}
+
+impl JsonSchema for IpNetwork {
+    fn schema_name() -> String {
+        "IpNetwork".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                title: Some(String::from("IP network")),
+                description: Some(String::from(
+                    "IPv4 or IPv6 network in CIDR notation, e.g. 10.0.0.0/8",
+                )),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl ReferencedConfigValue for IpList {
+    fn schema() -> ParameterSchema {
+        ParameterSchema::IpList
+    }
+}
+
+impl TryFrom<Parameter> for IpList {
+    type Error = ();
+
+    fn try_from(value: Parameter) -> Result<Self, Self::Error> {
+        match value {
+            Parameter::IpList(ip_list) => Ok(ip_list),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_network() {
+        let network: IpNetwork = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(network.to_string(), "10.0.0.0/8");
+        assert!(network.contains(ip("10.200.0.1")));
+        assert!(network.contains(ip("::ffff:10.0.0.1")));
+        assert!(!network.contains(ip("11.0.0.1")));
+
+        let network: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains(ip("2001:db8:1::1")));
+        assert!(!network.contains(ip("2001:db9::1")));
+
+        assert_eq!("192.168.0.1".parse::<IpNetwork>().unwrap().prefix_len, 32);
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("office".parse::<IpNetwork>().is_err());
+
+        let network: IpNetwork = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(network.to_string(), "10.0.0.0/8");
+        assert!(network.contains(ip("10.1.2.3")));
+        assert!(network.contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn test_ip_list() {
+        let list = serde_yaml::from_str::<IpList>(
+            r#"
+---
+- deny: 10.1.0.0/16
+- allow: 10.0.0.0/8
+- allow: 192.168.1.15
+- allow: 2001:db8::/32
+- deny: 0.0.0.0/0
+"#,
+        )
+        .unwrap();
+
+        assert!(list.is_allowed(ip("10.2.3.4")));
+        assert!(!list.is_allowed(ip("10.1.3.4")));
+        assert!(list.is_allowed(ip("192.168.1.15")));
+        assert!(!list.is_allowed(ip("192.168.1.16")));
+        assert!(list.is_allowed(ip("2001:db8::5")));
+        assert!(!list.is_allowed(ip("2001:db9::5")));
+        assert!(list.is_allowed(ip("::ffff:10.2.3.4")));
+
+        let list = IpList::new(vec![
+            IpListEntry::Deny {
+                network: IpNetwork {
+                    addr: ip("::ffff:10.1.0.0"),
+                    prefix_len: 112,
+                },
+            },
+            IpListEntry::Allow {
+                network: "::ffff:10.0.0.0/104".parse().unwrap(),
+            },
+        ]);
+        assert!(list.is_allowed(ip("10.2.3.4")));
+        assert!(list.is_allowed(ip("::ffff:10.2.3.4")));
+        assert!(!list.is_allowed(ip("10.1.3.4")));
+        assert!(!list.is_allowed(ip("::ffff:10.1.3.4")));
+
+        // order matters: the broader deny goes first
+        let list = serde_yaml::from_str::<IpList>(
+            r#"
+---
+- deny: 0.0.0.0/0
+- allow: 10.0.0.0/8
+"#,
+        )
+        .unwrap();
+        assert!(!list.is_allowed(ip("10.2.3.4")));
+    }
+}
@@ -3,9 +3,12 @@ use crate::config_core::{
         acl::Acl,
         aws::{bucket::S3Bucket, credentials::AwsCredentials},
         google::{bucket::GcsBucket, credentials::GoogleCredentials},
+        ip_list::{IpList, IpListEntry},
         mime_types::{MimeType, MimeTypes},
     },
-    RawResponse, ResponseBody, StaticResponse, StatusCode,
+    rule::MethodWrapper,
+    CorsPolicy, DurationWrapper, MatchQuerySingleValue, RawResponse, ResponseBody, StaticResponse,
+    StatusCode,
 };
 pub use container::{Container, ContainerScope, Error};
 use core::{convert::TryFrom, fmt, fmt::Formatter, str::FromStr};
@@ -14,7 +17,9 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 pub mod acl;
 pub mod aws;
+pub mod cors_policy;
 pub mod google;
+pub mod ip_list;
 pub mod mime_types;
 pub mod static_response;
 
@@ -69,6 +74,12 @@ pub enum Parameter {
 
     #[serde(rename = "static-response")]
     StaticResponse(Box<StaticResponse>),
+
+    #[serde(rename = "cors-policy")]
+    CorsPolicy(CorsPolicy),
+
+    #[serde(rename = "ip-list")]
+    IpList(IpList),
 }
 
 impl Parameter {
@@ -81,6 +92,8 @@ impl Parameter {
             Parameter::Acl(_) => ParameterSchema::Acl,
             Parameter::MimeTypes(_) => ParameterSchema::MimeTypes,
             Parameter::StaticResponse(_) => ParameterSchema::StaticResponse,
+            Parameter::CorsPolicy(_) => ParameterSchema::CorsPolicy,
+            Parameter::IpList(_) => ParameterSchema::IpList,
         }
     }
     pub fn to_inner_yaml(&self) -> String {
@@ -92,6 +105,8 @@ impl Parameter {
             Parameter::Acl(inner) => serde_yaml::to_string(&inner).unwrap(),
             Parameter::MimeTypes(inner) => serde_yaml::to_string(&inner).unwrap(),
             Parameter::StaticResponse(resp) => serde_yaml::to_string(&resp).unwrap(),
+            Parameter::CorsPolicy(inner) => serde_yaml::to_string(&inner).unwrap(),
+            Parameter::IpList(inner) => serde_yaml::to_string(&inner).unwrap(),
         }
     }
 
@@ -104,11 +119,13 @@ impl Parameter {
             Parameter::Acl(inner) => serde_json::to_string_pretty(&inner).unwrap(),
             Parameter::MimeTypes(inner) => serde_json::to_string_pretty(&inner).unwrap(),
             Parameter::StaticResponse(inner) => serde_json::to_string_pretty(&inner).unwrap(),
+            Parameter::CorsPolicy(inner) => serde_json::to_string_pretty(&inner).unwrap(),
+            Parameter::IpList(inner) => serde_json::to_string_pretty(&inner).unwrap(),
         }
     }
 }
 
-pub const ALL_PARAMETER_SCHEMAS: [ParameterSchema; 7] = [
+pub const ALL_PARAMETER_SCHEMAS: [ParameterSchema; 9] = [
     ParameterSchema::AwsCredentials,
     ParameterSchema::S3Bucket,
     ParameterSchema::GoogleCredentials,
@@ -116,6 +133,8 @@ pub const ALL_PARAMETER_SCHEMAS: [ParameterSchema; 7] = [
     ParameterSchema::Acl,
     ParameterSchema::MimeTypes,
     ParameterSchema::StaticResponse,
+    ParameterSchema::CorsPolicy,
+    ParameterSchema::IpList,
 ];
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash, Copy, JsonSchema)]
@@ -140,6 +159,12 @@ pub enum ParameterSchema {
 
     #[serde(rename = "static-response")]
     StaticResponse,
+
+    #[serde(rename = "cors-policy")]
+    CorsPolicy,
+
+    #[serde(rename = "ip-list")]
+    IpList,
 }
 
 impl ParameterSchema {
@@ -202,6 +227,36 @@ impl ParameterSchema {
                 });
                 serde_yaml::to_string(&sample).unwrap()
             }
+            Self::CorsPolicy => {
+                let sample = CorsPolicy {
+                    allowed_origins: vec![MatchQuerySingleValue::Exact(
+                        "https://example.com".into(),
+                    )],
+                    allowed_methods: vec![
+                        MethodWrapper(http::Method::GET),
+                        MethodWrapper(http::Method::POST),
+                    ],
+                    allowed_headers: vec!["Content-Type".into()],
+                    exposed_headers: vec![],
+                    allow_credentials: false,
+                    max_age: Some(DurationWrapper(std::time::Duration::from_secs(600))),
+                };
+                serde_yaml::to_string(&sample).unwrap()
+            }
+            Self::IpList => {
+                let sample = IpList::new(vec![
+                    IpListEntry::Deny {
+                        network: "10.1.0.0/16".parse().unwrap(),
+                    },
+                    IpListEntry::Allow {
+                        network: "10.0.0.0/8".parse().unwrap(),
+                    },
+                    IpListEntry::Allow {
+                        network: "2001:db8::/32".parse().unwrap(),
+                    },
+                ]);
+                serde_yaml::to_string(&sample).unwrap()
+            }
         }
     }
 }
@@ -216,6 +271,8 @@ impl fmt::Display for ParameterSchema {
             ParameterSchema::Acl => "acl",
             ParameterSchema::MimeTypes => "mime-types",
             ParameterSchema::StaticResponse => "static-response",
+            ParameterSchema::CorsPolicy => "cors-policy",
+            ParameterSchema::IpList => "ip-list",
         };
 
         write!(f, "{}", s)
@@ -234,6 +291,8 @@ impl FromStr for ParameterSchema {
             "acl" => Ok(ParameterSchema::Acl),
             "mime-types" => Ok(ParameterSchema::MimeTypes),
             "static-response" => Ok(ParameterSchema::StaticResponse),
+            "cors-policy" => Ok(ParameterSchema::CorsPolicy),
+            "ip-list" => Ok(ParameterSchema::IpList),
             _ => Err(()),
         }
     }
@@ -263,6 +322,12 @@ impl TryFrom<(ParameterSchema, String)> for Parameter {
             (ParameterSchema::StaticResponse, s) => {
                 Ok(Parameter::StaticResponse(serde_yaml::from_str(s.as_str())?))
             }
+            (ParameterSchema::CorsPolicy, s) => {
+                Ok(Parameter::CorsPolicy(serde_yaml::from_str(s.as_str())?))
+            }
+            (ParameterSchema::IpList, s) => {
+                Ok(Parameter::IpList(serde_yaml::from_str(s.as_str())?))
+            }
         }
     }
 }
@@ -77,6 +77,17 @@ pub struct Refinable {
     pub rescue: Vec<RescueItem>,
 }
 
+impl Refinable {
+    pub fn disable_substitutions(&mut self) {
+        for static_response in self.static_responses.values_mut() {
+            static_response.disable_substitutions();
+        }
+        for rescue_item in &mut self.rescue {
+            rescue_item.disable_substitutions();
+        }
+    }
+}
+
 pub trait SharedEntity:
     DeserializeOwned
     + Serialize
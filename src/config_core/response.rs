@@ -70,6 +70,14 @@ pub struct RedirectResponse {
     pub headers: HeaderMapWrapper,
 }
 
+impl RedirectResponse {
+    pub fn disable_substitutions(&mut self) {
+        self.destination.disable_substitutions();
+        self.query_params.disable_substitutions();
+        self.headers.disable_substitutions();
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
 
 pub struct ResponseBody {
@@ -109,6 +117,15 @@ pub enum StaticResponse {
     Raw(RawResponse),
 }
 
+impl StaticResponse {
+    /// Render redirects as written, as configs before substitutions did
+    pub fn disable_substitutions(&mut self) {
+        if let StaticResponse::Redirect(redirect) = self {
+            redirect.disable_substitutions();
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
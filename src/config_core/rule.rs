@@ -1,19 +1,29 @@
 use crate::{
     config_core::{
-        catch::RescueItem, methods::MethodMatcher, path::MatchingPath,
-        path_modify::PathSegmentsModify, query::QueryMatcher, referenced::Container,
-        StaticResponse, StatusCode, StatusCodeRange,
+        catch::RescueItem,
+        header_matcher::{is_host_match, CookieMatcher, HeaderMatcher},
+        methods::MethodMatcher,
+        path::{MatchingPath, PathCaptures},
+        path_modify::PathSegmentsModify,
+        query::{MatchQueryValue, QueryMatcher},
+        referenced::{ip_list::IpList, Container, Parameter},
+        substitution::{AvailableCaptures, SubstitutionError, SubstitutionTemplate},
+        CorsPolicy, StaticResponse, StatusCode, StatusCodeRange,
     },
     entities::{
         schemars::{gen::SchemaGenerator, schema::Schema},
-        ProfileName, StaticResponseName,
+        ParameterName, ProfileName, RateLimiterName, StaticResponseName,
     },
 };
 use schemars::JsonSchema;
 
 use crate::{config_core::DurationWrapper, entities::Exception};
 use core::fmt;
-use http::{header::HeaderName, HeaderMap, HeaderValue};
+use hashbrown::HashMap;
+use http::{
+    header::{HeaderName, InvalidHeaderValue},
+    HeaderMap, HeaderValue, Method, Uri,
+};
 use schemars::{
     _serde_json::Value,
     schema::{InstanceType, Metadata, SchemaObject},
@@ -24,7 +34,9 @@ use smol_str::SmolStr;
 use std::{
     collections::BTreeMap,
     hash::{Hash, Hasher},
+    net::IpAddr,
 };
+use url::form_urlencoded;
 
 #[derive(Debug, Hash, PartialEq, Clone)]
 pub struct HeaderValueWrapper(HeaderValue);
@@ -76,7 +88,12 @@ mod header_value_ser {
 
 #[derive(Debug, Eq, Clone, Serialize, Deserialize, Default)]
 #[serde(transparent)]
-pub struct HeaderMapWrapper(#[serde(with = "http_serde::header_map")] pub HeaderMap);
+pub struct HeaderMapWrapper(
+    #[serde(with = "http_serde::header_map")] pub HeaderMap,
+    /// Render values as is, without substituting captures
+    #[serde(skip)]
+    bool,
+);
 
 impl JsonSchema for HeaderMapWrapper {
     fn schema_name() -> String {
@@ -99,7 +116,7 @@ impl JsonSchema for HeaderMapWrapper {
 
 impl From<HeaderMap> for HeaderMapWrapper {
     fn from(map: HeaderMap) -> Self {
-        HeaderMapWrapper(map)
+        HeaderMapWrapper(map, false)
     }
 }
 
@@ -122,6 +139,44 @@ impl HeaderMapWrapper {
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
+
+    /// Substitute captures into header values. Non-UTF-8 values are kept as is
+    pub fn substitute(&self, captures: &FilterMatch) -> Result<HeaderMap, InvalidHeaderValue> {
+        if self.1 {
+            return Ok(self.0.clone());
+        }
+
+        let mut substituted = HeaderMap::with_capacity(self.0.len());
+        for (name, value) in &self.0 {
+            let value = match value
+                .to_str()
+                .ok()
+                .and_then(|s| s.parse::<SubstitutionTemplate>().ok())
+            {
+                Some(template) if !template.is_static() => template.substitute(captures).parse()?,
+                _ => value.clone(),
+            };
+            substituted.append(name, value);
+        }
+        Ok(substituted)
+    }
+
+    pub fn validate_substitutions(
+        &self,
+        available: &AvailableCaptures,
+    ) -> Result<(), SubstitutionError> {
+        for value in self.0.values() {
+            if let Ok(s) = value.to_str() {
+                s.parse::<SubstitutionTemplate>()?.validate(available)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the values as written, without substituting captures
+    pub fn disable_substitutions(&mut self) {
+        self.1 = true;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, Eq, PartialEq)]
@@ -244,6 +299,19 @@ impl ModifyHeaders {
             && HeaderMapWrapper::is_empty(&self.append)
             && Vec::is_empty(&self.remove.0)
     }
+
+    pub fn validate_substitutions(
+        &self,
+        available: &AvailableCaptures,
+    ) -> Result<(), SubstitutionError> {
+        self.insert.validate_substitutions(available)?;
+        self.append.validate_substitutions(available)
+    }
+
+    pub fn disable_substitutions(&mut self) {
+        self.insert.disable_substitutions();
+        self.append.disable_substitutions();
+    }
 }
 
 #[derive(Debug, Hash, Serialize, Deserialize, Eq, PartialEq, Clone, JsonSchema)]
@@ -281,6 +349,27 @@ pub struct RequestModifications {
     pub query_params: ModifyQuery,
 }
 
+impl RequestModifications {
+    pub fn validate_substitutions(
+        &self,
+        available: &AvailableCaptures,
+    ) -> Result<(), SubstitutionError> {
+        self.headers.validate_substitutions(available)?;
+        for segment in self.path.iter().flatten() {
+            segment.0.validate(available)?;
+        }
+        self.query_params.validate_substitutions(available)
+    }
+
+    pub fn disable_substitutions(&mut self) {
+        self.headers.disable_substitutions();
+        for segment in self.path.iter_mut().flatten() {
+            segment.disable_substitutions();
+        }
+        self.query_params.disable_substitutions();
+    }
+}
+
 #[derive(Debug, Hash, Serialize, Deserialize, Eq, PartialEq, Clone, JsonSchema)]
 #[serde(tag = "strategy")]
 pub enum ModifyQueryStrategy {
@@ -309,7 +398,33 @@ pub struct ModifyQuery {
     pub strategy: ModifyQueryStrategy,
 
     #[serde(default)]
-    pub set: BTreeMap<SmolStr, SmolStr>,
+    pub set: BTreeMap<SmolStr, SubstitutionTemplate>,
+}
+
+impl ModifyQuery {
+    /// Query parameters to set, with captures substituted
+    pub fn substitute_set(&self, captures: &FilterMatch) -> BTreeMap<SmolStr, String> {
+        self.set
+            .iter()
+            .map(|(name, template)| (name.clone(), template.substitute(captures)))
+            .collect()
+    }
+
+    pub fn validate_substitutions(
+        &self,
+        available: &AvailableCaptures,
+    ) -> Result<(), SubstitutionError> {
+        for template in self.set.values() {
+            template.validate(available)?;
+        }
+        Ok(())
+    }
+
+    pub fn disable_substitutions(&mut self) {
+        for template in self.set.values_mut() {
+            *template = SubstitutionTemplate::literal(template.as_str());
+        }
+    }
 }
 
 #[derive(Default, Debug, Hash, Serialize, Deserialize, PartialEq, Clone, JsonSchema)]
@@ -364,10 +479,48 @@ pub struct Rule {
     #[serde(default)]
     pub cache: RuleCacheMode,
 
+    #[serde(default, rename = "rate-limit")]
+    pub rate_limit: Option<RateLimiterName>,
+
+    /// Answer preflight requests and add CORS headers to responses
+    #[serde(default)]
+    pub cors: Option<Container<CorsPolicy>>,
+
     #[serde(default)]
     pub profiles: Option<Vec<ProfileName>>,
 }
 
+impl Rule {
+    /// Ensure that all captures referenced in the rule action are provided by the filter.
+    ///
+    /// Shared static responses are not bound to the filter, so only inline responses are
+    /// checked.
+    pub fn validate_substitutions(&self) -> Result<(), SubstitutionError> {
+        let available = self.filter.available_captures();
+
+        match &self.action {
+            Action::Invoke {
+                modify_request: Some(modify_request),
+                ..
+            } => modify_request.validate_substitutions(&available),
+            Action::Respond {
+                static_response: Container::Value(StaticResponse::Redirect(redirect)),
+                ..
+            } => {
+                redirect.destination.validate_substitutions(&available)?;
+                redirect.query_params.validate_substitutions(&available)?;
+                redirect.headers.validate_substitutions(&available)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Render the rule action as written, as configs before substitutions did
+    pub fn disable_substitutions(&mut self) {
+        self.action.disable_substitutions();
+    }
+}
+
 #[derive(Debug, Hash, Serialize, Deserialize, PartialEq, Clone, Copy, JsonSchema)]
 
 pub enum TrailingSlashFilterRule {
@@ -387,6 +540,16 @@ impl Default for TrailingSlashFilterRule {
     }
 }
 
+impl TrailingSlashFilterRule {
+    pub fn is_match(&self, has_trailing_slash: bool) -> bool {
+        match self {
+            TrailingSlashFilterRule::Require => has_trailing_slash,
+            TrailingSlashFilterRule::Allow => true,
+            TrailingSlashFilterRule::Deny => !has_trailing_slash,
+        }
+    }
+}
+
 #[derive(Debug, Hash, Serialize, Deserialize, PartialEq, Clone, JsonSchema)]
 pub struct Filter {
     pub path: MatchingPath,
@@ -399,6 +562,183 @@ pub struct Filter {
 
     #[serde(rename = "trailing-slash", default)]
     pub trailing_slash: TrailingSlashFilterRule,
+
+    #[serde(default)]
+    pub headers: HeaderMatcher,
+
+    #[serde(default)]
+    pub cookies: CookieMatcher,
+
+    #[serde(default)]
+    pub host: Option<MatchQueryValue>,
+
+    /// Allowed client addresses
+    #[serde(default, rename = "client-ip")]
+    pub client_ip: Option<Container<IpList>>,
+}
+
+impl Filter {
+    pub fn is_match(&self, request: &MatchRequest<'_>) -> bool {
+        self.matches(request).is_some()
+    }
+
+    /// Evaluate the filter against the request, returning captured values on success
+    pub fn matches(&self, request: &MatchRequest<'_>) -> Option<FilterMatch> {
+        if !self.methods.is_match(request.method)
+            || !self.trailing_slash.is_match(request.trailing_slash)
+        {
+            return None;
+        }
+
+        if !self.headers.is_empty() || !self.cookies.is_empty() {
+            let empty;
+            let headers = match request.headers {
+                Some(headers) => headers,
+                None => {
+                    empty = HeaderMap::new();
+                    &empty
+                }
+            };
+            if !self.headers.is_match(headers) || !self.cookies.is_match(headers) {
+                return None;
+            }
+        }
+
+        if let Some(host) = &self.host {
+            if !is_host_match(host, request.host()) {
+                return None;
+            }
+        }
+
+        if let Some(client_ip) = &self.client_ip {
+            if !request.is_client_ip_allowed(client_ip) {
+                return None;
+            }
+        }
+
+        let path = self.path.matches(&request.path_segments)?;
+        let query = self.query_params.matches(&request.query)?;
+
+        Some(FilterMatch { path, query })
+    }
+
+    /// Captures, which may be referenced from the rule action
+    pub fn available_captures(&self) -> AvailableCaptures {
+        AvailableCaptures {
+            positional: self.path.positional_captures_count(),
+            segments: self.path.named_captures(),
+            query: self
+                .query_params
+                .inner
+                .iter()
+                .filter(|(_, matcher)| matches!(matcher, Some(matcher) if !matcher.is_exact()))
+                .map(|(name, _)| name.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Values captured by the successfully matched `Filter`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterMatch {
+    pub path: PathCaptures,
+    pub query: BTreeMap<SmolStr, SmolStr>,
+}
+
+/// Request attributes evaluated by `Filter`.
+///
+/// Path segments are kept percent-encoded, exactly as they are configured in path matchers.
+/// Query parameters are decoded, only the first value of repeated parameters is considered.
+#[derive(Debug, Clone)]
+pub struct MatchRequest<'a> {
+    pub method: &'a Method,
+    pub path_segments: Vec<&'a str>,
+    pub trailing_slash: bool,
+    pub query: BTreeMap<SmolStr, SmolStr>,
+    pub authority: Option<&'a str>,
+    pub headers: Option<&'a HeaderMap>,
+    pub client_ip: Option<IpAddr>,
+    pub parameters: Option<&'a HashMap<ParameterName, Parameter>>,
+}
+
+impl<'a> MatchRequest<'a> {
+    pub fn new(method: &'a Method, uri: &'a Uri) -> Self {
+        let path = uri.path();
+        let path_segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+        let trailing_slash = !path_segments.is_empty() && path.ends_with('/');
+
+        let mut query = BTreeMap::new();
+        if let Some(query_string) = uri.query() {
+            for (name, value) in form_urlencoded::parse(query_string.as_bytes()) {
+                query
+                    .entry(SmolStr::new(name))
+                    .or_insert_with(|| SmolStr::new(value));
+            }
+        }
+
+        MatchRequest {
+            method,
+            path_segments,
+            trailing_slash,
+            query,
+            authority: uri.authority().map(|authority| authority.as_str()),
+            headers: None,
+            client_ip: None,
+            parameters: None,
+        }
+    }
+
+    pub fn with_headers(mut self, headers: &'a HeaderMap) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+
+    pub fn with_client_ip(mut self, client_ip: IpAddr) -> Self {
+        self.client_ip = Some(client_ip);
+        self
+    }
+
+    /// Parameters used to resolve references in filters
+    pub fn with_parameters(mut self, parameters: &'a HashMap<ParameterName, Parameter>) -> Self {
+        self.parameters = Some(parameters);
+        self
+    }
+
+    /// Check the client address against the IP list. Unknown address, undefined parameter or
+    /// parameter of another schema never match.
+    pub fn is_client_ip_allowed(&self, ip_list: &Container<IpList>) -> bool {
+        let client_ip = match self.client_ip {
+            Some(client_ip) => client_ip,
+            None => return false,
+        };
+
+        match ip_list {
+            Container::Value(ip_list) => ip_list.is_allowed(client_ip),
+            Container::Parameter(name) => matches!(
+                self.parameters.and_then(|parameters| parameters.get(name)),
+                Some(Parameter::IpList(ip_list)) if ip_list.is_allowed(client_ip)
+            ),
+            Container::Shared(_) => false,
+        }
+    }
+
+    pub fn from_parts(parts: &'a http::request::Parts) -> Self {
+        Self::new(&parts.method, &parts.uri).with_headers(&parts.headers)
+    }
+
+    /// Requested host with optional port, taken from the URI or the `Host` header
+    pub fn host(&self) -> Option<&'a str> {
+        self.authority
+            .map(|authority| authority.rsplit('@').next().unwrap_or(authority))
+            .or_else(|| {
+                self.headers?
+                    .get(http::header::HOST)
+                    .and_then(|host| host.to_str().ok())
+            })
+    }
 }
 
 #[derive(Debug, Hash, Serialize, Deserialize, PartialEq, Clone, JsonSchema)]
@@ -469,6 +809,39 @@ impl Action {
             _ => None,
         }
     }
+
+    pub fn disable_substitutions(&mut self) {
+        match self {
+            Action::Invoke {
+                modify_request,
+                on_response,
+                rescue,
+            } => {
+                if let Some(modify_request) = modify_request {
+                    modify_request.disable_substitutions();
+                }
+                for on_response in on_response {
+                    on_response.modifications.headers.disable_substitutions();
+                }
+                for rescue_item in rescue {
+                    rescue_item.disable_substitutions();
+                }
+            }
+            Action::Respond {
+                static_response,
+                rescue,
+                ..
+            } => {
+                if let Container::Value(static_response) = static_response {
+                    static_response.disable_substitutions();
+                }
+                for rescue_item in rescue {
+                    rescue_item.disable_substitutions();
+                }
+            }
+            Action::NextHandler | Action::Throw { .. } => {}
+        }
+    }
 }
 
 #[cfg(test)]
@@ -498,7 +871,7 @@ mod test {
         insert_headers.insert("X-Amz-1", "1".parse().unwrap());
         assert_eq!(
             ModifyHeaders {
-                insert: HeaderMapWrapper(insert_headers),
+                insert: insert_headers.into(),
                 append: Default::default(),
                 remove: vec!["X-Amz-2".parse().unwrap()].into()
             },
@@ -530,7 +903,11 @@ remove:
                 path: MatchingPath::Root,
                 query_params: matcher,
                 methods: Default::default(),
-                trailing_slash: Default::default()
+                trailing_slash: Default::default(),
+                headers: Default::default(),
+                cookies: Default::default(),
+                host: None,
+                client_ip: None,
             },
             serde_yaml::from_str::<Filter>(
                 r#"
@@ -546,4 +923,244 @@ query-params:
             .unwrap()
         );
     }
+
+    #[test]
+    pub fn test_filter_matches() {
+        let filter = serde_yaml::from_str::<Filter>(
+            r#"
+---
+path: ["api", "?", "*"]
+query-params:
+  q1: "?"
+  q2: "v2"
+methods: ["GET", "HEAD"]
+trailing-slash: deny
+"#,
+        )
+        .unwrap();
+
+        let uri: Uri = "/api/users/1/edit?q1=a%20b&q2=v2&q1=ignored"
+            .parse()
+            .unwrap();
+        let filter_match = filter
+            .matches(&MatchRequest::new(&Method::GET, &uri))
+            .unwrap();
+        assert_eq!(
+            filter_match.path.positional,
+            vec![SmolStr::from("users"), SmolStr::from("1/edit")]
+        );
+        assert_eq!(
+            filter_match.query,
+            btreemap! {
+                SmolStr::from("q1") => SmolStr::from("a b"),
+            }
+        );
+
+        assert!(!filter.is_match(&MatchRequest::new(&Method::POST, &uri)));
+
+        let uri: Uri = "/api/users/1/?q1=a&q2=v2".parse().unwrap();
+        assert!(!filter.is_match(&MatchRequest::new(&Method::GET, &uri)));
+
+        let uri: Uri = "/api/users?q1=a&q2=v3".parse().unwrap();
+        assert!(!filter.is_match(&MatchRequest::new(&Method::GET, &uri)));
+
+        let uri: Uri = "/other/users?q1=a&q2=v2".parse().unwrap();
+        assert!(!filter.is_match(&MatchRequest::new(&Method::GET, &uri)));
+    }
+
+    #[test]
+    pub fn test_filter_headers_cookies_host() {
+        let filter = serde_yaml::from_str::<Filter>(
+            r#"
+---
+path: ["*"]
+headers:
+  X-Api-Version: "2"
+cookies:
+  beta: "1"
+host: ["api.example.com"]
+"#,
+        )
+        .unwrap();
+
+        let uri: Uri = "/users".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-version", "2".parse().unwrap());
+        headers.insert("cookie", "beta=1".parse().unwrap());
+        headers.insert("host", "api.example.com:443".parse().unwrap());
+
+        assert!(filter.is_match(&MatchRequest::new(&Method::GET, &uri).with_headers(&headers)));
+        assert!(!filter.is_match(&MatchRequest::new(&Method::GET, &uri)));
+
+        let uri: Uri = "https://other.example.com/users".parse().unwrap();
+        assert!(!filter.is_match(&MatchRequest::new(&Method::GET, &uri).with_headers(&headers)));
+
+        headers.insert("cookie", "beta=0".parse().unwrap());
+        let uri: Uri = "/users".parse().unwrap();
+        assert!(!filter.is_match(&MatchRequest::new(&Method::GET, &uri).with_headers(&headers)));
+    }
+
+    #[test]
+    pub fn test_filter_client_ip() {
+        let inline = serde_yaml::from_str::<Filter>(
+            r#"
+---
+path: ["admin", "*"]
+client-ip:
+  - allow: 192.168.0.0/16
+  - allow: 2001:db8::/32
+"#,
+        )
+        .unwrap();
+        let referenced = serde_yaml::from_str::<Filter>(
+            r#"
+---
+path: ["admin", "*"]
+client-ip: "@office-ranges"
+"#,
+        )
+        .unwrap();
+
+        let mut parameters = HashMap::new();
+        parameters.insert(
+            "office-ranges".parse().unwrap(),
+            Parameter::IpList(serde_yaml::from_str("[{allow: 192.168.0.0/16}]").unwrap()),
+        );
+
+        let uri: Uri = "/admin/users".parse().unwrap();
+        let office = MatchRequest::new(&Method::GET, &uri)
+            .with_client_ip("192.168.10.1".parse().unwrap())
+            .with_parameters(&parameters);
+        let outside = MatchRequest::new(&Method::GET, &uri)
+            .with_client_ip("8.8.8.8".parse().unwrap())
+            .with_parameters(&parameters);
+
+        assert!(inline.is_match(&office));
+        assert!(!inline.is_match(&outside));
+        assert!(!inline.is_match(&MatchRequest::new(&Method::GET, &uri)));
+
+        assert!(referenced.is_match(&office));
+        assert!(!referenced.is_match(&outside));
+        assert!(!referenced.is_match(
+            &MatchRequest::new(&Method::GET, &uri).with_client_ip("192.168.10.1".parse().unwrap())
+        ));
+    }
+
+    #[test]
+    pub fn test_match_request() {
+        let uri: Uri = "/".parse().unwrap();
+        let request = MatchRequest::new(&Method::GET, &uri);
+        assert!(request.path_segments.is_empty());
+        assert!(!request.trailing_slash);
+        assert!(request.query.is_empty());
+
+        let uri: Uri = "/a/b/".parse().unwrap();
+        let request = MatchRequest::new(&Method::GET, &uri);
+        assert_eq!(request.path_segments, vec!["a", "b"]);
+        assert!(request.trailing_slash);
+
+        assert!(TrailingSlashFilterRule::Require.is_match(true));
+        assert!(!TrailingSlashFilterRule::Require.is_match(false));
+        assert!(TrailingSlashFilterRule::Allow.is_match(false));
+        assert!(!TrailingSlashFilterRule::Deny.is_match(true));
+    }
+
+    #[test]
+    pub fn test_rule_substitutions() {
+        let rule = serde_yaml::from_str::<Rule>(
+            r#"
+---
+filter:
+  path: ["v1", "users", "/^(?P<id>[0-9]+)$/", "*"]
+  query-params:
+    lang: "?"
+    format: json
+action: invoke
+modify-request:
+  path: ["api", "user", "$2"]
+  query-params:
+    strategy: keep
+    set:
+      id: "{{ segments.id }}"
+      lang: "{{ query.lang }}"
+  headers:
+    insert:
+      x-user-id: "{{ segments.id }}"
+"#,
+        )
+        .unwrap();
+        rule.validate_substitutions().unwrap();
+
+        let uri: Uri = "/v1/users/42/a/b?lang=en&format=json".parse().unwrap();
+        let captures = rule
+            .filter
+            .matches(&MatchRequest::new(&Method::GET, &uri))
+            .unwrap();
+        let modify_request = rule.action.modify_request().unwrap();
+
+        assert_eq!(
+            crate::config_core::path_modify::substitute_path_segments(
+                modify_request.path.as_ref().unwrap(),
+                &captures
+            ),
+            vec!["api", "user", "a", "b"]
+        );
+        assert_eq!(
+            modify_request.query_params.substitute_set(&captures),
+            btreemap! {
+                SmolStr::from("id") => "42".to_string(),
+                SmolStr::from("lang") => "en".to_string(),
+            }
+        );
+        assert_eq!(
+            modify_request
+                .headers
+                .insert
+                .substitute(&captures)
+                .unwrap()
+                .get("x-user-id")
+                .unwrap(),
+            "42"
+        );
+    }
+
+    #[test]
+    pub fn test_rule_substitutions_errors() {
+        let rule = serde_yaml::from_str::<Rule>(
+            r#"
+---
+filter:
+  path: ["v1", "?"]
+  query-params:
+    format: json
+action: invoke
+modify-request:
+  path: ["$2"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rule.validate_substitutions(),
+            Err(SubstitutionError::UnknownReference(
+                crate::config_core::substitution::CaptureRef::Positional(2)
+            ))
+        );
+
+        let rule = serde_yaml::from_str::<Rule>(
+            r#"
+---
+filter:
+  path: ["v1", "?"]
+  query-params:
+    format: json
+action: respond
+static-response:
+  kind: redirect
+  redirect-type: found
+  destination: ["{{ query.format }}"]
+"#,
+        )
+        .unwrap();
+        assert!(rule.validate_substitutions().is_err());
+    }
 }
@@ -0,0 +1,584 @@
+//! Offline evaluation of handlers and rules.
+//!
+//! Mimics the way the gateway walks through mount points, handlers and rules, and explains
+//! which handler and rule would serve the request without sending any traffic.
+
+use crate::{
+    config_core::{
+        is_profile_active,
+        referenced::Container,
+        rule::{FilterMatch, MatchRequest},
+        Action, ClientConfig, ClientHandler, ProjectConfig, StaticResponse, StatusCode,
+    },
+    entities::{Exception, HandlerName, MountPointName, ProfileName, StaticResponseName},
+};
+use core::fmt;
+use http::{HeaderMap, Method, Uri};
+use std::{collections::BTreeMap, net::IpAddr};
+
+/// Request to evaluate
+#[derive(Debug, Clone)]
+pub struct SimulatedRequest {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub client_ip: Option<IpAddr>,
+}
+
+impl SimulatedRequest {
+    pub fn new(method: Method, uri: Uri) -> Self {
+        SimulatedRequest {
+            method,
+            uri,
+            headers: Default::default(),
+            client_ip: None,
+        }
+    }
+
+    pub fn match_request(&self) -> MatchRequest<'_> {
+        let request = MatchRequest::new(&self.method, &self.uri).with_headers(&self.headers);
+        match self.client_ip {
+            Some(client_ip) => request.with_client_ip(client_ip),
+            None => request,
+        }
+    }
+}
+
+/// Config where the handler is defined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerSource {
+    Project,
+    Client,
+}
+
+impl fmt::Display for HandlerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerSource::Project => write!(f, "project"),
+            HandlerSource::Client => write!(f, "client"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimulationStep {
+    /// mount point is not enabled in the active profile
+    MountPointInactive,
+
+    /// handler is not enabled in the active profile
+    HandlerInactive {
+        handler: HandlerName,
+        source: HandlerSource,
+    },
+
+    /// handler rules are being evaluated
+    HandlerEntered {
+        handler: HandlerName,
+        source: HandlerSource,
+        priority: u16,
+    },
+
+    /// rule is not enabled in the active profile
+    RuleInactive { handler: HandlerName, rule: usize },
+
+    /// rule filter doesn't match the request
+    RuleNotMatched { handler: HandlerName, rule: usize },
+
+    /// rule filter matches the request, the rule action is applied
+    RuleMatched {
+        handler: HandlerName,
+        rule: usize,
+        captures: FilterMatch,
+    },
+
+    /// rules processing stopped, moving on to the next handler
+    NextHandler { handler: HandlerName, rule: usize },
+
+    /// none of the handler rules matched the request
+    HandlerExhausted { handler: HandlerName },
+}
+
+impl fmt::Display for SimulationStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulationStep::MountPointInactive => {
+                write!(f, "mount point is not active in the profile")
+            }
+            SimulationStep::HandlerInactive { handler, source } => {
+                write!(
+                    f,
+                    "{} handler `{}` is not active in the profile",
+                    source, handler
+                )
+            }
+            SimulationStep::HandlerEntered {
+                handler,
+                source,
+                priority,
+            } => write!(
+                f,
+                "{} handler `{}` with priority {}",
+                source, handler, priority
+            ),
+            SimulationStep::RuleInactive { handler, rule } => write!(
+                f,
+                "rule #{} of `{}` is not active in the profile",
+                rule, handler
+            ),
+            SimulationStep::RuleNotMatched { handler, rule } => {
+                write!(f, "rule #{} of `{}` doesn't match", rule, handler)
+            }
+            SimulationStep::RuleMatched { handler, rule, .. } => {
+                write!(f, "rule #{} of `{}` matches", rule, handler)
+            }
+            SimulationStep::NextHandler { handler, rule } => write!(
+                f,
+                "rule #{} of `{}` moves on to the next handler",
+                rule, handler
+            ),
+            SimulationStep::HandlerExhausted { handler } => {
+                write!(f, "no rules of `{}` match", handler)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimulationOutcome {
+    /// request is served by the handler
+    Invoke { handler: HandlerName, rule: usize },
+
+    /// processing finished with the exception
+    Throw {
+        handler: HandlerName,
+        rule: usize,
+        exception: Exception,
+    },
+
+    /// processing finished with the static response
+    Respond {
+        handler: HandlerName,
+        rule: usize,
+        static_response: Container<StaticResponse, StaticResponseName>,
+        status_code: Option<StatusCode>,
+    },
+
+    /// no handler accepted the request
+    NotHandled,
+}
+
+impl fmt::Display for SimulationOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulationOutcome::Invoke { handler, rule } => {
+                write!(f, "invoke `{}` by rule #{}", handler, rule)
+            }
+            SimulationOutcome::Throw {
+                handler,
+                rule,
+                exception,
+            } => write!(
+                f,
+                "throw `{}` from `{}` by rule #{}",
+                exception, handler, rule
+            ),
+            SimulationOutcome::Respond {
+                handler,
+                rule,
+                static_response,
+                ..
+            } => {
+                let response = match static_response {
+                    Container::Shared(name) => format!("`{}`", name),
+                    Container::Parameter(name) => format!("`@{}`", name),
+                    Container::Value(_) => "inline static response".to_string(),
+                };
+                write!(
+                    f,
+                    "respond with {} from `{}` by rule #{}",
+                    response, handler, rule
+                )
+            }
+            SimulationOutcome::NotHandled => write!(f, "no handler accepted the request"),
+        }
+    }
+}
+
+/// Result of the request evaluation on a single mount point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub mount_point: MountPointName,
+    pub steps: Vec<SimulationStep>,
+    pub outcome: SimulationOutcome,
+}
+
+impl fmt::Display for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "mount point `{}`:", self.mount_point)?;
+        for step in &self.steps {
+            writeln!(f, "  - {}", step)?;
+        }
+        write!(f, "  => {}", self.outcome)
+    }
+}
+
+struct CandidateHandler {
+    name: HandlerName,
+    source: HandlerSource,
+    handler: ClientHandler,
+}
+
+/// Evaluate the request against every mount point defined in client and project configs.
+///
+/// Handlers of the same mount point from both configs are processed together, ordered by
+/// `priority`. Rules of each handler are evaluated in order until the first match.
+pub fn simulate(
+    client_config: &ClientConfig,
+    project_config: Option<&ProjectConfig>,
+    active_profile: &Option<ProfileName>,
+    request: &SimulatedRequest,
+) -> Vec<Simulation> {
+    let mut mount_points = BTreeMap::new();
+
+    if let Some(project_config) = project_config {
+        for (mount_point_name, mount) in &project_config.mount_points {
+            let candidates = mount_points
+                .entry(mount_point_name.clone())
+                .or_insert_with(|| (true, vec![]));
+            for (name, handler) in &mount.handlers {
+                candidates.1.push(CandidateHandler {
+                    name: name.clone(),
+                    source: HandlerSource::Project,
+                    handler: handler.clone().into(),
+                });
+            }
+        }
+    }
+
+    for (mount_point_name, mount) in &client_config.mount_points {
+        let candidates = mount_points
+            .entry(mount_point_name.clone())
+            .or_insert_with(|| (true, vec![]));
+        candidates.0 = is_profile_active(&mount.profiles, active_profile);
+        for (name, handler) in &mount.handlers {
+            candidates.1.push(CandidateHandler {
+                name: name.clone(),
+                source: HandlerSource::Client,
+                handler: handler.clone(),
+            });
+        }
+    }
+
+    mount_points
+        .into_iter()
+        .map(|(mount_point, (is_active, mut candidates))| {
+            if !is_active {
+                return Simulation {
+                    mount_point,
+                    steps: vec![SimulationStep::MountPointInactive],
+                    outcome: SimulationOutcome::NotHandled,
+                };
+            }
+
+            candidates.sort_by_key(|candidate| candidate.handler.priority);
+
+            let (steps, outcome) = simulate_handlers(&candidates, active_profile, request);
+
+            Simulation {
+                mount_point,
+                steps,
+                outcome,
+            }
+        })
+        .collect()
+}
+
+fn simulate_handlers(
+    candidates: &[CandidateHandler],
+    active_profile: &Option<ProfileName>,
+    request: &SimulatedRequest,
+) -> (Vec<SimulationStep>, SimulationOutcome) {
+    let match_request = request.match_request();
+    let mut steps = vec![];
+
+    'handlers: for candidate in candidates {
+        let handler = candidate.name.clone();
+
+        if !is_profile_active(&candidate.handler.profiles, active_profile) {
+            steps.push(SimulationStep::HandlerInactive {
+                handler,
+                source: candidate.source,
+            });
+            continue;
+        }
+
+        steps.push(SimulationStep::HandlerEntered {
+            handler: handler.clone(),
+            source: candidate.source,
+            priority: candidate.handler.priority,
+        });
+
+        for (rule_idx, rule) in candidate.handler.rules.iter().enumerate() {
+            if !is_profile_active(&rule.profiles, active_profile) {
+                steps.push(SimulationStep::RuleInactive {
+                    handler: handler.clone(),
+                    rule: rule_idx,
+                });
+                continue;
+            }
+
+            let captures = match rule.filter.matches(&match_request) {
+                Some(captures) => captures,
+                None => {
+                    steps.push(SimulationStep::RuleNotMatched {
+                        handler: handler.clone(),
+                        rule: rule_idx,
+                    });
+                    continue;
+                }
+            };
+
+            steps.push(SimulationStep::RuleMatched {
+                handler: handler.clone(),
+                rule: rule_idx,
+                captures,
+            });
+
+            let outcome = match &rule.action {
+                Action::Invoke { .. } => SimulationOutcome::Invoke {
+                    handler,
+                    rule: rule_idx,
+                },
+                Action::NextHandler => {
+                    steps.push(SimulationStep::NextHandler {
+                        handler,
+                        rule: rule_idx,
+                    });
+                    continue 'handlers;
+                }
+                Action::Throw { exception, .. } => SimulationOutcome::Throw {
+                    handler,
+                    rule: rule_idx,
+                    exception: exception.clone(),
+                },
+                Action::Respond {
+                    static_response,
+                    status_code,
+                    ..
+                } => SimulationOutcome::Respond {
+                    handler,
+                    rule: rule_idx,
+                    static_response: static_response.clone(),
+                    status_code: *status_code,
+                },
+            };
+
+            return (steps, outcome);
+        }
+
+        steps.push(SimulationStep::HandlerExhausted { handler });
+    }
+
+    (steps, SimulationOutcome::NotHandled)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config_core::Config;
+
+    const CLIENT_YAML: &str = r#"---
+version: 1.1.0
+revision: 1
+name: simulated
+upstreams:
+  backend:
+    port: 3000
+mount-points:
+  main:
+    handlers:
+      api:
+        kind: proxy
+        upstream: backend
+        priority: 20
+        rules:
+          - filter:
+              path: ["api", "*"]
+              methods: ["POST"]
+            action: throw
+            exception: "api:method-not-allowed"
+          - filter:
+              path: ["api", "?", "*"]
+            action: invoke
+          - filter:
+              path: ["*"]
+            action: next-handler
+      debug:
+        kind: pass-through
+        priority: 10
+        profiles: ["dev"]
+      fallback:
+        kind: proxy
+        upstream: backend
+        priority: 30
+        rules:
+          - filter:
+              path: ["private", "*"]
+            action: respond
+            static-response: not-found
+            status-code: 404
+            profiles: ["prod"]
+          - filter:
+              path: ["private", "*"]
+            action: throw
+            exception: "not-found"
+  inactive:
+    profiles: ["dev"]
+    handlers:
+      any:
+        kind: pass-through
+        priority: 10
+"#;
+
+    const PROJECT_YAML: &str = r#"---
+version: 1.1.0
+mount-points:
+  main:
+    handlers:
+      auth:
+        kind: pass-through
+        priority: 5
+        rules:
+          - filter:
+              path: ["*"]
+            action: next-handler
+"#;
+
+    fn simulate_main(
+        uri: &str,
+        method: Method,
+        profile: Option<&str>,
+        project: bool,
+    ) -> Simulation {
+        let client_config = ClientConfig::parse(CLIENT_YAML).unwrap();
+        let project_config = ProjectConfig::parse(PROJECT_YAML).unwrap();
+        let request = SimulatedRequest::new(method, uri.parse().unwrap());
+
+        simulate(
+            &client_config,
+            if project { Some(&project_config) } else { None },
+            &profile.map(|p| p.parse().unwrap()),
+            &request,
+        )
+        .into_iter()
+        .find(|simulation| simulation.mount_point.as_str() == "main")
+        .unwrap()
+    }
+
+    #[test]
+    pub fn test_invoke() {
+        let simulation = simulate_main("/api/users/1", Method::GET, None, false);
+        assert_eq!(
+            simulation.outcome,
+            SimulationOutcome::Invoke {
+                handler: "api".parse().unwrap(),
+                rule: 1
+            }
+        );
+        assert!(matches!(
+            simulation.steps[0],
+            SimulationStep::HandlerInactive { .. }
+        ));
+        assert!(matches!(
+            &simulation.steps[3],
+            SimulationStep::RuleMatched { captures, .. }
+                if captures.path.positional == vec!["users", "1"]
+        ));
+    }
+
+    #[test]
+    pub fn test_throw() {
+        let simulation = simulate_main("/api/users", Method::POST, None, false);
+        assert_eq!(
+            simulation.outcome,
+            SimulationOutcome::Throw {
+                handler: "api".parse().unwrap(),
+                rule: 0,
+                exception: "api:method-not-allowed".parse().unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_next_handler_and_profiles() {
+        let simulation = simulate_main("/private/data", Method::GET, Some("prod"), true);
+
+        assert_eq!(
+            simulation.steps[0],
+            SimulationStep::HandlerEntered {
+                handler: "auth".parse().unwrap(),
+                source: HandlerSource::Project,
+                priority: 5,
+            }
+        );
+        assert!(simulation.steps.contains(&SimulationStep::HandlerInactive {
+            handler: "debug".parse().unwrap(),
+            source: HandlerSource::Client,
+        }));
+        assert!(simulation.steps.contains(&SimulationStep::NextHandler {
+            handler: "api".parse().unwrap(),
+            rule: 2,
+        }));
+        assert!(matches!(
+            simulation.outcome,
+            SimulationOutcome::Respond {
+                rule: 0,
+                status_code: Some(_),
+                ..
+            }
+        ));
+
+        let simulation = simulate_main("/private/data", Method::GET, None, true);
+        assert!(simulation.steps.contains(&SimulationStep::RuleInactive {
+            handler: "fallback".parse().unwrap(),
+            rule: 0,
+        }));
+        assert!(matches!(
+            simulation.outcome,
+            SimulationOutcome::Throw { rule: 1, .. }
+        ));
+    }
+
+    #[test]
+    pub fn test_debug_profile() {
+        let simulation = simulate_main("/anything", Method::GET, Some("dev"), false);
+        assert_eq!(
+            simulation.outcome,
+            SimulationOutcome::Invoke {
+                handler: "debug".parse().unwrap(),
+                rule: 0
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_not_handled() {
+        let client_config = ClientConfig::parse(CLIENT_YAML).unwrap();
+        let request = SimulatedRequest::new(Method::GET, "/".parse().unwrap());
+        let simulations = simulate(&client_config, None, &None, &request);
+
+        let inactive = simulations
+            .iter()
+            .find(|simulation| simulation.mount_point.as_str() == "inactive")
+            .unwrap();
+        assert_eq!(inactive.steps, vec![SimulationStep::MountPointInactive]);
+        assert_eq!(inactive.outcome, SimulationOutcome::NotHandled);
+
+        let main = simulations
+            .iter()
+            .find(|simulation| simulation.mount_point.as_str() == "main")
+            .unwrap();
+        assert_eq!(main.outcome, SimulationOutcome::NotHandled);
+        assert!(main.to_string().contains("no handler accepted the request"));
+    }
+}
@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config_core::{cache::Cache, post_processing::PostProcessing, rebase::Rebase},
+    entities::Upstream,
+};
+use core::hash::Hasher;
+use hashbrown::HashSet;
+use http::{header::HeaderName, HeaderMap};
+use schemars::JsonSchema;
+use smol_str::SmolStr;
+
+/// Proxy, which splits traffic between several upstreams according to their weights
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash, JsonSchema)]
+pub struct SplitProxy {
+    pub upstreams: Vec<WeightedUpstream>,
+
+    /// Keep the client on the same upstream, while weights are unchanged
+    #[serde(default)]
+    pub sticky: Option<StickyAssignment>,
+
+    /// Header, which forces the upstream by its name
+    #[serde(rename = "override-header", default)]
+    pub override_header: Option<SmolStr>,
+
+    #[serde(flatten, default)]
+    pub rebase: Rebase,
+
+    #[serde(default)]
+    pub cache: Cache,
+
+    #[serde(rename = "post-processing", default)]
+    pub post_processing: PostProcessing,
+
+    #[serde(default = "default_websockets")]
+    pub websockets: bool,
+}
+
+fn default_websockets() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, JsonSchema)]
+pub struct WeightedUpstream {
+    pub upstream: Upstream,
+    pub weight: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, JsonSchema)]
+#[serde(tag = "by")]
+pub enum StickyAssignment {
+    #[serde(rename = "cookie")]
+    Cookie { name: SmolStr },
+
+    #[serde(rename = "header")]
+    Header { name: SmolStr },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SplitProxyError {
+    #[error("no upstreams defined")]
+    NoUpstreams,
+
+    #[error("total weight should be greater than zero")]
+    ZeroTotalWeight,
+
+    #[error("upstream {0} is used more than once")]
+    DuplicateUpstream(Upstream),
+
+    #[error("bad header name `{0}`")]
+    BadHeaderName(SmolStr),
+}
+
+impl StickyAssignment {
+    /// Value identifying the client
+    pub fn key<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        match self {
+            StickyAssignment::Header { name } => headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok()),
+            StickyAssignment::Cookie { name } => headers
+                .get_all(http::header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(cookie_name, _)| cookie_name.trim() == name.as_str())
+                .map(|(_, value)| value.trim()),
+        }
+    }
+}
+
+impl SplitProxy {
+    pub fn validate(&self) -> Result<(), SplitProxyError> {
+        if self.upstreams.is_empty() {
+            return Err(SplitProxyError::NoUpstreams);
+        }
+
+        if self.total_weight() == 0 {
+            return Err(SplitProxyError::ZeroTotalWeight);
+        }
+
+        let mut seen = HashSet::new();
+        for weighted in &self.upstreams {
+            if !seen.insert(&weighted.upstream) {
+                return Err(SplitProxyError::DuplicateUpstream(
+                    weighted.upstream.clone(),
+                ));
+            }
+        }
+
+        let sticky_header = match &self.sticky {
+            Some(StickyAssignment::Header { name }) => Some(name),
+            _ => None,
+        };
+        for name in sticky_header.into_iter().chain(&self.override_header) {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| SplitProxyError::BadHeaderName(name.clone()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn total_weight(&self) -> u64 {
+        self.upstreams
+            .iter()
+            .map(|weighted| u64::from(weighted.weight))
+            .sum()
+    }
+
+    /// Choose the upstream for the request.
+    ///
+    /// Override header wins, then sticky assignment. Otherwise the upstream is chosen
+    /// by `random`, which should be uniformly distributed.
+    pub fn select(&self, headers: &HeaderMap, random: u64) -> Option<&Upstream> {
+        if let Some(override_header) = &self.override_header {
+            let forced = headers
+                .get(override_header.as_str())
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| {
+                    self.upstreams
+                        .iter()
+                        .find(|weighted| weighted.upstream.as_str() == value)
+                });
+            if let Some(weighted) = forced {
+                return Some(&weighted.upstream);
+            }
+        }
+
+        let point = match self.sticky.as_ref().and_then(|sticky| sticky.key(headers)) {
+            Some(key) => {
+                let mut hasher = seahash::SeaHasher::new();
+                hasher.write(key.as_bytes());
+                hasher.finish()
+            }
+            None => random,
+        };
+
+        self.upstream_at(point)
+    }
+
+    fn upstream_at(&self, point: u64) -> Option<&Upstream> {
+        let total_weight = self.total_weight();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut point = point % total_weight;
+        for weighted in &self.upstreams {
+            let weight = u64::from(weighted.weight);
+            if point < weight {
+                return Some(&weighted.upstream);
+            }
+            point -= weight;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn split_proxy() -> SplitProxy {
+        serde_yaml::from_str(
+            r#"
+---
+upstreams:
+  - upstream: stable
+    weight: 90
+  - upstream: canary
+    weight: 10
+sticky:
+  by: cookie
+  name: session
+override-header: x-upstream
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    pub fn test_select() {
+        let proxy = split_proxy();
+        proxy.validate().unwrap();
+
+        let headers = HeaderMap::new();
+        assert_eq!(proxy.select(&headers, 0).unwrap().as_str(), "stable");
+        assert_eq!(proxy.select(&headers, 89).unwrap().as_str(), "stable");
+        assert_eq!(proxy.select(&headers, 90).unwrap().as_str(), "canary");
+        assert_eq!(proxy.select(&headers, 199).unwrap().as_str(), "canary");
+
+        let canary_count = (0..1000u64)
+            .filter(|random| proxy.select(&headers, *random).unwrap().as_str() == "canary")
+            .count();
+        assert_eq!(canary_count, 100);
+
+        let mut forced = HeaderMap::new();
+        forced.insert("x-upstream", "canary".parse().unwrap());
+        assert_eq!(proxy.select(&forced, 0).unwrap().as_str(), "canary");
+        forced.insert("x-upstream", "unknown".parse().unwrap());
+        assert_eq!(proxy.select(&forced, 0).unwrap().as_str(), "stable");
+    }
+
+    #[test]
+    pub fn test_sticky() {
+        let proxy = split_proxy();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "theme=dark; session=abc123".parse().unwrap());
+
+        let first = proxy.select(&headers, 0).unwrap().clone();
+        for random in 1..100 {
+            assert_eq!(proxy.select(&headers, random).unwrap(), &first);
+        }
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let mut proxy = split_proxy();
+        proxy.upstreams[1].upstream = "stable".parse().unwrap();
+        assert!(matches!(
+            proxy.validate(),
+            Err(SplitProxyError::DuplicateUpstream(_))
+        ));
+
+        let mut proxy = split_proxy();
+        for weighted in &mut proxy.upstreams {
+            weighted.weight = 0;
+        }
+        assert!(matches!(
+            proxy.validate(),
+            Err(SplitProxyError::ZeroTotalWeight)
+        ));
+    }
+}
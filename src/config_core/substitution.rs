@@ -0,0 +1,461 @@
+use crate::{
+    config_core::rule::FilterMatch,
+    entities::schemars::{gen::SchemaGenerator, schema::Schema},
+};
+use core::fmt;
+use schemars::JsonSchema;
+use serde::{de, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+use smol_str::SmolStr;
+use std::{collections::BTreeSet, str::FromStr};
+
+pub const SEGMENTS_SCOPE: &str = "segments";
+pub const QUERY_SCOPE: &str = "query";
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum SubstitutionError {
+    #[error("unterminated `{{{{` in `{0}`")]
+    Unterminated(SmolStr),
+
+    #[error("unknown capture scope `{0}`, expected `segments` or `query`")]
+    UnknownScope(SmolStr),
+
+    #[error("empty capture name in `{0}`")]
+    EmptyName(SmolStr),
+
+    #[error("positional captures start from 1")]
+    ZeroPosition,
+
+    #[error("position of the capture `{0}` is too large")]
+    PositionOverflow(SmolStr),
+
+    #[error("capture `{0}` is not provided by the filter")]
+    UnknownReference(CaptureRef),
+}
+
+/// Reference to the value captured by the rule filter
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum CaptureRef {
+    /// `$1` or `{{ segments.1 }}`, starting from 1
+    Positional(usize),
+
+    /// `{{ segments.name }}`, named group of the path regex
+    Segment(SmolStr),
+
+    /// `{{ query.name }}`, value of the matched query parameter
+    Query(SmolStr),
+}
+
+impl fmt::Display for CaptureRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureRef::Positional(idx) => write!(f, "${}", idx),
+            CaptureRef::Segment(name) => write!(f, "{{{{ {}.{} }}}}", SEGMENTS_SCOPE, name),
+            CaptureRef::Query(name) => write!(f, "{{{{ {}.{} }}}}", QUERY_SCOPE, name),
+        }
+    }
+}
+
+impl CaptureRef {
+    fn resolve<'a>(&self, captures: &'a FilterMatch) -> Option<&'a SmolStr> {
+        match self {
+            CaptureRef::Positional(idx) => captures.path.positional.get(idx - 1),
+            CaptureRef::Segment(name) => captures.path.named.get(name),
+            CaptureRef::Query(name) => captures.query.get(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum TemplatePart {
+    Literal(SmolStr),
+    /// Reference along with its text in the template
+    Capture(CaptureRef, SmolStr),
+}
+
+/// String with references to captured values.
+///
+/// `$N` refers to the positional capture, `$$` is the escaped `$`. `{{ segments.name }}` and
+/// `{{ query.name }}` refer to named path regex groups and query parameters respectively.
+///
+/// Templates are only validated in configs since `SUBSTITUTIONS_MIN_VERSION`. Older configs
+/// may contain `$` and `{{` literally, so their templates are turned into `literal` ones,
+/// and malformed templates are deserialized as literals. References to missing captures are
+/// rendered as written.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct SubstitutionTemplate {
+    source: SmolStr,
+    parts: Vec<TemplatePart>,
+}
+
+impl SubstitutionTemplate {
+    pub fn as_str(&self) -> &str {
+        self.source.as_str()
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.references().next().is_none()
+    }
+
+    pub fn references(&self) -> impl Iterator<Item = &CaptureRef> {
+        self.parts.iter().filter_map(|part| match part {
+            TemplatePart::Capture(reference, _) => Some(reference),
+            TemplatePart::Literal(_) => None,
+        })
+    }
+
+    /// Template, which renders the string as is
+    pub fn literal(s: &str) -> Self {
+        SubstitutionTemplate {
+            source: s.into(),
+            parts: if s.is_empty() {
+                vec![]
+            } else {
+                vec![TemplatePart::Literal(s.into())]
+            },
+        }
+    }
+
+    /// Render the template. References to missing captures are kept as written
+    pub fn substitute(&self, captures: &FilterMatch) -> String {
+        let mut result = String::with_capacity(self.source.len());
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => result.push_str(literal),
+                TemplatePart::Capture(reference, raw) => {
+                    result.push_str(reference.resolve(captures).unwrap_or(raw))
+                }
+            }
+        }
+        result
+    }
+
+    pub fn validate(&self, available: &AvailableCaptures) -> Result<(), SubstitutionError> {
+        // malformed templates are deserialized as literals, so parse the source again
+        let parsed = self.source.parse::<SubstitutionTemplate>()?;
+        let unknown = parsed
+            .references()
+            .find(|reference| !available.contains(reference))
+            .cloned();
+        match unknown {
+            Some(reference) => Err(SubstitutionError::UnknownReference(reference)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_reference(expr: &str) -> Result<CaptureRef, SubstitutionError> {
+    let (scope, name) = match expr.split_once('.') {
+        Some((scope, name)) => (scope.trim(), name.trim()),
+        None => return Err(SubstitutionError::EmptyName(expr.into())),
+    };
+
+    if name.is_empty() {
+        return Err(SubstitutionError::EmptyName(expr.into()));
+    }
+
+    match scope {
+        SEGMENTS_SCOPE => match name.parse::<usize>() {
+            Ok(0) => Err(SubstitutionError::ZeroPosition),
+            Ok(idx) => Ok(CaptureRef::Positional(idx)),
+            Err(_) if name.bytes().all(|b| b.is_ascii_digit()) => {
+                Err(SubstitutionError::PositionOverflow(name.into()))
+            }
+            Err(_) => Ok(CaptureRef::Segment(name.into())),
+        },
+        QUERY_SCOPE => Ok(CaptureRef::Query(name.into())),
+        _ => Err(SubstitutionError::UnknownScope(scope.into())),
+    }
+}
+
+impl FromStr for SubstitutionTemplate {
+    type Err = SubstitutionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+
+        while let Some(pos) = rest.find(['$', '{']) {
+            literal.push_str(&rest[..pos]);
+            let tail = &rest[pos..];
+
+            if let Some(after) = tail.strip_prefix("$$") {
+                literal.push('$');
+                rest = after;
+            } else if let Some(after) = tail.strip_prefix('$') {
+                let digits_len = after
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(after.len());
+                if digits_len == 0 {
+                    literal.push('$');
+                } else {
+                    let digits = &after[..digits_len];
+                    let idx = digits
+                        .parse::<usize>()
+                        .map_err(|_| SubstitutionError::PositionOverflow(digits.into()))?;
+                    if idx == 0 {
+                        return Err(SubstitutionError::ZeroPosition);
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(literal.as_str().into()));
+                        literal.clear();
+                    }
+                    parts.push(TemplatePart::Capture(
+                        CaptureRef::Positional(idx),
+                        tail[..digits_len + 1].into(),
+                    ));
+                }
+                rest = &after[digits_len..];
+            } else if let Some(after) = tail.strip_prefix("{{") {
+                let end = after
+                    .find("}}")
+                    .ok_or_else(|| SubstitutionError::Unterminated(s.into()))?;
+                let reference = parse_reference(after[..end].trim())?;
+                if !literal.is_empty() {
+                    parts.push(TemplatePart::Literal(literal.as_str().into()));
+                    literal.clear();
+                }
+                parts.push(TemplatePart::Capture(reference, tail[..end + 4].into()));
+                rest = &after[end + 2..];
+            } else {
+                literal.push('{');
+                rest = &tail[1..];
+            }
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal.into()));
+        }
+
+        Ok(SubstitutionTemplate {
+            source: s.into(),
+            parts,
+        })
+    }
+}
+
+impl AsRef<str> for SubstitutionTemplate {
+    fn as_ref(&self) -> &str {
+        self.source.as_ref()
+    }
+}
+
+impl fmt::Display for SubstitutionTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl JsonSchema for SubstitutionTemplate {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> String {
+        String::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+impl Serialize for SubstitutionTemplate {
+    fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.source.as_str())
+    }
+}
+
+struct SubstitutionTemplateVisitor;
+
+impl<'de> Visitor<'de> for SubstitutionTemplateVisitor {
+    type Value = SubstitutionTemplate;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "string, optionally referring captures as \"$1\" or \"{{{{ segments.name }}}}\""
+        )
+    }
+
+    /// Malformed templates are kept as literals and reported by `validate`
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(value
+            .parse()
+            .unwrap_or_else(|_| SubstitutionTemplate::literal(value)))
+    }
+}
+
+impl<'de> Deserialize<'de> for SubstitutionTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(SubstitutionTemplateVisitor)
+    }
+}
+
+/// Captures, which the filter provides on the successful match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AvailableCaptures {
+    pub positional: usize,
+    pub segments: BTreeSet<SmolStr>,
+    pub query: BTreeSet<SmolStr>,
+}
+
+impl AvailableCaptures {
+    pub fn contains(&self, reference: &CaptureRef) -> bool {
+        match reference {
+            CaptureRef::Positional(idx) => *idx >= 1 && *idx <= self.positional,
+            CaptureRef::Segment(name) => self.segments.contains(name),
+            CaptureRef::Query(name) => self.query.contains(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config_core::path::PathCaptures;
+    use maplit::btreemap;
+
+    fn captures() -> FilterMatch {
+        FilterMatch {
+            path: PathCaptures {
+                positional: vec!["users".into(), "42".into()],
+                named: btreemap! {
+                    "id".into() => "42".into(),
+                },
+            },
+            query: btreemap! {
+                "lang".into() => "en".into(),
+            },
+        }
+    }
+
+    #[test]
+    pub fn test_parse() {
+        let template: SubstitutionTemplate = "/api/$1?id={{ segments.id }}&l={{query.lang}}"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            template.references().cloned().collect::<Vec<_>>(),
+            vec![
+                CaptureRef::Positional(1),
+                CaptureRef::Segment("id".into()),
+                CaptureRef::Query("lang".into()),
+            ]
+        );
+        assert_eq!(
+            template.as_str(),
+            "/api/$1?id={{ segments.id }}&l={{query.lang}}"
+        );
+
+        let template: SubstitutionTemplate = "cost: 5$, $$1 {x}".parse().unwrap();
+        assert!(template.is_static());
+        assert_eq!(template.substitute(&captures()), "cost: 5$, $1 {x}");
+
+        assert_eq!(
+            "{{ segments.2 }}"
+                .parse::<SubstitutionTemplate>()
+                .unwrap()
+                .references()
+                .collect::<Vec<_>>(),
+            vec![&CaptureRef::Positional(2)]
+        );
+    }
+
+    #[test]
+    pub fn test_parse_errors() {
+        assert!(matches!(
+            "{{ segments.id".parse::<SubstitutionTemplate>(),
+            Err(SubstitutionError::Unterminated(_))
+        ));
+        assert!(matches!(
+            "{{ headers.host }}".parse::<SubstitutionTemplate>(),
+            Err(SubstitutionError::UnknownScope(_))
+        ));
+        assert!(matches!(
+            "{{ query. }}".parse::<SubstitutionTemplate>(),
+            Err(SubstitutionError::EmptyName(_))
+        ));
+        assert!(matches!(
+            "{{ query }}".parse::<SubstitutionTemplate>(),
+            Err(SubstitutionError::EmptyName(_))
+        ));
+        assert_eq!(
+            "$0".parse::<SubstitutionTemplate>(),
+            Err(SubstitutionError::ZeroPosition)
+        );
+
+        let template = serde_yaml::from_str::<SubstitutionTemplate>("\"{{ a.b }}\"").unwrap();
+        assert!(template.is_static());
+        assert_eq!(template.substitute(&captures()), "{{ a.b }}");
+        assert_eq!(
+            template.validate(&Default::default()),
+            Err(SubstitutionError::UnknownScope("a".into()))
+        );
+    }
+
+    #[test]
+    pub fn test_position_overflow() {
+        assert_eq!(
+            "$99999999999999999999999".parse::<SubstitutionTemplate>(),
+            Err(SubstitutionError::PositionOverflow(
+                "99999999999999999999999".into()
+            ))
+        );
+        assert!(matches!(
+            "{{ segments.99999999999999999999999 }}".parse::<SubstitutionTemplate>(),
+            Err(SubstitutionError::PositionOverflow(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_substitute() {
+        let template: SubstitutionTemplate =
+            "$1/{{ segments.id }}/{{ query.lang }}/{{ query.missing }}$3"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            template.substitute(&captures()),
+            "users/42/en/{{ query.missing }}$3"
+        );
+
+        let template: SubstitutionTemplate = "from $5".parse().unwrap();
+        assert_eq!(template.substitute(&captures()), "from $5");
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let available = AvailableCaptures {
+            positional: 2,
+            segments: vec!["id".into()].into_iter().collect(),
+            query: Default::default(),
+        };
+
+        let template: SubstitutionTemplate = "$2{{ segments.id }}".parse().unwrap();
+        assert!(template.validate(&available).is_ok());
+
+        let template: SubstitutionTemplate = "$3".parse().unwrap();
+        assert_eq!(
+            template.validate(&available),
+            Err(SubstitutionError::UnknownReference(CaptureRef::Positional(
+                3
+            )))
+        );
+
+        let template: SubstitutionTemplate = "{{ query.lang }}".parse().unwrap();
+        assert_eq!(
+            template.validate(&available).unwrap_err().to_string(),
+            "capture `{{ query.lang }}` is not provided by the filter"
+        );
+    }
+}
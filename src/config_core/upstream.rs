@@ -11,8 +11,9 @@ use smol_str::SmolStr;
 use crate::config_core::rule::MethodWrapper;
 use std::{
     collections::BTreeMap,
+    fmt,
     hash::{Hash, Hasher},
-    net::AddrParseError,
+    net::{AddrParseError, IpAddr},
     num::ParseIntError,
     str::FromStr,
     time::Duration,
@@ -28,13 +29,26 @@ pub enum UpstreamSocketAddrParseError {
 
     #[error("malformed addr")]
     Malformed,
+
+    #[error("port is not set")]
+    NoPort,
+
+    #[error("unix socket can't be combined with host or port")]
+    UnixWithHostOrPort,
 }
 
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 // #[schemars(deny_unknown_fields)]
 pub struct UpstreamSocketAddr {
+    #[serde(default)]
     pub port: u16,
     pub host: Option<String>,
+
+    /// Path to the Unix domain socket, used instead of host and port
+    #[serde(default)]
+    pub unix: Option<String>,
 }
 
 impl Hash for UpstreamSocketAddr {
@@ -44,14 +58,54 @@ impl Hash for UpstreamSocketAddr {
     }
 }
 
+impl UpstreamSocketAddr {
+    pub fn unix(path: impl Into<String>) -> Self {
+        UpstreamSocketAddr {
+            port: 0,
+            host: None,
+            unix: Some(path.into()),
+        }
+    }
+
+    pub fn get_host(&self) -> String {
+        self.host.clone().unwrap_or_else(|| "127.0.0.1".to_string())
+    }
+
+    pub fn validate(&self) -> Result<(), UpstreamSocketAddrParseError> {
+        match &self.unix {
+            Some(path) if path.is_empty() => Err(UpstreamSocketAddrParseError::Malformed),
+            Some(_) if self.host.is_some() || self.port != 0 => {
+                Err(UpstreamSocketAddrParseError::UnixWithHostOrPort)
+            }
+            None if self.port == 0 => Err(UpstreamSocketAddrParseError::NoPort),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.unix {
+            Some(path) => write!(f, "{}{}", UNIX_SOCKET_PREFIX, path),
+            None => write!(f, "{}:{}", self.get_host(), self.port),
+        }
+    }
+}
+
 impl FromStr for UpstreamSocketAddr {
     type Err = UpstreamSocketAddrParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(if let Some(stripped) = s.strip_prefix(':') {
+        Ok(if let Some(path) = s.strip_prefix(UNIX_SOCKET_PREFIX) {
+            if path.is_empty() {
+                return Err(UpstreamSocketAddrParseError::Malformed);
+            }
+            UpstreamSocketAddr::unix(path)
+        } else if let Some(stripped) = s.strip_prefix(':') {
             UpstreamSocketAddr {
                 port: stripped.parse()?,
                 host: None,
+                unix: None,
             }
         } else if s.contains(':') {
             let mut parts: Vec<_> = s.split(':').collect();
@@ -64,6 +118,7 @@ impl FromStr for UpstreamSocketAddr {
             UpstreamSocketAddr {
                 port,
                 host: Some(addr),
+                unix: None,
             }
         } else {
             return Err(UpstreamSocketAddrParseError::Malformed);
@@ -82,22 +137,267 @@ pub struct UpstreamDefinition {
 
     #[serde(default)]
     pub profiles: Option<Vec<ProfileName>>,
+
+    /// Additional addresses, serving the same upstream
+    #[serde(default)]
+    pub addresses: Vec<UpstreamSocketAddr>,
+
+    /// Connect to all IPs resolved from the hostname, instead of the first one
+    #[serde(rename = "resolve-all", default)]
+    pub resolve_all: bool,
+
+    #[serde(rename = "load-balancing", default)]
+    pub load_balancing: LoadBalancing,
+
+    /// Connect to the upstream over TLS
+    #[serde(default)]
+    pub tls: Option<UpstreamTls>,
+
+    #[serde(rename = "circuit-breaker", default)]
+    pub circuit_breaker: Option<CircuitBreaker>,
+
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+
+    /// Forget the UDP session, if no datagrams are passed in either direction for this long
+    #[serde(rename = "udp-idle-timeout", default)]
+    pub udp_idle_timeout: Option<DurationWrapper>,
+}
+
+/// Name, under which the circuit breaker state is reported along with health checks
+pub const CIRCUIT_BREAKER_PROBE_NAME: &str = "circuit-breaker";
+
+fn default_circuit_failure_threshold() -> u32 {
+    5
+}
+
+fn default_cool_down() -> DurationWrapper {
+    DurationWrapper(Duration::from_secs(30))
+}
+
+/// Reject connections to the upstream immediately, while it's failing
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+pub struct CircuitBreaker {
+    /// Consecutive connection failures or timeouts, which open the circuit
+    #[serde(
+        rename = "failure-threshold",
+        default = "default_circuit_failure_threshold"
+    )]
+    pub failure_threshold: u32,
+
+    /// Time the circuit stays open, before a trial connection is allowed
+    #[serde(rename = "cool-down", default = "default_cool_down")]
+    pub cool_down: DurationWrapper,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CircuitBreakerError {
+    #[error("failure threshold should be greater than zero")]
+    ZeroFailureThreshold,
+}
+
+impl CircuitBreaker {
+    pub fn validate(&self) -> Result<(), CircuitBreakerError> {
+        if self.failure_threshold == 0 {
+            return Err(CircuitBreakerError::ZeroFailureThreshold);
+        }
+        Ok(())
+    }
+}
+
+/// TLS settings of connections to the upstream
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+pub struct UpstreamTls {
+    /// Name for SNI and certificate verification. Upstream host is used by default
+    #[serde(rename = "server-name", default)]
+    pub server_name: Option<SmolStr>,
+
+    /// Path to PEM file with trusted CA certificates, used instead of system roots
+    #[serde(rename = "ca-bundle", default)]
+    pub ca_bundle: Option<String>,
+
+    #[serde(rename = "client-certificate", default)]
+    pub client_certificate: Option<ClientCertificate>,
+
+    /// Don't verify the upstream certificate. Intended for development only
+    #[serde(rename = "insecure-skip-verify", default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// Paths to PEM files with the client certificate chain and its private key
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+pub struct ClientCertificate {
+    pub certificate: String,
+
+    #[serde(rename = "private-key")]
+    pub private_key: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpstreamTlsError {
+    #[error("server name should not be empty")]
+    EmptyServerName,
+
+    #[error("server name should be a DNS name, not an IP address")]
+    IpServerName,
+
+    #[error("server-name is required to verify the certificate of {0}")]
+    ServerNameRequired(String),
+
+    #[error("ca-bundle has no effect with insecure-skip-verify")]
+    CaBundleWithoutVerification,
+}
+
+impl UpstreamTls {
+    pub fn validate(&self) -> Result<(), UpstreamTlsError> {
+        if let Some(server_name) = &self.server_name {
+            if server_name.trim().is_empty() {
+                return Err(UpstreamTlsError::EmptyServerName);
+            }
+            if server_name.parse::<IpAddr>().is_ok() {
+                return Err(UpstreamTlsError::IpServerName);
+            }
+        }
+        if self.insecure_skip_verify && self.ca_bundle.is_some() {
+            return Err(UpstreamTlsError::CaBundleWithoutVerification);
+        }
+        Ok(())
+    }
+}
+
+/// Strategy of choosing the upstream endpoint for the new connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Eq, PartialEq, JsonSchema)]
+pub enum LoadBalancing {
+    #[serde(rename = "round-robin")]
+    RoundRobin,
+
+    #[serde(rename = "least-connections")]
+    LeastConnections,
+
+    #[serde(rename = "random")]
+    Random,
+
+    /// Same balance key is routed to the same endpoint, while it's available
+    #[serde(rename = "consistent-hash")]
+    ConsistentHash,
+}
+
+impl Default for LoadBalancing {
+    fn default() -> Self {
+        LoadBalancing::RoundRobin
+    }
+}
+
+/// Transport protocol of the upstream
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Eq, PartialEq, JsonSchema)]
+pub enum UpstreamProtocol {
+    #[serde(rename = "tcp")]
+    Tcp,
+
+    /// Datagrams are forwarded with boundaries preserved
+    #[serde(rename = "udp")]
+    Udp,
+}
+
+impl Default for UpstreamProtocol {
+    fn default() -> Self {
+        UpstreamProtocol::Tcp
+    }
+}
+
+pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpstreamProtocolError {
+    #[error("{0} is not supported by UDP upstreams")]
+    UnsupportedOverUdp(&'static str),
+
+    #[error("{0} is only supported by UDP upstreams")]
+    UdpOnly(&'static str),
 }
 
 impl UpstreamDefinition {
     pub fn on_default_host(port: u16) -> Self {
         UpstreamDefinition {
-            addr: UpstreamSocketAddr { port, host: None },
+            addr: UpstreamSocketAddr {
+                port,
+                host: None,
+                unix: None,
+            },
             health_checks: BTreeMap::new(),
             profiles: None,
+            addresses: vec![],
+            resolve_all: false,
+            load_balancing: Default::default(),
+            tls: None,
+            circuit_breaker: None,
+            protocol: Default::default(),
+            udp_idle_timeout: None,
         }
     }
 
     pub fn get_host(&self) -> String {
-        self.addr
-            .host
-            .clone()
-            .unwrap_or_else(|| "127.0.0.1".to_string())
+        self.addr.get_host()
+    }
+
+    /// Primary address followed by additional ones
+    pub fn all_addrs(&self) -> impl Iterator<Item = &UpstreamSocketAddr> {
+        std::iter::once(&self.addr).chain(&self.addresses)
+    }
+
+    pub fn udp_idle_timeout(&self) -> Duration {
+        self.udp_idle_timeout
+            .as_ref()
+            .map_or(DEFAULT_UDP_IDLE_TIMEOUT, |timeout| timeout.0)
+    }
+
+    /// Certificates are verified by DNS name only, so it should be set explicitly for
+    /// addresses without one
+    pub fn validate_tls(&self) -> Result<(), UpstreamTlsError> {
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+
+            if tls.server_name.is_none() && !tls.insecure_skip_verify {
+                if let Some(addr) = self
+                    .all_addrs()
+                    .find(|addr| addr.unix.is_some() || addr.get_host().parse::<IpAddr>().is_ok())
+                {
+                    return Err(UpstreamTlsError::ServerNameRequired(addr.to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_protocol(&self) -> Result<(), UpstreamProtocolError> {
+        match self.protocol {
+            UpstreamProtocol::Tcp => {
+                if self.udp_idle_timeout.is_some() {
+                    return Err(UpstreamProtocolError::UdpOnly("udp-idle-timeout"));
+                }
+            }
+            UpstreamProtocol::Udp => {
+                if self.tls.is_some() {
+                    return Err(UpstreamProtocolError::UnsupportedOverUdp("tls"));
+                }
+                if self.circuit_breaker.is_some() {
+                    return Err(UpstreamProtocolError::UnsupportedOverUdp("circuit-breaker"));
+                }
+                if self.all_addrs().any(|addr| addr.unix.is_some()) {
+                    return Err(UpstreamProtocolError::UnsupportedOverUdp("unix socket"));
+                }
+                if self
+                    .health_checks
+                    .values()
+                    .any(|probe| probe.exec.is_none())
+                {
+                    return Err(UpstreamProtocolError::UnsupportedOverUdp(
+                        "health check, other than exec,",
+                    ));
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -105,20 +405,37 @@ impl UpstreamDefinition {
 #[serde(tag = "kind")]
 // #[schemars(deny_unknown_fields)]
 pub enum ProbeDetails {
+    /// Failures are reported to the cloud
     #[serde(rename = "liveness")]
     Liveness,
+
+    /// Failures stop new connections to the upstream address
+    #[serde(rename = "readiness")]
+    Readiness,
 }
 
 fn default_status_code_range() -> StatusCodeRange {
     StatusCodeRange::Single(StatusCode::OK)
 }
 
+fn default_success_threshold() -> u32 {
+    1
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
 // #[schemars(deny_unknown_fields)]
 pub struct Probe {
     #[serde(flatten)]
     pub details: ProbeDetails,
-    pub path: SmolStr,
+
+    /// Path of the HTTP request. Required, unless `tcp` or `exec` probe is used
+    #[serde(default)]
+    pub path: Option<SmolStr>,
+
     pub timeout: DurationWrapper,
     pub period: DurationWrapper,
 
@@ -130,6 +447,141 @@ pub struct Probe {
 
     #[serde(rename = "expected-status-code", default = "default_status_code_range")]
     pub expected_status_code: StatusCodeRange,
+
+    /// Assertions on the HTTP response body, checked in addition to the status code
+    #[serde(rename = "expected-body", default)]
+    pub expected_body: Option<BodyAssertion>,
+
+    /// Only check that the connection to the upstream could be established
+    #[serde(default)]
+    pub tcp: bool,
+
+    /// Run the local command instead of connecting to the upstream
+    #[serde(default)]
+    pub exec: Option<ExecProbe>,
+
+    /// Consecutive successes required to become healthy
+    #[serde(rename = "success-threshold", default = "default_success_threshold")]
+    pub success_threshold: u32,
+
+    /// Consecutive failures required to become unhealthy
+    #[serde(rename = "failure-threshold", default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+
+    /// Delay before the first check
+    #[serde(rename = "initial-delay", default)]
+    pub initial_delay: Option<DurationWrapper>,
+}
+
+/// Local command, which succeeds on zero exit code
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+pub struct ExecProbe {
+    /// Program followed by its arguments
+    pub command: Vec<String>,
+}
+
+/// All of the set conditions should hold for the body
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, JsonSchema)]
+pub struct BodyAssertion {
+    /// Substring, which should be present in the body
+    #[serde(default)]
+    pub contains: Option<String>,
+
+    /// Regular expression, which should match the body
+    #[serde(default)]
+    pub regex: Option<String>,
+
+    /// JSON pointer to the value, which should be present in the JSON body
+    #[serde(rename = "json-pointer", default)]
+    pub json_pointer: Option<String>,
+
+    /// Expected value at `json-pointer`. Strings are compared without quotes
+    #[serde(default)]
+    pub equals: Option<String>,
+}
+
+impl BodyAssertion {
+    pub fn validate(&self) -> Result<(), ProbeError> {
+        if self.equals.is_some() && self.json_pointer.is_none() {
+            return Err(ProbeError::EqualsWithoutJsonPointer);
+        }
+        if self.contains.is_none() && self.regex.is_none() && self.json_pointer.is_none() {
+            return Err(ProbeError::EmptyBodyAssertion);
+        }
+        if let Some(pointer) = &self.json_pointer {
+            if !pointer.is_empty() && !pointer.starts_with('/') {
+                return Err(ProbeError::BadJsonPointer(pointer.clone()));
+            }
+        }
+        self.compile()?;
+        Ok(())
+    }
+
+    /// Compile the assertion, so that it can be checked repeatedly
+    pub fn compile(&self) -> Result<BodyMatcher, ProbeError> {
+        let regex = self
+            .regex
+            .as_ref()
+            .map(|regex| regex::Regex::new(regex).map_err(|_| ProbeError::BadRegex(regex.clone())))
+            .transpose()?;
+
+        Ok(BodyMatcher {
+            assertion: self.clone(),
+            regex,
+        })
+    }
+}
+
+/// Compiled `BodyAssertion`
+#[derive(Debug, Clone)]
+pub struct BodyMatcher {
+    assertion: BodyAssertion,
+    regex: Option<regex::Regex>,
+}
+
+impl BodyMatcher {
+    /// Check the response body. Bodies, which are not valid UTF-8 or JSON, when
+    /// it's required, never match
+    pub fn is_match(&self, body: &[u8]) -> bool {
+        let text = match std::str::from_utf8(body) {
+            Ok(text) => text,
+            Err(_) => return false,
+        };
+
+        if let Some(contains) = &self.assertion.contains {
+            if !text.contains(contains.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(text) {
+                return false;
+            }
+        }
+
+        if let Some(pointer) = &self.assertion.json_pointer {
+            let json: serde_json::Value = match serde_json::from_str(text) {
+                Ok(json) => json,
+                Err(_) => return false,
+            };
+            let value = match json.pointer(pointer) {
+                Some(value) => value,
+                None => return false,
+            };
+            if let Some(expected) = &self.assertion.equals {
+                let is_equal = match value {
+                    serde_json::Value::String(s) => s == expected,
+                    other => &other.to_string() == expected,
+                };
+                if !is_equal {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -142,6 +594,33 @@ pub enum ProbeError {
 
     #[error("period is below threshold of {}", format_duration(*threshold))]
     PeriodBelowThreshold { threshold: Duration },
+
+    #[error("path is required for HTTP probe")]
+    NoPath,
+
+    #[error("only one of `tcp` and `exec` may be set")]
+    ConflictingProbeTypes,
+
+    #[error("{0} is only supported by HTTP probes")]
+    HttpOnly(&'static str),
+
+    #[error("exec command is empty")]
+    EmptyCommand,
+
+    #[error("body assertion should have at least one condition")]
+    EmptyBodyAssertion,
+
+    #[error("bad regex `{0}`")]
+    BadRegex(String),
+
+    #[error("bad JSON pointer `{0}`")]
+    BadJsonPointer(String),
+
+    #[error("`equals` requires `json-pointer`")]
+    EqualsWithoutJsonPointer,
+
+    #[error("thresholds should be greater than zero")]
+    ZeroThreshold,
 }
 
 impl Probe {
@@ -159,6 +638,142 @@ impl Probe {
                 threshold: Probe::TIMEOUT_THRESHOLD,
             });
         }
+
+        if self.success_threshold == 0 || self.failure_threshold == 0 {
+            return Err(ProbeError::ZeroThreshold);
+        }
+
+        if self.tcp && self.exec.is_some() {
+            return Err(ProbeError::ConflictingProbeTypes);
+        }
+        if let Some(exec) = &self.exec {
+            if exec
+                .command
+                .first()
+                .filter(|program| !program.is_empty())
+                .is_none()
+            {
+                return Err(ProbeError::EmptyCommand);
+            }
+        }
+
+        if self.is_http() {
+            match &self.path {
+                None => return Err(ProbeError::NoPath),
+                Some(path) if !path.starts_with('/') => return Err(ProbeError::BadPath),
+                Some(_) => {}
+            }
+            if let Some(expected_body) = &self.expected_body {
+                expected_body.validate()?;
+            }
+        } else if self.path.is_some() {
+            return Err(ProbeError::HttpOnly("path"));
+        } else if self.expected_body.is_some() {
+            return Err(ProbeError::HttpOnly("expected-body"));
+        }
+
         Ok(())
     }
+
+    pub fn is_readiness(&self) -> bool {
+        self.details == ProbeDetails::Readiness
+    }
+
+    /// Whether the probe sends HTTP requests
+    pub fn is_http(&self) -> bool {
+        !self.tcp && self.exec.is_none()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn probe(yaml: &str) -> Probe {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    pub fn test_probe_validate() {
+        probe("{kind: liveness, path: /health, timeout: 2s, period: 10s}")
+            .validate()
+            .unwrap();
+        probe("{kind: liveness, tcp: true, timeout: 2s, period: 10s}")
+            .validate()
+            .unwrap();
+        probe("{kind: liveness, exec: {command: [pg_isready, -q]}, timeout: 2s, period: 10s}")
+            .validate()
+            .unwrap();
+
+        assert!(matches!(
+            probe("{kind: liveness, timeout: 2s, period: 10s}").validate(),
+            Err(ProbeError::NoPath)
+        ));
+        assert!(matches!(
+            probe("{kind: readiness, tcp: true, failure-threshold: 0, timeout: 2s, period: 10s}")
+                .validate(),
+            Err(ProbeError::ZeroThreshold)
+        ));
+        assert!(matches!(
+            probe("{kind: liveness, path: /, tcp: true, timeout: 2s, period: 10s}").validate(),
+            Err(ProbeError::HttpOnly(_))
+        ));
+        assert!(matches!(
+            probe("{kind: liveness, tcp: true, exec: {command: [true]}, timeout: 2s, period: 10s}")
+                .validate(),
+            Err(ProbeError::ConflictingProbeTypes)
+        ));
+        assert!(matches!(
+            probe("{kind: liveness, exec: {command: []}, timeout: 2s, period: 10s}").validate(),
+            Err(ProbeError::EmptyCommand)
+        ));
+        assert!(matches!(
+            probe(
+                "{kind: liveness, path: /, expected-body: {regex: '('}, timeout: 2s, period: 10s}"
+            )
+            .validate(),
+            Err(ProbeError::BadRegex(_))
+        ));
+        assert!(matches!(
+            probe(
+                "{kind: liveness, path: /, expected-body: {equals: ok}, timeout: 2s, period: 10s}"
+            )
+            .validate(),
+            Err(ProbeError::EqualsWithoutJsonPointer)
+        ));
+    }
+
+    #[test]
+    pub fn test_body_assertion() {
+        let assertion: BodyAssertion = serde_yaml::from_str(
+            r#"
+---
+contains: database
+regex: "\\d+ ms"
+json-pointer: /checks/database/status
+equals: up
+"#,
+        )
+        .unwrap();
+        assertion.validate().unwrap();
+        let assertion = assertion.compile().unwrap();
+
+        assert!(assertion
+            .is_match(br#"{"checks": {"database": {"status": "up", "latency": "12 ms"}}}"#));
+        assert!(!assertion
+            .is_match(br#"{"checks": {"database": {"status": "down", "latency": "12 ms"}}}"#));
+        assert!(!assertion.is_match(br#"{"checks": {"database": {"status": "up"}}}"#));
+        assert!(!assertion.is_match(b"database is up, 12 ms"));
+
+        let assertion = BodyAssertion {
+            contains: None,
+            regex: None,
+            json_pointer: Some("/ready".to_string()),
+            equals: Some("true".to_string()),
+        }
+        .compile()
+        .unwrap();
+        assert!(assertion.is_match(br#"{"ready": true}"#));
+        assert!(!assertion.is_match(br#"{"ready": "yes"}"#));
+    }
 }
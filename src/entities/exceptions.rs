@@ -37,6 +37,8 @@ exceptions! {
 
     MODIFICATION_ERROR => "modification-error"
 
+    RATE_LIMIT_EXCEEDED => "rate-limit-error", "exceeded"
+
     S3_BAD_CONFIGURATION => "s3-error", "bad-configuration"
 
     GCS_BAD_CONFIGURATION => "gcs-error", "config-error"
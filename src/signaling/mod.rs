@@ -62,6 +62,12 @@ pub enum UnhealthyReason {
     },
     #[serde(rename = "request-error")]
     RequestError { err: String },
+    #[serde(rename = "unexpected-body")]
+    UnexpectedBody,
+    #[serde(rename = "bad-exit-code")]
+    BadExitCode { code: Option<i32> },
+    #[serde(rename = "circuit-open")]
+    CircuitOpen,
 }
 
 impl fmt::Display for UnhealthyReason {
@@ -76,6 +82,18 @@ impl fmt::Display for UnhealthyReason {
             UnhealthyReason::RequestError { err } => {
                 write!(f, "request error: `{}`", err)
             }
+            UnhealthyReason::UnexpectedBody => {
+                write!(f, "unexpected body")
+            }
+            UnhealthyReason::BadExitCode { code: Some(code) } => {
+                write!(f, "exit code {}", code)
+            }
+            UnhealthyReason::BadExitCode { code: None } => {
+                write!(f, "terminated by signal")
+            }
+            UnhealthyReason::CircuitOpen => {
+                write!(f, "circuit breaker is open")
+            }
         }
     }
 }
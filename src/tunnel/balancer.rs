@@ -0,0 +1,903 @@
+//! Selection of the upstream endpoint for new tunneled connections
+
+use crate::{
+    config_core::{CircuitBreaker, LoadBalancing, UpstreamDefinition, UNIX_SOCKET_PREFIX},
+    entities::{SmolStr, Upstream},
+    tunnel::{
+        circuit_breaker::{Circuit, CircuitState},
+        Conn,
+    },
+};
+use core::fmt;
+use futures::{channel::mpsc, Future};
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time::{timeout_at, Instant},
+};
+use tracing::{info, warn};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Connection to the upstream endpoint, either TCP or Unix socket
+pub type EndpointStream = Box<dyn Conn>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EndpointAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for EndpointAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndpointAddr::Tcp(addr) => write!(f, "{}", addr),
+            EndpointAddr::Unix(path) => write!(f, "{}{}", UNIX_SOCKET_PREFIX, path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for EndpointAddr {
+    fn from(addr: SocketAddr) -> Self {
+        EndpointAddr::Tcp(addr)
+    }
+}
+
+impl EndpointAddr {
+    pub async fn connect(&self) -> io::Result<EndpointStream> {
+        match self {
+            EndpointAddr::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr).await?)),
+            EndpointAddr::Unix(path) => connect_unix(path).await,
+        }
+    }
+
+    /// Bind the local UDP socket and connect it to the endpoint
+    pub async fn connect_udp(&self) -> io::Result<UdpSocket> {
+        match self {
+            EndpointAddr::Tcp(addr) => {
+                let local_addr: SocketAddr = if addr.is_ipv4() {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                let socket = UdpSocket::bind(local_addr).await?;
+                socket.connect(addr).await?;
+                Ok(socket)
+            }
+            EndpointAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "datagrams are not supported over unix sockets",
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &Path) -> io::Result<EndpointStream> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &Path) -> io::Result<EndpointStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+/// Resolved address of the upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub addr: EndpointAddr,
+
+    /// Configured host, which this endpoint was resolved from
+    pub host: SmolStr,
+
+    /// Configured address, which this endpoint was resolved from
+    pub source: SmolStr,
+}
+
+#[derive(Default, Debug)]
+struct BalancerState {
+    next: usize,
+    connections: HashMap<EndpointAddr, usize>,
+
+    /// Liveness probes, which currently fail on the configured address
+    unhealthy: HashMap<SmolStr, HashSet<SmolStr>>,
+
+    /// Readiness probes, which currently fail on the configured address
+    not_ready: HashMap<SmolStr, HashSet<SmolStr>>,
+
+    circuit: Circuit,
+}
+
+type CircuitSubscriber = mpsc::UnboundedSender<(Upstream, CircuitState)>;
+
+/// Shared state of load balancing between upstream endpoints
+#[derive(Default, Debug, Clone)]
+pub struct UpstreamBalancer {
+    inner: Arc<Mutex<HashMap<Upstream, BalancerState>>>,
+    circuit_subscribers: Arc<Mutex<Vec<CircuitSubscriber>>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectError {
+    #[error("circuit breaker is open")]
+    CircuitOpen,
+
+    /// All endpoints are reported as not ready. This is not a failure of the upstream,
+    /// so it's not counted by the circuit breaker
+    #[error("no ready upstream endpoints available")]
+    NoReadyEndpoints,
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Keeps the connection counted as active, until dropped
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    balancer: UpstreamBalancer,
+    upstream: Upstream,
+    endpoint: Endpoint,
+}
+
+impl ConnectionGuard {
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(state) = self.balancer.inner.lock().get_mut(&self.upstream) {
+            if let Some(count) = state.connections.get_mut(&self.endpoint.addr) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    state.connections.remove(&self.endpoint.addr);
+                }
+            }
+        }
+    }
+}
+
+/// Record the result of the probe on the configured address
+fn set_probe_result(
+    failed: &mut HashMap<SmolStr, HashSet<SmolStr>>,
+    probe: &str,
+    source: &str,
+    is_ok: bool,
+) {
+    if is_ok {
+        if let Some(probes) = failed.get_mut(source) {
+            probes.remove(probe);
+            if probes.is_empty() {
+                failed.remove(source);
+            }
+        }
+    } else {
+        failed
+            .entry(source.into())
+            .or_default()
+            .insert(probe.into());
+    }
+}
+
+impl UpstreamBalancer {
+    /// Report health of the configured upstream address, as seen by the liveness probe.
+    /// Addresses are unhealthy, while any of the probes fails on them
+    pub fn set_endpoint_health(
+        &self,
+        upstream: &Upstream,
+        probe: &str,
+        source: &str,
+        is_healthy: bool,
+    ) {
+        let mut locked = self.inner.lock();
+        let state = locked.entry(upstream.clone()).or_default();
+        set_probe_result(&mut state.unhealthy, probe, source, is_healthy);
+    }
+
+    /// Forget health and readiness reported by the probe, e.g. when it's removed
+    pub fn clear_probe(&self, upstream: &Upstream, probe: &str) {
+        if let Some(state) = self.inner.lock().get_mut(upstream) {
+            for failed in [&mut state.unhealthy, &mut state.not_ready].iter_mut() {
+                failed.retain(|_, probes| {
+                    probes.remove(probe);
+                    !probes.is_empty()
+                });
+            }
+        }
+    }
+
+    /// Report readiness of the configured upstream address. Addresses, which are not
+    /// ready according to any of the probes, don't receive new connections
+    pub fn set_endpoint_readiness(
+        &self,
+        upstream: &Upstream,
+        probe: &str,
+        source: &str,
+        is_ready: bool,
+    ) {
+        let mut locked = self.inner.lock();
+        let state = locked.entry(upstream.clone()).or_default();
+        set_probe_result(&mut state.not_ready, probe, source, is_ready);
+    }
+
+    /// Forget the state of the upstream, which is removed from the config
+    pub fn remove_upstream(&self, upstream: &Upstream) {
+        self.inner.lock().remove(upstream);
+    }
+
+    pub fn active_connections(&self, upstream: &Upstream, addr: &EndpointAddr) -> usize {
+        self.inner
+            .lock()
+            .get(upstream)
+            .and_then(|state| state.connections.get(addr).copied())
+            .unwrap_or_default()
+    }
+
+    /// Order endpoints for connection attempts: the first one is preferred, the rest are
+    /// used for failover.
+    ///
+    /// Endpoints, which are not ready, are never returned. Unhealthy endpoints are skipped,
+    /// unless all of them are unhealthy.
+    pub fn order(
+        &self,
+        upstream: &Upstream,
+        strategy: LoadBalancing,
+        endpoints: Vec<Endpoint>,
+        balance_key: Option<&str>,
+        random: u64,
+    ) -> Vec<Endpoint> {
+        let mut locked = self.inner.lock();
+        let state = locked.entry(upstream.clone()).or_default();
+
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = endpoints
+            .into_iter()
+            .filter(|endpoint| !state.not_ready.contains_key(&endpoint.source))
+            .partition(|endpoint| !state.unhealthy.contains_key(&endpoint.source));
+        let mut endpoints = if healthy.is_empty() {
+            unhealthy
+        } else {
+            healthy
+        };
+
+        if endpoints.is_empty() {
+            return endpoints;
+        }
+
+        let len = endpoints.len();
+        match (strategy, balance_key) {
+            (LoadBalancing::RoundRobin, _) => {
+                endpoints.rotate_left(state.next % len);
+                state.next = state.next.wrapping_add(1);
+            }
+            (LoadBalancing::LeastConnections, _) => {
+                endpoints.rotate_left(state.next % len);
+                state.next = state.next.wrapping_add(1);
+                endpoints.sort_by_key(|endpoint| {
+                    state
+                        .connections
+                        .get(&endpoint.addr)
+                        .copied()
+                        .unwrap_or_default()
+                });
+            }
+            (LoadBalancing::ConsistentHash, Some(key)) => {
+                // rendezvous hashing: removal of the endpoint only moves keys assigned to it
+                endpoints.sort_by_key(|endpoint| {
+                    std::cmp::Reverse(seahash::hash(
+                        format!("{}/{}", key, endpoint.addr).as_bytes(),
+                    ))
+                });
+            }
+            (LoadBalancing::Random, _) | (LoadBalancing::ConsistentHash, None) => {
+                endpoints.rotate_left((random % len as u64) as usize);
+            }
+        }
+
+        endpoints
+    }
+
+    fn connection_started(&self, upstream: &Upstream, endpoint: Endpoint) -> ConnectionGuard {
+        *self
+            .inner
+            .lock()
+            .entry(upstream.clone())
+            .or_default()
+            .connections
+            .entry(endpoint.addr.clone())
+            .or_default() += 1;
+
+        ConnectionGuard {
+            balancer: self.clone(),
+            upstream: upstream.clone(),
+            endpoint,
+        }
+    }
+
+    /// Connect to the first endpoint available, failing over to the next one on error.
+    /// `handshake` is performed on the established stream with the endpoint host. Its
+    /// failure is handled as a failure to connect. `connect_timeout` limits all attempts
+    /// together, including handshakes.
+    ///
+    /// If the circuit breaker is configured, the connection is rejected immediately while
+    /// the circuit is open.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn connect<S, F, Fut>(
+        &self,
+        upstream: &Upstream,
+        strategy: LoadBalancing,
+        endpoints: Vec<Endpoint>,
+        balance_key: Option<&str>,
+        connect_timeout: Duration,
+        circuit_breaker: Option<&CircuitBreaker>,
+        handshake: F,
+    ) -> Result<(S, ConnectionGuard), ConnectError>
+    where
+        F: Fn(EndpointStream, SmolStr) -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+    {
+        match circuit_breaker {
+            Some(settings) => {
+                if !self.with_circuit(upstream, |circuit| circuit.allow(settings, Instant::now())) {
+                    return Err(ConnectError::CircuitOpen);
+                }
+            }
+            None => self.with_circuit(upstream, Circuit::record_success),
+        }
+
+        let res = self
+            .connect_any(
+                upstream,
+                strategy,
+                endpoints,
+                balance_key,
+                connect_timeout,
+                handshake,
+            )
+            .await;
+
+        if let Some(settings) = circuit_breaker {
+            self.with_circuit(upstream, |circuit| match &res {
+                Ok(_) => circuit.record_success(),
+                Err(ConnectError::NoReadyEndpoints) => {}
+                Err(_) => circuit.record_failure(settings, Instant::now()),
+            });
+        }
+
+        res
+    }
+
+    async fn connect_any<S, F, Fut>(
+        &self,
+        upstream: &Upstream,
+        strategy: LoadBalancing,
+        endpoints: Vec<Endpoint>,
+        balance_key: Option<&str>,
+        connect_timeout: Duration,
+        handshake: F,
+    ) -> Result<(S, ConnectionGuard), ConnectError>
+    where
+        F: Fn(EndpointStream, SmolStr) -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+    {
+        let deadline = Instant::now() + connect_timeout;
+        let random = thread_rng().gen();
+        let mut last_error = None;
+
+        for endpoint in self.order(upstream, strategy, endpoints, balance_key, random) {
+            let connect = async {
+                let stream = endpoint.addr.connect().await?;
+                handshake(stream, endpoint.host.clone()).await
+            };
+            match timeout_at(deadline, connect).await {
+                Ok(Ok(stream)) => {
+                    return Ok((stream, self.connection_started(upstream, endpoint)));
+                }
+                Ok(Err(e)) => {
+                    info!("error connecting to {}. error: {:?}", endpoint.addr, e);
+                    last_error = Some(e);
+                }
+                Err(_) => {
+                    info!("timeout connecting to {}", endpoint.addr);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout").into());
+                }
+            }
+        }
+
+        Err(last_error.map_or(ConnectError::NoReadyEndpoints, ConnectError::Io))
+    }
+
+    /// Bind the UDP socket to the first endpoint available. There is no handshake, so
+    /// failover only happens if the socket can't be bound or connected.
+    pub async fn connect_udp(
+        &self,
+        upstream: &Upstream,
+        strategy: LoadBalancing,
+        endpoints: Vec<Endpoint>,
+        balance_key: Option<&str>,
+    ) -> io::Result<(UdpSocket, ConnectionGuard)> {
+        let random = thread_rng().gen();
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            "no ready upstream endpoints available",
+        );
+
+        for endpoint in self.order(upstream, strategy, endpoints, balance_key, random) {
+            match endpoint.addr.connect_udp().await {
+                Ok(socket) => {
+                    return Ok((socket, self.connection_started(upstream, endpoint)));
+                }
+                Err(e) => {
+                    info!("error connecting to {}. error: {:?}", endpoint.addr, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Modify the circuit of the upstream, notifying subscribers when it opens or closes
+    fn with_circuit<R>(&self, upstream: &Upstream, f: impl FnOnce(&mut Circuit) -> R) -> R {
+        let (r, was_state, new_state) = {
+            let mut locked = self.inner.lock();
+            let circuit = &mut locked.entry(upstream.clone()).or_default().circuit;
+            let was_state = circuit.state();
+            let r = f(circuit);
+            (r, was_state, circuit.state())
+        };
+
+        if (was_state == CircuitState::Closed) != (new_state == CircuitState::Closed) {
+            info!(
+                "circuit breaker of upstream {} is {:?}",
+                upstream, new_state
+            );
+            self.circuit_subscribers
+                .lock()
+                .retain(|tx| tx.unbounded_send((upstream.clone(), new_state)).is_ok());
+        }
+
+        r
+    }
+
+    /// Receive circuit state, each time the circuit opens or closes
+    pub fn subscribe_circuit_changes(&self) -> mpsc::UnboundedReceiver<(Upstream, CircuitState)> {
+        let (tx, rx) = mpsc::unbounded();
+        self.circuit_subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Upstreams, which currently reject connections by the circuit breaker
+    pub fn open_circuits(&self) -> Vec<Upstream> {
+        self.inner
+            .lock()
+            .iter()
+            .filter(|(_, state)| state.circuit.state() != CircuitState::Closed)
+            .map(|(upstream, _)| upstream.clone())
+            .collect()
+    }
+}
+
+/// Resolve all addresses of the upstream.
+///
+/// Addresses, which failed to resolve, are skipped. Error is returned only if nothing
+/// is resolved.
+pub async fn resolve_endpoints(
+    resolver: &TokioAsyncResolver,
+    upstream: &Upstream,
+    upstream_definition: &UpstreamDefinition,
+) -> Result<Vec<Endpoint>, crate::tunnel::Error> {
+    let mut endpoints = Vec::new();
+
+    for addr in upstream_definition.all_addrs() {
+        let source = SmolStr::from(addr.to_string());
+
+        if let Some(path) = &addr.unix {
+            endpoints.push(Endpoint {
+                addr: EndpointAddr::Unix(path.into()),
+                host: "localhost".into(),
+                source,
+            });
+            continue;
+        }
+
+        let host = addr.get_host();
+        let host_name = SmolStr::from(host.as_str());
+
+        let ips = if let Ok(ip_addr) = host.parse::<IpAddr>() {
+            vec![ip_addr]
+        } else {
+            match resolver.lookup_ip(host.as_str()).await {
+                Ok(lookup) if upstream_definition.resolve_all => lookup.iter().collect(),
+                Ok(lookup) => lookup.iter().take(1).collect(),
+                Err(e) => {
+                    warn!("resolver error on {}: {}", host, e);
+                    vec![]
+                }
+            }
+        };
+
+        endpoints.extend(ips.into_iter().map(|ip| Endpoint {
+            addr: SocketAddr::from((ip, addr.port)).into(),
+            host: host_name.clone(),
+            source: source.clone(),
+        }));
+    }
+
+    if endpoints.is_empty() {
+        return Err(crate::tunnel::Error::UpstreamResolveError {
+            upstream: upstream.clone(),
+            host: upstream_definition.get_host(),
+        });
+    }
+
+    Ok(endpoints)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+
+    fn endpoints() -> Vec<Endpoint> {
+        (1..=3)
+            .map(|n| Endpoint {
+                addr: EndpointAddr::Tcp(format!("10.0.0.{}:80", n).parse().unwrap()),
+                host: format!("backend-{}", n).into(),
+                source: format!("backend-{}:80", n).into(),
+            })
+            .collect()
+    }
+
+    fn first(ordered: Vec<Endpoint>) -> EndpointAddr {
+        ordered.into_iter().next().unwrap().addr
+    }
+
+    #[test]
+    pub fn test_round_robin_and_health() {
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+
+        let picked = (0..3)
+            .map(|_| {
+                first(balancer.order(&upstream, LoadBalancing::RoundRobin, endpoints(), None, 0))
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(picked.len(), 3);
+
+        balancer.set_endpoint_health(&upstream, "http", "backend-1:80", false);
+        balancer.set_endpoint_health(&upstream, "http", "backend-2:80", false);
+        let ordered = balancer.order(&upstream, LoadBalancing::RoundRobin, endpoints(), None, 0);
+        assert_eq!(ordered.len(), 1);
+        assert_eq!(ordered[0].addr.to_string(), "10.0.0.3:80");
+
+        // other probe doesn't override the failure
+        balancer.set_endpoint_health(&upstream, "tcp", "backend-1:80", true);
+        let ordered = balancer.order(&upstream, LoadBalancing::RoundRobin, endpoints(), None, 0);
+        assert_eq!(ordered.len(), 1);
+
+        // everything is unhealthy: try all of them anyway
+        balancer.set_endpoint_health(&upstream, "tcp", "backend-3:80", false);
+        let ordered = balancer.order(&upstream, LoadBalancing::RoundRobin, endpoints(), None, 0);
+        assert_eq!(ordered.len(), 3);
+
+        balancer.clear_probe(&upstream, "http");
+        let ordered = balancer.order(&upstream, LoadBalancing::RoundRobin, endpoints(), None, 0);
+        assert_eq!(ordered.len(), 2);
+        assert!(ordered
+            .iter()
+            .all(|endpoint| endpoint.source != "backend-3:80"));
+    }
+
+    #[test]
+    pub fn test_readiness() {
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+        let order = || balancer.order(&upstream, LoadBalancing::RoundRobin, endpoints(), None, 0);
+
+        balancer.set_endpoint_readiness(&upstream, "ready", "backend-1:80", false);
+        balancer.set_endpoint_readiness(&upstream, "warmup", "backend-1:80", false);
+        balancer.set_endpoint_readiness(&upstream, "ready", "backend-2:80", false);
+        assert_eq!(order().len(), 1);
+
+        balancer.set_endpoint_readiness(&upstream, "ready", "backend-1:80", true);
+        balancer.set_endpoint_readiness(&upstream, "ready", "backend-3:80", false);
+        assert!(order().is_empty());
+
+        balancer.clear_probe(&upstream, "warmup");
+        let ordered = order();
+        assert_eq!(ordered.len(), 1);
+        assert_eq!(ordered[0].source, "backend-1:80");
+    }
+
+    #[test]
+    pub fn test_least_connections() {
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+        let busy = endpoints()[0].addr.clone();
+        let idle = endpoints()[1].addr.clone();
+
+        let guards = (0..2)
+            .map(|_| balancer.connection_started(&upstream, endpoints()[0].clone()))
+            .chain(std::iter::once(
+                balancer.connection_started(&upstream, endpoints()[2].clone()),
+            ))
+            .collect::<Vec<_>>();
+        assert_eq!(balancer.active_connections(&upstream, &busy), 2);
+
+        for _ in 0..3 {
+            let ordered = balancer.order(
+                &upstream,
+                LoadBalancing::LeastConnections,
+                endpoints(),
+                None,
+                0,
+            );
+            assert_eq!(ordered[0].addr, idle);
+            assert_eq!(ordered[2].addr, busy);
+        }
+
+        drop(guards);
+        assert_eq!(balancer.active_connections(&upstream, &busy), 0);
+    }
+
+    #[test]
+    pub fn test_consistent_hash() {
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+
+        let order = |endpoints, key, random| {
+            first(balancer.order(
+                &upstream,
+                LoadBalancing::ConsistentHash,
+                endpoints,
+                Some(key),
+                random,
+            ))
+        };
+
+        for key in &["user-1", "user-2", "user-3", "user-4"] {
+            let chosen = order(endpoints(), key, 0);
+            for random in 1..10 {
+                assert_eq!(order(endpoints(), key, random), chosen);
+            }
+
+            // removal of other endpoint doesn't affect the assignment
+            let removed = endpoints()
+                .into_iter()
+                .find(|endpoint| endpoint.addr != chosen)
+                .unwrap();
+            let remaining = endpoints()
+                .into_iter()
+                .filter(|endpoint| endpoint != &removed)
+                .collect();
+            assert_eq!(order(remaining, key, 0), chosen);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        use crate::config_core::DurationWrapper;
+        use futures::StreamExt;
+
+        let balancer = UpstreamBalancer::default();
+        let mut changes = balancer.subscribe_circuit_changes();
+        let upstream: Upstream = "backend".parse().unwrap();
+        let settings = CircuitBreaker {
+            failure_threshold: 2,
+            cool_down: DurationWrapper(Duration::from_secs(60)),
+        };
+        let refused = vec![Endpoint {
+            addr: EndpointAddr::Tcp("127.0.0.1:1".parse().unwrap()),
+            host: "127.0.0.1".into(),
+            source: "127.0.0.1:1".into(),
+        }];
+
+        let connect = |settings| {
+            balancer.connect(
+                &upstream,
+                LoadBalancing::RoundRobin,
+                refused.clone(),
+                None,
+                Duration::from_secs(1),
+                settings,
+                |stream, _| future::ok(stream),
+            )
+        };
+
+        for _ in 0..2 {
+            assert!(matches!(
+                connect(Some(&settings)).await,
+                Err(ConnectError::Io(_))
+            ));
+        }
+        assert!(matches!(
+            connect(Some(&settings)).await,
+            Err(ConnectError::CircuitOpen)
+        ));
+        assert_eq!(
+            changes.next().await,
+            Some((upstream.clone(), CircuitState::Open))
+        );
+        assert_eq!(balancer.open_circuits(), vec![upstream.clone()]);
+
+        // circuit breaker is removed from the config
+        assert!(matches!(connect(None).await, Err(ConnectError::Io(_))));
+        assert_eq!(
+            changes.next().await,
+            Some((upstream.clone(), CircuitState::Closed))
+        );
+        assert!(balancer.open_circuits().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_handshake() {
+        use crate::config_core::DurationWrapper;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+        let settings = CircuitBreaker {
+            failure_threshold: 1,
+            cool_down: DurationWrapper(Duration::from_secs(60)),
+        };
+        let endpoints = vec![
+            Endpoint {
+                addr: EndpointAddr::Tcp(addr),
+                host: "bad".into(),
+                source: "bad".into(),
+            },
+            Endpoint {
+                addr: EndpointAddr::Tcp(addr),
+                host: "good".into(),
+                source: "good".into(),
+            },
+        ];
+        let connect = |endpoints| {
+            balancer.connect(
+                &upstream,
+                LoadBalancing::RoundRobin,
+                endpoints,
+                None,
+                Duration::from_secs(1),
+                Some(&settings),
+                |_, host: SmolStr| async move {
+                    if host == "good" {
+                        Ok(host)
+                    } else {
+                        Err(io::Error::new(io::ErrorKind::Other, "handshake failed"))
+                    }
+                },
+            )
+        };
+
+        let (host, _guard) = connect(endpoints.clone()).await.unwrap();
+        assert_eq!(host, "good");
+
+        assert!(matches!(
+            connect(endpoints[..1].to_vec()).await,
+            Err(ConnectError::Io(_))
+        ));
+        assert!(matches!(
+            connect(endpoints).await,
+            Err(ConnectError::CircuitOpen)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_ready_endpoints() {
+        use crate::config_core::DurationWrapper;
+
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+        let settings = CircuitBreaker {
+            failure_threshold: 1,
+            cool_down: DurationWrapper(Duration::from_secs(60)),
+        };
+        for endpoint in endpoints() {
+            balancer.set_endpoint_readiness(&upstream, "ready", &endpoint.source, false);
+        }
+
+        for _ in 0..3 {
+            let res = balancer
+                .connect(
+                    &upstream,
+                    LoadBalancing::RoundRobin,
+                    endpoints(),
+                    None,
+                    Duration::from_secs(1),
+                    Some(&settings),
+                    |stream, _| future::ok(stream),
+                )
+                .await;
+            assert!(matches!(res, Err(ConnectError::NoReadyEndpoints)));
+        }
+        assert!(balancer.open_circuits().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_deadline() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+        let endpoints = (0..5)
+            .map(|n| Endpoint {
+                addr: EndpointAddr::Tcp(addr),
+                host: "localhost".into(),
+                source: format!("backend-{}", n).into(),
+            })
+            .collect();
+
+        let started_at = Instant::now();
+        let res = balancer
+            .connect(
+                &upstream,
+                LoadBalancing::RoundRobin,
+                endpoints,
+                None,
+                Duration::from_millis(200),
+                None,
+                |_, _| future::pending::<io::Result<()>>(),
+            )
+            .await;
+        assert!(matches!(res, Err(ConnectError::Io(e)) if e.kind() == io::ErrorKind::TimedOut));
+        assert!(started_at.elapsed() < Duration::from_millis(500));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_failover_to_unix_socket() {
+        let path = std::env::temp_dir().join(format!("exogress-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let balancer = UpstreamBalancer::default();
+        let upstream: Upstream = "backend".parse().unwrap();
+        let endpoints = vec![
+            Endpoint {
+                addr: EndpointAddr::Tcp("127.0.0.1:1".parse().unwrap()),
+                host: "127.0.0.1".into(),
+                source: "127.0.0.1:1".into(),
+            },
+            Endpoint {
+                addr: EndpointAddr::Unix(path.clone()),
+                host: "localhost".into(),
+                source: format!("unix:{}", path.display()).into(),
+            },
+        ];
+
+        let (_stream, guard) = balancer
+            .connect(
+                &upstream,
+                LoadBalancing::RoundRobin,
+                endpoints,
+                None,
+                Duration::from_secs(1),
+                None,
+                |stream, _| future::ok(stream),
+            )
+            .await
+            .unwrap();
+        assert_eq!(guard.endpoint().addr, EndpointAddr::Unix(path.clone()));
+        assert!(listener.accept().await.is_ok());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,106 @@
+//! Circuit breaker for connections to the upstream
+
+use crate::config_core::CircuitBreaker;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Connections are allowed
+    Closed,
+
+    /// Connections are rejected until the cool-down passes
+    Open,
+
+    /// Single trial connection is in progress
+    HalfOpen,
+}
+
+impl Default for CircuitState {
+    fn default() -> Self {
+        CircuitState::Closed
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl Circuit {
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Check if the connection attempt is allowed. After the cool-down, only the
+    /// single trial connection is allowed, until it completes or another cool-down passes
+    pub fn allow(&mut self, settings: &CircuitBreaker, now: Instant) -> bool {
+        match (self.state, self.opened_at) {
+            (CircuitState::Closed, _) => true,
+            (_, Some(opened_at)) if now < opened_at + settings.cool_down.0 => false,
+            _ => {
+                self.state = CircuitState::HalfOpen;
+                self.opened_at = Some(now);
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    pub fn record_failure(&mut self, settings: &CircuitBreaker, now: Instant) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.state == CircuitState::HalfOpen
+            || self.consecutive_failures >= settings.failure_threshold
+        {
+            self.state = CircuitState::Open;
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config_core::DurationWrapper;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_circuit() {
+        let settings = CircuitBreaker {
+            failure_threshold: 2,
+            cool_down: DurationWrapper(Duration::from_secs(30)),
+        };
+        let start = Instant::now();
+        let mut circuit = Circuit::default();
+
+        circuit.record_failure(&settings, start);
+        assert!(circuit.allow(&settings, start));
+        circuit.record_success();
+        circuit.record_failure(&settings, start);
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        circuit.record_failure(&settings, start);
+        assert_eq!(circuit.state(), CircuitState::Open);
+        assert!(!circuit.allow(&settings, start + Duration::from_secs(29)));
+
+        // single trial after the cool-down
+        let trial_at = start + Duration::from_secs(30);
+        assert!(circuit.allow(&settings, trial_at));
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        assert!(!circuit.allow(&settings, trial_at));
+
+        circuit.record_failure(&settings, trial_at);
+        assert_eq!(circuit.state(), CircuitState::Open);
+        assert!(!circuit.allow(&settings, trial_at + Duration::from_secs(1)));
+
+        assert!(circuit.allow(&settings, trial_at + Duration::from_secs(30)));
+        circuit.record_success();
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        assert!(circuit.allow(&settings, trial_at + Duration::from_secs(31)));
+    }
+}
@@ -0,0 +1,388 @@
+//! Compression of the data, sent to the tunneled slot.
+//!
+//! `Zstd` compresses each frame independently. `ZstdStream` keeps the compression context
+//! for the lifetime of the slot and flushes it after each frame, so that the following
+//! frames refer to the data, which is already sent. It gives much better ratio on small
+//! HTTP frames, but every frame of the slot should pass through the context in order, so
+//! the frames are always sent compressed.
+//!
+//! With adaptive compression the sender stops compressing for a while, once a few
+//! consecutive frames turned out to be incompressible, e.g. gzipped bodies or images.
+//! It doesn't require the support from the peer, since each frame is marked as
+//! compressed or plain anyway.
+
+use crate::tunnel::{connector::Compression, proto::MAX_PAYLOAD_LEN};
+use std::{
+    convert::TryInto,
+    io,
+    io::{Read, Write},
+};
+use zstd::stream::raw::{CParameter, DParameter, Decoder, Encoder, InBuffer, Operation, OutBuffer};
+
+const ZSTD_LEVEL: i32 = 0;
+
+/// Window of the streaming context. Limits the memory, used by each slot
+const ZSTD_STREAM_WINDOW_LOG: u32 = 17;
+
+const STREAM_BUF_LEN: usize = 16 * 1024;
+
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_LGWIN: u32 = 18;
+const BROTLI_BUF_LEN: usize = 4096;
+
+/// Length of the uncompressed data, prepended to lz4 frames
+const LZ4_SIZE_LEN: usize = 4;
+
+/// Consecutive incompressible frames, after which adaptive compression is paused
+const INCOMPRESSIBLE_FRAMES_THRESHOLD: u32 = 4;
+
+/// Frames sent plain, before adaptive compression is tried again
+const PAUSED_FRAMES: u32 = 64;
+
+enum Algorithm {
+    Plain,
+    Zstd(zstd::block::Compressor),
+    ZstdStream(Encoder<'static>),
+    Lz4,
+    Brotli,
+}
+
+#[derive(Default)]
+struct Adaptive {
+    incompressible_frames: u32,
+    paused_frames_left: u32,
+}
+
+pub struct Compressor {
+    algorithm: Algorithm,
+    adaptive: Option<Adaptive>,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Self {
+        let algorithm = match compression {
+            Compression::Plain => Algorithm::Plain,
+            Compression::Zstd => Algorithm::Zstd(Default::default()),
+            Compression::ZstdStream => {
+                let mut encoder = Encoder::new(ZSTD_LEVEL).expect("Unable to create encoder");
+                encoder
+                    .set_parameter(CParameter::WindowLog(ZSTD_STREAM_WINDOW_LOG))
+                    .expect("Unable to set window");
+                Algorithm::ZstdStream(encoder)
+            }
+            Compression::Lz4 => Algorithm::Lz4,
+            Compression::Brotli => Algorithm::Brotli,
+        };
+
+        Compressor {
+            algorithm,
+            adaptive: None,
+        }
+    }
+
+    /// Send frames plain for a while, if they don't compress
+    pub fn adaptive(compression: Compression) -> Self {
+        Compressor {
+            adaptive: Some(Default::default()),
+            ..Compressor::new(compression)
+        }
+    }
+
+    /// Returns the data to send, and whether it's compressed
+    pub fn compress(&mut self, buf: Vec<u8>) -> (Vec<u8>, bool) {
+        if let Some(adaptive) = &mut self.adaptive {
+            if adaptive.paused_frames_left > 0 {
+                adaptive.paused_frames_left -= 1;
+                return (buf, false);
+            }
+        }
+
+        let len = buf.len();
+        let (maybe_compressed, is_compressed) = self.compress_frame(buf);
+
+        if let Some(adaptive) = &mut self.adaptive {
+            // less than 5% saved
+            if !is_compressed || maybe_compressed.len() * 20 > len * 19 {
+                adaptive.incompressible_frames += 1;
+                if adaptive.incompressible_frames >= INCOMPRESSIBLE_FRAMES_THRESHOLD {
+                    adaptive.incompressible_frames = 0;
+                    adaptive.paused_frames_left = PAUSED_FRAMES;
+                }
+            } else {
+                adaptive.incompressible_frames = 0;
+            }
+        }
+
+        (maybe_compressed, is_compressed)
+    }
+
+    fn compress_frame(&mut self, buf: Vec<u8>) -> (Vec<u8>, bool) {
+        let compressed = match &mut self.algorithm {
+            Algorithm::Plain => return (buf, false),
+            Algorithm::Zstd(compressor) => compressor
+                .compress(&buf, ZSTD_LEVEL)
+                .expect("Unable to compress"),
+            Algorithm::ZstdStream(encoder) => {
+                let compressed = compress_stream(encoder, &buf).expect("Unable to compress");
+                return (compressed, true);
+            }
+            Algorithm::Lz4 => lz4_flex::block::compress_prepend_size(&buf),
+            Algorithm::Brotli => compress_brotli(&buf).expect("Unable to compress"),
+        };
+
+        if compressed.len() < buf.len() {
+            (compressed, true)
+        } else {
+            (buf, false)
+        }
+    }
+}
+
+fn compress_brotli(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut writer =
+        brotli::CompressorWriter::new(Vec::new(), BROTLI_BUF_LEN, BROTLI_QUALITY, BROTLI_LGWIN);
+    writer.write_all(buf)?;
+    Ok(writer.into_inner())
+}
+
+fn compress_stream(encoder: &mut Encoder<'static>, buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    let mut out_buf = [0u8; STREAM_BUF_LEN];
+    let mut input = InBuffer::around(buf);
+
+    while input.pos < buf.len() {
+        let mut output = OutBuffer::around(&mut out_buf[..]);
+        encoder.run(&mut input, &mut output)?;
+        compressed.extend_from_slice(output.as_slice());
+    }
+
+    loop {
+        let mut output = OutBuffer::around(&mut out_buf[..]);
+        let remaining = encoder.flush(&mut output)?;
+        compressed.extend_from_slice(output.as_slice());
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    Ok(compressed)
+}
+
+pub enum Decompressor {
+    Plain,
+    Zstd(zstd::block::Decompressor),
+    ZstdStream(Decoder<'static>),
+    Lz4,
+    Brotli,
+}
+
+impl Decompressor {
+    pub fn new(compression: Compression) -> Self {
+        match compression {
+            Compression::Plain => Decompressor::Plain,
+            Compression::Zstd => Decompressor::Zstd(Default::default()),
+            Compression::ZstdStream => {
+                let mut decoder = Decoder::new().expect("Unable to create decoder");
+                decoder
+                    .set_parameter(DParameter::WindowLogMax(ZSTD_STREAM_WINDOW_LOG))
+                    .expect("Unable to set window");
+                Decompressor::ZstdStream(decoder)
+            }
+            Compression::Lz4 => Decompressor::Lz4,
+            Compression::Brotli => Decompressor::Brotli,
+        }
+    }
+
+    pub fn decompress(&mut self, buf: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Decompressor::Plain => Ok(buf),
+            Decompressor::Zstd(compressor) => compressor.decompress(&buf, MAX_PAYLOAD_LEN),
+            Decompressor::ZstdStream(decoder) => decompress_stream(decoder, &buf),
+            Decompressor::Lz4 => decompress_lz4(&buf),
+            Decompressor::Brotli => decompress_brotli(&buf),
+        }
+    }
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "decompressed frame is too large",
+    )
+}
+
+fn decompress_lz4(buf: &[u8]) -> io::Result<Vec<u8>> {
+    if buf.len() < LZ4_SIZE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "lz4 frame is too short",
+        ));
+    }
+    let (size, compressed) = buf.split_at(LZ4_SIZE_LEN);
+    let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+    if size > MAX_PAYLOAD_LEN {
+        return Err(too_large());
+    }
+
+    lz4_flex::block::decompress(compressed, size)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+fn decompress_brotli(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    brotli::Decompressor::new(buf, BROTLI_BUF_LEN)
+        .take(MAX_PAYLOAD_LEN as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > MAX_PAYLOAD_LEN {
+        return Err(too_large());
+    }
+    Ok(decompressed)
+}
+
+fn decompress_stream(decoder: &mut Decoder<'static>, buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    let mut out_buf = [0u8; STREAM_BUF_LEN];
+    let mut input = InBuffer::around(buf);
+
+    loop {
+        let mut output = OutBuffer::around(&mut out_buf[..]);
+        decoder.run(&mut input, &mut output)?;
+        let is_full = output.pos == STREAM_BUF_LEN;
+        decompressed.extend_from_slice(output.as_slice());
+
+        if decompressed.len() > MAX_PAYLOAD_LEN {
+            return Err(too_large());
+        }
+
+        if input.pos == buf.len() && !is_full {
+            break;
+        }
+    }
+
+    Ok(decompressed)
+}
+
+pub struct Compressors {
+    pub compressor: Compressor,
+    pub decompressor: Decompressor,
+}
+
+impl Compressors {
+    pub fn new(compression: Compression, adaptive: bool) -> Self {
+        Compressors {
+            compressor: if adaptive {
+                Compressor::adaptive(compression)
+            } else {
+                Compressor::new(compression)
+            },
+            decompressor: Decompressor::new(compression),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frames() -> Vec<Vec<u8>> {
+        (0..50)
+            .map(|i| {
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\nx-request-id: {}\r\n\r\n{{\"id\":{},\"status\":\"ok\"}}",
+                    i * 7919,
+                    i
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn test_stream_compression() {
+        let mut compressor = Compressor::new(Compression::ZstdStream);
+        let mut decompressor = Decompressor::new(Compression::ZstdStream);
+        let mut block_compressor = Compressor::new(Compression::Zstd);
+
+        let (mut stream_len, mut block_len) = (0, 0);
+        for frame in frames() {
+            let (compressed, is_compressed) = compressor.compress(frame.clone());
+            assert!(is_compressed);
+            stream_len += compressed.len();
+            block_len += block_compressor.compress(frame.clone()).0.len();
+
+            assert_eq!(decompressor.decompress(compressed).unwrap(), frame);
+        }
+
+        assert!(stream_len * 2 < block_len);
+
+        let large = vec![7; MAX_PAYLOAD_LEN];
+        let (compressed, _) = compressor.compress(large.clone());
+        assert_eq!(decompressor.decompress(compressed).unwrap(), large);
+    }
+
+    #[test]
+    pub fn test_stream_decompression_limit() {
+        let mut compressor = Compressor::new(Compression::ZstdStream);
+        let mut decompressor = Decompressor::new(Compression::ZstdStream);
+
+        let (compressed, _) = compressor.compress(vec![0; MAX_PAYLOAD_LEN + 1]);
+        assert!(decompressor.decompress(compressed).is_err());
+    }
+
+    #[test]
+    pub fn test_lz4_and_brotli() {
+        for &compression in &[Compression::Lz4, Compression::Brotli] {
+            let mut compressor = Compressor::new(compression);
+            let mut decompressor = Decompressor::new(compression);
+
+            for frame in frames() {
+                let (maybe_compressed, is_compressed) = compressor.compress(frame.clone());
+                if is_compressed {
+                    assert_eq!(decompressor.decompress(maybe_compressed).unwrap(), frame);
+                } else {
+                    assert_eq!(maybe_compressed, frame);
+                }
+            }
+        }
+
+        assert!(decompress_lz4(&[1, 2]).is_err());
+        let mut too_large = ((MAX_PAYLOAD_LEN + 1) as u32).to_le_bytes().to_vec();
+        too_large.push(0);
+        assert!(decompress_lz4(&too_large).is_err());
+    }
+
+    #[test]
+    pub fn test_adaptive() {
+        // xorshift, so that the frames are incompressible
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut incompressible = || {
+            (0..1024)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect::<Vec<u8>>()
+        };
+
+        let mut compressor = Compressor::adaptive(Compression::Zstd);
+        for _ in 0..INCOMPRESSIBLE_FRAMES_THRESHOLD {
+            assert!(!compressor.compress(incompressible()).1);
+        }
+
+        let compressible = vec![7; 1024];
+        for _ in 0..PAUSED_FRAMES {
+            let (sent, is_compressed) = compressor.compress(compressible.clone());
+            assert!(!is_compressed);
+            assert_eq!(sent, compressible);
+        }
+        assert!(compressor.compress(compressible.clone()).1);
+
+        let mut compressor = Compressor::new(Compression::Zstd);
+        for _ in 0..INCOMPRESSIBLE_FRAMES_THRESHOLD {
+            compressor.compress(incompressible());
+        }
+        assert!(compressor.compress(compressible).1);
+    }
+}
@@ -2,8 +2,8 @@ use std::io;
 
 use crate::{
     common_utils::uri_ext::UriExt,
-    entities::{HandlerName, StringIdentifierParseError, Upstream},
-    tunnel::{Conn, TunneledConnection},
+    entities::{HandlerName, SmolStr, StringIdentifierParseError, Upstream},
+    tunnel::{MixedChannel, TunneledConnection},
 };
 use core::fmt;
 use futures::{
@@ -14,25 +14,72 @@ use futures::{
     FutureExt, SinkExt,
 };
 use hyper::{service::Service, Uri};
+use rw_stream_sink::RwStreamSink;
 use serde::{Deserialize, Serialize};
 use std::{
     hash::{Hash, Hasher},
     str::FromStr,
 };
+use tokio_util::compat::FuturesAsyncReadCompatExt;
 use ulid::Ulid;
 use url::Url;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Compression {
     Plain,
     Zstd,
+
+    /// Zstd with the context, shared by all frames of the slot
+    ZstdStream,
+
+    /// Fast, but less dense
+    Lz4,
+
+    /// Dense, but CPU-intensive
+    Brotli,
+}
+
+impl Compression {
+    /// Compression to use, if this one is not supported by the peer
+    pub fn fallback(self) -> Compression {
+        match self {
+            Compression::Brotli => Compression::ZstdStream,
+            Compression::ZstdStream => Compression::Zstd,
+            Compression::Zstd | Compression::Lz4 | Compression::Plain => Compression::Plain,
+        }
+    }
+}
+
+/// Kind of the tunneled slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SlotKind {
+    Stream,
+
+    /// Each item is forwarded as a single datagram to the UDP upstream
+    Datagrams,
 }
 
+impl Default for SlotKind {
+    fn default() -> Self {
+        SlotKind::Stream
+    }
+}
+
+/// Datagrams, exchanged with the UDP upstream. Each item of the stream is a datagram
+/// received from the upstream, each item sent to the sink is a datagram sent to it.
+/// Empty datagrams are not forwarded.
+pub type TunneledDatagrams = MixedChannel;
+
 /// Connect through established TCP tunnel
 #[derive(Clone)]
 pub struct Connector {
     req_tx: mpsc::Sender<ConnectorRequest>,
     ulid: Ulid,
+
+    /// Used for HTTP connections
+    compression: Compression,
+
+    adaptive_compression: bool,
 }
 
 impl Hash for Connector {
@@ -125,9 +172,12 @@ impl FromStr for ConnectTarget {
 }
 
 pub struct ConnectorRequest {
-    pub tx: oneshot::Sender<Box<dyn Conn + 'static>>,
+    pub tx: oneshot::Sender<MixedChannel>,
     pub target: ConnectTarget,
     pub compression: Compression,
+    pub adaptive_compression: bool,
+    pub balance_key: Option<SmolStr>,
+    pub kind: SlotKind,
 }
 
 impl Connector {
@@ -135,15 +185,68 @@ impl Connector {
         Connector {
             req_tx,
             ulid: Ulid::new(),
+            compression: Compression::ZstdStream,
+            adaptive_compression: true,
         }
     }
 
+    /// Compression of HTTP connections. Falls back to the one supported by the client
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Send frames plain for a while, once they turn out to be incompressible
+    pub fn with_adaptive_compression(mut self, adaptive_compression: bool) -> Self {
+        self.adaptive_compression = adaptive_compression;
+        self
+    }
+
     pub fn retrieve_connection(
         &self,
         connect_target: ConnectTarget,
         compression: Compression,
     ) -> BoxFuture<'static, Result<TunneledConnection, crate::tunnel::Error>> {
+        self.retrieve_balanced_connection(connect_target, compression, None)
+    }
+
+    /// Retrieve connection, passing the key for consistent-hash balancing between
+    /// upstream endpoints
+    pub fn retrieve_balanced_connection(
+        &self,
+        connect_target: ConnectTarget,
+        compression: Compression,
+        balance_key: Option<SmolStr>,
+    ) -> BoxFuture<'static, Result<TunneledConnection, crate::tunnel::Error>> {
+        let channel = self.open_slot(connect_target, compression, balance_key, SlotKind::Stream);
+
+        async move {
+            let channel = channel.await?;
+            Ok(TunneledConnection::new(Box::new(
+                RwStreamSink::new(channel).compat(),
+            )))
+        }
+        .boxed()
+    }
+
+    /// Open the datagram slot to the UDP upstream
+    pub fn retrieve_datagrams(
+        &self,
+        upstream: Upstream,
+        compression: Compression,
+    ) -> BoxFuture<'static, Result<TunneledDatagrams, crate::tunnel::Error>> {
+        self.open_slot(upstream.into(), compression, None, SlotKind::Datagrams)
+    }
+
+    fn open_slot(
+        &self,
+        connect_target: ConnectTarget,
+        compression: Compression,
+        balance_key: Option<SmolStr>,
+        kind: SlotKind,
+    ) -> BoxFuture<'static, Result<MixedChannel, crate::tunnel::Error>> {
         let mut req_tx = self.req_tx.clone();
+        let adaptive_compression = self.adaptive_compression;
 
         async move {
             let (wait_tx, wait_rx) = oneshot::channel();
@@ -153,6 +256,9 @@ impl Connector {
                     tx: wait_tx,
                     target: connect_target,
                     compression,
+                    adaptive_compression,
+                    balance_key,
+                    kind,
                 })
                 .await
                 .map_err(|_| {
@@ -162,14 +268,14 @@ impl Connector {
                     )
                 })?;
 
-            let c = wait_rx.await.map_err(|_| {
+            let channel = wait_rx.await.map_err(|_| {
                 io::Error::new(
                     io::ErrorKind::Other,
                     "tunnel already closed: unable to wait for new connection",
                 )
             })?;
 
-            Ok(TunneledConnection::new(c))
+            Ok(channel)
         }
         .boxed()
     }
@@ -197,7 +303,7 @@ impl Service<Uri> for Connector {
         let target_result: Result<ConnectTarget, crate::tunnel::Error> =
             extract_connect_target(dst);
         match target_result {
-            Ok(target) => self.retrieve_connection(target, Compression::Zstd),
+            Ok(target) => self.retrieve_connection(target, self.compression),
             Err(e) => futures::future::ready(Err(e)).boxed(),
         }
     }
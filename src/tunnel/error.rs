@@ -47,4 +47,27 @@ pub enum Error {
 
     #[error("connect target parse error: {0}")]
     ConnectTargetParseError(#[from] ConnectTargetParseError),
+
+    #[error("bad window update of {0} bytes")]
+    BadWindowUpdate(usize),
+
+    #[error("peer exceeded the flow control window")]
+    FlowControlViolation,
+
+    #[error("unsupported tunnel protocol version {0}")]
+    UnsupportedProtocolVersion(u16),
+
+    #[error("peer acknowledged {0} frames, which were not sent")]
+    BadAcknowledgement(u64),
+
+    #[error("bad acknowledgement of {0} bytes")]
+    BadAckPayload(usize),
+
+    #[cfg(feature = "quic")]
+    #[error("QUIC connection error: {0}")]
+    QuicConnection(#[from] quinn::ConnectionError),
+
+    #[cfg(feature = "quic")]
+    #[error("QUIC connect error: {0}")]
+    QuicConnect(#[from] quinn::ConnectError),
 }
@@ -0,0 +1,266 @@
+//! Credit-based flow control of tunneled slots.
+//!
+//! Each side announces its receive window for the slot during the connect handshake:
+//! server in `ConnectRequest`, client in `Accepted`. The peer may only send that much
+//! data, until the window is extended by `WindowUpdate`. So the data received from the
+//! tunnel is always accepted without blocking, and the slow consumer only stalls its own
+//! slot. If any of the peers doesn't announce the window, the slot is not flow-controlled.
+
+use crate::tunnel::{proto::MAX_PAYLOAD_LEN, Error};
+use futures::{channel::mpsc, stream::BoxStream, SinkExt, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, sync::Arc};
+use tokio::sync::Semaphore;
+
+/// Receive window, announced for each new slot
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// Largest send window, accepted from the peer
+pub const MAX_WINDOW: u32 = 16 * 1024 * 1024;
+
+/// Payload of `Accepted` from peers, supporting flow control
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcceptedPayload {
+    pub window: u32,
+}
+
+impl AcceptedPayload {
+    /// Old peers send empty payload
+    pub fn decode(payload: &[u8]) -> Result<Option<Self>, Error> {
+        if payload.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_cbor::from_slice(payload)?))
+    }
+}
+
+pub fn encode_window_update(increment: u32) -> Vec<u8> {
+    increment.to_be_bytes().to_vec()
+}
+
+pub fn decode_window_update(payload: &[u8]) -> Result<u32, Error> {
+    let bytes = payload
+        .try_into()
+        .map_err(|_| Error::BadWindowUpdate(payload.len()))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Credits for sending data to the peer
+#[derive(Debug, Clone)]
+pub struct SendWindow {
+    credits: Arc<Semaphore>,
+    max_chunk: usize,
+}
+
+impl SendWindow {
+    /// The window, announced by the peer, is clamped to `MAX_WINDOW`
+    pub fn new(window: u32) -> Self {
+        let window = window.min(MAX_WINDOW);
+        SendWindow {
+            credits: Arc::new(Semaphore::new(window as usize)),
+            max_chunk: MAX_PAYLOAD_LEN.min(window as usize).max(1),
+        }
+    }
+
+    /// Largest chunk of data, which fits into the window
+    pub fn max_chunk(&self) -> usize {
+        self.max_chunk
+    }
+
+    /// Wait until the peer allows to send `len` bytes. `len` should not exceed `max_chunk`
+    pub async fn acquire(&self, len: usize) {
+        if let Ok(permit) = self.credits.acquire_many(len as u32).await {
+            permit.forget();
+        }
+    }
+
+    /// Peer can't return more credits than it was given, so the credits never
+    /// exceed `MAX_WINDOW`
+    pub fn grant(&self, increment: u32) -> Result<(), Error> {
+        let credits = self.credits.available_permits() as u64 + u64::from(increment);
+        if credits > u64::from(MAX_WINDOW) {
+            return Err(Error::FlowControlViolation);
+        }
+        self.credits.add_permits(increment as usize);
+        Ok(())
+    }
+}
+
+/// Accounting of the data received from the peer
+#[derive(Debug)]
+pub struct RecvWindow {
+    window: u32,
+
+    /// Received, but not yet returned to the peer
+    outstanding: u32,
+
+    /// Passed to the consumer, but not yet returned to the peer
+    consumed: u32,
+}
+
+impl RecvWindow {
+    pub fn new(window: u32) -> Self {
+        RecvWindow {
+            window,
+            outstanding: 0,
+            consumed: 0,
+        }
+    }
+
+    pub fn received(&mut self, len: usize) -> Result<(), Error> {
+        let outstanding = u64::from(self.outstanding) + len as u64;
+        if outstanding > u64::from(self.window) {
+            return Err(Error::FlowControlViolation);
+        }
+        self.outstanding = outstanding as u32;
+        Ok(())
+    }
+
+    /// Record data, passed to the consumer. Returns the increment, which should be sent
+    /// to the peer, once half of the window is consumed
+    pub fn consumed(&mut self, len: usize) -> Option<u32> {
+        self.consumed = self.consumed.saturating_add(len as u32);
+        if self.consumed < self.window / 2 {
+            return None;
+        }
+
+        let increment = self.consumed.min(self.outstanding);
+        self.outstanding -= increment;
+        self.consumed = 0;
+        Some(increment)
+    }
+}
+
+/// Flow control state of the slot, if negotiated with the peer
+#[derive(Debug, Clone)]
+pub struct SlotFlowControl {
+    pub send: SendWindow,
+    pub recv: Arc<Mutex<RecvWindow>>,
+}
+
+impl SlotFlowControl {
+    pub fn new(send_window: u32, recv_window: u32) -> Self {
+        SlotFlowControl {
+            send: SendWindow::new(send_window),
+            recv: Arc::new(Mutex::new(RecvWindow::new(recv_window))),
+        }
+    }
+
+    /// Send data to the peer in chunks, which fit into the window
+    pub fn max_chunk(flow_control: Option<&Self>) -> usize {
+        flow_control.map_or(MAX_PAYLOAD_LEN, |fc| fc.send.max_chunk())
+    }
+}
+
+/// Sender of the data, received from the tunnel for the slot. Flow-controlled slots are
+/// never blocked, since the amount of buffered data is limited by the window.
+#[derive(Debug, Clone)]
+pub enum SlotSender {
+    Bounded(mpsc::Sender<Vec<u8>>),
+    Unbounded(mpsc::UnboundedSender<Vec<u8>>),
+}
+
+impl SlotSender {
+    pub async fn send(&mut self, buf: Vec<u8>) -> Result<(), mpsc::SendError> {
+        match self {
+            SlotSender::Bounded(tx) => tx.send(buf).await,
+            SlotSender::Unbounded(tx) => tx.unbounded_send(buf).map_err(|e| e.into_send_error()),
+        }
+    }
+
+    /// Close the channel for all senders. The data, which is already sent, is still received
+    pub fn close(&self) {
+        match self {
+            SlotSender::Bounded(tx) => tx.clone().close_channel(),
+            SlotSender::Unbounded(tx) => tx.close_channel(),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match self {
+            SlotSender::Bounded(tx) => tx.is_closed(),
+            SlotSender::Unbounded(tx) => tx.is_closed(),
+        }
+    }
+}
+
+pub fn slot_channel(
+    flow_control: Option<&SlotFlowControl>,
+) -> (SlotSender, BoxStream<'static, Vec<u8>>) {
+    match flow_control {
+        Some(_) => {
+            let (tx, rx) = mpsc::unbounded();
+            (SlotSender::Unbounded(tx), rx.boxed())
+        }
+        None => {
+            let (tx, rx) = mpsc::channel(4);
+            (SlotSender::Bounded(tx), rx.boxed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_recv_window() {
+        let mut window = RecvWindow::new(100);
+        window.received(60).unwrap();
+        assert_eq!(window.consumed(30), None);
+        window.received(40).unwrap();
+        assert!(matches!(
+            window.received(1),
+            Err(Error::FlowControlViolation)
+        ));
+
+        assert_eq!(window.consumed(30), Some(60));
+        window.received(60).unwrap();
+        assert!(window.received(1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_window() {
+        let window = SendWindow::new(10);
+        assert_eq!(window.max_chunk(), 10);
+        window.acquire(10).await;
+
+        let blocked = tokio::time::timeout(std::time::Duration::from_millis(50), window.acquire(1));
+        assert!(blocked.await.is_err());
+
+        window.grant(5).unwrap();
+        window.acquire(5).await;
+    }
+
+    #[test]
+    pub fn test_send_window_bounds() {
+        let window = SendWindow::new(u32::MAX);
+        assert_eq!(window.max_chunk(), MAX_PAYLOAD_LEN);
+        assert!(matches!(window.grant(1), Err(Error::FlowControlViolation)));
+
+        let window = SendWindow::new(MAX_WINDOW - 10);
+        window.grant(10).unwrap();
+        assert!(matches!(window.grant(1), Err(Error::FlowControlViolation)));
+        assert!(matches!(
+            SendWindow::new(0).grant(u32::MAX),
+            Err(Error::FlowControlViolation)
+        ));
+    }
+
+    #[test]
+    pub fn test_negotiation() {
+        assert!(AcceptedPayload::decode(&[]).unwrap().is_none());
+        let payload = serde_cbor::to_vec(&AcceptedPayload { window: 1024 }).unwrap();
+        assert_eq!(
+            AcceptedPayload::decode(&payload).unwrap().unwrap().window,
+            1024
+        );
+
+        assert_eq!(
+            decode_window_update(&encode_window_update(65536)).unwrap(),
+            65536
+        );
+        assert!(decode_window_update(&[1, 2]).is_err());
+    }
+}
@@ -19,6 +19,9 @@ fn parse_client_header(value: u64) -> Result<ClientPacket, Error> {
         COMMON_CODE_CLOSED => ClientHeader::Common(CommonHeader::Closed),
         COMMON_CODE_PING => ClientHeader::Common(CommonHeader::Ping),
         COMMON_CODE_PONG => ClientHeader::Common(CommonHeader::Pong),
+        COMMON_CODE_WINDOW_UPDATE => ClientHeader::Common(CommonHeader::WindowUpdate),
+        COMMON_CODE_WRITE_CLOSED => ClientHeader::Common(CommonHeader::WriteClosed),
+        COMMON_CODE_ACK => ClientHeader::Common(CommonHeader::Ack),
         CLIENT_CODE_ACCEPTED => ClientHeader::Accepted,
         CLIENT_CODE_REJECTED => ClientHeader::Rejected,
         code => return Err(Error::UnknownCode { code }),
@@ -40,6 +43,9 @@ fn parse_server_header(value: u64) -> Result<ServerPacket, Error> {
         COMMON_CODE_CLOSED => ServerHeader::Common(CommonHeader::Closed),
         COMMON_CODE_PING => ServerHeader::Common(CommonHeader::Ping),
         COMMON_CODE_PONG => ServerHeader::Common(CommonHeader::Pong),
+        COMMON_CODE_WINDOW_UPDATE => ServerHeader::Common(CommonHeader::WindowUpdate),
+        COMMON_CODE_WRITE_CLOSED => ServerHeader::Common(CommonHeader::WriteClosed),
+        COMMON_CODE_ACK => ServerHeader::Common(CommonHeader::Ack),
         SERVER_CODE_CONNECT_REQUEST => ServerHeader::ConnectRequest,
         SERVER_CODE_TUNNEL_CLOSE => ServerHeader::TunnelClose,
         code => return Err(Error::UnknownCode { code }),
@@ -63,6 +69,9 @@ fn encode_server_header(slot: Slot, header: ServerHeader) -> u64 {
         Common(DataPlain) => COMMON_CODE_DATA_PLAIN,
         Common(DataCompressed) => COMMON_CODE_DATA_COMPRESSED,
         Common(Closed) => COMMON_CODE_CLOSED,
+        Common(WindowUpdate) => COMMON_CODE_WINDOW_UPDATE,
+        Common(WriteClosed) => COMMON_CODE_WRITE_CLOSED,
+        Common(Ack) => COMMON_CODE_ACK,
         ConnectRequest { .. } => SERVER_CODE_CONNECT_REQUEST,
         TunnelClose { .. } => SERVER_CODE_TUNNEL_CLOSE,
     };
@@ -82,6 +91,9 @@ fn encode_client_header(slot: Slot, header: ClientHeader) -> u64 {
         Common(Closed) => COMMON_CODE_CLOSED,
         Common(Ping) => COMMON_CODE_PING,
         Common(Pong) => COMMON_CODE_PONG,
+        Common(WindowUpdate) => COMMON_CODE_WINDOW_UPDATE,
+        Common(WriteClosed) => COMMON_CODE_WRITE_CLOSED,
+        Common(Ack) => COMMON_CODE_ACK,
         Accepted => CLIENT_CODE_ACCEPTED,
         Rejected => CLIENT_CODE_REJECTED,
     };
@@ -162,6 +174,9 @@ mod test {
             ServerHeader::ConnectRequest,
             ServerHeader::Common(CommonHeader::DataPlain),
             ServerHeader::Common(CommonHeader::Closed),
+            ServerHeader::Common(CommonHeader::WindowUpdate),
+            ServerHeader::Common(CommonHeader::WriteClosed),
+            ServerHeader::Common(CommonHeader::Ack),
         ];
 
         for server_header in server_headers.into_iter() {
@@ -185,6 +200,9 @@ mod test {
             ClientHeader::Rejected,
             ClientHeader::Common(CommonHeader::DataPlain),
             ClientHeader::Common(CommonHeader::Closed),
+            ClientHeader::Common(CommonHeader::WindowUpdate),
+            ClientHeader::Common(CommonHeader::WriteClosed),
+            ClientHeader::Common(CommonHeader::Ack),
         ];
 
         for client_header in client_headers.into_iter() {
@@ -0,0 +1,332 @@
+//! Negotiation of the tunnel protocol version and capabilities.
+//!
+//! The client advertises supported protocol versions and capabilities in `TunnelHello`,
+//! and the server picks the version and the subset of capabilities in `TunnelHelloResponse`.
+//! Peers, which don't negotiate, are treated as `LEGACY_PROTOCOL_VERSION` with
+//! `Capabilities::legacy()`. Unknown fields and compression algorithms are ignored, so
+//! that new capabilities may be added without breaking older peers.
+
+use crate::tunnel::{
+    connector::Compression,
+    proto::{TunnelHello, TunnelHelloResponse},
+    session::ResumeToken,
+    Error,
+};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
+
+/// Version, used by peers without negotiation
+pub const LEGACY_PROTOCOL_VERSION: u16 = 0;
+
+pub const PROTOCOL_VERSION: u16 = 1;
+
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u16] = &[LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Compression algorithms besides plain, which is always supported
+    #[serde(default, deserialize_with = "deserialize_known")]
+    pub compression: Vec<Compression>,
+
+    /// Per-slot window updates
+    #[serde(default)]
+    pub flow_control: bool,
+
+    #[serde(default)]
+    pub half_close: bool,
+
+    #[serde(default)]
+    pub datagrams: bool,
+
+    /// Resumption of the session after reconnect
+    #[serde(default)]
+    pub resumption: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaybeKnown<T> {
+    Known(T),
+    Unknown(IgnoredAny),
+}
+
+fn deserialize_known<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Vec::<MaybeKnown<T>>::deserialize(deserializer)?
+        .into_iter()
+        .filter_map(|item| match item {
+            MaybeKnown::Known(item) => Some(item),
+            MaybeKnown::Unknown(_) => None,
+        })
+        .collect())
+}
+
+impl Capabilities {
+    /// Capabilities of peers, which don't negotiate
+    pub fn legacy() -> Self {
+        Capabilities {
+            compression: vec![Compression::Zstd],
+            flow_control: false,
+            half_close: false,
+            datagrams: false,
+            resumption: false,
+        }
+    }
+
+    /// Capabilities, implemented by this side
+    pub fn supported() -> Self {
+        Capabilities {
+            compression: vec![
+                Compression::Zstd,
+                Compression::ZstdStream,
+                Compression::Lz4,
+                Compression::Brotli,
+            ],
+            flow_control: true,
+            half_close: true,
+            datagrams: true,
+            resumption: true,
+        }
+    }
+
+    /// Capabilities, supported by both sides
+    pub fn intersect(&self, other: &Capabilities) -> Self {
+        Capabilities {
+            compression: self
+                .compression
+                .iter()
+                .filter(|compression| other.compression.contains(compression))
+                .copied()
+                .collect(),
+            flow_control: self.flow_control && other.flow_control,
+            half_close: self.half_close && other.half_close,
+            datagrams: self.datagrams && other.datagrams,
+            resumption: self.resumption && other.resumption,
+        }
+    }
+
+    /// Fall back to the negotiated compression, if the requested one is not negotiated
+    pub fn compression(&self, requested: Compression) -> Compression {
+        let mut compression = requested;
+        while compression != Compression::Plain && !self.compression.contains(&compression) {
+            compression = compression.fallback();
+        }
+        compression
+    }
+}
+
+/// Protocol version and capabilities, chosen for the tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub protocol_version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Negotiated {
+    pub fn legacy() -> Self {
+        Negotiated {
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Capabilities::legacy(),
+        }
+    }
+
+    /// Latest version with all the supported capabilities
+    pub fn current() -> Self {
+        Negotiated {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+        }
+    }
+
+    /// Validate the choice of the server on the client side
+    pub fn accept(protocol_version: u16, capabilities: Capabilities) -> Result<Self, Error> {
+        if !SUPPORTED_PROTOCOL_VERSIONS.contains(&protocol_version) {
+            return Err(Error::UnsupportedProtocolVersion(protocol_version));
+        }
+
+        let supported = if protocol_version == LEGACY_PROTOCOL_VERSION {
+            Capabilities::legacy()
+        } else {
+            Capabilities::supported()
+        };
+
+        Ok(Negotiated {
+            protocol_version,
+            capabilities: capabilities.intersect(&supported),
+        })
+    }
+}
+
+impl TunnelHello {
+    /// Choose the highest protocol version and the capabilities, supported by both sides.
+    /// Returns `None` if there is no common version
+    pub fn negotiate(
+        &self,
+        supported_versions: &[u16],
+        capabilities: &Capabilities,
+    ) -> Option<Negotiated> {
+        let protocol_version = if self.protocol_versions.is_empty() {
+            &[LEGACY_PROTOCOL_VERSION][..]
+        } else {
+            &self.protocol_versions[..]
+        }
+        .iter()
+        .filter(|version| supported_versions.contains(version))
+        .max()
+        .copied()?;
+
+        let capabilities = if protocol_version == LEGACY_PROTOCOL_VERSION {
+            Capabilities::legacy().intersect(capabilities)
+        } else {
+            self.capabilities.intersect(capabilities)
+        };
+
+        Some(Negotiated {
+            protocol_version,
+            capabilities,
+        })
+    }
+}
+
+impl TunnelHelloResponse {
+    /// New tunnel. The resume token is only sent if resumption is negotiated
+    pub fn accepted(
+        tunnel_id: crate::entities::TunnelId,
+        negotiated: Negotiated,
+        resume_token: Option<ResumeToken>,
+    ) -> Self {
+        let resume_token = resume_token.filter(|_| negotiated.capabilities.resumption);
+        TunnelHelloResponse::Ok {
+            tunnel_id,
+            protocol_version: negotiated.protocol_version,
+            capabilities: negotiated.capabilities,
+            resume_token,
+            resumed: None,
+        }
+    }
+
+    /// Session, re-attached by the client, with the number of frames received from it
+    pub fn resumed(
+        tunnel_id: crate::entities::TunnelId,
+        negotiated: Negotiated,
+        resume_token: ResumeToken,
+        received: u64,
+    ) -> Self {
+        TunnelHelloResponse::Ok {
+            tunnel_id,
+            protocol_version: negotiated.protocol_version,
+            capabilities: negotiated.capabilities,
+            resume_token: Some(resume_token),
+            resumed: Some(received),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct FutureCapabilities {
+        compression: Vec<String>,
+        flow_control: bool,
+        multipath: bool,
+    }
+
+    #[test]
+    pub fn test_unknown_capabilities() {
+        let encoded = serde_cbor::to_vec(&FutureCapabilities {
+            compression: vec!["Brotli9000".to_string(), "Zstd".to_string()],
+            flow_control: true,
+            multipath: true,
+        })
+        .unwrap();
+
+        let capabilities: Capabilities = serde_cbor::from_slice(&encoded).unwrap();
+        assert_eq!(capabilities.compression, vec![Compression::Zstd]);
+        assert!(capabilities.flow_control);
+        assert!(!capabilities.half_close);
+    }
+
+    #[test]
+    pub fn test_negotiate() {
+        let hello = |protocol_versions: Vec<u16>, capabilities: Capabilities| TunnelHello {
+            config_name: "config".parse().unwrap(),
+            account_name: "account".parse().unwrap(),
+            project_name: "project".parse().unwrap(),
+            instance_id: Default::default(),
+            jwt_token: "token".into(),
+            protocol_versions,
+            capabilities,
+            resume: None,
+        };
+
+        let negotiated = hello(vec![], Capabilities::legacy())
+            .negotiate(SUPPORTED_PROTOCOL_VERSIONS, &Capabilities::supported())
+            .unwrap();
+        assert_eq!(negotiated, Negotiated::legacy());
+        assert_eq!(
+            negotiated.capabilities.compression(Compression::ZstdStream),
+            Compression::Zstd
+        );
+
+        let negotiated = hello(vec![0, 1, 7], Capabilities::supported())
+            .negotiate(SUPPORTED_PROTOCOL_VERSIONS, &Capabilities::supported())
+            .unwrap();
+        assert_eq!(negotiated, Negotiated::current());
+
+        let without_flow_control = Capabilities {
+            flow_control: false,
+            ..Capabilities::supported()
+        };
+        let negotiated = hello(vec![0, 1], Capabilities::supported())
+            .negotiate(SUPPORTED_PROTOCOL_VERSIONS, &without_flow_control)
+            .unwrap();
+        assert_eq!(negotiated.protocol_version, PROTOCOL_VERSION);
+        assert!(!negotiated.capabilities.flow_control);
+        assert_eq!(
+            negotiated.capabilities.compression(Compression::Zstd),
+            Compression::Zstd
+        );
+
+        assert!(hello(vec![7], Capabilities::supported())
+            .negotiate(SUPPORTED_PROTOCOL_VERSIONS, &Capabilities::supported())
+            .is_none());
+    }
+
+    #[test]
+    pub fn test_legacy_response() {
+        #[derive(Serialize)]
+        enum LegacyTunnelHelloResponse {
+            Ok {
+                tunnel_id: crate::entities::TunnelId,
+            },
+        }
+
+        let encoded = serde_cbor::to_vec(&LegacyTunnelHelloResponse::Ok {
+            tunnel_id: Default::default(),
+        })
+        .unwrap();
+
+        match serde_cbor::from_slice(&encoded).unwrap() {
+            TunnelHelloResponse::Ok {
+                protocol_version,
+                capabilities,
+                ..
+            } => {
+                assert_eq!(
+                    Negotiated::accept(protocol_version, capabilities).unwrap(),
+                    Negotiated::legacy()
+                );
+            }
+            TunnelHelloResponse::Err { .. } => panic!("bad response"),
+        }
+
+        assert!(matches!(
+            Negotiated::accept(7, Capabilities::supported()),
+            Err(Error::UnsupportedProtocolVersion(7))
+        ));
+    }
+}
@@ -0,0 +1,567 @@
+//! In-memory harness for end-to-end tests of the tunnel protocol.
+//!
+//! `server_connection` and `client_listener` are connected through in-memory duplex
+//! streams and a relay, which decodes the frames, so that they may be dropped, delayed or
+//! reordered in each direction. Upstreams of the client are configured as usual and may
+//! point to `EchoUpstream`s on loopback.
+
+use crate::{
+    config_core::{refinable::Refinable, ClientConfig, ClientConfigRevision, UpstreamDefinition},
+    entities::Upstream,
+    tunnel::{
+        client_framed, client_listener, server_connection, server_framed, ClientPacket, Connector,
+        Error, MixedChannel, Negotiated, ServerPacket, TunnelSettings, TunnelStats,
+    },
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future, pin_mut, Sink, SinkExt, Stream, StreamExt,
+};
+use parking_lot::{Mutex, RwLock};
+use rw_stream_sink::RwStreamSink;
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::TcpListener, task::JoinHandle, time::sleep};
+use tracing::debug;
+use trust_dns_resolver::{TokioAsyncResolver, TokioHandle};
+
+const DUPLEX_BUF_LEN: usize = 256 * 1024;
+
+/// Fault, injected into the matching frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Drop,
+    /// Deliver the frame after the delay. Following frames may overtake it
+    Delay(Duration),
+    /// Deliver the frame after the next one
+    Reorder,
+}
+
+type Matcher<P> = Box<dyn Fn(&P, &[u8]) -> bool + Send>;
+
+/// Faults of the frames in one direction. The first matching fault is applied
+pub struct Faults<P> {
+    rules: Arc<Mutex<Vec<(Matcher<P>, Fault)>>>,
+}
+
+impl<P> Clone for Faults<P> {
+    fn clone(&self) -> Self {
+        Faults {
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+impl<P> Default for Faults<P> {
+    fn default() -> Self {
+        Faults {
+            rules: Default::default(),
+        }
+    }
+}
+
+impl<P> Faults<P> {
+    /// Apply the fault to every frame, matching the packet and the payload
+    pub fn inject(&self, fault: Fault, matcher: impl Fn(&P, &[u8]) -> bool + Send + 'static) {
+        self.rules.lock().push((Box::new(matcher), fault));
+    }
+
+    pub fn clear(&self) {
+        self.rules.lock().clear();
+    }
+
+    fn find(&self, packet: &P, payload: &[u8]) -> Option<Fault> {
+        self.rules
+            .lock()
+            .iter()
+            .find(|(matcher, _)| matcher(packet, payload))
+            .map(|(_, fault)| *fault)
+    }
+}
+
+async fn relay<P: Send + 'static>(
+    from: impl Stream<Item = Result<(P, Vec<u8>), Error>>,
+    to: impl Sink<(P, Vec<u8>), Error = Error>,
+    faults: Faults<P>,
+) -> Result<(), Error> {
+    let (mut frames_tx, frames_rx) = mpsc::channel(16);
+
+    let deliver = frames_rx.map(Ok::<_, Error>).forward(to);
+
+    let inject = async move {
+        pin_mut!(from);
+        let mut held = None;
+
+        while let Some((packet, payload)) = from.next().await.transpose()? {
+            match faults.find(&packet, &payload) {
+                None => {
+                    frames_tx.send((packet, payload)).await?;
+                    if let Some(frame) = held.take() {
+                        frames_tx.send(frame).await?;
+                    }
+                }
+                Some(Fault::Drop) => {
+                    debug!("drop frame");
+                }
+                Some(Fault::Delay(delay)) => {
+                    let mut frames_tx = frames_tx.clone();
+                    tokio::spawn(async move {
+                        sleep(delay).await;
+                        let _ = frames_tx.send((packet, payload)).await;
+                    });
+                }
+                Some(Fault::Reorder) => {
+                    if let Some(frame) = held.replace((packet, payload)) {
+                        frames_tx.send(frame).await?;
+                    }
+                }
+            }
+        }
+
+        Ok::<(), Error>(())
+    };
+
+    future::try_join(inject, deliver).await.map(|_| ())
+}
+
+/// TCP server on loopback, which echoes the data back and shuts down writing on EOF
+pub struct EchoUpstream {
+    port: u16,
+    _stop_tx: oneshot::Sender<()>,
+}
+
+impl EchoUpstream {
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind(&SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 0)).await?;
+        let port = listener.local_addr()?.port();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+        let accept = async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = conn.into_split();
+                    tokio::io::copy(&mut reader, &mut writer).await?;
+                    tokio::io::AsyncWriteExt::shutdown(&mut writer).await
+                });
+            }
+        };
+
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = accept => {},
+                _ = stop_rx => {},
+            }
+        });
+
+        Ok(EchoUpstream {
+            port,
+            _stop_tx: stop_tx,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn definition(&self) -> UpstreamDefinition {
+        UpstreamDefinition::on_default_host(self.port)
+    }
+}
+
+/// Server and client sides of the tunnel, connected in memory
+pub struct TunnelHarness {
+    pub connector: Connector,
+
+    /// Faults of the frames, sent by the server
+    pub to_client: Faults<ServerPacket>,
+
+    /// Faults of the frames, sent by the client
+    pub to_server: Faults<ClientPacket>,
+
+    pub client_config: Arc<RwLock<ClientConfig>>,
+
+    pub server_stats: TunnelStats,
+
+    pub client_stats: TunnelStats,
+
+    /// Connections to the internal server of the client
+    pub internal_connections: mpsc::Receiver<RwStreamSink<MixedChannel>>,
+
+    kill_tx: Option<oneshot::Sender<()>>,
+    server: JoinHandle<Result<(), Error>>,
+    client: JoinHandle<Result<bool, Error>>,
+}
+
+impl TunnelHarness {
+    pub fn start(
+        negotiated: Negotiated,
+        settings: TunnelSettings,
+        upstreams: BTreeMap<Upstream, UpstreamDefinition>,
+    ) -> Result<Self, Error> {
+        let resolver = TokioAsyncResolver::from_system_conf(TokioHandle)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        let client_config = Arc::new(RwLock::new(ClientConfig {
+            version: crate::config_core::CURRENT_VERSION.clone(),
+            revision: ClientConfigRevision(1),
+            name: "harness".parse().unwrap(),
+            mount_points: Default::default(),
+            upstreams,
+            rate_limiters: Default::default(),
+            refinable: Refinable {
+                static_responses: Default::default(),
+                rescue: vec![],
+            },
+        }));
+
+        let (server_io, server_relay_io) = tokio::io::duplex(DUPLEX_BUF_LEN);
+        let (client_io, client_relay_io) = tokio::io::duplex(DUPLEX_BUF_LEN);
+
+        let to_client = Faults::default();
+        let to_server = Faults::default();
+        let (kill_tx, kill_rx) = oneshot::channel::<()>();
+
+        tokio::spawn({
+            let (to_server_tx, from_server_rx) = client_framed(server_relay_io).split();
+            let (to_client_tx, from_client_rx) = server_framed(client_relay_io).split();
+            let relays = future::try_join(
+                relay(from_server_rx, to_client_tx, to_client.clone()),
+                relay(from_client_rx, to_server_tx, to_server.clone()),
+            );
+
+            async move {
+                tokio::select! {
+                    r = relays => {
+                        debug!("tunnel harness relay closed: {:?}", r.map(|_| ()));
+                    },
+                    _ = kill_rx => {
+                        debug!("tunnel harness transport killed");
+                    },
+                }
+            }
+        });
+
+        let (bg, connector, server_stats) =
+            server_connection(server_framed(server_io), negotiated.clone(), settings);
+        let server = tokio::spawn(bg);
+
+        let (internal_server_connector, internal_connections) = mpsc::channel(1);
+        let (listener, client_stats) = client_listener(
+            client_framed(client_io),
+            negotiated,
+            settings,
+            client_config.clone(),
+            internal_server_connector,
+            &None,
+            resolver,
+            Default::default(),
+        );
+        let client = tokio::spawn(listener);
+
+        Ok(TunnelHarness {
+            connector,
+            to_client,
+            to_server,
+            client_config,
+            server_stats,
+            client_stats,
+            internal_connections,
+            kill_tx: Some(kill_tx),
+            server,
+            client,
+        })
+    }
+
+    /// Abruptly close the transport in both directions
+    pub fn kill_transport(&mut self) {
+        self.kill_tx.take();
+    }
+
+    /// Wait for the server side to finish
+    pub async fn server_result(&mut self) -> Result<(), Error> {
+        (&mut self.server)
+            .await
+            .expect("server connection panicked")
+    }
+
+    /// Wait for the client side to finish. Returns whether it should reconnect
+    pub async fn client_result(&mut self) -> Result<bool, Error> {
+        (&mut self.client).await.expect("client listener panicked")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config_core::{CircuitBreaker, DurationWrapper},
+        tunnel::{
+            Capabilities, ClientHeader, CommonHeader, Compression, ServerHeader, TunneledConnection,
+        },
+    };
+    use serde::Deserialize;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    };
+
+    async fn echo(connection: &mut TunneledConnection, data: &[u8]) {
+        connection.write_all(data).await.unwrap();
+        let mut read_buf = vec![0; data.len()];
+        connection.read_exact(&mut read_buf).await.unwrap();
+        assert_eq!(read_buf, data);
+    }
+
+    async fn harness(
+        negotiated: Negotiated,
+        settings: TunnelSettings,
+    ) -> (TunnelHarness, EchoUpstream) {
+        let upstream = EchoUpstream::start().await.unwrap();
+        let mut upstreams = BTreeMap::new();
+        upstreams.insert("echo".parse().unwrap(), upstream.definition());
+
+        (
+            TunnelHarness::start(negotiated, settings, upstreams).unwrap(),
+            upstream,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_reject() {
+        let (harness, _upstream) = harness(Negotiated::current(), Default::default()).await;
+
+        let mut connection = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Zstd)
+            .await
+            .unwrap();
+        echo(&mut connection, b"hello").await;
+
+        assert!(harness
+            .connector
+            .retrieve_connection("unknown.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_open_legacy_peer() {
+        // rejection reasons, known to peers without negotiation
+        #[derive(Deserialize)]
+        enum LegacyRejectionReason {
+            ConnectionRefused { error_message: String },
+            UpstreamNotFound,
+        }
+
+        let (harness, _upstream) = harness(Negotiated::legacy(), Default::default()).await;
+
+        let closed_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        harness.client_config.write().upstreams.insert(
+            "down".parse().unwrap(),
+            UpstreamDefinition {
+                circuit_breaker: Some(CircuitBreaker {
+                    failure_threshold: 1,
+                    cool_down: DurationWrapper(Duration::from_secs(60)),
+                }),
+                ..UpstreamDefinition::on_default_host(closed_port)
+            },
+        );
+
+        let rejections = Arc::new(Mutex::new(vec![]));
+        harness.to_server.inject(Fault::Drop, {
+            let rejections = rejections.clone();
+            move |packet, payload| {
+                if packet.header() == ClientHeader::Rejected {
+                    let reason = match serde_cbor::from_slice(payload).unwrap() {
+                        LegacyRejectionReason::ConnectionRefused { error_message } => error_message,
+                        LegacyRejectionReason::UpstreamNotFound => "not found".to_string(),
+                    };
+                    rejections.lock().push(reason);
+                }
+                false
+            }
+        });
+
+        for _ in 0..2 {
+            assert!(harness
+                .connector
+                .retrieve_connection("down.upstream.exg".parse().unwrap(), Compression::Plain)
+                .await
+                .is_err());
+        }
+        assert_eq!(rejections.lock()[1], "circuit breaker is open");
+
+        let mut connection = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .unwrap();
+        echo(&mut connection, b"hello").await;
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let (harness, _upstream) = harness(Negotiated::current(), Default::default()).await;
+
+        let mut connection = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Zstd)
+            .await
+            .unwrap();
+        echo(&mut connection, &[7; 10000]).await;
+        assert!(harness
+            .connector
+            .retrieve_connection("unknown.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .is_err());
+
+        let echo_target = "echo.upstream.exg".parse().unwrap();
+        for stats in &[&harness.server_stats, &harness.client_stats] {
+            assert_eq!(stats.slots_open(), 1);
+            assert!(stats.compression_ratio().unwrap() < 0.1);
+
+            let target = stats.target(&echo_target);
+            assert_eq!(target.bytes_sent(), 10000);
+            assert_eq!(target.bytes_received(), 10000);
+            assert_eq!(target.connect_latency().count(), 1);
+
+            let unknown = stats.target(&"unknown.upstream.exg".parse().unwrap());
+            assert_eq!(unknown.rejections(), 1);
+            assert_eq!(unknown.slots_opened(), 0);
+        }
+        assert!(harness.server_stats.to_prometheus().contains(
+            "exogress_tunnel_rejections_total{target=\"unknown.upstream.exg\",reason=\"upstream_not_found\"} 1\n"
+        ));
+
+        drop(connection);
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(harness.server_stats.slots_open(), 0);
+        assert_eq!(harness.client_stats.slots_open(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drop_connect_request() {
+        let (harness, _upstream) = harness(Negotiated::current(), Default::default()).await;
+
+        harness.to_client.inject(Fault::Drop, |packet, _| {
+            packet.header() == ServerHeader::ConnectRequest
+        });
+        let connect = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Plain);
+        assert!(timeout(Duration::from_millis(300), connect).await.is_err());
+
+        harness.to_client.clear();
+        let mut connection = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .unwrap();
+        echo(&mut connection, b"hello").await;
+    }
+
+    #[tokio::test]
+    async fn test_close_race() {
+        let (mut harness, _upstream) = harness(Negotiated::legacy(), Default::default()).await;
+
+        // the client echoes the data back, before it learns that the server has closed the slot
+        harness
+            .to_client
+            .inject(Fault::Delay(Duration::from_millis(200)), |packet, _| {
+                packet.header() == ServerHeader::Common(CommonHeader::Closed)
+            });
+        let mut connection = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .unwrap();
+        connection.write_all(b"hello").await.unwrap();
+        drop(connection);
+
+        sleep(Duration::from_millis(300)).await;
+        harness.to_client.clear();
+
+        let mut connection = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .unwrap();
+        echo(&mut connection, b"still alive").await;
+
+        harness.kill_transport();
+        timeout(Duration::from_secs(5), harness.server_result())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kill_transport() {
+        let (mut harness, _upstream) = harness(Negotiated::current(), Default::default()).await;
+
+        let mut connection = harness
+            .connector
+            .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .unwrap();
+        echo(&mut connection, b"hello").await;
+
+        harness.kill_transport();
+
+        assert!(timeout(Duration::from_secs(5), harness.client_result())
+            .await
+            .unwrap()
+            .unwrap());
+        let mut buf = vec![];
+        assert!(
+            timeout(Duration::from_secs(5), connection.read_to_end(&mut buf))
+                .await
+                .unwrap()
+                .map(|_| buf.is_empty())
+                .unwrap_or(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pings() {
+        let settings = TunnelSettings {
+            ping_period: Duration::from_millis(50),
+            wait_pong_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let negotiated = Negotiated {
+            capabilities: Capabilities {
+                resumption: false,
+                ..Capabilities::supported()
+            },
+            ..Negotiated::current()
+        };
+        let (mut harness, _upstream) = harness(negotiated, settings).await;
+
+        harness
+            .to_client
+            .inject(Fault::Delay(Duration::from_millis(100)), |packet, _| {
+                packet.header() == ServerHeader::Common(CommonHeader::Pong)
+            });
+        sleep(Duration::from_millis(250)).await;
+        assert!(harness.client_stats.rtt().latest().unwrap() >= Duration::from_millis(100));
+        assert!(harness.server_stats.rtt().latest().unwrap() < Duration::from_millis(100));
+
+        harness.to_server.inject(Fault::Drop, |packet, _| {
+            packet.header() == ClientHeader::Common(CommonHeader::Pong)
+        });
+        timeout(Duration::from_secs(1), harness.server_result())
+            .await
+            .expect("server doesn't close the tunnel without pongs")
+            .unwrap();
+    }
+}
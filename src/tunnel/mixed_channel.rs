@@ -18,6 +18,8 @@ pub struct MixedChannel {
     rx: Option<Fuse<mpsc::Receiver<Vec<u8>>>>,
     sink_waker: Option<Waker>,
     stream_waker: Option<Waker>,
+    half_close: bool,
+    shutdown_sent: bool,
 }
 
 impl MixedChannel {
@@ -33,10 +35,20 @@ impl MixedChannel {
             rx: Some(rx_receiver.fuse()),
             sink_waker: None,
             stream_waker: None,
+            half_close: false,
+            shutdown_sent: false,
         };
 
         (channel, tx_receiver, rx_sender)
     }
+
+    /// In half-close mode the end of the stream doesn't close the sink, and closing
+    /// the sink doesn't close the stream. Closing the sink is sent as an empty buffer,
+    /// so that it's distinguishable from dropping the channel.
+    pub fn with_half_close(mut self, half_close: bool) -> Self {
+        self.half_close = half_close;
+        self
+    }
 }
 
 impl Stream for MixedChannel {
@@ -50,9 +62,11 @@ impl Stream for MixedChannel {
             if let Some(r) = res {
                 Poll::Ready(Some(Ok(r)))
             } else {
-                self.tx.close_channel();
-                if let Some(w) = self.sink_waker.as_ref() {
-                    w.wake_by_ref()
+                if !self.half_close {
+                    self.tx.close_channel();
+                    if let Some(w) = self.sink_waker.as_ref() {
+                        w.wake_by_ref()
+                    }
                 }
                 Poll::Ready(None)
             }
@@ -84,6 +98,10 @@ impl Sink<Vec<u8>> for MixedChannel {
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        if item.is_empty() {
+            return Ok(());
+        }
+
         Pin::new(&mut self.tx).start_send(item).map_err(|_| {
             self.close_stream();
             io::Error::new(
@@ -108,10 +126,25 @@ impl Sink<Vec<u8>> for MixedChannel {
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.sink_waker = Some(cx.waker().clone());
 
+        if self.half_close && !self.shutdown_sent {
+            ready!(self.as_mut().poll_ready(cx))?;
+            Pin::new(&mut self.tx).start_send(Vec::new()).map_err(|_| {
+                self.close_stream();
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "tunnel closed: could not send shutdown to mix",
+                )
+            })?;
+            self.shutdown_sent = true;
+        }
+
+        let half_close = self.half_close;
         Pin::new(&mut self.tx)
             .poll_close(cx)
             .map(|r| {
-                self.close_stream();
+                if !half_close {
+                    self.close_stream();
+                }
                 r
             })
             .map_err(|_| {
@@ -148,7 +181,7 @@ mod test {
     use crate::tunnel::{
         client_listener,
         framed::{client_framed, server_framed},
-        server_connection,
+        server_connection, Negotiated,
     };
 
     use super::*;
@@ -235,6 +268,24 @@ mod test {
         assert!(matches!(sender.await, Ok(Err(_))));
     }
 
+    #[tokio::test]
+    async fn test_mixed_channel_half_close() {
+        let (ch, mut ch_tx, mut ch_rx) = MixedChannel::new(2, 2);
+        let mut rw = RwStreamSink::new(ch.with_half_close(true));
+
+        rw.write_all(&[1, 2]).await.unwrap();
+        rw.close().await.unwrap();
+        assert_eq!(ch_rx.next().await.unwrap(), vec![1, 2]);
+        assert!(ch_rx.next().await.unwrap().is_empty());
+        assert!(ch_rx.next().await.is_none());
+
+        ch_tx.send(vec![3]).await.unwrap();
+        mem::drop(ch_tx);
+        let mut buf = vec![];
+        rw.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, vec![3]);
+    }
+
     #[tokio::test]
     async fn test_channel() {
         let (mut rw, mut ch1_tx, mut ch2_rx) = to_async_rw(2, 2);
@@ -288,6 +339,7 @@ mod test {
                         name: "my-config".parse().unwrap(),
                         mount_points: Default::default(),
                         upstreams,
+                        rate_limiters: Default::default(),
                         refinable: Refinable {
                             static_responses: Default::default(),
                             rescue: vec![],
@@ -299,13 +351,17 @@ mod test {
                 let (internal_server_connector, _new_conn_rx) = mpsc::channel(1);
 
                 let tunnel = TcpStream::connect(&binded_to).await.unwrap();
-                tokio::spawn(client_listener(
+                let (listener, _) = client_listener(
                     client_framed(tunnel),
+                    Negotiated::current(),
+                    Default::default(),
                     client_config,
                     internal_server_connector,
                     &None,
                     resolver,
-                ));
+                    Default::default(),
+                );
+                tokio::spawn(listener);
 
                 let response =
                     b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nResponse body\r\n";
@@ -320,7 +376,11 @@ mod test {
 
         let (server_side, _remote_addr) = server_side_listener.accept().await.unwrap();
 
-        let (bg, connector) = server_connection(server_framed(server_side));
+        let (bg, connector, _) = server_connection(
+            server_framed(server_side),
+            Negotiated::current(),
+            Default::default(),
+        );
 
         tokio::spawn(bg);
 
@@ -1,16 +1,58 @@
-pub use connector::{Compression, ConnectTarget, Connector, ConnectorRequest, INT_SUFFIX};
+pub use balancer::{
+    resolve_endpoints, ConnectError, ConnectionGuard, Endpoint, EndpointAddr, EndpointStream,
+    UpstreamBalancer,
+};
+pub use circuit_breaker::CircuitState;
+pub use compression::{Compressor, Decompressor};
+pub use connector::{
+    Compression, ConnectTarget, Connector, ConnectorRequest, SlotKind, TunneledDatagrams,
+    INT_SUFFIX,
+};
 pub use error::Error;
 pub use framed::{client_framed, server_framed};
+pub use handshake::{
+    Capabilities, Negotiated, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    SUPPORTED_PROTOCOL_VERSIONS,
+};
 pub use proto::{
-    client_listener, server_connection, Conn, ServerPacket, TunnelHello, TunnelHelloResponse,
-    TunneledConnection,
+    client_listener, server_connection, ClientHeader, ClientPacket, CommonHeader, Conn,
+    ServerHeader, ServerPacket, Slot, TunnelHello, TunnelHelloResponse, TunneledConnection,
+};
+#[cfg(feature = "quic")]
+pub use quic::{
+    close_no_reconnect, quic_accept, quic_client_config, quic_client_listener, quic_connect,
+    quic_server_config, QuicConnection, QuicHello, SlotResponse, QUIC_ALPN_PROTOCOL,
+    QUIC_CODE_CLOSE_NO_RECONNECT,
+};
+pub use rtt::Rtt;
+pub use session::{
+    DetachedSessions, ResumeRequest, ResumeToken, Session, SessionTunnel, RESUME_WINDOW,
+};
+pub use settings::{TunnelSettings, TunnelSettingsError};
+pub use stats::{encode_prometheus, Histogram, TargetStats, TunnelStats, CONNECT_LATENCY_BUCKETS};
+pub use upstream_tls::{
+    UpstreamStream, UpstreamTlsConnector, UpstreamTlsConnectorError, UpstreamTlsConnectors,
 };
 
+mod balancer;
+mod circuit_breaker;
+mod compression;
 mod connector;
 mod error;
+mod flow_control;
 mod framed;
+mod handshake;
+#[cfg(any(test, feature = "tunnel-harness"))]
+pub mod harness;
 mod mixed_channel;
 mod proto;
+#[cfg(feature = "quic")]
+mod quic;
+mod rtt;
+mod session;
+mod settings;
+mod stats;
+mod upstream_tls;
 
 pub use mixed_channel::{to_async_rw, MixedChannel};
 
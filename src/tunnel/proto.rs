@@ -1,10 +1,13 @@
 use crate::{
-    entities::{AccountName, ConfigName, InstanceId, ProfileName, ProjectName, SmolStr, TunnelId},
+    entities::{
+        AccountName, ConfigName, InstanceId, ProfileName, ProjectName, SmolStr, TunnelId, Upstream,
+    },
     tunnel::connector::ConnectorRequest,
 };
 use bytes::BytesMut;
 use futures::{
     channel::{mpsc, oneshot},
+    future::{self, AbortHandle, Abortable},
     pin_mut, select_biased,
     stream::StreamExt,
     task::{Context, Poll},
@@ -17,26 +20,33 @@ use std::{
     convert::{TryFrom, TryInto},
     fmt,
     fmt::Formatter,
-    io, mem,
-    net::{IpAddr, SocketAddr},
+    io,
     sync::Arc,
-    time::Duration,
 };
 use stop_handle::{stop_handle, StopHandle};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     macros::support::Pin,
-    net::TcpStream,
-    time::{sleep, timeout},
+    time::sleep,
 };
 use tracing::{debug, info, warn};
 use trust_dns_resolver::TokioAsyncResolver;
 
 use crate::{
-    config_core::ClientConfig,
+    config_core::{ClientConfig, UpstreamDefinition, UpstreamProtocol},
     tunnel::{
-        connector::{Compression, ConnectTarget, Connector},
-        mixed_channel::to_async_rw,
+        balancer::{resolve_endpoints, ConnectError, UpstreamBalancer},
+        compression::Compressors,
+        connector::{Compression, ConnectTarget, Connector, SlotKind},
+        flow_control::{
+            decode_window_update, encode_window_update, slot_channel, AcceptedPayload,
+            SlotFlowControl, SlotSender, INITIAL_WINDOW,
+        },
+        handshake::{Capabilities, Negotiated, PROTOCOL_VERSION},
+        session::{ResumeRequest, ResumeToken, RESUME_WINDOW},
+        settings::TunnelSettings,
+        stats::{PendingSlot, SlotStats, TunnelStats},
+        upstream_tls::UpstreamTlsConnectors,
         Error, MixedChannel,
     },
 };
@@ -45,12 +55,12 @@ use parking_lot::RwLock;
 use rand::{thread_rng, Rng};
 use rw_stream_sink::RwStreamSink;
 use serde::{Deserialize, Serialize};
-use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RejectionReason {
     ConnectionRefused { error_message: String },
     UpstreamNotFound,
+    CircuitOpen,
 }
 
 impl fmt::Display for RejectionReason {
@@ -60,6 +70,22 @@ impl fmt::Display for RejectionReason {
                 write!(f, "connection refused: {}", error_message)
             }
             RejectionReason::UpstreamNotFound => write!(f, "upstream not found"),
+            RejectionReason::CircuitOpen => write!(f, "circuit breaker is open"),
+        }
+    }
+}
+
+impl RejectionReason {
+    /// Reason, which the peer is able to decode. Peers without negotiation fail on
+    /// `CircuitOpen` and close the whole tunnel, so it's sent as `ConnectionRefused`
+    pub(crate) fn for_peer(self, protocol_version: u16) -> Self {
+        match self {
+            RejectionReason::CircuitOpen if protocol_version < PROTOCOL_VERSION => {
+                RejectionReason::ConnectionRefused {
+                    error_message: self.to_string(),
+                }
+            }
+            reason => reason,
         }
     }
 }
@@ -71,12 +97,41 @@ pub struct TunnelHello {
     pub project_name: ProjectName,
     pub instance_id: InstanceId,
     pub jwt_token: SmolStr,
+
+    /// Empty for peers without version negotiation
+    #[serde(default)]
+    pub protocol_versions: Vec<u16>,
+
+    #[serde(default = "Capabilities::legacy")]
+    pub capabilities: Capabilities,
+
+    /// Re-attach to the session, the connection of which was lost
+    #[serde(default)]
+    pub resume: Option<ResumeRequest>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TunnelHelloResponse {
-    Ok { tunnel_id: TunnelId },
-    Err { msg: String },
+    Ok {
+        tunnel_id: TunnelId,
+
+        #[serde(default)]
+        protocol_version: u16,
+
+        #[serde(default = "Capabilities::legacy")]
+        capabilities: Capabilities,
+
+        /// Present if the session may be resumed after reconnect
+        #[serde(default)]
+        resume_token: Option<ResumeToken>,
+
+        /// Frames received from the client, if the session is resumed
+        #[serde(default)]
+        resumed: Option<u64>,
+    },
+    Err {
+        msg: String,
+    },
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -125,6 +180,9 @@ pub enum CommonHeader {
     Closed,
     Ping,
     Pong,
+    WindowUpdate,
+    WriteClosed,
+    Ack,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -147,6 +205,14 @@ impl ServerPacket {
             slot: Slot(0),
         }
     }
+
+    pub fn header(&self) -> ServerHeader {
+        self.header
+    }
+
+    pub fn slot(&self) -> Slot {
+        self.slot
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -162,11 +228,24 @@ pub struct ClientPacket {
     pub(crate) slot: Slot,
 }
 
+impl ClientPacket {
+    pub fn header(&self) -> ClientHeader {
+        self.header
+    }
+
+    pub fn slot(&self) -> Slot {
+        self.slot
+    }
+}
+
 pub const COMMON_CODE_DATA_PLAIN: u8 = 0;
 pub const COMMON_CODE_DATA_COMPRESSED: u8 = 1;
 pub const COMMON_CODE_CLOSED: u8 = 2;
 pub const COMMON_CODE_PING: u8 = 3;
 pub const COMMON_CODE_PONG: u8 = 4;
+pub const COMMON_CODE_WINDOW_UPDATE: u8 = 5;
+pub const COMMON_CODE_WRITE_CLOSED: u8 = 6;
+pub const COMMON_CODE_ACK: u8 = 7;
 
 pub const CLIENT_CODE_ACCEPTED: u8 = MAX_CODE_VALUE as u8;
 pub const CLIENT_CODE_REJECTED: u8 = (MAX_CODE_VALUE - 1) as u8;
@@ -182,122 +261,132 @@ pub const MAX_SLOT_NUM: u64 = MAX_HEADER_CODE >> 4;
 //3 bytes - 4 bits, reserved for codes
 pub const MAX_PAYLOAD_LEN: usize = u16::MAX as usize;
 
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
-
-enum Compressor {
-    Plain,
-    Zstd(zstd::block::Compressor),
-}
-
-impl Compressor {
-    pub fn new(compression: Compression) -> Self {
-        match compression {
-            Compression::Plain => Compressor::Plain,
-            Compression::Zstd => Compressor::Zstd(Default::default()),
-        }
-    }
-
-    pub fn compress(&mut self, buf: Vec<u8>) -> (Vec<u8>, bool) {
-        match self {
-            Compressor::Plain => (buf, false),
-            Compressor::Zstd(compressor) => {
-                let compressed = compressor.compress(&buf, 0).expect("Unable to compress");
-
-                // info!(
-                //     "compress ratio = {}",
-                //     buf.len() as f32 / compressed.len() as f32
-                // );
-                if compressed.len() < buf.len() {
-                    (compressed, true)
-                } else {
-                    (buf, false)
-                }
-            }
-        }
-    }
-}
-
-enum Decompressor {
-    Plain,
-    Zstd(zstd::block::Decompressor),
+#[derive(Clone)]
+pub struct Connection {
+    stop_handle: StopHandle<()>,
+    /// Stop sending to the peer, but deliver the data, received from it
+    abort_write: AbortHandle,
+    tunnel_to_tcp_tx: SlotSender,
+    compressors: Arc<Mutex<Compressors>>,
+    flow_control: Option<SlotFlowControl>,
+    stats: SlotStats,
 }
 
-impl Decompressor {
-    pub fn new(compression: Compression) -> Self {
-        match compression {
-            Compression::Plain => Decompressor::Plain,
-            Compression::Zstd => Decompressor::Zstd(Default::default()),
-        }
-    }
-
-    pub fn decompress(&mut self, buf: Vec<u8>) -> Result<Vec<u8>, io::Error> {
-        match self {
-            Decompressor::Plain => Ok(buf),
-            Decompressor::Zstd(compressor) => compressor.decompress(&buf, MAX_PAYLOAD_LEN),
-        }
+impl Connection {
+    /// The peer will not send any more data to the slot
+    fn write_closed_by_peer(&self) {
+        self.tunnel_to_tcp_tx.close();
     }
-}
-
-struct Compressors {
-    compressor: Compressor,
-    decompressor: Decompressor,
-}
 
-impl Compressors {
-    pub fn new(compression: Compression) -> Self {
-        Compressors {
-            compressor: Compressor::new(compression),
-            decompressor: Decompressor::new(compression),
+    /// The peer closed the slot. If it has closed writing before, the data, which is
+    /// already received, is still delivered to the connection
+    fn closed_by_peer(&self) {
+        if self.tunnel_to_tcp_tx.is_closed() {
+            self.abort_write.abort();
+        } else {
+            self.stop_handle.stop(());
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Connection {
-    stop_handle: StopHandle<()>,
-    tunnel_to_tcp_tx: mpsc::Sender<Vec<u8>>,
-    compressors: Arc<Mutex<Compressors>>,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConnectRequestPayload {
-    target: ConnectTarget,
-    compression: Compression,
+    pub(crate) target: ConnectTarget,
+    pub(crate) compression: Compression,
+    #[serde(default)]
+    pub(crate) balance_key: Option<SmolStr>,
+    /// Receive window of the server, if it supports flow control
+    #[serde(default)]
+    pub(crate) window: Option<u32>,
+    /// Only sent to peers, supporting datagrams
+    #[serde(default)]
+    pub(crate) kind: SlotKind,
+    /// Send incompressible frames plain
+    #[serde(default)]
+    pub(crate) adaptive_compression: bool,
 }
 
 // FIXME: abstraction is clearly broken here. we should not access client_config and
 // handle particular request
-pub async fn client_listener(
+#[allow(clippy::too_many_arguments)]
+pub fn client_listener<'a>(
     tunnel: impl Stream<Item = Result<(ServerPacket, Vec<u8>), Error>>
         + Sink<(ClientPacket, Vec<u8>), Error = Error>
         + Send
         + 'static,
+    negotiated: Negotiated,
+    settings: TunnelSettings,
+    client_config: Arc<RwLock<ClientConfig>>,
+    internal_server_connector: mpsc::Sender<RwStreamSink<MixedChannel>>,
+    active_profile: &'a Option<ProfileName>,
+    resolver: TokioAsyncResolver,
+    balancer: UpstreamBalancer,
+) -> (
+    impl Future<Output = Result<bool, crate::tunnel::error::Error>> + 'a,
+    TunnelStats,
+) {
+    let stats = TunnelStats::default();
+
+    let listener = listen(
+        tunnel,
+        negotiated,
+        settings,
+        stats.clone(),
+        client_config,
+        internal_server_connector,
+        active_profile,
+        resolver,
+        balancer,
+    );
+
+    (listener, stats)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn listen(
+    tunnel: impl Stream<Item = Result<(ServerPacket, Vec<u8>), Error>>
+        + Sink<(ClientPacket, Vec<u8>), Error = Error>
+        + Send
+        + 'static,
+    negotiated: Negotiated,
+    settings: TunnelSettings,
+    stats: TunnelStats,
     client_config: Arc<RwLock<ClientConfig>>,
     mut internal_server_connector: mpsc::Sender<RwStreamSink<MixedChannel>>,
     active_profile: &Option<ProfileName>,
     resolver: TokioAsyncResolver,
+    balancer: UpstreamBalancer,
 ) -> Result<bool, crate::tunnel::error::Error> {
+    let flow_control_enabled = negotiated.capabilities.flow_control;
+    let half_close = negotiated.capabilities.half_close;
+    let protocol_version = negotiated.protocol_version;
     let storage = Arc::new(Mutex::new(HashMap::<Slot, Connection>::new()));
+    let tls_connectors = UpstreamTlsConnectors::default();
     let just_closed_by_us = Arc::new(Mutex::new(LruCache::<Slot, ()>::with_expiry_duration(
-        Duration::from_secs(5),
+        settings.closed_slot_expiry,
     )));
 
     let (tx, mut rx) = tunnel.split();
 
     let (outgoing_messages_tx, outgoing_messages_rx) = mpsc::channel::<(_, Vec<u8>)>(16);
-    let ping_period = Duration::from_secs(5);
-    let wait_pong_timeout = ping_period * 3;
+    let ping_period = settings.ping_period;
+    let connect_timeout = settings.connect_timeout;
+    let mut wait_pong_timeout = settings.wait_pong_timeout;
+    if negotiated.capabilities.resumption {
+        // pings, sent while the session is detached, are answered after it's resumed
+        wait_pong_timeout += RESUME_WINDOW;
+    }
 
     let (mut received_pongs_tx, received_pongs_rx) = mpsc::channel(1);
 
     let periodic_pinger = {
-        shadow_clone!(mut outgoing_messages_tx);
+        shadow_clone!(mut outgoing_messages_tx, stats);
 
         #[allow(unreachable_code)]
         async move {
             loop {
                 sleep(ping_period).await;
-                let payload = vec![];
+                let payload = stats.rtt().ping_payload();
                 outgoing_messages_tx
                     .send((
                         ClientPacket {
@@ -323,7 +412,7 @@ pub async fn client_listener(
     };
 
     let read_future = {
-        shadow_clone!(client_config, storage, outgoing_messages_tx, just_closed_by_us, mut outgoing_messages_tx, active_profile);
+        shadow_clone!(client_config, storage, tls_connectors, outgoing_messages_tx, just_closed_by_us, mut outgoing_messages_tx, active_profile, stats);
 
         async move {
             while let Some(res) = rx.next().await {
@@ -337,115 +426,148 @@ pub async fn client_listener(
                                 let req = serde_cbor::from_slice::<ConnectRequestPayload>(&payload)?;
                                 let target = req.target;
                                 let compression = req.compression;
-                                match target {
-                                    ConnectTarget::Upstream(upstream) => {
+                                let adaptive_compression = req.adaptive_compression;
+                                let balance_key = req.balance_key;
+                                let kind = req.kind;
+                                let pending = stats.slot_requested(&target);
+                                let flow_control = req
+                                    .window
+                                    .filter(|_| flow_control_enabled)
+                                    .map(|window| SlotFlowControl::new(window, INITIAL_WINDOW));
+                                let accepted_payload = if flow_control.is_some() {
+                                    serde_cbor::to_vec(&AcceptedPayload { window: INITIAL_WINDOW }).unwrap()
+                                } else {
+                                    Default::default()
+                                };
+                                match (target, kind) {
+                                    (ConnectTarget::Upstream(upstream), SlotKind::Stream) => {
                                         tokio::spawn({
-                                            shadow_clone!(resolver, storage, client_config, mut outgoing_messages_tx, just_closed_by_us, active_profile);
+                                            shadow_clone!(resolver, balancer, tls_connectors, storage, client_config, mut outgoing_messages_tx, just_closed_by_us, active_profile, flow_control, accepted_payload);
 
                                             async move {
                                                 let maybe_upstream_target = client_config.read().resolve_upstream(&upstream, &active_profile);
 
                                                 if let Some(upstream_target) = maybe_upstream_target {
-                                                    let host = upstream_target.get_host();
-                                                    let ip_addr = if let Ok(ip_addr) = host.parse::<IpAddr>() {
-                                                        ip_addr
-                                                    } else {
-                                                        let r = async {
-                                                            resolver
-                                                                .lookup_ip(host.as_str())
-                                                                .await
-                                                                .map_err(|e| {
-                                                                    warn!("resolver error: {}", e);
-                                                                    crate::tunnel::Error::UpstreamResolveError {
-                                                                        upstream: upstream.clone(),
-                                                                        host: host.as_str().to_string(),
-                                                                    }
-                                                                })
-                                                                .into_iter()
-                                                                .next()
-                                                                .ok_or_else(|| crate::tunnel::Error::UpstreamResolveError {
-                                                                    upstream: upstream.clone(),
-                                                                    host: host.as_str().to_string(),
-                                                                })?
-                                                                .into_iter()
-                                                                .next()
-                                                                .ok_or_else(|| crate::tunnel::Error::UpstreamResolveError {
-                                                                    upstream,
-                                                                    host: host.as_str().to_string(),
-                                                                })
-                                                        }.await;
-
-                                                        match r {
-                                                            Ok(r) => r,
-                                                            Err(e) => {
-                                                                warn!("error resolving upstream: {}", e);
-
-                                                                let payload = serde_cbor::to_vec(&RejectionReason::ConnectionRefused {
-                                                                    error_message: e.to_string(),
-                                                                }).unwrap();
-
-                                                                outgoing_messages_tx.send((
-                                                                    ClientPacket {
-                                                                        header: ClientHeader::Rejected,
-                                                                        slot,
-                                                                    },
-                                                                    payload,
-                                                                )).await?;
-
-                                                                return Err(e);
-                                                            }
+                                                    if upstream_target.protocol != UpstreamProtocol::Tcp {
+                                                        debug!("upstream {} doesn't accept TCP connections", upstream);
+                                                        let rejection = RejectionReason::ConnectionRefused {
+                                                            error_message: "upstream doesn't accept TCP connections".to_string(),
+                                                        };
+                                                        pending.rejected(&rejection);
+                                                        let payload = serde_cbor::to_vec(&rejection).unwrap();
+
+                                                        outgoing_messages_tx.send((
+                                                            ClientPacket {
+                                                                header: ClientHeader::Rejected,
+                                                                slot,
+                                                            },
+                                                            payload,
+                                                        )).await?;
+
+                                                        return Ok(());
+                                                    }
+
+                                                    let endpoints = match resolve_endpoints(&resolver, &upstream, &upstream_target).await {
+                                                        Ok(endpoints) => endpoints,
+                                                        Err(e) => {
+                                                            warn!("error resolving upstream: {}", e);
+
+                                                            let rejection = RejectionReason::ConnectionRefused {
+                                                                error_message: e.to_string(),
+                                                            };
+                                                            pending.rejected(&rejection);
+                                                            let payload = serde_cbor::to_vec(&rejection).unwrap();
+
+                                                            outgoing_messages_tx.send((
+                                                                ClientPacket {
+                                                                    header: ClientHeader::Rejected,
+                                                                    slot,
+                                                                },
+                                                                payload,
+                                                            )).await?;
+
+                                                            return Err(e);
                                                         }
                                                     };
-                                                    let connect_to: SocketAddr = (ip_addr, upstream_target.addr.port).into();
-                                                    let res = timeout(
-                                                        CONNECT_TIMEOUT,
-                                                        TcpStream::connect(connect_to),
+
+                                                    let res = balancer.connect(
+                                                        &upstream,
+                                                        upstream_target.load_balancing,
+                                                        endpoints,
+                                                        balance_key.as_deref(),
+                                                        connect_timeout,
+                                                        upstream_target.circuit_breaker.as_ref(),
+                                                        |stream, host| tls_connectors.wrap(upstream_target.tls.as_ref(), host, stream),
                                                     ).await;
 
                                                     match res {
-                                                        Ok(Ok(mut tcp)) => {
+                                                        Ok((stream, connection_guard)) => {
+                                                            let slot_stats = pending.accepted();
+
                                                             outgoing_messages_tx.send((
                                                                 ClientPacket {
                                                                     header: ClientHeader::Accepted,
                                                                     slot,
                                                                 },
-                                                                Default::default())
+                                                                accepted_payload)
                                                             ).await?;
 
-                                                            let (tunnel_to_tcp_tx, mut tunnel_to_tcp_rx) = mpsc::channel(4);
+                                                            let (tunnel_to_tcp_tx, mut tunnel_to_tcp_rx) = slot_channel(flow_control.as_ref());
                                                             let (stop_handle, mut stop_wait) = stop_handle::<()>();
+                                                            let (abort_write, abort_write_registration) = AbortHandle::new_pair();
 
-                                                            let compressors = Arc::new(Mutex::new(Compressors::new(compression)));
+                                                            let compressors = Arc::new(Mutex::new(Compressors::new(compression, adaptive_compression)));
 
                                                             storage.lock().insert(
                                                                 slot,
                                                                 Connection {
                                                                     stop_handle,
+                                                                    abort_write,
                                                                     tunnel_to_tcp_tx,
                                                                     compressors: compressors.clone(),
+                                                                    flow_control: flow_control.clone(),
+                                                                    stats: slot_stats.clone(),
                                                                 });
 
                                                             tokio::spawn({
-                                                                shadow_clone!(storage, outgoing_messages_tx, just_closed_by_us, compressors);
+                                                                shadow_clone!(storage, outgoing_messages_tx, just_closed_by_us, compressors, flow_control);
 
                                                                 async move {
-                                                                    let (mut from_tcp, mut to_tcp) = tcp.split();
+                                                                    let _connection_guard = connection_guard;
+                                                                    let (mut from_tcp, mut to_tcp) = tokio::io::split(stream);
 
                                                                     let forward_to_tunnel = {
-                                                                        shadow_clone!(outgoing_messages_tx, compressors);
+                                                                        shadow_clone!(outgoing_messages_tx, compressors, flow_control);
 
-                                                                        async move {
+                                                                        let forward = async move {
                                                                             loop {
                                                                                 shadow_clone!(mut outgoing_messages_tx);
                                                                                 let mut buf = BytesMut::new();
-                                                                                buf.resize(MAX_PAYLOAD_LEN, 0);
+                                                                                buf.resize(SlotFlowControl::max_chunk(flow_control.as_ref()), 0);
 
                                                                                 let num_bytes = from_tcp.read(&mut buf).await?;
 
                                                                                 if num_bytes == 0 {
+                                                                                    if half_close {
+                                                                                        outgoing_messages_tx.send((
+                                                                                            ClientPacket {
+                                                                                                header: ClientHeader::Common(CommonHeader::WriteClosed),
+                                                                                                slot,
+                                                                                            },
+                                                                                            Default::default()
+                                                                                        ))
+                                                                                            .await
+                                                                                            .map_err(|_|
+                                                                                                io::Error::new(io::ErrorKind::Other, "channel closed")
+                                                                                            )?;
+                                                                                    }
                                                                                     break;
                                                                                 }
 
+                                                                                if let Some(flow_control) = &flow_control {
+                                                                                    flow_control.send.acquire(num_bytes).await;
+                                                                                }
+
                                                                                 buf.truncate(num_bytes);
 
                                                                                 let buf = buf.freeze().to_vec();
@@ -453,6 +575,7 @@ pub async fn client_listener(
                                                                                     .lock()
                                                                                     .compressor
                                                                                     .compress(buf);
+                                                                                slot_stats.sent(num_bytes, maybe_compressed.len());
 
                                                                                 outgoing_messages_tx.send((
                                                                                     ClientPacket {
@@ -468,14 +591,39 @@ pub async fn client_listener(
                                                                             }
 
                                                                             Ok::<(), io::Error>(())
-                                                                        }.fuse()
+                                                                        };
+
+                                                                        Abortable::new(forward, abort_write_registration)
+                                                                            .map(|res| res.unwrap_or(Ok(())))
+                                                                            .fuse()
                                                                     };
 
                                                                     let forward_to_connection = {
+                                                                        shadow_clone!(mut outgoing_messages_tx);
+
                                                                         async move {
                                                                             while let Some(buf) = tunnel_to_tcp_rx.next().await {
                                                                                 to_tcp.write_all(&buf).await?;
+
+                                                                                let maybe_increment = flow_control
+                                                                                    .as_ref()
+                                                                                    .and_then(|flow_control| flow_control.recv.lock().consumed(buf.len()));
+                                                                                if let Some(increment) = maybe_increment {
+                                                                                    outgoing_messages_tx.send((
+                                                                                        ClientPacket {
+                                                                                            header: ClientHeader::Common(CommonHeader::WindowUpdate),
+                                                                                            slot,
+                                                                                        },
+                                                                                        encode_window_update(increment)
+                                                                                    ))
+                                                                                        .await
+                                                                                        .map_err(|_|
+                                                                                            io::Error::new(io::ErrorKind::Other, "channel closed")
+                                                                                        )?;
+                                                                                }
                                                                             }
+                                                                            to_tcp.shutdown().await?;
+
                                                                             Ok::<(), io::Error>(())
                                                                         }.fuse()
                                                                     };
@@ -484,9 +632,13 @@ pub async fn client_listener(
                                                                         shadow_clone!(mut outgoing_messages_tx, just_closed_by_us);
 
                                                                         async move {
-                                                                            let res = tokio::select! {
-                                                                                res = forward_to_tunnel => res,
-                                                                                res = forward_to_connection => res,
+                                                                            let res = if half_close {
+                                                                                future::try_join(forward_to_tunnel, forward_to_connection).await.map(|_| ())
+                                                                            } else {
+                                                                                tokio::select! {
+                                                                                    res = forward_to_tunnel => res,
+                                                                                    res = forward_to_connection => res,
+                                                                                }
                                                                             };
 
                                                                             debug!("connection on slot {} closed {:?}", slot, res);
@@ -515,12 +667,12 @@ pub async fn client_listener(
                                                                 }
                                                             });
                                                         }
-                                                        Ok(Err(e)) => {
-                                                            info!("error connecting to {:?}. error: {:?}", connect_to, e);
+                                                        Err(ConnectError::CircuitOpen) => {
+                                                            debug!("circuit breaker of upstream {} is open", upstream);
 
-                                                            let payload = serde_cbor::to_vec(&RejectionReason::ConnectionRefused {
-                                                                error_message: e.to_string(),
-                                                            }).unwrap();
+                                                            let rejection = RejectionReason::CircuitOpen;
+                                                            pending.rejected(&rejection);
+                                                            let payload = serde_cbor::to_vec(&rejection.for_peer(protocol_version)).unwrap();
 
                                                             outgoing_messages_tx.send((
                                                                 ClientPacket {
@@ -531,10 +683,13 @@ pub async fn client_listener(
                                                             )).await?;
                                                         }
                                                         Err(e) => {
-                                                            info!("error connecting: {}", e);
-                                                            let payload = serde_cbor::to_vec(&RejectionReason::ConnectionRefused {
-                                                                error_message: "timeout".to_string(),
-                                                            }).unwrap();
+                                                            info!("error connecting to upstream {}: {}", upstream, e);
+
+                                                            let rejection = RejectionReason::ConnectionRefused {
+                                                                error_message: e.to_string(),
+                                                            };
+                                                            pending.rejected(&rejection);
+                                                            let payload = serde_cbor::to_vec(&rejection).unwrap();
 
                                                             outgoing_messages_tx.send((
                                                                 ClientPacket {
@@ -547,8 +702,9 @@ pub async fn client_listener(
                                                     }
                                                 } else {
                                                     debug!("error connecting to {:?}. not found in config", upstream);
-                                                    let payload = serde_cbor::to_vec(&RejectionReason::UpstreamNotFound)
-                                                        .unwrap();
+                                                    let rejection = RejectionReason::UpstreamNotFound;
+                                                    pending.rejected(&rejection);
+                                                    let payload = serde_cbor::to_vec(&rejection).unwrap();
 
                                                     outgoing_messages_tx.send((
                                                         ClientPacket {
@@ -562,32 +718,77 @@ pub async fn client_listener(
                                             }
                                         });
                                     }
-                                    ConnectTarget::Internal(_) => {
+                                    (ConnectTarget::Upstream(upstream), SlotKind::Datagrams) => {
+                                        let upstream_target = client_config.read().resolve_upstream(&upstream, &active_profile);
+
+                                        tokio::spawn(forward_datagrams(
+                                            DatagramSlot {
+                                                slot,
+                                                upstream,
+                                                compression,
+                                                adaptive_compression,
+                                                balance_key,
+                                                flow_control,
+                                                accepted_payload,
+                                                pending,
+                                            },
+                                            upstream_target,
+                                            resolver.clone(),
+                                            balancer.clone(),
+                                            storage.clone(),
+                                            just_closed_by_us.clone(),
+                                            outgoing_messages_tx.clone(),
+                                        ));
+                                    }
+                                    (ConnectTarget::Internal(handler), SlotKind::Datagrams) => {
+                                        debug!("datagrams to internal handler {} requested", handler);
+                                        let rejection = RejectionReason::ConnectionRefused {
+                                            error_message: "datagrams are only supported by upstreams".to_string(),
+                                        };
+                                        pending.rejected(&rejection);
+                                        let payload = serde_cbor::to_vec(&rejection).unwrap();
+
+                                        outgoing_messages_tx.send((
+                                            ClientPacket {
+                                                header: ClientHeader::Rejected,
+                                                slot,
+                                            },
+                                            payload,
+                                        )).await?;
+                                    }
+                                    (ConnectTarget::Internal(_), SlotKind::Stream) => {
                                         let (ch, mut tx, mut rx) = MixedChannel::new(16, 16);
+                                        let ch = ch.with_half_close(half_close);
 
                                         tokio::spawn({
                                             shadow_clone!(mut outgoing_messages_tx, storage, just_closed_by_us);
 
                                             async move {
+                                                let slot_stats = pending.accepted();
+
                                                 outgoing_messages_tx.send((
                                                     ClientPacket {
                                                         header: ClientHeader::Accepted,
                                                         slot,
                                                     },
-                                                    Default::default())
+                                                    accepted_payload)
                                                 ).await?;
 
-                                                let (tunnel_to_tcp_tx, mut tunnel_to_tcp_rx) = mpsc::channel(4);
+                                                let (tunnel_to_tcp_tx, mut tunnel_to_tcp_rx) = slot_channel(flow_control.as_ref());
                                                 let (stop_handle, mut stop_wait) = stop_handle::<()>();
+                                                let (abort_write, abort_write_registration) = AbortHandle::new_pair();
 
-                                                let compressors = Arc::new(Mutex::new(Compressors::new(compression)));
+                                                let compressors = Arc::new(Mutex::new(Compressors::new(compression, adaptive_compression)));
 
                                                 storage.lock().insert(
                                                     slot,
                                                     Connection {
                                                         stop_handle,
+                                                        abort_write,
                                                         tunnel_to_tcp_tx,
                                                         compressors: compressors.clone(),
+                                                        flow_control: flow_control.clone(),
+                                                        stats: slot_stats.clone(),
                                                     });
 
                                                 tokio::spawn({
@@ -595,16 +796,40 @@ pub async fn client_listener(
 
                                                     async move {
                                                         let forward_to_tunnel = {
-                                                            shadow_clone!(mut outgoing_messages_tx, compressors);
+                                                            shadow_clone!(mut outgoing_messages_tx, compressors, flow_control);
+
+                                                            let forward = async move {
+                                                                let mut write_closed = false;
 
-                                                            async move {
                                                                 while let Some(buf) = rx.next().await {
-                                                                    for chunk in buf.chunks(MAX_PAYLOAD_LEN) {
+                                                                    if buf.is_empty() {
+                                                                        // the internal server has shut down writing
+                                                                        outgoing_messages_tx.send((
+                                                                            ClientPacket {
+                                                                                header: ClientHeader::Common(CommonHeader::WriteClosed),
+                                                                                slot,
+                                                                            },
+                                                                            Default::default()
+                                                                        ))
+                                                                            .await
+                                                                            .map_err(|_|
+                                                                                io::Error::new(io::ErrorKind::Other, "channel closed")
+                                                                            )?;
+                                                                        write_closed = true;
+                                                                        continue;
+                                                                    }
+
+                                                                    for chunk in buf.chunks(SlotFlowControl::max_chunk(flow_control.as_ref())) {
+                                                                        if let Some(flow_control) = &flow_control {
+                                                                            flow_control.send.acquire(chunk.len()).await;
+                                                                        }
+
                                                                         let buf = chunk.to_vec();
                                                                         let (maybe_compressed, is_compressed) = compressors
                                                                             .lock()
                                                                             .compressor
                                                                             .compress(buf);
+                                                                        slot_stats.sent(chunk.len(), maybe_compressed.len());
 
                                                                         outgoing_messages_tx.send((
                                                                             ClientPacket {
@@ -619,17 +844,45 @@ pub async fn client_listener(
                                                                     }
                                                                 }
 
+                                                                if half_close && !write_closed {
+                                                                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection dropped"));
+                                                                }
+
                                                                 Ok::<(), io::Error>(())
-                                                            }.fuse()
+                                                            };
+
+                                                            Abortable::new(forward, abort_write_registration)
+                                                                .map(|res| res.unwrap_or(Ok(())))
+                                                                .fuse()
                                                         };
 
                                                         let forward_to_internal_server = {
+                                                            shadow_clone!(mut outgoing_messages_tx);
+
                                                             async move {
                                                                 while let Some(buf) = tunnel_to_tcp_rx.next().await {
+                                                                    let len = buf.len();
                                                                     tx
                                                                         .send(buf)
                                                                         .await
-                                                                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "channel closed"))?
+                                                                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "channel closed"))?;
+
+                                                                    let maybe_increment = flow_control
+                                                                        .as_ref()
+                                                                        .and_then(|flow_control| flow_control.recv.lock().consumed(len));
+                                                                    if let Some(increment) = maybe_increment {
+                                                                        outgoing_messages_tx.send((
+                                                                            ClientPacket {
+                                                                                header: ClientHeader::Common(CommonHeader::WindowUpdate),
+                                                                                slot,
+                                                                            },
+                                                                            encode_window_update(increment)
+                                                                        ))
+                                                                            .await
+                                                                            .map_err(|_|
+                                                                                io::Error::new(io::ErrorKind::Other, "channel closed")
+                                                                            )?;
+                                                                    }
                                                                 }
                                                                 Ok::<(), io::Error>(())
                                                             }.fuse()
@@ -639,9 +892,13 @@ pub async fn client_listener(
                                                             shadow_clone!(mut outgoing_messages_tx);
 
                                                             async move {
-                                                                let res = tokio::select! {
-                                                                    res = forward_to_tunnel => res,
-                                                                    res = forward_to_internal_server => res,
+                                                                let res = if half_close {
+                                                                    future::try_join(forward_to_tunnel, forward_to_internal_server).await.map(|_| ())
+                                                                } else {
+                                                                    tokio::select! {
+                                                                        res = forward_to_tunnel => res,
+                                                                        res = forward_to_internal_server => res,
+                                                                    }
                                                                 };
 
                                                                 debug!("connection on slot {} closed {:?}", slot, res);
@@ -688,10 +945,17 @@ pub async fn client_listener(
                                              .clone(),
                                          client_connection
                                              .compressors
+                                             .clone(),
+                                         client_connection
+                                             .flow_control
+                                             .clone(),
+                                         client_connection
+                                             .stats
                                              .clone()
                                         )
                                     });
-                                if let Some((mut tunnel_to_tcp_tx, compressors)) = maybe_slot_info {
+                                if let Some((mut tunnel_to_tcp_tx, compressors, flow_control, slot_stats)) = maybe_slot_info {
+                                    let wire_len = payload.len();
                                     let decompressed = if let ServerHeader::Common(CommonHeader::DataCompressed) = header {
                                         compressors
                                             .lock()
@@ -700,6 +964,11 @@ pub async fn client_listener(
                                     } else {
                                         payload
                                     };
+                                    slot_stats.received(wire_len, decompressed.len());
+
+                                    if let Some(flow_control) = flow_control {
+                                        flow_control.recv.lock().received(decompressed.len())?;
+                                    }
 
                                     tunnel_to_tcp_tx
                                         .send(decompressed)
@@ -716,9 +985,7 @@ pub async fn client_listener(
                                     .lock()
                                     .remove(&slot)
                                 {
-                                    slot
-                                        .stop_handle
-                                        .stop(());
+                                    slot.closed_by_peer();
                                 } else if just_closed_by_us.lock().get(&slot).is_none() {
                                     warn!("unknown slot {}, closing connection", slot);
                                     return Err(Error::UnknownSlot(slot));
@@ -726,8 +993,32 @@ pub async fn client_listener(
                                     debug!("ignore unknown slot, possible race-condition");
                                 }
                             }
+                            ServerHeader::Common(CommonHeader::WriteClosed) => {
+                                if let Some(slot) = storage
+                                    .lock()
+                                    .get(&slot)
+                                {
+                                    slot.write_closed_by_peer();
+                                } else if just_closed_by_us.lock().get(&slot).is_none() {
+                                    warn!("unknown slot {}, closing connection", slot);
+                                    return Err(Error::UnknownSlot(slot));
+                                } else {
+                                    debug!("ignore unknown slot, possible race-condition");
+                                }
+                            }
+                            ServerHeader::Common(CommonHeader::WindowUpdate) => {
+                                let increment = decode_window_update(&payload)?;
+                                let maybe_flow_control = storage
+                                    .lock()
+                                    .get(&slot)
+                                    .and_then(|client_connection| client_connection.flow_control.clone());
+                                if let Some(flow_control) = maybe_flow_control {
+                                    flow_control.send.grant(increment)?;
+                                } else {
+                                    debug!("ignore window update on slot {} without flow control", slot);
+                                }
+                            }
                             ServerHeader::Common(CommonHeader::Ping) => {
-                                let payload = vec![];
                                 outgoing_messages_tx.send((
                                     ClientPacket {
                                         header: ClientHeader::Common(CommonHeader::Pong),
@@ -737,8 +1028,12 @@ pub async fn client_listener(
                                 )).await?;
                             },
                             ServerHeader::Common(CommonHeader::Pong) => {
+                                stats.rtt().pong_received(&payload);
                                 received_pongs_tx.send(()).await?;
                             },
+                            ServerHeader::Common(CommonHeader::Ack) => {
+                                debug!("ignore ack outside of resumable session");
+                            },
                         }
                     }
                     Err(e) => {
@@ -769,6 +1064,10 @@ pub async fn client_listener(
         _r = periodic_pinger => Ok::<bool, crate::tunnel::error::Error>(true),
     };
 
+    for (_, conn) in storage.lock().drain() {
+        conn.stop_handle.stop(());
+    }
+
     match &res {
         Ok(r) => {
             info!(will_reconnect = %r, "tunnel closed");
@@ -781,8 +1080,221 @@ pub async fn client_listener(
     Ok(res?)
 }
 
+/// Datagram slot, requested by the server
+struct DatagramSlot {
+    slot: Slot,
+    upstream: Upstream,
+    compression: Compression,
+    adaptive_compression: bool,
+    balance_key: Option<SmolStr>,
+    flow_control: Option<SlotFlowControl>,
+    accepted_payload: Vec<u8>,
+    pending: PendingSlot,
+}
+
+/// Exchange datagrams between the slot and the UDP upstream, sending each datagram in
+/// a separate data frame. The slot is closed after `udp-idle-timeout` of inactivity.
+async fn forward_datagrams(
+    datagram_slot: DatagramSlot,
+    upstream_target: Option<UpstreamDefinition>,
+    resolver: TokioAsyncResolver,
+    balancer: UpstreamBalancer,
+    storage: Arc<Mutex<HashMap<Slot, Connection>>>,
+    just_closed_by_us: Arc<Mutex<LruCache<Slot, ()>>>,
+    mut outgoing_messages_tx: mpsc::Sender<(ClientPacket, Vec<u8>)>,
+) -> Result<(), crate::tunnel::Error> {
+    let DatagramSlot {
+        slot,
+        upstream,
+        compression,
+        adaptive_compression,
+        balance_key,
+        flow_control,
+        accepted_payload,
+        pending,
+    } = datagram_slot;
+
+    let refused = |error_message: String| RejectionReason::ConnectionRefused { error_message };
+
+    let res = async {
+        let upstream_target = match upstream_target {
+            Some(upstream_target) if upstream_target.protocol == UpstreamProtocol::Udp => {
+                upstream_target
+            }
+            Some(_) => return Err(refused("upstream doesn't accept datagrams".to_string())),
+            None => return Err(RejectionReason::UpstreamNotFound),
+        };
+
+        let endpoints = resolve_endpoints(&resolver, &upstream, &upstream_target)
+            .await
+            .map_err(|e| refused(e.to_string()))?;
+
+        let (socket, connection_guard) = balancer
+            .connect_udp(
+                &upstream,
+                upstream_target.load_balancing,
+                endpoints,
+                balance_key.as_deref(),
+            )
+            .await
+            .map_err(|e| refused(e.to_string()))?;
+
+        Ok((socket, connection_guard, upstream_target.udp_idle_timeout()))
+    }
+    .await;
+
+    let (socket, _connection_guard, idle_timeout) = match res {
+        Ok(r) => r,
+        Err(rejection) => {
+            info!("error opening datagrams to upstream {}: {}", upstream, rejection);
+            pending.rejected(&rejection);
+
+            outgoing_messages_tx
+                .send((
+                    ClientPacket {
+                        header: ClientHeader::Rejected,
+                        slot,
+                    },
+                    serde_cbor::to_vec(&rejection).unwrap(),
+                ))
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let slot_stats = pending.accepted();
+
+    outgoing_messages_tx
+        .send((
+            ClientPacket {
+                header: ClientHeader::Accepted,
+                slot,
+            },
+            accepted_payload,
+        ))
+        .await?;
+
+    let (tunnel_to_socket_tx, mut tunnel_to_socket_rx) = slot_channel(flow_control.as_ref());
+    let (stop_handle, stop_wait) = stop_handle::<()>();
+    let (abort_write, abort_write_registration) = AbortHandle::new_pair();
+
+    let compressors = Arc::new(Mutex::new(Compressors::new(compression, adaptive_compression)));
+
+    storage.lock().insert(
+        slot,
+        Connection {
+            stop_handle,
+            abort_write,
+            tunnel_to_tcp_tx: tunnel_to_socket_tx,
+            compressors: compressors.clone(),
+            flow_control: flow_control.clone(),
+            stats: slot_stats.clone(),
+        },
+    );
+
+    let forward = {
+        shadow_clone!(mut outgoing_messages_tx);
+
+        async move {
+            let max_chunk = SlotFlowControl::max_chunk(flow_control.as_ref());
+            let mut buf = vec![0; MAX_PAYLOAD_LEN];
+
+            loop {
+                tokio::select! {
+                    res = socket.recv(&mut buf) => {
+                        let num_bytes = res?;
+                        if num_bytes > max_chunk {
+                            warn!("drop datagram of {} bytes on slot {}, exceeding {} bytes", num_bytes, slot, max_chunk);
+                            continue;
+                        }
+
+                        if let Some(flow_control) = &flow_control {
+                            flow_control.send.acquire(num_bytes).await;
+                        }
+
+                        let (maybe_compressed, is_compressed) = compressors
+                            .lock()
+                            .compressor
+                            .compress(buf[..num_bytes].to_vec());
+                        slot_stats.sent(num_bytes, maybe_compressed.len());
+
+                        outgoing_messages_tx
+                            .send((
+                                ClientPacket {
+                                    header: ClientHeader::Common(if is_compressed {
+                                        CommonHeader::DataCompressed
+                                    } else {
+                                        CommonHeader::DataPlain
+                                    }),
+                                    slot,
+                                },
+                                maybe_compressed,
+                            ))
+                            .await
+                            .map_err(|_| io::Error::new(io::ErrorKind::Other, "channel closed"))?;
+                    }
+                    maybe_datagram = tunnel_to_socket_rx.next() => {
+                        let datagram = match maybe_datagram {
+                            Some(datagram) => datagram,
+                            None => break,
+                        };
+
+                        socket.send(&datagram).await?;
+
+                        let maybe_increment = flow_control
+                            .as_ref()
+                            .and_then(|flow_control| flow_control.recv.lock().consumed(datagram.len()));
+                        if let Some(increment) = maybe_increment {
+                            outgoing_messages_tx
+                                .send((
+                                    ClientPacket {
+                                        header: ClientHeader::Common(CommonHeader::WindowUpdate),
+                                        slot,
+                                    },
+                                    encode_window_update(increment),
+                                ))
+                                .await
+                                .map_err(|_| io::Error::new(io::ErrorKind::Other, "channel closed"))?;
+                        }
+                    }
+                    _ = sleep(idle_timeout) => {
+                        debug!("datagrams on slot {} idle for {:?}", slot, idle_timeout);
+                        break;
+                    }
+                }
+            }
+
+            Ok::<(), io::Error>(())
+        }
+    };
+
+    let res = tokio::select! {
+        res = Abortable::new(forward, abort_write_registration) => res.unwrap_or(Ok(())),
+        _ = stop_wait => Ok(()),
+    };
+
+    debug!("datagrams on slot {} closed {:?}", slot, res);
+
+    if storage.lock().remove(&slot).is_some() {
+        let _ = outgoing_messages_tx
+            .send((
+                ClientPacket {
+                    header: ClientHeader::Common(CommonHeader::Closed),
+                    slot,
+                },
+                Default::default(),
+            ))
+            .await;
+    }
+
+    just_closed_by_us.lock().insert(slot, ());
+
+    Ok(())
+}
+
 pub enum ServerConnection {
-    Initiating((oneshot::Sender<Box<dyn Conn>>, Compression)),
+    Initiating((oneshot::Sender<MixedChannel>, Compression, bool, SlotKind, PendingSlot)),
     Established(Connection),
 }
 
@@ -808,16 +1320,26 @@ impl ServerConnection {
         }
     }
 
-    fn get_compression(&self) -> Option<Compression> {
+    /// Compression and whether it's adaptive
+    fn get_compression(&self) -> Option<(Compression, bool)> {
+        match self {
+            ServerConnection::Initiating((_, compression, adaptive_compression, _, _)) => {
+                Some((*compression, *adaptive_compression))
+            }
+            ServerConnection::Established(_) => None,
+        }
+    }
+
+    fn get_kind(&self) -> Option<SlotKind> {
         match self {
-            ServerConnection::Initiating((_, compression)) => Some(*compression),
+            ServerConnection::Initiating((_, _, _, kind, _)) => Some(*kind),
             ServerConnection::Established(_) => None,
         }
     }
 
-    fn take_initiating_stream(self) -> Option<oneshot::Sender<Box<dyn Conn>>> {
+    fn take_initiating(self) -> Option<(oneshot::Sender<MixedChannel>, PendingSlot)> {
         match self {
-            ServerConnection::Initiating((tcp_stream, _)) => Some(tcp_stream),
+            ServerConnection::Initiating((tcp_stream, _, _, _, pending)) => Some((tcp_stream, pending)),
             ServerConnection::Established(_) => None,
         }
     }
@@ -832,22 +1354,34 @@ pub fn server_connection(
         + Sink<(ServerPacket, Vec<u8>), Error = Error>
         + Send
         + 'static,
+    negotiated: Negotiated,
+    settings: TunnelSettings,
 ) -> (
     impl Future<Output = Result<(), crate::tunnel::Error>> + Send + 'static,
     crate::tunnel::connector::Connector,
+    TunnelStats,
 ) {
+    let stats = TunnelStats::default();
     let storage = Arc::new(Mutex::new(HashMap::<Slot, ServerConnection>::new()));
     let (new_connection_req_tx, mut new_connection_req_rx) = mpsc::channel(2);
     let just_closed_by_us = Arc::new(Mutex::new(LruCache::<Slot, ()>::with_expiry_duration(
-        Duration::from_secs(5),
+        settings.closed_slot_expiry,
     )));
 
     let (mut received_pongs_tx, received_pongs_rx) = mpsc::channel(1);
 
-    let ping_period = Duration::from_secs(5);
-    let wait_pong_timeout = ping_period * 3;
+    let ping_period = settings.ping_period;
+    let mut wait_pong_timeout = settings.wait_pong_timeout;
+    if negotiated.capabilities.resumption {
+        // pings, sent while the session is detached, are answered after it's resumed
+        wait_pong_timeout += RESUME_WINDOW;
+    }
+
+    let half_close = negotiated.capabilities.half_close;
 
     let f = {
+        shadow_clone!(stats);
+
         async move {
             let slot_counter = Mutex::new(0u32);
 
@@ -856,7 +1390,7 @@ pub fn server_connection(
             let (outgoing_messages_tx, outgoing_messages_rx) = mpsc::channel(16);
 
             let accept_connect_future = {
-                shadow_clone!(mut outgoing_messages_tx, storage);
+                shadow_clone!(mut outgoing_messages_tx, storage, stats);
 
                 #[allow(unreachable_code)]
                 async move {
@@ -864,8 +1398,23 @@ pub fn server_connection(
                         tx: ready_async_channel_tx,
                         target: connect_target,
                         compression,
+                        adaptive_compression,
+                        balance_key,
+                        kind,
                     }) = new_connection_req_rx.next().await
                     {
+                        if kind == SlotKind::Datagrams && !negotiated.capabilities.datagrams {
+                            warn!("client doesn't support datagrams, rejecting slot to {:?}", connect_target);
+                            continue;
+                        }
+
+                        let compression = negotiated.capabilities.compression(compression);
+                        let window = if negotiated.capabilities.flow_control {
+                            Some(INITIAL_WINDOW)
+                        } else {
+                            None
+                        };
+
                         let slot = {
                             let mut locked_slot_counter = slot_counter.lock();
 
@@ -883,9 +1432,10 @@ pub fn server_connection(
                                 }
                             }
                         };
+                        let pending = stats.slot_requested(&connect_target);
                         storage.lock().insert(
                             slot,
-                            ServerConnection::Initiating((ready_async_channel_tx, compression)),
+                            ServerConnection::Initiating((ready_async_channel_tx, compression, adaptive_compression, kind, pending)),
                         );
 
                         outgoing_messages_tx
@@ -897,6 +1447,10 @@ pub fn server_connection(
                                 serde_cbor::to_vec(&ConnectRequestPayload {
                                     target: connect_target,
                                     compression,
+                                    balance_key,
+                                    window,
+                                    kind,
+                                    adaptive_compression,
                                 })
                                 .unwrap(),
                             ))
@@ -910,7 +1464,7 @@ pub fn server_connection(
             };
 
             let read_future = {
-                shadow_clone!(storage, mut outgoing_messages_tx);
+                shadow_clone!(storage, mut outgoing_messages_tx, stats);
 
                 async move {
                     while let Some(res) = rx.next().await {
@@ -918,28 +1472,41 @@ pub fn server_connection(
                             Ok((ClientPacket { header, slot }, payload)) => {
                                 match header {
                                     ClientHeader::Accepted => {
+                                        let flow_control = AcceptedPayload::decode(&payload)?
+                                            .map(|accepted| SlotFlowControl::new(accepted.window, INITIAL_WINDOW));
+
                                         let s = &mut *storage.lock();
 
                                         match s.entry(slot) {
-                                            Entry::Occupied(mut e) => {
+                                            Entry::Occupied(e) => {
                                                 if e.get().is_initiating() {
-                                                    let (tunnel_to_tcp_tx, mut tunnel_to_channel) = mpsc::channel::<Vec<u8>>(4);
+                                                    let (tunnel_to_tcp_tx, mut tunnel_to_channel) = slot_channel(flow_control.as_ref());
                                                     let (stop_handle, mut stop_wait) = stop_handle::<()>();
+                                                    let (abort_write, abort_write_registration) = AbortHandle::new_pair();
+
+                                                    let (compression, adaptive_compression) = e.get().get_compression().unwrap();
+                                                    let kind = e.get().get_kind().unwrap();
+                                                    // datagram slots are closed in both directions at once
+                                                    let half_close = half_close && kind == SlotKind::Stream;
 
-                                                    let compression = e.get().get_compression().unwrap();
+                                                    let compressors = Arc::new(Mutex::new(Compressors::new(compression, adaptive_compression)));
 
-                                                    let compressors = Arc::new(Mutex::new(Compressors::new(compression)));
+                                                    let (ready_connection_resolver, pending) = e.remove().take_initiating().unwrap();
+                                                    let slot_stats = pending.accepted();
 
-                                                    let ready_connection_resolver = mem::replace(e.get_mut(), ServerConnection::Established(Connection {
+                                                    s.insert(slot, ServerConnection::Established(Connection {
                                                         stop_handle,
+                                                        abort_write,
                                                         tunnel_to_tcp_tx,
                                                         compressors: compressors.clone(),
-                                                    })).take_initiating_stream().unwrap();
+                                                        flow_control: flow_control.clone(),
+                                                        stats: slot_stats.clone(),
+                                                    }));
 
-                                                    let (channel, mut from_tunnel_tx, mut to_tunnel_rx) = to_async_rw(16, 16);
+                                                    let (channel, mut from_tunnel_tx, mut to_tunnel_rx) = MixedChannel::new(16, 16);
 
                                                     ready_connection_resolver
-                                                        .send(Box::new(channel.compat()))
+                                                        .send(channel.with_half_close(half_close))
                                                         .map_err(|_| io::Error::new(io::ErrorKind::Other, "tunnel closed: could ot sed to read_connection_resolver"))?;
 
                                                     tokio::spawn({
@@ -947,17 +1514,48 @@ pub fn server_connection(
 
                                                         async move {
                                                             let forward_to_tunnel = {
-                                                                shadow_clone!(outgoing_messages_tx, compressors);
+                                                                shadow_clone!(outgoing_messages_tx, compressors, flow_control);
+
+                                                                let forward = async move {
+                                                                    let mut write_closed = false;
 
-                                                                async move {
                                                                     while let Some(buf) = to_tunnel_rx.next().await {
-                                                                        for chunk in buf.chunks(MAX_PAYLOAD_LEN) {
+                                                                        if buf.is_empty() {
+                                                                            // the connection has shut down writing
                                                                             shadow_clone!(mut outgoing_messages_tx);
 
+                                                                            outgoing_messages_tx.send((
+                                                                                ServerPacket {
+                                                                                    header: ServerHeader::Common(CommonHeader::WriteClosed),
+                                                                                    slot,
+                                                                                },
+                                                                                Default::default()
+                                                                            )).await
+                                                                                .map_err(|_|
+                                                                                    io::Error::new(io::ErrorKind::Other, "channel closed")
+                                                                                )?;
+                                                                            write_closed = true;
+                                                                            continue;
+                                                                        }
+
+                                                                        let max_chunk = SlotFlowControl::max_chunk(flow_control.as_ref());
+                                                                        if kind == SlotKind::Datagrams && buf.len() > max_chunk {
+                                                                            warn!("drop datagram of {} bytes on slot {}, exceeding {} bytes", buf.len(), slot, max_chunk);
+                                                                            continue;
+                                                                        }
+
+                                                                        for chunk in buf.chunks(max_chunk) {
+                                                                            shadow_clone!(mut outgoing_messages_tx);
+
+                                                                            if let Some(flow_control) = &flow_control {
+                                                                                flow_control.send.acquire(chunk.len()).await;
+                                                                            }
+
                                                                             let (maybe_compressed, is_compressed) = compressors
                                                                                 .lock()
                                                                                 .compressor
                                                                                 .compress(chunk.to_vec());
+                                                                            slot_stats.sent(chunk.len(), maybe_compressed.len());
 
                                                                             outgoing_messages_tx.send((
                                                                                 ServerPacket {
@@ -971,18 +1569,45 @@ pub fn server_connection(
                                                                         }
                                                                     }
 
+                                                                    if half_close && !write_closed {
+                                                                        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection dropped"));
+                                                                    }
+
                                                                     Ok::<(), io::Error>(())
-                                                                }.fuse()
+                                                                };
+
+                                                                Abortable::new(forward, abort_write_registration)
+                                                                    .map(|res| res.unwrap_or(Ok(())))
+                                                                    .fuse()
                                                             };
 
                                                             let forward_to_connection = {
+                                                                shadow_clone!(mut outgoing_messages_tx);
+
                                                                 async move {
                                                                     while let Some(buf) = tunnel_to_channel.next().await {
+                                                                        let len = buf.len();
                                                                         from_tunnel_tx
                                                                             .send(buf)
                                                                             .await
                                                                             .map_err(|_| io::Error::new(io::ErrorKind::Other, "tunnel closed: could not send to from_tunnel_tx"))
                                                                             ?;
+
+                                                                        let maybe_increment = flow_control
+                                                                            .as_ref()
+                                                                            .and_then(|flow_control| flow_control.recv.lock().consumed(len));
+                                                                        if let Some(increment) = maybe_increment {
+                                                                            outgoing_messages_tx.send((
+                                                                                ServerPacket {
+                                                                                    header: ServerHeader::Common(CommonHeader::WindowUpdate),
+                                                                                    slot,
+                                                                                },
+                                                                                encode_window_update(increment)
+                                                                            )).await
+                                                                                .map_err(|_|
+                                                                                    io::Error::new(io::ErrorKind::Other, "channel closed")
+                                                                                )?;
+                                                                        }
                                                                     }
                                                                     Ok::<(), io::Error>(())
                                                                 }.fuse()
@@ -992,9 +1617,13 @@ pub fn server_connection(
                                                                 shadow_clone!(mut outgoing_messages_tx, just_closed_by_us);
 
                                                                 async move {
-                                                                    let res = tokio::select! {
-                                                                        res = forward_to_tunnel => res,
-                                                                        res = forward_to_connection => res,
+                                                                    let res = if half_close {
+                                                                        future::try_join(forward_to_tunnel, forward_to_connection).await.map(|_| ())
+                                                                    } else {
+                                                                        tokio::select! {
+                                                                            res = forward_to_tunnel => res,
+                                                                            res = forward_to_connection => res,
+                                                                        }
                                                                     };
 
                                                                     debug!("connection on slot {} closed {:?}", slot, res);
@@ -1054,7 +1683,9 @@ pub fn server_connection(
                                         {
                                             Entry::Occupied(a) => {
                                                 if a.get().is_initiating() {
-                                                    a.remove_entry();
+                                                    if let Some((_, pending)) = a.remove().take_initiating() {
+                                                        pending.rejected(&error);
+                                                    }
                                                 } else {
                                                     warn!("received Rejected while connection is not in Initiating state");
                                                     return Err(Error::ConnectionHandshakeOnEstablishedConnection);
@@ -1077,6 +1708,7 @@ pub fn server_connection(
                                             .map(|r| r.established().cloned());
                                         if let Some(slot) = res {
                                             if let Some(conn) = slot {
+                                                let wire_len = payload.len();
                                                 let decompressed = if let ClientHeader::Common(CommonHeader::DataCompressed) = header {
                                                     conn
                                                         .compressors
@@ -1086,6 +1718,11 @@ pub fn server_connection(
                                                 } else {
                                                     payload
                                                 };
+                                                conn.stats.received(wire_len, decompressed.len());
+
+                                                if let Some(flow_control) = &conn.flow_control {
+                                                    flow_control.recv.lock().received(decompressed.len())?;
+                                                }
 
                                                 conn
                                                     .tunnel_to_tcp_tx
@@ -1109,15 +1746,45 @@ pub fn server_connection(
                                             .remove(&slot)
                                         {
                                             if let Some(conn) = slot.into_established() {
-                                                conn.stop_handle.stop(());
+                                                conn.closed_by_peer();
                                             } else {
                                                 warn!("closed command received during connection initialization");
                                                 return Err(Error::CommandOnInitiatingConnection);
                                             }
                                         }
                                     }
+                                    ClientHeader::Common(CommonHeader::WriteClosed) => {
+                                        let res = storage
+                                            .lock()
+                                            .get(&slot)
+                                            .map(|r| r.established().cloned());
+                                        match res {
+                                            Some(Some(conn)) => {
+                                                conn.write_closed_by_peer();
+                                            }
+                                            Some(None) => {
+                                                warn!("write closed received during connection initialization");
+                                                return Err(Error::CommandOnInitiatingConnection);
+                                            }
+                                            None => {
+                                                debug!("ignore write closed on unknown slot {}", slot);
+                                            }
+                                        }
+                                    }
+                                    ClientHeader::Common(CommonHeader::WindowUpdate) => {
+                                        let increment = decode_window_update(&payload)?;
+                                        let maybe_flow_control = storage
+                                            .lock()
+                                            .get(&slot)
+                                            .and_then(|r| r.established())
+                                            .and_then(|conn| conn.flow_control.clone());
+                                        if let Some(flow_control) = maybe_flow_control {
+                                            flow_control.send.grant(increment)?;
+                                        } else {
+                                            debug!("ignore window update on slot {} without flow control", slot);
+                                        }
+                                    }
                                     ClientHeader::Common(CommonHeader::Ping) => {
-                                        let payload = vec![];
                                         outgoing_messages_tx.send((
                                             ServerPacket {
                                                 header: ServerHeader::Common(CommonHeader::Pong),
@@ -1127,8 +1794,12 @@ pub fn server_connection(
                                         )).await?;
                                     }
                                     ClientHeader::Common(CommonHeader::Pong) => {
+                                        stats.rtt().pong_received(&payload);
                                         received_pongs_tx.send(()).await?;
                                     }
+                                    ClientHeader::Common(CommonHeader::Ack) => {
+                                        debug!("ignore ack outside of resumable session");
+                                    }
                                 }
                             }
                             Err(e) => {
@@ -1154,12 +1825,12 @@ pub fn server_connection(
 
             #[allow(unreachable_code)]
             let periodic_pinger = {
-                shadow_clone!(mut outgoing_messages_tx);
+                shadow_clone!(mut outgoing_messages_tx, stats);
 
                 async move {
                     loop {
                         sleep(ping_period).await;
-                        let payload = vec![];
+                        let payload = stats.rtt().ping_payload();
                         outgoing_messages_tx
                             .send((
                                 ServerPacket {
@@ -1182,7 +1853,7 @@ pub fn server_connection(
             pin_mut!(pongs_timeout);
             pin_mut!(periodic_pinger);
 
-            select_biased! {
+            let r = select_biased! {
                 r = accept_connect_future => r,
                 r = read_future => r,
                 r = write_future => r,
@@ -1191,11 +1862,19 @@ pub fn server_connection(
                     warn!("timeout waiting for pong on tunnel. closing");
                     Ok(())
                 }
+            };
+
+            for (_, conn) in storage.lock().drain() {
+                if let Some(conn) = conn.into_established() {
+                    conn.stop_handle.stop(());
+                }
             }
+
+            r
         }
     };
 
-    (f, Connector::new(new_connection_req_tx))
+    (f, Connector::new(new_connection_req_tx), stats)
 }
 
 pub struct TunneledConnection {
@@ -1237,6 +1916,8 @@ impl AsyncWrite for TunneledConnection {
         Pin::new(&mut self.inner).poll_flush(cx)
     }
 
+    /// If half-close is negotiated for the tunnel, only writing is shut down, and the
+    /// data from the upstream may still be read
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -1250,15 +1931,19 @@ mod test {
     use crate::config_core::CURRENT_VERSION;
     use std::net::{IpAddr, SocketAddr};
 
-    use tokio::net::TcpListener;
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
     use crate::tunnel::framed::{client_framed, server_framed};
 
     use super::*;
-    use crate::config_core::{
-        refinable::Refinable, ClientConfig, ClientConfigRevision, UpstreamDefinition,
+    use crate::{
+        config_core::{
+            refinable::Refinable, ClientConfig, ClientConfigRevision, DurationWrapper,
+            UpstreamDefinition,
+        },
+        tunnel::harness::{EchoUpstream, TunnelHarness},
     };
-    use std::collections::BTreeMap;
+    use std::{collections::BTreeMap, mem, time::Duration};
     use trust_dns_resolver::TokioHandle;
 
     #[tokio::test]
@@ -1282,7 +1967,11 @@ mod test {
             async move {
                 let (server_side, _remote_addr) = server_side_listener.accept().await.unwrap();
 
-                let (bg, connector) = server_connection(server_framed(server_side));
+                let (bg, connector, _) = server_connection(
+                    server_framed(server_side),
+                    Negotiated::current(),
+                    Default::default(),
+                );
 
                 tokio::spawn(bg);
 
@@ -1300,7 +1989,7 @@ mod test {
                 let mut tunneled2 = connector
                     .retrieve_connection(
                         "backend.upstream.exg".parse().unwrap(),
-                        Compression::Plain,
+                        Compression::ZstdStream,
                     )
                     .await
                     .unwrap();
@@ -1351,6 +2040,7 @@ mod test {
                 name: "my-config".parse().unwrap(),
                 mount_points: Default::default(),
                 upstreams,
+                rate_limiters: Default::default(),
                 refinable: Refinable {
                     static_responses: Default::default(),
                     rescue: vec![],
@@ -1365,15 +2055,17 @@ mod test {
             async move {
                 let client_side = TcpStream::connect(&server_side_socket).await.unwrap();
 
-                client_listener(
+                let (listener, _) = client_listener(
                     client_framed(client_side),
+                    Negotiated::current(),
+                    Default::default(),
                     client_config,
                     internal_server_connector,
                     &None,
                     resolver,
-                )
-                .await
-                .unwrap();
+                    Default::default(),
+                );
+                listener.await.unwrap();
             }
         });
 
@@ -1404,4 +2096,196 @@ mod test {
 
         send_handle.await.unwrap();
     }
+
+    async fn loopback_listener() -> (TcpListener, u16) {
+        let listener = TcpListener::bind(&SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[tokio::test]
+    async fn test_stalled_slot_does_not_block_others() {
+        // accepts connections, but never reads from them
+        let (stalled_upstream, stalled_port) = loopback_listener().await;
+        tokio::spawn(async move {
+            let mut accepted = vec![];
+            while let Ok((conn, _)) = stalled_upstream.accept().await {
+                accepted.push(conn);
+            }
+        });
+
+        let echo_upstream = EchoUpstream::start().await.unwrap();
+
+        let mut upstreams = BTreeMap::new();
+        upstreams.insert(
+            "stalled".parse().unwrap(),
+            UpstreamDefinition::on_default_host(stalled_port),
+        );
+        upstreams.insert("echo".parse().unwrap(), echo_upstream.definition());
+
+        let harness =
+            TunnelHarness::start(Negotiated::current(), Default::default(), upstreams).unwrap();
+        let connector = &harness.connector;
+
+        let mut stalled = connector
+            .retrieve_connection("stalled.upstream.exg".parse().unwrap(), Compression::Plain)
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            let buf = vec![1; 1024 * 1024];
+            loop {
+                if stalled.write_all(&buf).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // let the stalled upstream fill all the buffers
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let echo = async {
+            let mut echo = connector
+                .retrieve_connection("echo.upstream.exg".parse().unwrap(), Compression::Plain)
+                .await
+                .unwrap();
+            let buf = vec![2; MAX_PAYLOAD_LEN * 2];
+            echo.write_all(&buf).await.unwrap();
+            let mut read_buf = vec![0; buf.len()];
+            echo.read_exact(&mut read_buf).await.unwrap();
+            assert_eq!(buf, read_buf);
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), echo)
+            .await
+            .expect("neighbour slot is blocked by the stalled one");
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        // reads the request until EOF, then responds
+        let (respond_after_eof, respond_after_eof_port) = loopback_listener().await;
+        tokio::spawn(async move {
+            let (mut conn, _) = respond_after_eof.accept().await.unwrap();
+            let mut request = vec![];
+            conn.read_to_end(&mut request).await.unwrap();
+            request.reverse();
+            conn.write_all(&request).await.unwrap();
+        });
+
+        // sends the greeting and shuts down writing, then reads until EOF
+        let (greet_first, greet_first_port) = loopback_listener().await;
+        let (greeted_tx, greeted_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut conn, _) = greet_first.accept().await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            conn.shutdown().await.unwrap();
+            let mut buf = vec![];
+            conn.read_to_end(&mut buf).await.unwrap();
+            greeted_tx.send(buf).unwrap();
+        });
+
+        let mut upstreams = BTreeMap::new();
+        upstreams.insert(
+            "respond-after-eof".parse().unwrap(),
+            UpstreamDefinition::on_default_host(respond_after_eof_port),
+        );
+        upstreams.insert(
+            "greet-first".parse().unwrap(),
+            UpstreamDefinition::on_default_host(greet_first_port),
+        );
+
+        let harness =
+            TunnelHarness::start(Negotiated::current(), Default::default(), upstreams).unwrap();
+        let connector = &harness.connector;
+
+        let half_closed = async {
+            let mut conn = connector
+                .retrieve_connection(
+                    "respond-after-eof.upstream.exg".parse().unwrap(),
+                    Compression::Plain,
+                )
+                .await
+                .unwrap();
+            conn.write_all(&[1, 2, 3]).await.unwrap();
+            conn.shutdown().await.unwrap();
+            let mut response = vec![];
+            conn.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, vec![3, 2, 1]);
+
+            let mut conn = connector
+                .retrieve_connection("greet-first.upstream.exg".parse().unwrap(), Compression::Zstd)
+                .await
+                .unwrap();
+            let mut greeting = vec![];
+            conn.read_to_end(&mut greeting).await.unwrap();
+            assert_eq!(greeting, b"hello");
+            conn.write_all(b"bye").await.unwrap();
+            conn.shutdown().await.unwrap();
+            assert_eq!(greeted_rx.await.unwrap(), b"bye");
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), half_closed)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_datagrams() {
+        let echo = UdpSocket::bind(&SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 0))
+            .await
+            .unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65536];
+            loop {
+                let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        let mut upstreams = BTreeMap::new();
+        upstreams.insert(
+            "echo".parse().unwrap(),
+            UpstreamDefinition {
+                protocol: UpstreamProtocol::Udp,
+                udp_idle_timeout: Some(DurationWrapper(Duration::from_millis(300))),
+                ..UpstreamDefinition::on_default_host(echo_port)
+            },
+        );
+        upstreams.insert(
+            "tcp".parse().unwrap(),
+            UpstreamDefinition::on_default_host(echo_port),
+        );
+
+        let harness =
+            TunnelHarness::start(Negotiated::current(), Default::default(), upstreams).unwrap();
+        let connector = &harness.connector;
+
+        let datagrams = async {
+            let mut datagrams = connector
+                .retrieve_datagrams("echo".parse().unwrap(), Compression::ZstdStream)
+                .await
+                .unwrap();
+
+            let sent: Vec<Vec<u8>> = vec![vec![1], vec![2; 1000], vec![3; 30000], vec![4, 5]];
+            for datagram in &sent {
+                datagrams.send(datagram.clone()).await.unwrap();
+                assert_eq!(&datagrams.next().await.unwrap().unwrap(), datagram);
+            }
+
+            // closed on idle timeout
+            assert!(datagrams.next().await.is_none());
+
+            assert!(connector
+                .retrieve_datagrams("tcp".parse().unwrap(), Compression::Plain)
+                .await
+                .is_err());
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), datagrams)
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,848 @@
+//! QUIC transport of tunnels.
+//!
+//! Unlike TLS over TCP, where all the slots are multiplexed into a single
+//! length-delimited stream, each slot is a separate bidirectional QUIC stream. So the
+//! slots don't share the congestion window and don't block each other on packet loss.
+//!
+//! The client opens the control stream and exchanges `TunnelHello` and
+//! `TunnelHelloResponse`, same as over TLS. Then the server opens a stream for each slot
+//! and sends `ConnectRequestPayload`, and the client responds with `SlotResponse`. After
+//! that the stream carries the raw data of the slot. QUIC streams are flow-controlled and
+//! may be finished in each direction independently, so neither flow control nor
+//! half-close requires the support in the tunnel protocol. The data is not compressed,
+//! and datagrams are not supported.
+
+use crate::{
+    config_core::{ClientConfig, UpstreamProtocol},
+    entities::{ProfileName, SmolStr, TunnelId, Upstream},
+    tunnel::{
+        balancer::{resolve_endpoints, ConnectError, ConnectionGuard, UpstreamBalancer},
+        connector::{Compression, ConnectTarget, Connector, ConnectorRequest, SlotKind},
+        handshake::{Capabilities, Negotiated},
+        proto::{ConnectRequestPayload, RejectionReason, TunnelHello, TunnelHelloResponse},
+        settings::TunnelSettings,
+        stats::{SlotStats, TunnelStats},
+        upstream_tls::{UpstreamStream, UpstreamTlsConnectors},
+        Error, MixedChannel,
+    },
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future, Future, SinkExt, StreamExt,
+};
+use parking_lot::RwLock;
+use quinn::{
+    Certificate, CertificateChain, ClientConfigBuilder, ConnectionError, IncomingBiStreams,
+    NewConnection, PrivateKey, RecvStream, SendStream, ServerConfigBuilder, TransportConfig,
+    VarInt,
+};
+use rw_stream_sink::RwStreamSink;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{convert::TryInto, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
+use trust_dns_resolver::TokioAsyncResolver;
+
+pub const QUIC_ALPN_PROTOCOL: &[u8] = b"exotun-quic";
+
+/// Closes the connection of the client, which should not reconnect
+pub const QUIC_CODE_CLOSE_NO_RECONNECT: u32 = 1;
+
+const READ_BUF_LEN: usize = 16 * 1024;
+
+/// Response of the client to `ConnectRequestPayload`
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SlotResponse {
+    Accepted,
+    Rejected(RejectionReason),
+}
+
+impl Capabilities {
+    /// Capabilities of QUIC tunnels. Flow control and half-close are provided by QUIC
+    pub fn quic() -> Self {
+        Capabilities {
+            compression: vec![],
+            flow_control: true,
+            half_close: true,
+            datagrams: false,
+            resumption: false,
+        }
+    }
+}
+
+/// Config of the gateway endpoint. Keep-alives are sent each `ping_period` of settings
+pub fn quic_server_config(
+    cert_chain: CertificateChain,
+    key: PrivateKey,
+    settings: &TunnelSettings,
+) -> Result<quinn::ServerConfig, rustls::TLSError> {
+    let mut config = quinn::ServerConfig::default();
+    config.transport = Arc::new(transport_config(settings));
+
+    let mut builder = ServerConfigBuilder::new(config);
+    builder.certificate(cert_chain, key)?;
+    builder.protocols(&[QUIC_ALPN_PROTOCOL]);
+    Ok(builder.build())
+}
+
+/// Config of the client endpoint, trusting the native certificates, and `extra_ca`, if set
+pub fn quic_client_config(
+    extra_ca: Option<Certificate>,
+    settings: &TunnelSettings,
+) -> Result<quinn::ClientConfig, webpki::Error> {
+    let mut builder = ClientConfigBuilder::default();
+    builder.protocols(&[QUIC_ALPN_PROTOCOL]);
+    if let Some(ca) = extra_ca {
+        builder.add_certificate_authority(ca)?;
+    }
+
+    let mut config = builder.build();
+    config.transport = Arc::new(transport_config(settings));
+    Ok(config)
+}
+
+/// Keep-alives are sent each `ping_period`, and the connection is closed if nothing is
+/// received for `wait_pong_timeout`
+fn transport_config(settings: &TunnelSettings) -> TransportConfig {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(settings.ping_period));
+    // timeouts beyond the bounds of QUIC are effectively infinite
+    if transport
+        .max_idle_timeout(Some(settings.wait_pong_timeout))
+        .is_err()
+    {
+        let _ = transport.max_idle_timeout(None);
+    }
+    transport
+}
+
+/// Copy RTT, estimated by QUIC, to the stats each `ping_period`
+async fn sample_rtt(connection: &quinn::Connection, stats: &TunnelStats, ping_period: Duration) {
+    let mut interval = tokio::time::interval(ping_period);
+    loop {
+        interval.tick().await;
+        stats.rtt().sample(connection.rtt());
+    }
+}
+
+/// Close the connection, so that the client doesn't reconnect
+pub fn close_no_reconnect(connection: &quinn::Connection) {
+    connection.close(VarInt::from_u32(QUIC_CODE_CLOSE_NO_RECONNECT), b"");
+}
+
+async fn write_message<T: Serialize>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    msg: &T,
+) -> Result<(), Error> {
+    let encoded = serde_cbor::to_vec(msg)?;
+    stream.write_u16(encoded.len().try_into()?).await?;
+    stream.write_all(&encoded).await?;
+    Ok(())
+}
+
+async fn read_message<T: DeserializeOwned>(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<T, Error> {
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0u8; len.into()];
+    stream.read_exact(&mut buf).await?;
+    Ok(serde_cbor::from_slice(&buf)?)
+}
+
+/// Established QUIC connection with the control stream
+pub struct QuicConnection {
+    connection: quinn::Connection,
+    bi_streams: IncomingBiStreams,
+    /// Kept open for the lifetime of the tunnel
+    _control: (SendStream, RecvStream),
+}
+
+impl QuicConnection {
+    pub fn connection(&self) -> &quinn::Connection {
+        &self.connection
+    }
+}
+
+/// Connect to the gateway and exchange `TunnelHello` on the control stream
+pub async fn quic_connect(
+    endpoint: &quinn::Endpoint,
+    addr: SocketAddr,
+    server_name: &str,
+    hello: &TunnelHello,
+) -> Result<(TunnelHelloResponse, QuicConnection), Error> {
+    let NewConnection {
+        connection,
+        bi_streams,
+        ..
+    } = endpoint.connect(&addr, server_name)?.await?;
+
+    let (mut send, mut recv) = connection.open_bi().await?;
+    write_message(&mut send, hello).await?;
+    let response = read_message(&mut recv).await?;
+
+    Ok((
+        response,
+        QuicConnection {
+            connection,
+            bi_streams,
+            _control: (send, recv),
+        },
+    ))
+}
+
+/// Connection of the client, which has sent `TunnelHello`
+pub struct QuicHello {
+    pub hello: TunnelHello,
+    connection: QuicConnection,
+}
+
+/// Accept the connection of the client and read `TunnelHello` from the control stream
+pub async fn quic_accept(connecting: quinn::Connecting) -> Result<QuicHello, Error> {
+    let NewConnection {
+        connection,
+        mut bi_streams,
+        ..
+    } = connecting.await?;
+
+    let (send, mut recv) = bi_streams
+        .next()
+        .await
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no control stream"))??;
+    let hello = read_message(&mut recv).await?;
+
+    Ok(QuicHello {
+        hello,
+        connection: QuicConnection {
+            connection,
+            bi_streams,
+            _control: (send, recv),
+        },
+    })
+}
+
+impl QuicHello {
+    pub fn connection(&self) -> &quinn::Connection {
+        self.connection.connection()
+    }
+
+    /// Accept the tunnel. Returns the future, serving it, the connector to open slots and
+    /// the stats of the tunnel
+    pub async fn accept(
+        mut self,
+        tunnel_id: TunnelId,
+        negotiated: Negotiated,
+        settings: TunnelSettings,
+    ) -> Result<
+        (
+            impl Future<Output = Result<(), Error>> + Send + 'static,
+            Connector,
+            TunnelStats,
+        ),
+        Error,
+    > {
+        write_message(
+            &mut self.connection._control.0,
+            &TunnelHelloResponse::accepted(tunnel_id, negotiated, None),
+        )
+        .await?;
+
+        Ok(quic_server_connection(self.connection, settings))
+    }
+
+    pub async fn reject(mut self, msg: String) -> Result<(), Error> {
+        write_message(
+            &mut self.connection._control.0,
+            &TunnelHelloResponse::Err { msg },
+        )
+        .await?;
+        self.connection
+            ._control
+            .0
+            .finish()
+            .await
+            .map_err(io::Error::from)?;
+        Ok(())
+    }
+}
+
+fn quic_server_connection(
+    connection: QuicConnection,
+    settings: TunnelSettings,
+) -> (
+    impl Future<Output = Result<(), Error>> + Send + 'static,
+    Connector,
+    TunnelStats,
+) {
+    let (new_connection_req_tx, mut new_connection_req_rx) = mpsc::channel(2);
+    let stats = TunnelStats::default();
+
+    let f = {
+        let stats = stats.clone();
+
+        async move {
+            let QuicConnection {
+                connection,
+                mut bi_streams,
+                _control,
+            } = connection;
+
+            let accept_connect = async {
+                while let Some(ConnectorRequest {
+                    tx,
+                    target,
+                    balance_key,
+                    kind,
+                    ..
+                }) = new_connection_req_rx.next().await
+                {
+                    if kind == SlotKind::Datagrams {
+                        warn!(
+                            "datagrams are not supported over QUIC, rejecting slot to {:?}",
+                            target
+                        );
+                        continue;
+                    }
+
+                    tokio::spawn({
+                        let connection = connection.clone();
+                        let stats = stats.clone();
+
+                        async move {
+                            if let Err(e) =
+                                open_slot(connection, tx, target, balance_key, stats).await
+                            {
+                                debug!("QUIC slot closed with error: {}", e);
+                            }
+                        }
+                    });
+                }
+
+                Ok::<(), Error>(())
+            };
+
+            let incoming_streams = async {
+                // the client doesn't open streams besides the control one
+                while let Some(res) = bi_streams.next().await {
+                    res?;
+                    warn!("unexpected stream, opened by the client");
+                }
+
+                Ok::<(), Error>(())
+            };
+
+            tokio::select! {
+                r = accept_connect => r,
+                r = incoming_streams => r,
+                _ = sample_rtt(&connection, &stats, settings.ping_period) => Ok(()),
+            }
+        }
+    };
+
+    (f, Connector::new(new_connection_req_tx), stats)
+}
+
+async fn open_slot(
+    connection: quinn::Connection,
+    ready_channel_tx: oneshot::Sender<MixedChannel>,
+    target: ConnectTarget,
+    balance_key: Option<SmolStr>,
+    stats: TunnelStats,
+) -> Result<(), Error> {
+    let pending = stats.slot_requested(&target);
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    write_message(
+        &mut send,
+        &ConnectRequestPayload {
+            target,
+            compression: Compression::Plain,
+            balance_key,
+            window: None,
+            kind: SlotKind::Stream,
+            adaptive_compression: false,
+        },
+    )
+    .await?;
+
+    match read_message(&mut recv).await? {
+        SlotResponse::Accepted => {}
+        SlotResponse::Rejected(reason) => {
+            info!(
+                "slot connection in tunnel rejected by client with reason: {}",
+                reason
+            );
+            pending.rejected(&reason);
+            return Ok(());
+        }
+    }
+    let slot_stats = pending.accepted();
+
+    let (channel, from_tunnel_tx, to_tunnel_rx) = MixedChannel::new(16, 16);
+    if ready_channel_tx
+        .send(channel.with_half_close(true))
+        .is_err()
+    {
+        debug!("slot is not awaited anymore");
+        return Ok(());
+    }
+
+    forward_channel(send, recv, from_tunnel_tx, to_tunnel_rx, slot_stats).await?;
+
+    Ok(())
+}
+
+/// Forward the data between the QUIC stream and the channel in half-close mode. Empty
+/// buffer from the channel finishes the stream, and the end of the stream drops the sender.
+/// The data is not compressed, so wire bytes are counted same as the payload
+async fn forward_channel(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    mut from_quic_tx: mpsc::Sender<Vec<u8>>,
+    mut to_quic_rx: mpsc::Receiver<Vec<u8>>,
+    stats: SlotStats,
+) -> io::Result<()> {
+    let to_quic = async {
+        while let Some(buf) = to_quic_rx.next().await {
+            if buf.is_empty() {
+                send.finish().await?;
+                return Ok(());
+            }
+            send.write_all(&buf).await?;
+            stats.sent(buf.len(), buf.len());
+        }
+
+        let _ = send.reset(VarInt::from_u32(0));
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "connection dropped",
+        ))
+    };
+
+    let from_quic = async {
+        let mut buf = vec![0u8; READ_BUF_LEN];
+        loop {
+            let num_bytes = match recv.read(&mut buf).await? {
+                Some(num_bytes) => num_bytes,
+                None => return Ok(()),
+            };
+            stats.received(num_bytes, num_bytes);
+            from_quic_tx
+                .send(buf[..num_bytes].to_vec())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "channel closed"))?;
+        }
+    };
+
+    future::try_join(to_quic, from_quic).await.map(|_| ())
+}
+
+/// Forward the data between the QUIC stream and the upstream connection
+async fn forward_upstream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    upstream: UpstreamStream,
+    stats: SlotStats,
+) -> io::Result<()> {
+    let (mut from_upstream, mut to_upstream) = tokio::io::split(upstream);
+
+    let to_quic = async {
+        let mut buf = vec![0u8; READ_BUF_LEN];
+        loop {
+            let num_bytes = from_upstream.read(&mut buf).await?;
+            if num_bytes == 0 {
+                send.finish().await?;
+                return Ok(());
+            }
+            send.write_all(&buf[..num_bytes]).await?;
+            stats.sent(num_bytes, num_bytes);
+        }
+    };
+
+    let from_quic = async {
+        let mut buf = vec![0u8; READ_BUF_LEN];
+        while let Some(num_bytes) = recv.read(&mut buf).await? {
+            to_upstream.write_all(&buf[..num_bytes]).await?;
+            stats.received(num_bytes, num_bytes);
+        }
+        to_upstream.shutdown().await
+    };
+
+    future::try_join(to_quic, from_quic).await.map(|_| ())
+}
+
+/// Serve slots, opened by the gateway, until the connection is closed. The future returns
+/// true if the tunnel should be reconnected
+#[allow(clippy::too_many_arguments)]
+pub fn quic_client_listener<'a>(
+    connection: QuicConnection,
+    negotiated: Negotiated,
+    settings: TunnelSettings,
+    client_config: Arc<RwLock<ClientConfig>>,
+    internal_server_connector: mpsc::Sender<RwStreamSink<MixedChannel>>,
+    active_profile: &'a Option<ProfileName>,
+    resolver: TokioAsyncResolver,
+    balancer: UpstreamBalancer,
+) -> (impl Future<Output = Result<bool, Error>> + 'a, TunnelStats) {
+    let stats = TunnelStats::default();
+
+    let listener = quic_listen(
+        connection,
+        negotiated,
+        settings,
+        stats.clone(),
+        client_config,
+        internal_server_connector,
+        active_profile,
+        resolver,
+        balancer,
+    );
+
+    (listener, stats)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn quic_listen(
+    connection: QuicConnection,
+    negotiated: Negotiated,
+    settings: TunnelSettings,
+    stats: TunnelStats,
+    client_config: Arc<RwLock<ClientConfig>>,
+    internal_server_connector: mpsc::Sender<RwStreamSink<MixedChannel>>,
+    active_profile: &Option<ProfileName>,
+    resolver: TokioAsyncResolver,
+    balancer: UpstreamBalancer,
+) -> Result<bool, Error> {
+    let tls_connectors = UpstreamTlsConnectors::default();
+    let QuicConnection {
+        connection,
+        mut bi_streams,
+        ..
+    } = connection;
+
+    let serve = async {
+        loop {
+            match bi_streams.next().await {
+                Some(Ok((send, recv))) => {
+                    tokio::spawn({
+                        let slot = ClientSlot {
+                            protocol_version: negotiated.protocol_version,
+                            connect_timeout: settings.connect_timeout,
+                            stats: stats.clone(),
+                            client_config: client_config.clone(),
+                            internal_server_connector: internal_server_connector.clone(),
+                            active_profile: active_profile.clone(),
+                            resolver: resolver.clone(),
+                            balancer: balancer.clone(),
+                            tls_connectors: tls_connectors.clone(),
+                        };
+
+                        async move {
+                            if let Err(e) = slot.serve(send, recv).await {
+                                debug!("QUIC slot closed with error: {}", e);
+                            }
+                        }
+                    });
+                }
+                Some(Err(ConnectionError::ApplicationClosed(close)))
+                    if close.error_code == VarInt::from_u32(QUIC_CODE_CLOSE_NO_RECONNECT) =>
+                {
+                    return Ok(false);
+                }
+                Some(Err(e)) => {
+                    warn!("QUIC tunnel closed: {}", e);
+                    return Ok(true);
+                }
+                None => return Ok(true),
+            }
+        }
+    };
+
+    tokio::select! {
+        r = serve => r,
+        _ = sample_rtt(&connection, &stats, settings.ping_period) => Ok(true),
+    }
+}
+
+/// Everything required to serve the slot on the client
+struct ClientSlot {
+    protocol_version: u16,
+    connect_timeout: Duration,
+    stats: TunnelStats,
+    client_config: Arc<RwLock<ClientConfig>>,
+    internal_server_connector: mpsc::Sender<RwStreamSink<MixedChannel>>,
+    active_profile: Option<ProfileName>,
+    resolver: TokioAsyncResolver,
+    balancer: UpstreamBalancer,
+    tls_connectors: UpstreamTlsConnectors,
+}
+
+impl ClientSlot {
+    async fn serve(mut self, mut send: SendStream, mut recv: RecvStream) -> Result<(), Error> {
+        let req: ConnectRequestPayload = read_message(&mut recv).await?;
+        let pending = self.stats.slot_requested(&req.target);
+
+        match (req.target, req.kind) {
+            (_, SlotKind::Datagrams) => {
+                let reason = RejectionReason::ConnectionRefused {
+                    error_message: "datagrams are not supported over QUIC".to_string(),
+                };
+                pending.rejected(&reason);
+                write_message(&mut send, &SlotResponse::Rejected(reason)).await?;
+            }
+            (ConnectTarget::Upstream(upstream), SlotKind::Stream) => {
+                match self.connect(&upstream, req.balance_key.as_deref()).await {
+                    Ok((stream, _connection_guard)) => {
+                        let slot_stats = pending.accepted();
+                        write_message(&mut send, &SlotResponse::Accepted).await?;
+                        forward_upstream(send, recv, stream, slot_stats).await?;
+                    }
+                    Err(reason) => {
+                        pending.rejected(&reason);
+                        let reason = reason.for_peer(self.protocol_version);
+                        write_message(&mut send, &SlotResponse::Rejected(reason)).await?;
+                    }
+                }
+            }
+            (ConnectTarget::Internal(_), SlotKind::Stream) => {
+                let (ch, tx, rx) = MixedChannel::new(16, 16);
+                self.internal_server_connector
+                    .send(RwStreamSink::new(ch.with_half_close(true)))
+                    .await?;
+                let slot_stats = pending.accepted();
+                write_message(&mut send, &SlotResponse::Accepted).await?;
+                forward_channel(send, recv, tx, rx, slot_stats).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn connect(
+        &self,
+        upstream: &Upstream,
+        balance_key: Option<&str>,
+    ) -> Result<(UpstreamStream, ConnectionGuard), RejectionReason> {
+        let refused = |error_message: String| RejectionReason::ConnectionRefused { error_message };
+
+        let upstream_target = self
+            .client_config
+            .read()
+            .resolve_upstream(upstream, &self.active_profile)
+            .ok_or_else(|| {
+                debug!("error connecting to {:?}. not found in config", upstream);
+                RejectionReason::UpstreamNotFound
+            })?;
+
+        if upstream_target.protocol != UpstreamProtocol::Tcp {
+            return Err(refused(
+                "upstream doesn't accept TCP connections".to_string(),
+            ));
+        }
+
+        let endpoints = resolve_endpoints(&self.resolver, upstream, &upstream_target)
+            .await
+            .map_err(|e| refused(e.to_string()))?;
+
+        let (stream, connection_guard) = self
+            .balancer
+            .connect(
+                upstream,
+                upstream_target.load_balancing,
+                endpoints,
+                balance_key,
+                self.connect_timeout,
+                upstream_target.circuit_breaker.as_ref(),
+                |stream, host| {
+                    self.tls_connectors
+                        .wrap(upstream_target.tls.as_ref(), host, stream)
+                },
+            )
+            .await
+            .map_err(|e| match e {
+                ConnectError::CircuitOpen => RejectionReason::CircuitOpen,
+                e => {
+                    info!("error connecting to upstream {}: {}", upstream, e);
+                    refused(e.to_string())
+                }
+            })?;
+
+        Ok((stream, connection_guard))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config_core::{
+            refinable::Refinable, ClientConfigRevision, UpstreamDefinition, CURRENT_VERSION,
+        },
+        tunnel::handshake::SUPPORTED_PROTOCOL_VERSIONS,
+    };
+    use std::{
+        collections::BTreeMap,
+        net::{IpAddr, SocketAddr},
+    };
+    use tokio::net::TcpListener;
+    use trust_dns_resolver::TokioHandle;
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let resolver = TokioAsyncResolver::from_system_conf(TokioHandle).unwrap();
+        let localhost = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 0);
+
+        // reads the request until EOF, then responds
+        let respond_after_eof = TcpListener::bind(&localhost).await.unwrap();
+        let respond_after_eof_port = respond_after_eof.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = respond_after_eof.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    conn.read_to_end(&mut request).await.unwrap();
+                    request.reverse();
+                    conn.write_all(&request).await.unwrap();
+                });
+            }
+        });
+
+        let mut upstreams = BTreeMap::new();
+        upstreams.insert(
+            "respond-after-eof".parse().unwrap(),
+            UpstreamDefinition::on_default_host(respond_after_eof_port),
+        );
+
+        let client_config = Arc::new(RwLock::new(ClientConfig {
+            version: CURRENT_VERSION.clone(),
+            revision: ClientConfigRevision(1),
+            name: "my-config".parse().unwrap(),
+            mount_points: Default::default(),
+            upstreams,
+            rate_limiters: Default::default(),
+            refinable: Refinable {
+                static_responses: Default::default(),
+                rescue: vec![],
+            },
+        }));
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+        let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+
+        let mut server_builder = quinn::Endpoint::builder();
+        server_builder.listen(
+            quic_server_config(
+                CertificateChain::from_certs(vec![cert_der.clone()]),
+                key,
+                &TunnelSettings::default(),
+            )
+            .unwrap(),
+        );
+        let (server_endpoint, mut incoming) = server_builder.bind(&localhost).unwrap();
+        let server_addr = server_endpoint.local_addr().unwrap();
+
+        let mut client_builder = quinn::Endpoint::builder();
+        client_builder.default_client_config(
+            quic_client_config(Some(cert_der), &TunnelSettings::default()).unwrap(),
+        );
+        let (client_endpoint, _) = client_builder.bind(&localhost).unwrap();
+
+        let (internal_server_connector, _new_conn_rx) = mpsc::channel(1);
+
+        let (reconnect_tx, reconnect_rx) = oneshot::channel();
+        let (client_stats_tx, client_stats_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let hello = TunnelHello {
+                config_name: "config".parse().unwrap(),
+                account_name: "account".parse().unwrap(),
+                project_name: "project".parse().unwrap(),
+                instance_id: Default::default(),
+                jwt_token: "token".into(),
+                protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+                capabilities: Capabilities::quic(),
+                resume: None,
+            };
+            let (response, connection) =
+                quic_connect(&client_endpoint, server_addr, "localhost", &hello)
+                    .await
+                    .unwrap();
+            let negotiated = match response {
+                TunnelHelloResponse::Ok {
+                    protocol_version,
+                    capabilities,
+                    ..
+                } => Negotiated::accept(protocol_version, capabilities).unwrap(),
+                TunnelHelloResponse::Err { msg } => panic!("tunnel rejected: {}", msg),
+            };
+
+            let (listener, client_stats) = quic_client_listener(
+                connection,
+                negotiated,
+                TunnelSettings::default(),
+                client_config,
+                internal_server_connector,
+                &None,
+                resolver,
+                Default::default(),
+            );
+            client_stats_tx.send(client_stats).unwrap();
+            let should_reconnect = listener.await.unwrap();
+            reconnect_tx.send(should_reconnect).unwrap();
+        });
+
+        let quic_hello = quic_accept(incoming.next().await.unwrap()).await.unwrap();
+        let negotiated = quic_hello
+            .hello
+            .negotiate(SUPPORTED_PROTOCOL_VERSIONS, &Capabilities::quic())
+            .unwrap();
+        let connection = quic_hello.connection().clone();
+        let (bg, connector, server_stats) = quic_hello
+            .accept(Default::default(), negotiated, TunnelSettings::default())
+            .await
+            .unwrap();
+        let client_stats = client_stats_rx.await.unwrap();
+        tokio::spawn(bg);
+
+        let half_closed = async {
+            for i in 0..3u8 {
+                let mut conn = connector
+                    .retrieve_connection(
+                        "respond-after-eof.upstream.exg".parse().unwrap(),
+                        Compression::ZstdStream,
+                    )
+                    .await
+                    .unwrap();
+                conn.write_all(&[i, 2, 3]).await.unwrap();
+                conn.shutdown().await.unwrap();
+                let mut response = vec![];
+                conn.read_to_end(&mut response).await.unwrap();
+                assert_eq!(response, vec![3, 2, i]);
+            }
+
+            assert!(connector
+                .retrieve_connection("unknown.upstream.exg".parse().unwrap(), Compression::Plain)
+                .await
+                .is_err());
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), half_closed)
+            .await
+            .unwrap();
+
+        let target = "respond-after-eof.upstream.exg".parse().unwrap();
+        for stats in &[&client_stats, &server_stats] {
+            let target = stats.target(&target);
+            assert_eq!(target.slots_opened(), 3);
+            assert_eq!(target.bytes_sent() + target.bytes_received(), 18);
+        }
+        let unknown = "unknown.upstream.exg".parse().unwrap();
+        assert_eq!(client_stats.target(&unknown).rejections(), 1);
+        assert_eq!(server_stats.target(&unknown).rejections(), 1);
+
+        close_no_reconnect(&connection);
+        assert!(!tokio::time::timeout(Duration::from_secs(5), reconnect_rx)
+            .await
+            .unwrap()
+            .unwrap());
+    }
+}
@@ -0,0 +1,109 @@
+//! Round-trip time of the tunnel, measured with pings.
+//!
+//! Each ping carries the time it was sent, and the peer echoes it back in the pong.
+//! Pongs from older peers are empty and don't produce samples. Neither do pongs to the
+//! pings sent before the resumed session is reattached, since those include the time
+//! the connection was lost.
+
+use parking_lot::Mutex;
+use std::{convert::TryInto, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+/// Weight of the previous estimate in the smoothed RTT, as in RFC 6298
+const SMOOTHING_FACTOR: u32 = 8;
+
+#[derive(Debug, Default)]
+struct Estimate {
+    latest: Option<Duration>,
+    smoothed: Option<Duration>,
+    /// Since `started`, in the precision of the ping payload
+    reattached_at: Duration,
+}
+
+/// Shared handle, updated by the tunnel on each pong
+#[derive(Debug, Clone)]
+pub struct Rtt {
+    started: Instant,
+    estimate: Arc<Mutex<Estimate>>,
+}
+
+impl Default for Rtt {
+    fn default() -> Self {
+        Rtt {
+            started: Instant::now(),
+            estimate: Default::default(),
+        }
+    }
+}
+
+impl Rtt {
+    pub fn latest(&self) -> Option<Duration> {
+        self.estimate.lock().latest
+    }
+
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.estimate.lock().smoothed
+    }
+
+    pub(crate) fn ping_payload(&self) -> Vec<u8> {
+        let sent_at: u64 = self.started.elapsed().as_micros().try_into().unwrap();
+        sent_at.to_be_bytes().to_vec()
+    }
+
+    pub(crate) fn pong_received(&self, payload: &[u8]) {
+        if let Ok(sent_at) = payload.try_into() {
+            let sent_at = Duration::from_micros(u64::from_be_bytes(sent_at));
+            if sent_at < self.estimate.lock().reattached_at {
+                return;
+            }
+            if let Some(rtt) = self.started.elapsed().checked_sub(sent_at) {
+                self.sample(rtt);
+            }
+        }
+    }
+
+    /// Session is attached to the new connection
+    pub fn reattached(&self) {
+        let reattached_at: u64 = self.started.elapsed().as_micros().try_into().unwrap();
+        self.estimate.lock().reattached_at = Duration::from_micros(reattached_at);
+    }
+
+    pub(crate) fn sample(&self, rtt: Duration) {
+        let mut estimate = self.estimate.lock();
+        estimate.latest = Some(rtt);
+        estimate.smoothed = Some(match estimate.smoothed {
+            Some(smoothed) => (smoothed * (SMOOTHING_FACTOR - 1) + rtt) / SMOOTHING_FACTOR,
+            None => rtt,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_rtt() {
+        let rtt = Rtt::default();
+        rtt.pong_received(&[]);
+        assert_eq!(rtt.latest(), None);
+
+        rtt.pong_received(&rtt.ping_payload());
+        assert!(rtt.latest().unwrap() < Duration::from_secs(1));
+
+        let rtt = Rtt::default();
+        let before_reattach = rtt.ping_payload();
+        std::thread::sleep(Duration::from_millis(1));
+        rtt.reattached();
+        rtt.pong_received(&before_reattach);
+        assert_eq!(rtt.latest(), None);
+        rtt.pong_received(&rtt.ping_payload());
+        assert!(rtt.latest().is_some());
+
+        let rtt = Rtt::default();
+        rtt.sample(Duration::from_millis(800));
+        rtt.sample(Duration::from_millis(0));
+        assert_eq!(rtt.latest(), Some(Duration::from_millis(0)));
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(700)));
+    }
+}
@@ -0,0 +1,428 @@
+//! Resumption of tunnel sessions after transient disconnects.
+//!
+//! If resumption is negotiated, the server returns the resume token in
+//! `TunnelHelloResponse`, and the tunnel protocol runs over the `Session`, which
+//! outlives the TLS connection. Each side counts the frames received from the peer and
+//! periodically acknowledges them with `Ack`. The frames, which are not acknowledged
+//! yet, are kept by the sender. Once the connection is lost, the client reconnects with
+//! `ResumeRequest` during `RESUME_WINDOW`, both sides exchange the number of frames
+//! received, and the frames lost in transit are sent again. So the open slots, along
+//! with their compression contexts and flow control windows, survive the reconnect.
+
+use crate::{
+    entities::{SmolStr, TunnelId},
+    tunnel::{
+        proto::{ClientHeader, ClientPacket, CommonHeader, ServerHeader, ServerPacket, Slot},
+        Error,
+    },
+};
+use futures::{
+    channel::mpsc,
+    task::{Context, Poll},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use lru_time_cache::LruCache;
+use parking_lot::Mutex;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
+    io,
+    pin::Pin,
+    time::Duration,
+};
+
+/// Time to reconnect, before the session and all its slots are dropped
+pub const RESUME_WINDOW: Duration = Duration::from_secs(10);
+
+/// Frames received, after which the acknowledgement is sent
+const ACK_FRAMES: u64 = 32;
+
+const RESUME_TOKEN_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeToken(SmolStr);
+
+impl ResumeToken {
+    pub fn generate() -> Self {
+        let token: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(RESUME_TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        ResumeToken(token.into())
+    }
+}
+
+/// Sent by the client in `TunnelHello` to re-attach to the session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeRequest {
+    pub tunnel_id: TunnelId,
+    pub token: ResumeToken,
+    /// Frames received from the server
+    pub received: u64,
+}
+
+/// Packets, which carry acknowledgements of the session
+pub trait SessionPacket: Copy + Send + 'static {
+    fn ack() -> Self;
+
+    fn is_ack(&self) -> bool;
+}
+
+impl SessionPacket for ServerPacket {
+    fn ack() -> Self {
+        ServerPacket {
+            header: ServerHeader::Common(CommonHeader::Ack),
+            slot: Slot::try_from(0u32).unwrap(),
+        }
+    }
+
+    fn is_ack(&self) -> bool {
+        self.header == ServerHeader::Common(CommonHeader::Ack)
+    }
+}
+
+impl SessionPacket for ClientPacket {
+    fn ack() -> Self {
+        ClientPacket {
+            header: ClientHeader::Common(CommonHeader::Ack),
+            slot: Slot::try_from(0u32).unwrap(),
+        }
+    }
+
+    fn is_ack(&self) -> bool {
+        self.header == ClientHeader::Common(CommonHeader::Ack)
+    }
+}
+
+fn encode_ack(received: u64) -> Vec<u8> {
+    received.to_be_bytes().to_vec()
+}
+
+fn decode_ack(payload: &[u8]) -> Result<u64, Error> {
+    let bytes = payload
+        .try_into()
+        .map_err(|_| Error::BadAckPayload(payload.len()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+struct Unacked<O> {
+    /// Frames, acknowledged by the peer
+    acknowledged: u64,
+    frames: VecDeque<(O, Vec<u8>)>,
+}
+
+impl<O> Unacked<O> {
+    /// Forget the frames, which the peer has received
+    fn acknowledge(&mut self, received: u64) -> Result<(), Error> {
+        let sent = self.acknowledged + self.frames.len() as u64;
+        if received < self.acknowledged || received > sent {
+            return Err(Error::BadAcknowledgement(received));
+        }
+
+        self.frames.drain(..(received - self.acknowledged) as usize);
+        self.acknowledged = received;
+
+        Ok(())
+    }
+}
+
+/// Tunnel session, which may be attached to the new connection, once the previous one
+/// is lost. Receives `I` packets from the peer and sends `O`
+pub struct Session<I, O> {
+    /// Frames from the peer to the tunnel protocol
+    incoming_tx: mpsc::Sender<(I, Vec<u8>)>,
+    /// Frames from the tunnel protocol to the peer
+    outgoing_rx: mpsc::Receiver<(O, Vec<u8>)>,
+    unacked: Mutex<Unacked<O>>,
+    /// Frames received from the peer
+    received: u64,
+}
+
+impl<I, O> Session<I, O>
+where
+    I: SessionPacket,
+    O: SessionPacket,
+{
+    /// Returns the session and the tunnel, to run the protocol over
+    pub fn new() -> (Self, SessionTunnel<I, O>) {
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(16);
+
+        let session = Session {
+            incoming_tx,
+            outgoing_rx,
+            unacked: Mutex::new(Unacked {
+                acknowledged: 0,
+                frames: Default::default(),
+            }),
+            received: 0,
+        };
+
+        (
+            session,
+            SessionTunnel {
+                incoming_rx,
+                outgoing_tx,
+            },
+        )
+    }
+
+    /// Frames received from the peer. Sent to it on resume
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Run the session over the connection. `peer_received` is the number of frames the
+    /// peer has received during the previous connections. Returns `Ok` once the tunnel
+    /// protocol is finished, and the error if the connection is lost, so that the session
+    /// may be attached again
+    pub async fn attach<T>(&mut self, transport: T, peer_received: u64) -> Result<(), Error>
+    where
+        T: Stream<Item = Result<(I, Vec<u8>), Error>> + Sink<(O, Vec<u8>), Error = Error>,
+    {
+        let Session {
+            incoming_tx,
+            outgoing_rx,
+            unacked,
+            received,
+        } = self;
+        let unacked = &*unacked;
+
+        let (mut tx, mut rx) = transport.split();
+
+        let lost = {
+            let mut unacked = unacked.lock();
+            unacked.acknowledge(peer_received)?;
+            unacked.frames.iter().cloned().collect::<Vec<_>>()
+        };
+        for frame in lost {
+            tx.feed(frame).await?;
+        }
+        tx.flush().await?;
+
+        let (mut ack_tx, mut ack_rx) = mpsc::channel(1);
+
+        let read = async {
+            while let Some((packet, payload)) = rx.next().await.transpose()? {
+                if packet.is_ack() {
+                    unacked.lock().acknowledge(decode_ack(&payload)?)?;
+                    continue;
+                }
+
+                // not flushed, so that the frame is counted once it's delivered
+                if incoming_tx.feed((packet, payload)).await.is_err() {
+                    // the tunnel protocol is finished
+                    return Ok(());
+                }
+
+                *received += 1;
+                if *received % ACK_FRAMES == 0 {
+                    // acknowledgements are cumulative, so the skipped one is sent with the next
+                    let _ = ack_tx.try_send(*received);
+                }
+            }
+
+            Err::<(), Error>(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "tunnel connection closed").into(),
+            )
+        };
+
+        let write = async {
+            loop {
+                tokio::select! {
+                    maybe_frame = outgoing_rx.next() => match maybe_frame {
+                        Some(frame) => {
+                            unacked.lock().frames.push_back(frame.clone());
+                            tx.send(frame).await?;
+                        }
+                        None => {
+                            tx.close().await?;
+                            return Ok::<(), Error>(());
+                        }
+                    },
+                    Some(received) = ack_rx.next() => {
+                        tx.send((O::ack(), encode_ack(received))).await?;
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            res = read => res,
+            res = write => res,
+        }
+    }
+}
+
+/// Tunnel protocol side of the session
+pub struct SessionTunnel<I, O> {
+    incoming_rx: mpsc::Receiver<(I, Vec<u8>)>,
+    outgoing_tx: mpsc::Sender<(O, Vec<u8>)>,
+}
+
+impl<I, O> Stream for SessionTunnel<I, O> {
+    type Item = Result<(I, Vec<u8>), Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming_rx.poll_next_unpin(cx).map(|r| r.map(Ok))
+    }
+}
+
+impl<I, O> Sink<(O, Vec<u8>)> for SessionTunnel<I, O> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.outgoing_tx.poll_ready_unpin(cx).map_err(From::from)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: (O, Vec<u8>)) -> Result<(), Error> {
+        self.outgoing_tx.start_send_unpin(item).map_err(From::from)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.outgoing_tx.poll_flush_unpin(cx).map_err(From::from)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.outgoing_tx.poll_close_unpin(cx).map_err(From::from)
+    }
+}
+
+/// Sessions of the server, the connections of which are lost. Sessions can't be resumed
+/// after `RESUME_WINDOW`, and are dropped on the next `detach` or `resume`. Their slots
+/// are closed once the protocol gets no pong for `wait_pong_timeout + RESUME_WINDOW`
+pub struct DetachedSessions<I, O> {
+    inner: Mutex<LruCache<TunnelId, (ResumeToken, Session<I, O>)>>,
+}
+
+impl<I, O> Default for DetachedSessions<I, O> {
+    fn default() -> Self {
+        DetachedSessions {
+            inner: Mutex::new(LruCache::with_expiry_duration(RESUME_WINDOW)),
+        }
+    }
+}
+
+impl<I, O> DetachedSessions<I, O> {
+    pub fn detach(&self, tunnel_id: TunnelId, token: ResumeToken, session: Session<I, O>) {
+        self.inner.lock().insert(tunnel_id, (token, session));
+    }
+
+    /// Take the session, if it's not expired and the token matches
+    pub fn resume(&self, request: &ResumeRequest) -> Option<Session<I, O>> {
+        let mut inner = self.inner.lock();
+        match inner.get(&request.tunnel_id) {
+            Some((token, _)) if *token == request.token => {}
+            _ => return None,
+        }
+        inner.remove(&request.tunnel_id).map(|(_, session)| session)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tunnel::{client_framed, server_framed};
+    use tokio::io::duplex;
+
+    fn data(n: u32) -> (ClientPacket, Vec<u8>) {
+        (
+            ClientPacket {
+                header: ClientHeader::Common(CommonHeader::DataPlain),
+                slot: Slot::try_from(1u32).unwrap(),
+            },
+            n.to_be_bytes().to_vec(),
+        )
+    }
+
+    #[tokio::test]
+    pub async fn test_resume() {
+        let (mut client, mut client_tunnel) = Session::<ServerPacket, ClientPacket>::new();
+        let (mut server, mut server_tunnel) = Session::<ClientPacket, ServerPacket>::new();
+
+        let frames = 1000;
+
+        tokio::spawn(async move {
+            for n in 0..frames {
+                client_tunnel.send(data(n)).await.unwrap();
+            }
+        });
+
+        let receive = tokio::spawn(async move {
+            for n in 0..frames {
+                let (packet, payload) = server_tunnel.next().await.unwrap().unwrap();
+                assert_eq!((packet, payload), data(n));
+            }
+            server_tunnel
+        });
+
+        let sessions = DetachedSessions::default();
+        let tunnel_id = TunnelId::default();
+        let token = ResumeToken::generate();
+
+        // each connection is lost after a few frames, with more frames in transit
+        let mut attempts = 0;
+        while server.received() < u64::from(frames) {
+            let (client_side, server_side) = duplex(1024);
+            let server_received = server.received();
+            let client_received = client.received();
+
+            tokio::select! {
+                _ = client.attach(client_framed(client_side), server_received) => {},
+                res = server.attach(server_framed(server_side).take(37), client_received) => {
+                    assert!(res.is_err());
+                },
+            }
+
+            sessions.detach(tunnel_id, token.clone(), server);
+            assert!(sessions
+                .resume(&ResumeRequest {
+                    tunnel_id,
+                    token: ResumeToken::generate(),
+                    received: client.received(),
+                })
+                .is_none());
+            server = sessions
+                .resume(&ResumeRequest {
+                    tunnel_id,
+                    token: token.clone(),
+                    received: client.received(),
+                })
+                .unwrap();
+
+            attempts += 1;
+        }
+        assert!(attempts > 20);
+
+        let _server_tunnel = receive.await.unwrap();
+
+        // the protocol on the client side is finished, so the session is closed
+        let (client_side, server_side) = duplex(64);
+        let server_received = server.received();
+        let client_received = client.received();
+        let (client_res, server_res) = tokio::join!(
+            client.attach(client_framed(client_side), server_received),
+            server.attach(server_framed(server_side), client_received),
+        );
+        assert!(client_res.is_ok());
+        assert!(server_res.is_err());
+    }
+
+    #[test]
+    pub fn test_acknowledge() {
+        let mut unacked = Unacked {
+            acknowledged: 0,
+            frames: (0..5).map(data).collect(),
+        };
+
+        unacked.acknowledge(3).unwrap();
+        assert_eq!(unacked.frames.front(), Some(&data(3)));
+        assert!(unacked.acknowledge(2).is_err());
+        assert!(unacked.acknowledge(6).is_err());
+        unacked.acknowledge(5).unwrap();
+        assert!(unacked.frames.is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+//! Keepalive and timeouts of the tunnel.
+//!
+//! The defaults suit most links. On high-latency links, such as mobile or satellite ones,
+//! pings should be sent less often and the peer should be given more time to answer.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelSettings {
+    /// Interval between pings
+    pub ping_period: Duration,
+
+    /// The tunnel is closed if no pong is received for this long. Extended by
+    /// `RESUME_WINDOW` if resumption is negotiated
+    pub wait_pong_timeout: Duration,
+
+    /// Timeout of connecting to the upstream
+    pub connect_timeout: Duration,
+
+    /// Frames to the slots, recently closed by this side, are ignored for this long
+    pub closed_slot_expiry: Duration,
+}
+
+impl Default for TunnelSettings {
+    fn default() -> Self {
+        TunnelSettings {
+            ping_period: Duration::from_secs(5),
+            wait_pong_timeout: Duration::from_secs(15),
+            connect_timeout: Duration::from_secs(10),
+            closed_slot_expiry: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TunnelSettingsError {
+    #[error("ping period should be less than pong timeout")]
+    PingPeriodNotBelowPongTimeout,
+}
+
+impl TunnelSettings {
+    pub fn validate(&self) -> Result<(), TunnelSettingsError> {
+        if self.ping_period >= self.wait_pong_timeout {
+            return Err(TunnelSettingsError::PingPeriodNotBelowPongTimeout);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_validate() {
+        TunnelSettings::default().validate().unwrap();
+
+        let satellite = TunnelSettings {
+            ping_period: Duration::from_secs(30),
+            ..Default::default()
+        };
+        assert_eq!(
+            satellite.validate(),
+            Err(TunnelSettingsError::PingPeriodNotBelowPongTimeout)
+        );
+        TunnelSettings {
+            wait_pong_timeout: Duration::from_secs(90),
+            ..satellite
+        }
+        .validate()
+        .unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+//! Counters of the tunnel, kept per connect target and exported in the Prometheus text
+//! format.
+//!
+//! Bytes are counted both as the payload of the slots and as sent on the wire, after
+//! compression. Connect latency is measured from the connect request to the slot being
+//! accepted, so on the server it includes the time the client takes to connect upstream.
+
+use crate::{
+    entities::SmolStr,
+    tunnel::{proto::RejectionReason, rtt::Rtt, ConnectTarget},
+};
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Upper bounds of the connect latency histogram buckets, in seconds
+pub const CONNECT_LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const REJECTION_REASONS: [&str; 3] = ["connection_refused", "upstream_not_found", "circuit_open"];
+
+fn rejection_index(reason: &RejectionReason) -> usize {
+    match reason {
+        RejectionReason::ConnectionRefused { .. } => 0,
+        RejectionReason::UpstreamNotFound => 1,
+        RejectionReason::CircuitOpen => 2,
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: CONNECT_LATENCY_BUCKETS
+                .iter()
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(idx) = CONNECT_LATENCY_BUCKETS
+            .iter()
+            .position(|upper_bound| secs <= *upper_bound)
+        {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            value.as_micros().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// Cumulative counts for each of `CONNECT_LATENCY_BUCKETS`
+    pub fn cumulative_buckets(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .scan(0, |acc, bucket| {
+                *acc += bucket.load(Ordering::Relaxed);
+                Some(*acc)
+            })
+            .collect()
+    }
+}
+
+/// Counters of the slots to a single connect target
+#[derive(Debug, Default)]
+pub struct TargetStats {
+    slots_open: AtomicU64,
+    slots_opened: AtomicU64,
+    rejections: [AtomicU64; 3],
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    wire_bytes_sent: AtomicU64,
+    wire_bytes_received: AtomicU64,
+    connect_latency: Histogram,
+}
+
+impl TargetStats {
+    pub fn slots_open(&self) -> u64 {
+        self.slots_open.load(Ordering::Relaxed)
+    }
+
+    pub fn slots_opened(&self) -> u64 {
+        self.slots_opened.load(Ordering::Relaxed)
+    }
+
+    pub fn rejections(&self) -> u64 {
+        self.rejections
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Payload bytes, sent to the peer
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes, received from the peer
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes, sent to the peer after compression
+    pub fn wire_bytes_sent(&self) -> u64 {
+        self.wire_bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes, received from the peer before decompression
+    pub fn wire_bytes_received(&self) -> u64 {
+        self.wire_bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn connect_latency(&self) -> &Histogram {
+        &self.connect_latency
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    targets: Mutex<BTreeMap<SmolStr, Arc<TargetStats>>>,
+    rtt: Rtt,
+}
+
+/// Shared handle to the stats of a tunnel
+#[derive(Debug, Clone, Default)]
+pub struct TunnelStats {
+    inner: Arc<Inner>,
+}
+
+impl TunnelStats {
+    pub fn rtt(&self) -> &Rtt {
+        &self.inner.rtt
+    }
+
+    /// Stats of each connect target, by its hostname
+    pub fn targets(&self) -> Vec<(SmolStr, Arc<TargetStats>)> {
+        self.inner
+            .targets
+            .lock()
+            .iter()
+            .map(|(target, stats)| (target.clone(), stats.clone()))
+            .collect()
+    }
+
+    pub fn target(&self, target: &ConnectTarget) -> Arc<TargetStats> {
+        self.inner
+            .targets
+            .lock()
+            .entry(target.hostname().into())
+            .or_default()
+            .clone()
+    }
+
+    pub fn slots_open(&self) -> u64 {
+        self.targets()
+            .iter()
+            .map(|(_, stats)| stats.slots_open())
+            .sum()
+    }
+
+    /// Wire bytes per payload byte in both directions. `None` until any data is sent
+    pub fn compression_ratio(&self) -> Option<f64> {
+        let (payload, wire) = self
+            .targets()
+            .iter()
+            .fold((0, 0), |(payload, wire), (_, stats)| {
+                (
+                    payload + stats.bytes_sent() + stats.bytes_received(),
+                    wire + stats.wire_bytes_sent() + stats.wire_bytes_received(),
+                )
+            });
+        if payload == 0 {
+            None
+        } else {
+            Some(wire as f64 / payload as f64)
+        }
+    }
+
+    pub(crate) fn slot_requested(&self, target: &ConnectTarget) -> PendingSlot {
+        PendingSlot {
+            target: self.target(target),
+            requested_at: Instant::now(),
+        }
+    }
+
+    pub fn to_prometheus(&self) -> String {
+        encode_prometheus(vec![(&[][..], self)])
+    }
+}
+
+/// Slot, requested but not yet accepted or rejected
+#[derive(Debug)]
+pub(crate) struct PendingSlot {
+    target: Arc<TargetStats>,
+    requested_at: Instant,
+}
+
+impl PendingSlot {
+    pub(crate) fn accepted(self) -> SlotStats {
+        self.target
+            .connect_latency
+            .observe(self.requested_at.elapsed());
+        self.target.slots_opened.fetch_add(1, Ordering::Relaxed);
+        self.target.slots_open.fetch_add(1, Ordering::Relaxed);
+        SlotStats(Arc::new(OpenSlot {
+            target: self.target,
+        }))
+    }
+
+    pub(crate) fn rejected(self, reason: &RejectionReason) {
+        self.target.rejections[rejection_index(reason)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct OpenSlot {
+    target: Arc<TargetStats>,
+}
+
+impl Drop for OpenSlot {
+    fn drop(&mut self) {
+        self.target.slots_open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of the open slot. The slot is counted as open until all the clones are dropped
+#[derive(Debug, Clone)]
+pub(crate) struct SlotStats(Arc<OpenSlot>);
+
+impl SlotStats {
+    pub(crate) fn sent(&self, len: usize, wire_len: usize) {
+        let target = &self.0.target;
+        target.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        target
+            .wire_bytes_sent
+            .fetch_add(wire_len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, wire_len: usize, len: usize) {
+        let target = &self.0.target;
+        target
+            .bytes_received
+            .fetch_add(len as u64, Ordering::Relaxed);
+        target
+            .wire_bytes_received
+            .fetch_add(wire_len as u64, Ordering::Relaxed);
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(&str, &str)], extra: &[(&str, &str)]) -> String {
+    let labels = labels
+        .iter()
+        .chain(extra)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect::<Vec<_>>();
+
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Counters and gauges of a target: name, type, help and value
+type TargetMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&TargetStats) -> u64,
+);
+
+const TARGET_METRICS: &[TargetMetric] = &[
+    (
+        "exogress_tunnel_slots_open",
+        "gauge",
+        "Slots, currently open",
+        TargetStats::slots_open,
+    ),
+    (
+        "exogress_tunnel_slots_opened_total",
+        "counter",
+        "Slots, accepted since the tunnel is established",
+        TargetStats::slots_opened,
+    ),
+    (
+        "exogress_tunnel_sent_bytes_total",
+        "counter",
+        "Payload bytes, sent to the peer",
+        TargetStats::bytes_sent,
+    ),
+    (
+        "exogress_tunnel_received_bytes_total",
+        "counter",
+        "Payload bytes, received from the peer",
+        TargetStats::bytes_received,
+    ),
+    (
+        "exogress_tunnel_sent_wire_bytes_total",
+        "counter",
+        "Bytes, sent to the peer after compression",
+        TargetStats::wire_bytes_sent,
+    ),
+    (
+        "exogress_tunnel_received_wire_bytes_total",
+        "counter",
+        "Bytes, received from the peer before decompression",
+        TargetStats::wire_bytes_received,
+    ),
+];
+
+/// Encode the stats of several tunnels, distinguished by the constant labels, e.g.
+/// the tunnel ID. Each metric family is written once, as required by the format
+pub fn encode_prometheus<'a>(
+    tunnels: impl IntoIterator<Item = (&'a [(&'a str, &'a str)], &'a TunnelStats)>,
+) -> String {
+    let tunnels = tunnels
+        .into_iter()
+        .map(|(labels, stats)| (labels, stats, stats.targets()))
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP exogress_tunnel_rtt_seconds Smoothed round-trip time of the tunnel"
+    );
+    let _ = writeln!(out, "# TYPE exogress_tunnel_rtt_seconds gauge");
+    for (labels, stats, _) in &tunnels {
+        if let Some(rtt) = stats.rtt().smoothed() {
+            let _ = writeln!(
+                out,
+                "exogress_tunnel_rtt_seconds{} {}",
+                format_labels(labels, &[]),
+                rtt.as_secs_f64()
+            );
+        }
+    }
+
+    for (name, kind, help, value) in TARGET_METRICS {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, _, targets) in &tunnels {
+            for (target, stats) in targets {
+                let _ = writeln!(
+                    out,
+                    "{}{} {}",
+                    name,
+                    format_labels(labels, &[("target", target)]),
+                    value(stats)
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP exogress_tunnel_rejections_total Slots, rejected by the client"
+    );
+    let _ = writeln!(out, "# TYPE exogress_tunnel_rejections_total counter");
+    for (labels, _, targets) in &tunnels {
+        for (target, stats) in targets {
+            for (reason, counter) in REJECTION_REASONS.iter().zip(&stats.rejections) {
+                let _ = writeln!(
+                    out,
+                    "exogress_tunnel_rejections_total{} {}",
+                    format_labels(labels, &[("target", target), ("reason", reason)]),
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP exogress_tunnel_connect_latency_seconds Time from the connect request to the slot being accepted"
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE exogress_tunnel_connect_latency_seconds histogram"
+    );
+    for (labels, _, targets) in &tunnels {
+        for (target, stats) in targets {
+            let histogram = stats.connect_latency();
+            for (upper_bound, count) in CONNECT_LATENCY_BUCKETS
+                .iter()
+                .zip(histogram.cumulative_buckets())
+            {
+                let _ = writeln!(
+                    out,
+                    "exogress_tunnel_connect_latency_seconds_bucket{} {}",
+                    format_labels(
+                        labels,
+                        &[("target", target), ("le", &upper_bound.to_string())]
+                    ),
+                    count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "exogress_tunnel_connect_latency_seconds_bucket{} {}",
+                format_labels(labels, &[("target", target), ("le", "+Inf")]),
+                histogram.count()
+            );
+            let _ = writeln!(
+                out,
+                "exogress_tunnel_connect_latency_seconds_sum{} {}",
+                format_labels(labels, &[("target", target)]),
+                histogram.sum().as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "exogress_tunnel_connect_latency_seconds_count{} {}",
+                format_labels(labels, &[("target", target)]),
+                histogram.count()
+            );
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_slot_stats() {
+        let stats = TunnelStats::default();
+        let upstream: ConnectTarget = "upstream.upstream.exg".parse().unwrap();
+
+        stats
+            .slot_requested(&upstream)
+            .rejected(&RejectionReason::CircuitOpen);
+        let slot = stats.slot_requested(&upstream).accepted();
+        slot.sent(100, 20);
+        slot.received(30, 50);
+        assert_eq!(stats.slots_open(), 1);
+        assert_eq!(stats.compression_ratio(), Some(50.0 / 150.0));
+
+        let slot_clone = slot.clone();
+        drop(slot);
+        assert_eq!(stats.slots_open(), 1);
+        drop(slot_clone);
+        assert_eq!(stats.slots_open(), 0);
+
+        let target = stats.target(&upstream);
+        assert_eq!(target.slots_opened(), 1);
+        assert_eq!(target.rejections(), 1);
+        assert_eq!(target.bytes_sent(), 100);
+        assert_eq!(target.wire_bytes_received(), 30);
+        assert_eq!(target.connect_latency().count(), 1);
+    }
+
+    #[test]
+    pub fn test_prometheus() {
+        let stats = TunnelStats::default();
+        let upstream: ConnectTarget = "upstream.upstream.exg".parse().unwrap();
+        let slot = stats.slot_requested(&upstream).accepted();
+        slot.sent(10, 10);
+
+        let other = TunnelStats::default();
+        other
+            .slot_requested(&upstream)
+            .rejected(&RejectionReason::UpstreamNotFound);
+
+        let encoded = encode_prometheus(vec![
+            (&[("tunnel", "a")][..], &stats),
+            (&[("tunnel", "b\"")][..], &other),
+        ]);
+
+        assert_eq!(
+            encoded
+                .lines()
+                .filter(|line| line.starts_with("# TYPE exogress_tunnel_slots_open "))
+                .count(),
+            1
+        );
+        assert!(encoded.contains(
+            "exogress_tunnel_slots_open{tunnel=\"a\",target=\"upstream.upstream.exg\"} 1\n"
+        ));
+        assert!(encoded.contains(
+            "exogress_tunnel_sent_bytes_total{tunnel=\"a\",target=\"upstream.upstream.exg\"} 10\n"
+        ));
+        assert!(encoded.contains("exogress_tunnel_rejections_total{tunnel=\"b\\\"\",target=\"upstream.upstream.exg\",reason=\"upstream_not_found\"} 1\n"));
+        assert!(encoded.contains("exogress_tunnel_connect_latency_seconds_bucket{tunnel=\"a\",target=\"upstream.upstream.exg\",le=\"+Inf\"} 1\n"));
+        assert!(encoded.contains("exogress_tunnel_connect_latency_seconds_bucket{tunnel=\"a\",target=\"upstream.upstream.exg\",le=\"10\"} 1\n"));
+        assert!(!encoded.contains("exogress_tunnel_rtt_seconds{"));
+    }
+}
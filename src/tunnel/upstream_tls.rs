@@ -0,0 +1,278 @@
+//! TLS connections to upstreams
+
+use crate::{
+    common_utils::tls::load_native_certs_safe, config_core::UpstreamTls, entities::SmolStr,
+    tunnel::balancer::EndpointStream,
+};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use std::{fs::File, io, io::BufReader, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client::TlsStream, webpki::DNSNameRef, TlsConnector};
+use tokio_util::either::Either;
+
+/// Connection to the upstream, either plain or over TLS
+pub type UpstreamStream = Either<TlsStream<EndpointStream>, EndpointStream>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpstreamTlsConnectorError {
+    #[error("could not read {path}: {error}")]
+    Read { path: String, error: io::Error },
+
+    #[error("no certificates found in {0}")]
+    NoCertificates(String),
+
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+
+    #[error("TLS error: {0}")]
+    Tls(#[from] TLSError),
+}
+
+/// Server name for SNI, when the upstream host is not a DNS name and the certificate
+/// is not verified
+const FALLBACK_SERVER_NAME: &str = "localhost";
+
+/// Accepts any server certificate, for `insecure-skip-verify`
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, UpstreamTlsConnectorError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| UpstreamTlsConnectorError::Read {
+            path: path.to_string(),
+            error,
+        })
+}
+
+/// Connector, configured according to the upstream TLS settings
+#[derive(Clone)]
+pub struct UpstreamTlsConnector {
+    connector: TlsConnector,
+    server_name: Option<SmolStr>,
+    insecure_skip_verify: bool,
+}
+
+impl UpstreamTlsConnector {
+    pub fn new(tls: &UpstreamTls) -> Result<Self, UpstreamTlsConnectorError> {
+        let mut config = ClientConfig::new();
+
+        if tls.insecure_skip_verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        } else if let Some(ca_bundle) = &tls.ca_bundle {
+            let (valid, _) = config
+                .root_store
+                .add_pem_file(&mut open(ca_bundle)?)
+                .map_err(|_| UpstreamTlsConnectorError::NoCertificates(ca_bundle.clone()))?;
+            if valid == 0 {
+                return Err(UpstreamTlsConnectorError::NoCertificates(ca_bundle.clone()));
+            }
+        } else {
+            load_native_certs_safe(&mut config);
+        }
+
+        if let Some(client_certificate) = &tls.client_certificate {
+            let chain = certs(&mut open(&client_certificate.certificate)?)
+                .ok()
+                .filter(|chain| !chain.is_empty())
+                .ok_or_else(|| {
+                    UpstreamTlsConnectorError::NoCertificates(
+                        client_certificate.certificate.clone(),
+                    )
+                })?;
+
+            let key_path = &client_certificate.private_key;
+            let key = pkcs8_private_keys(&mut open(key_path)?)
+                .ok()
+                .and_then(|mut keys| keys.pop())
+                .or_else(|| {
+                    rsa_private_keys(&mut open(key_path).ok()?)
+                        .ok()
+                        .and_then(|mut keys| keys.pop())
+                })
+                .ok_or_else(|| UpstreamTlsConnectorError::NoPrivateKey(key_path.clone()))?;
+
+            config.set_single_client_cert(chain, key)?;
+        }
+
+        Ok(UpstreamTlsConnector {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: tls.server_name.clone(),
+            insecure_skip_verify: tls.insecure_skip_verify,
+        })
+    }
+
+    /// Establish TLS session over the stream. `host` is used as a server name,
+    /// unless it's overridden in settings
+    pub async fn connect<IO>(&self, host: &str, stream: IO) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.as_str(),
+            None if self.insecure_skip_verify && DNSNameRef::try_from_ascii_str(host).is_err() => {
+                FALLBACK_SERVER_NAME
+            }
+            None => host,
+        };
+        let dns_name = DNSNameRef::try_from_ascii_str(server_name).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad TLS server name `{}`", server_name),
+            )
+        })?;
+
+        self.connector.connect(dns_name, stream).await
+    }
+}
+
+/// Connectors, built for each distinct TLS settings, so that certificates are not
+/// loaded on every connection
+#[derive(Default, Clone)]
+pub struct UpstreamTlsConnectors {
+    inner: Arc<Mutex<HashMap<UpstreamTls, UpstreamTlsConnector>>>,
+}
+
+impl UpstreamTlsConnectors {
+    pub fn get(
+        &self,
+        tls: &UpstreamTls,
+    ) -> Result<UpstreamTlsConnector, UpstreamTlsConnectorError> {
+        if let Some(connector) = self.inner.lock().get(tls) {
+            return Ok(connector.clone());
+        }
+
+        let connector = UpstreamTlsConnector::new(tls)?;
+        self.inner.lock().insert(tls.clone(), connector.clone());
+
+        Ok(connector)
+    }
+
+    /// Wrap the connection into TLS, if it's configured for the upstream
+    pub async fn wrap(
+        &self,
+        tls: Option<&UpstreamTls>,
+        host: SmolStr,
+        stream: EndpointStream,
+    ) -> io::Result<UpstreamStream> {
+        match tls {
+            Some(tls) => {
+                let connector = self
+                    .get(tls)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                Ok(Either::Left(connector.connect(&host, stream).await?))
+            }
+            None => Ok(Either::Right(stream)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::{NoClientAuth, PrivateKey, ServerConfig};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    #[tokio::test]
+    async fn test_loopback_handshake() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let ca_bundle =
+            std::env::temp_dir().join(format!("exogress-ca-{}.pem", std::process::id()));
+        std::fs::write(&ca_bundle, cert.serialize_pem().unwrap()).unwrap();
+
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config
+            .set_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(stream).await {
+                        let _ = stream.write_all(b"hello").await;
+                        let _ = stream.shutdown().await;
+                    }
+                });
+            }
+        });
+
+        let connectors = UpstreamTlsConnectors::default();
+        let connect = |tls: UpstreamTls| {
+            let connectors = connectors.clone();
+            async move {
+                let stream: EndpointStream = Box::new(TcpStream::connect(addr).await?);
+                let mut stream = connectors
+                    .wrap(Some(&tls), "127.0.0.1".into(), stream)
+                    .await?;
+                let mut buf = String::new();
+                stream.read_to_string(&mut buf).await?;
+                Ok::<_, io::Error>(buf)
+            }
+        };
+        let tls = UpstreamTls {
+            server_name: Some("localhost".into()),
+            ca_bundle: Some(ca_bundle.to_str().unwrap().to_string()),
+            client_certificate: None,
+            insecure_skip_verify: false,
+        };
+
+        assert_eq!(connect(tls.clone()).await.unwrap(), "hello");
+
+        assert!(connect(UpstreamTls {
+            server_name: Some("other.example.com".into()),
+            ..tls.clone()
+        })
+        .await
+        .is_err());
+
+        // IP address is not a valid server name, unless verification is skipped
+        assert!(connect(UpstreamTls {
+            server_name: None,
+            ..tls.clone()
+        })
+        .await
+        .is_err());
+        assert_eq!(
+            connect(UpstreamTls {
+                server_name: None,
+                ca_bundle: None,
+                client_certificate: None,
+                insecure_skip_verify: true,
+            })
+            .await
+            .unwrap(),
+            "hello"
+        );
+
+        let _ = std::fs::remove_file(&ca_bundle);
+    }
+}